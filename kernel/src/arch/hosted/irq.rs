@@ -3,14 +3,53 @@
 
 use xous_kernel::{PID, TID};
 
-pub fn enable_irq(_irq_no: usize) {
-    unimplemented!()
-}
+// Hosted mode has no interrupt controller. An interrupt can still be claimed,
+// which is only bookkeeping, and tests raise mock interrupts by calling
+// `irq::handle()` themselves.
+
+pub fn enable_irq(_irq_no: usize) {}
 
 pub fn disable_irq(_irq_no: usize) -> Result<(), xous_kernel::Error> {
-    unimplemented!()
+    Ok(())
+}
+
+/// A process can't be called into in hosted mode, so there's no handler to
+/// jump to.
+#[cfg(not(test))]
+pub fn make_callback(
+    _pid: PID,
+    _pc: *const usize,
+    _irq_no: usize,
+    _arg: *mut usize,
+) -> Result<(), xous_kernel::Error> {
+    Err(xous_kernel::Error::UnhandledSyscall)
+}
+
+#[cfg(test)]
+lazy_static::lazy_static! {
+    /// The handlers `irq::handle()` would have jumped to, by process and IRQ
+    pub static ref MOCK_CALLBACKS: std::sync::Mutex<Vec<(PID, usize)>> =
+        std::sync::Mutex::new(Vec::new());
+}
+
+/// Notes the handler in `MOCK_CALLBACKS` in place of jumping to it
+#[cfg(test)]
+pub fn make_callback(
+    pid: PID,
+    _pc: *const usize,
+    irq_no: usize,
+    _arg: *mut usize,
+) -> Result<(), xous_kernel::Error> {
+    MOCK_CALLBACKS.lock().unwrap().push((pid, irq_no));
+    Ok(())
 }
 
 pub unsafe fn set_isr_return_pair(_pid: PID, _ctx: TID) {
     unimplemented!()
 }
+
+/// Return the time for interrupt latency measurements, in microseconds.
+#[cfg(feature = "irq-latency")]
+pub fn latency_timestamp() -> u64 {
+    super::START_TIME.elapsed().as_micros() as u64
+}
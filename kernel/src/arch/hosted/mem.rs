@@ -3,6 +3,7 @@
 
 pub const PAGE_SIZE: usize = 4096;
 use crate::mem::MemoryManager;
+use core::convert::TryInto;
 use xous_kernel::{Error, MemoryFlags, PID};
 
 pub const DEFAULT_HEAP_BASE: usize = 0x2000_0000;
@@ -109,10 +110,20 @@ pub fn unmap_page_inner(_mm: &mut MemoryManager, virt: usize) -> Result<usize, E
     Ok(virt)
 }
 
+pub fn guard_page_inner(_mm: &mut MemoryManager, _virt: usize) -> Result<(), Error> {
+    Ok(())
+}
+
 pub fn hand_page_to_user(_virt: *mut u8) -> Result<(), Error> {
     unimplemented!()
 }
 
+/// Hosted processes allocate their own mappings, already zeroed, so there's
+/// nothing for the kernel to defer.
+pub fn hand_page_to_user_unzeroed(_virt: *mut u8) -> Result<(), Error> {
+    Ok(())
+}
+
 pub fn virt_to_phys(virt: usize) -> Result<usize, Error> {
     Ok(virt)
 }
@@ -123,4 +134,94 @@ pub fn page_flags(_virt: usize) -> Option<MemoryFlags> {
 
 pub fn update_page_flags(_virt: usize, _flags: MemoryFlags) -> Result<(), xous_kernel::Error> {
     Ok(())
-}
\ No newline at end of file
+}
+
+std::thread_local!(
+    static CALL_MEMORY: core::cell::Cell<Option<xous_kernel::MemoryRange>> = core::cell::Cell::new(None)
+);
+
+/// Handle a syscall with `f`, noting the buffer that came along with it, if
+/// any. In a hosted environment the kernel has its own copy of it, and that's
+/// all of the process's memory the kernel can see while handling the call.
+pub fn with_call_memory<R>(range: Option<xous_kernel::MemoryRange>, f: impl FnOnce() -> R) -> R {
+    CALL_MEMORY.with(|mem| mem.set(range));
+    let result = f();
+    CALL_MEMORY.with(|mem| mem.set(None));
+    result
+}
+
+/// Check that the `len` bytes at `start` lie within the current call's buffer.
+fn check_user_range(start: usize, len: usize) -> Result<(), Error> {
+    let end = start.checked_add(len).ok_or(Error::BadAddress)?;
+    if len == 0 {
+        return Ok(());
+    }
+    match CALL_MEMORY.with(|mem| mem.get()) {
+        Some(mem) if start >= mem.as_ptr() as usize && end <= mem.as_ptr() as usize + mem.len() => {
+            Ok(())
+        }
+        _ => Err(Error::BadAddress),
+    }
+}
+
+/// Copy `dst.len()` bytes from address `src` of the current process into `dst`.
+///
+/// # Errors
+///
+/// * BadAddress - Some of the range is outside the buffer sent with the call
+pub fn copy_from_user(src: usize, dst: &mut [u8]) -> Result<(), Error> {
+    check_user_range(src, dst.len())?;
+    dst.copy_from_slice(unsafe { core::slice::from_raw_parts(src as *const u8, dst.len()) });
+    Ok(())
+}
+
+/// Copy `src` to address `dst` of the current process, to be sent back to it
+/// along with the syscall's result.
+///
+/// # Errors
+///
+/// * BadAddress - Some of the range is outside the buffer sent with the call
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), Error> {
+    check_user_range(dst, src.len())?;
+    unsafe { core::slice::from_raw_parts_mut(dst as *mut u8, src.len()) }.copy_from_slice(src);
+    Ok(())
+}
+
+/// Zero `len` bytes at address `dst` of the current process.
+///
+/// # Errors
+///
+/// * BadAddress - Some of the range is outside the buffer sent with the call
+pub fn clear_user(dst: usize, len: usize) -> Result<(), Error> {
+    check_user_range(dst, len)?;
+    unsafe { (dst as *mut u8).write_bytes(0, len) };
+    Ok(())
+}
+
+/// Copy the words in `range` into `words`.
+pub fn read_user_words(range: &xous_kernel::MemoryRange, words: &mut [usize]) -> Result<(), Error> {
+    if range.len() % core::mem::size_of::<usize>() != 0 {
+        return Err(Error::BadAlignment);
+    }
+    assert_eq!(words.len() * core::mem::size_of::<usize>(), range.len());
+    let mut bytes = vec![0u8; range.len()];
+    copy_from_user(range.as_ptr() as usize, &mut bytes)?;
+    for (word, chunk) in words
+        .iter_mut()
+        .zip(bytes.chunks_exact(core::mem::size_of::<usize>()))
+    {
+        *word = usize::from_ne_bytes(chunk.try_into().unwrap());
+    }
+    Ok(())
+}
+
+/// Copy `words` back over `range`, to be sent back to the process along with
+/// the syscall's result.
+pub fn write_user_words(range: &xous_kernel::MemoryRange, words: &[usize]) -> Result<(), Error> {
+    if range.len() % core::mem::size_of::<usize>() != 0 {
+        return Err(Error::BadAlignment);
+    }
+    assert_eq!(words.len() * core::mem::size_of::<usize>(), range.len());
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_ne_bytes()).collect();
+    copy_to_user(range.as_ptr() as usize, &bytes)
+}
@@ -22,13 +22,16 @@ use crossbeam_channel::{unbounded, Receiver, RecvError, RecvTimeoutError, Sender
 use xous_kernel::{ProcessInit, ProcessKey, Result, SysCall, ThreadInit, PID, TID};
 
 enum ThreadMessage {
-    SysCall(PID, TID, SysCall),
+    /// A call made by a thread of a process, on the connection numbered as
+    /// the `usize`
+    SysCall(PID, usize, TID, SysCall),
     NewConnection(TcpStream, ProcessKey),
 }
 
 #[derive(Debug)]
 enum NewPidMessage {
-    NewPid(PID),
+    /// The PID of the process that connected, and the number of its connection
+    NewPid(PID, usize),
 }
 
 #[derive(Debug)]
@@ -85,10 +88,23 @@ pub fn current_pid() -> PID {
     crate::arch::process::current_pid()
 }
 
+lazy_static::lazy_static! {
+    static ref START_TIME: std::time::Instant = std::time::Instant::now();
+}
+
+/// Return the number of milliseconds since the kernel first asked for the time.
+pub fn uptime_ms() -> u64 {
+    START_TIME.elapsed().as_millis() as u64
+}
+
 /// Each client gets its own connection and its own thread, which is handled here.
+/// Calls are tagged with `connection`, so that any still in flight once the
+/// process has been terminated can be told apart from those of a new process
+/// given the same PID.
 fn handle_connection(
     conn: TcpStream,
     pid: PID,
+    connection: usize,
     chn: Sender<ThreadMessage>,
     should_exit: std::sync::Arc<core::sync::atomic::AtomicBool>,
 ) {
@@ -98,7 +114,12 @@ fn handle_connection(
     //     ServerPacketWithData([usize; 9], Vec<u8>),
     // }
 
-    fn conn_thread(mut conn: TcpStream, sender: Sender<ThreadMessage>, pid: PID) {
+    fn conn_thread(
+        mut conn: TcpStream,
+        sender: Sender<ThreadMessage>,
+        pid: PID,
+        connection: usize,
+    ) {
         loop {
             let mut raw_data = [0u8; 9 * std::mem::size_of::<usize>()];
 
@@ -174,7 +195,7 @@ fn handle_connection(
             }
 
             sender
-                .send(ThreadMessage::SysCall(pid, thread_id, call))
+                .send(ThreadMessage::SysCall(pid, connection, thread_id, call))
                 .unwrap();
         }
     }
@@ -185,7 +206,7 @@ fn handle_connection(
     let conn_thread = std::thread::Builder::new()
         .name(format!("PID {}: client connection thread", pid))
         .spawn(move || {
-            conn_thread(conn, conn_sender, pid);
+            conn_thread(conn, conn_sender, pid, connection);
         })
         .unwrap();
 
@@ -318,6 +339,7 @@ fn handle_connection(
     );
     chn.send(ThreadMessage::SysCall(
         pid,
+        connection,
         1,
         xous_kernel::SysCall::TerminateProcess(0),
     ))
@@ -367,7 +389,7 @@ fn listen_thread(
         .expect("couldn't request a new PID");
 
         // The kernel will immediately respond with a new PID.
-        let NewPidMessage::NewPid(new_pid) = new_pid_channel
+        let NewPidMessage::NewPid(new_pid, connection) = new_pid_channel
             .recv()
             .expect("couldn't receive message from main thread");
         // println!("KERNEL({}): New client connected from {}", new_pid, _addr);
@@ -375,7 +397,7 @@ fn listen_thread(
         let should_exit = should_exit.clone();
         let jh = std::thread::Builder::new()
             .name(format!("kernel PID {} listener", new_pid))
-            .spawn(move || handle_connection(conn, new_pid, thr_chn, should_exit))
+            .spawn(move || handle_connection(conn, new_pid, connection, thr_chn, should_exit))
             .expect("couldn't spawn listen thread");
         clients.push((jh, conn_copy));
         false
@@ -471,6 +493,17 @@ fn listen_thread(
     exit_server(should_exit, clients);
 }
 
+/// Does what the timer interrupt does on baremetal: terminates the processes
+/// that missed their watchdogs, then wakes the threads whose sleep is up.
+/// Processes here all run at once, so none of them is the one interrupted.
+fn timer_expired() {
+    SystemServices::with_mut(|ss| {
+        ss.expire_watchdogs(None);
+        ss.wake_sleepers()
+    })
+    .expect("couldn't wake sleeping threads");
+}
+
 /// The idle function is run when there are no directly-runnable processes
 /// that kmain can activate. In a hosted environment,this is the primary
 /// thread that handles network communications, and this function never returns.
@@ -484,6 +517,7 @@ pub fn idle() -> bool {
     let pid1_key = PID1_KEY.with(|p1k| *p1k.borrow());
     let pid1_init = ProcessInit {
         key: ProcessKey::new(pid1_key),
+        page_quota: 0,
     };
     let process_1 = SystemServices::with_mut(|ss| ss.create_process(pid1_init)).unwrap();
     assert_eq!(process_1.pid().get(), 1);
@@ -534,6 +568,7 @@ pub fn idle() -> bool {
             let process_key = generate_pid_key();
             let init = xous_kernel::ProcessInit {
                 key: ProcessKey::new(process_key),
+                page_quota: 0,
             };
             let new_process = SystemServices::with_mut(|ss| ss.create_process(init)).unwrap();
             println!(" {:^5} |  {}", new_process, arg);
@@ -543,12 +578,36 @@ pub fn idle() -> bool {
         }
     }
 
-    while let Ok(msg) = message_receiver.recv() {
+    loop {
+        // Don't block past the next sleeping thread's wakeup or watchdog deadline, even if
+        // nothing else happens.
+        let msg = match SystemServices::with(|ss| ss.next_timer()) {
+            Some(wake_at) => {
+                let wait = wake_at.saturating_sub(uptime_ms());
+                match message_receiver.recv_timeout(std::time::Duration::from_millis(wait)) {
+                    // A steady stream of messages mustn't hold sleepers up
+                    Ok(msg) if wait == 0 => {
+                        timer_expired();
+                        msg
+                    }
+                    Ok(msg) => msg,
+                    Err(RecvTimeoutError::Timeout) => {
+                        timer_expired();
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match message_receiver.recv() {
+                Ok(msg) => msg,
+                Err(_) => break,
+            },
+        };
         match msg {
             ThreadMessage::NewConnection(conn, access_key) => {
                 // The new process should already have a PID registered. Convert its access key
                 // into a PID, and register the connection with the server.
-                let new_pid =
+                let (new_pid, connection) =
                     crate::arch::process::register_connection_for_key(conn, access_key).unwrap();
                 // println!(
                 //     "KERNEL: Access key {:?} mapped to PID {}",
@@ -557,7 +616,7 @@ pub fn idle() -> bool {
 
                 // Inform the backchannel of the new process ID.
                 new_pid_sender
-                    .send(NewPidMessage::NewPid(new_pid))
+                    .send(NewPidMessage::NewPid(new_pid, connection))
                     .expect("couldn't send new pid to new connection");
 
                 // conn.write_all(&new_pid.get().to_le_bytes())
@@ -574,16 +633,32 @@ pub fn idle() -> bool {
                     .unwrap();
                 }
             }
-            ThreadMessage::SysCall(pid, thread_id, call) => {
+            ThreadMessage::SysCall(pid, connection, thread_id, call) => {
                 // let measurement_start = std::time::Instant::now();
                 // println!("KERNEL({}): Received syscall {:?}", pid, call);
+
+                // If the process was terminated by its parent, its connection was shut
+                // down, but calls it made before then, and the connection thread's report
+                // of the closed socket, may still come in. Its PID may even belong to a
+                // new process by now. There's no one to answer, and nothing left to clean up.
+                if !crate::arch::process::is_current_connection(pid, connection) {
+                    continue;
+                }
                 crate::arch::process::set_current_pid(pid);
+                Process::current().enter_kernel(thread_id);
                 // println!("KERNEL({}): Now running as the new process", pid);
 
                 // If the call being made is to terminate the current process, we need to know
                 // because we won't be able to send a response.
                 let is_terminate = call == SysCall::TerminateProcess(0);
                 let is_shutdown = call == SysCall::Shutdown;
+                // A batch's results are written over the calls in its buffer, which goes
+                // back to the process whatever the outcome
+                let batch = if let SysCall::Batch(range) = &call {
+                    Some(*range)
+                } else {
+                    None
+                };
 
                 // For a "Shutdown" command, send the response before we issue the shutdown.
                 // This is because the "process" will be "terminated" (the network socket will be closed),
@@ -609,8 +684,10 @@ pub fn idle() -> bool {
                 }
 
                 // Handle the syscall within the Xous kernel
-                let response = crate::syscall::handle(pid, thread_id, false, call)
-                    .unwrap_or_else(Result::Error);
+                let response = crate::arch::mem::with_call_memory(call.memory(), || {
+                    crate::syscall::handle(pid, thread_id, false, call)
+                })
+                .unwrap_or_else(Result::Error);
 
                 // println!("KERNEL({}): Syscall response {:?}", pid, response);
                 // There's a response if it wasn't a blocked process and we're not terminating.
@@ -638,6 +715,10 @@ pub fn idle() -> bool {
                         let s = unsafe { core::slice::from_raw_parts(mem.as_ptr(), mem.len()) };
                         response_vec.extend_from_slice(s);
                     }
+                    if let Some(mem) = batch {
+                        let s = unsafe { core::slice::from_raw_parts(mem.as_ptr(), mem.len()) };
+                        response_vec.extend_from_slice(s);
+                    }
                     process.send(&response_vec).unwrap_or_else(|_e| {
                         // If we're unable to send data to the process, assume it's dead and terminate it.
                         eprintln!(
@@ -648,6 +729,15 @@ pub fn idle() -> bool {
                             .ok();
                     });
                     crate::arch::process::set_current_pid(existing_pid);
+                    // The connection thread boxed up the batch's buffer when it came in
+                    if let Some(mem) = batch {
+                        drop(unsafe {
+                            Box::from_raw(core::ptr::slice_from_raw_parts_mut(
+                                mem.as_mut_ptr(),
+                                mem.len(),
+                            ))
+                        });
+                    }
                     // println!(
                     //     "KERNEL [{:2}:{:2}] Syscall took {:7} usec",
                     //     pid,
@@ -28,6 +28,9 @@ struct ProcessImpl {
     /// The network connection to the client process.
     conn: Option<TcpStream>,
 
+    /// The number `register_connection_for_key()` gave `conn`
+    connection: usize,
+
     /// Memory that may need to be returned to the caller for each thread
     memory_to_return: [Option<Vec<u8>>; MAX_THREAD + 1],
 
@@ -38,6 +41,14 @@ struct ProcessImpl {
 
     /// The currently-active thread for this process
     current_thread: TID,
+
+    /// When each thread was last handed a syscall result. Every process runs
+    /// concurrently here, so a thread is considered to be running from then
+    /// until it makes its next syscall.
+    resumed_at: [Option<std::time::Instant>; MAX_THREAD + 1],
+
+    /// Total time this process' threads have spent running
+    cpu_time: std::time::Duration,
 }
 
 impl PartialEq for Process {
@@ -53,6 +64,9 @@ struct ProcessTable {
     /// The number of processes that exist
     total: usize,
 
+    /// The number of connections registered so far
+    connections: usize,
+
     /// The actual table contents
     table: Vec<Option<ProcessImpl>>,
 }
@@ -61,6 +75,7 @@ thread_local!(
     static PROCESS_TABLE: RefCell<ProcessTable> = RefCell::new(ProcessTable {
         current: unsafe { PID::new_unchecked(1) },
         total: 0,
+        connections: 0,
         table: Vec::new(),
     })
 );
@@ -91,18 +106,35 @@ pub fn set_current_pid(pid: PID) {
     });
 }
 
+/// Return how long the given process has spent running, in microseconds.
+pub fn cpu_time(pid: PID) -> u64 {
+    PROCESS_TABLE.with(|pt| {
+        let process_table = pt.borrow();
+        process_table
+            .table
+            .get(pid.get() as usize - 1)
+            .and_then(|p| p.as_ref())
+            .map(|p| p.cpu_time.as_micros() as u64)
+            .unwrap_or(0)
+    })
+}
+
 pub fn register_connection_for_key(
     mut conn: TcpStream,
     key: ProcessKey,
-) -> Result<PID, xous_kernel::Error> {
+) -> Result<(PID, usize), xous_kernel::Error> {
     PROCESS_TABLE.with(|pt| {
         let mut process_table = pt.borrow_mut();
+        let process_table = &mut *process_table;
+        let connection = process_table.connections + 1;
         for (pid_minus_1, process) in process_table.table.iter_mut().enumerate() {
             if let Some(process) = process.as_mut() {
                 if process.key == key && process.conn.is_none() {
                     conn.write_all(&[pid_minus_1 as u8 + 1]).unwrap();
                     process.conn = Some(conn);
-                    return Ok(PID::new(pid_minus_1 as u8 + 1).unwrap());
+                    process.connection = connection;
+                    process_table.connections = connection;
+                    return Ok((PID::new(pid_minus_1 as u8 + 1).unwrap(), connection));
                 }
             }
         }
@@ -110,6 +142,19 @@ pub fn register_connection_for_key(
     })
 }
 
+/// Whether `connection`, as numbered by `register_connection_for_key()`, is
+/// still the one `pid` is reached through. It isn't once the process has been
+/// destroyed, even if its PID has since gone to another process, so anything
+/// still coming in on it is from a process that's gone.
+pub fn is_current_connection(pid: PID, connection: usize) -> bool {
+    PROCESS_TABLE.with(|pt| {
+        matches!(
+            pt.borrow().table.get(pid.get() as usize - 1),
+            Some(Some(process)) if process.conn.is_some() && process.connection == connection
+        )
+    })
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 /// Everything required to keep track of a single thread of execution.
@@ -273,10 +318,39 @@ impl Process {
         })
     }
 
+    /// The number of threads that currently exist in this process.
+    pub fn thread_count(&self) -> usize {
+        PROCESS_TABLE.with(|pt| {
+            let process_table = pt.borrow();
+            let current_pid_idx = process_table.current.get() as usize - 1;
+            let process = process_table.table[current_pid_idx].as_ref().unwrap();
+            process.threads.iter().filter(|t| t.allocated).count()
+        })
+    }
+
+    /// Note that `tid` has just made a syscall, and charge the time since it
+    /// was last resumed to this process.
+    pub fn enter_kernel(&mut self, tid: TID) {
+        PROCESS_TABLE.with(|pt| {
+            let mut process_table = pt.borrow_mut();
+            let current_pid_idx = process_table.current.get() as usize - 1;
+            let process = process_table.table[current_pid_idx].as_mut().unwrap();
+            if let Some(Some(resumed_at)) = process.resumed_at.get_mut(tid).map(|r| r.take()) {
+                process.cpu_time += resumed_at.elapsed();
+            }
+        })
+    }
+
     pub fn thread_exists(&self, _tid: TID) -> bool {
         false
     }
 
+    /// Threads are joined by the host in this environment, so no return values
+    /// are ever kept around.
+    pub fn take_thread_exit_value(&mut self, _tid: TID) -> Option<usize> {
+        None
+    }
+
     pub fn set_thread_result(&mut self, tid: TID, result: xous_kernel::Result) {
         assert!(tid > 0);
         PROCESS_TABLE.with(|pt| {
@@ -349,10 +423,13 @@ impl Process {
             let process = ProcessImpl {
                 inner: Default::default(),
                 conn: None,
+                connection: 0,
                 key: init_data.key,
                 memory_to_return: filled_array![None; 32 /* MAX_THREAD */],
                 current_thread: INITIAL_TID,
                 threads: [Thread { allocated: false }; MAX_THREAD + 1],
+                resumed_at: [None; MAX_THREAD + 1],
+                cpu_time: std::time::Duration::default(),
             };
 
             process_table.total += 1;
@@ -395,6 +472,14 @@ impl Process {
             let process = &mut process_table.table[current_pid_idx].as_mut().unwrap();
             let conn = process.conn.as_mut().unwrap();
             conn.write_all(bytes).unwrap();
+
+            // Every response starts with the thread it's destined for, which
+            // is now free to run again.
+            let mut tid_bytes = [0u8; core::mem::size_of::<TID>()];
+            tid_bytes.copy_from_slice(&bytes[..core::mem::size_of::<TID>()]);
+            if let Some(resumed_at) = process.resumed_at.get_mut(TID::from_le_bytes(tid_bytes)) {
+                *resumed_at = Some(std::time::Instant::now());
+            }
             // conn.flush().unwrap();
         });
         Ok(())
@@ -10,6 +10,9 @@ use crate::services::SystemServices;
 use riscv::register::{scause, sepc, sstatus, stval, vexriscv::sim, vexriscv::sip};
 use xous_kernel::{SysCall, PID, TID};
 
+/// `scause` of a supervisor timer interrupt, as armed by `set_wakeup()`
+const SUPERVISOR_TIMER_INTERRUPT: usize = (1 << (usize::BITS - 1)) | 5;
+
 extern "Rust" {
     fn _xous_syscall_return_result(result: &xous_kernel::Result, context: &Thread) -> !;
 }
@@ -28,17 +31,64 @@ pub extern "C" fn enable_all_irqs() {
     sim::write(unsafe { SIM_BACKING });
 }
 
+/// Whether an interrupt handler is running. All interrupts are masked while it
+/// does, and the mask to restore when it returns is in `SIM_BACKING`, so only
+/// that may be changed: writing the live mask would unmask interrupts
+/// underneath the handler.
+fn in_isr() -> bool {
+    unsafe { PREVIOUS_PAIR.is_some() }
+}
+
 pub fn enable_irq(irq_no: usize) {
     // Note that the vexriscv "IRQ Mask" register is inverse-logic --
     // that is, setting a bit in the "mask" register unmasks (i.e. enables) it.
-    sim::write(sim::read() | (1 << irq_no));
+    if !in_isr() {
+        sim::write(sim::read() | (1 << irq_no));
+    }
+    unsafe { SIM_BACKING |= 1 << irq_no };
 }
 
 pub fn disable_irq(irq_no: usize) -> Result<(), xous_kernel::Error> {
-    sim::write(sim::read() & !(1 << irq_no));
+    if !in_isr() {
+        sim::write(sim::read() & !(1 << irq_no));
+    }
+    unsafe { SIM_BACKING &= !(1 << irq_no) };
     Ok(())
 }
 
+/// Return the time for interrupt latency measurements, in `time` CSR ticks.
+#[cfg(feature = "irq-latency")]
+pub fn latency_timestamp() -> u64 {
+    riscv::register::time::read64()
+}
+
+/// Return the interrupts asserted right now, masked or not. `sip` only shows
+/// what `sim` lets through, so the mask is opened for the read. This is only
+/// called on trap entry, where `sstatus.SIE` is clear, so nothing is taken
+/// while it's open.
+#[cfg(feature = "irq-latency")]
+fn irqs_asserted() -> usize {
+    let mask = sim::read();
+    sim::write(!0);
+    let asserted = sip::read();
+    sim::write(mask);
+    asserted
+}
+
+/// Disable all other IRQs and redirect into the handler for `irq_no` in
+/// userspace. The handler returns to `RETURN_FROM_ISR`.
+pub fn make_callback(
+    pid: PID,
+    pc: *const usize,
+    irq_no: usize,
+    arg: *mut usize,
+) -> Result<(), xous_kernel::Error> {
+    SystemServices::with_mut(|ss| {
+        disable_all_irqs();
+        ss.make_callback_to(pid, pc, irq_no, arg)
+    })
+}
+
 static mut PREVIOUS_PAIR: Option<(PID, TID)> = None;
 
 pub unsafe fn set_isr_return_pair(pid: PID, tid: TID) {
@@ -123,6 +173,10 @@ pub extern "C" fn trap_handler(
     a6: usize,
     a7: usize,
 ) -> ! {
+    // Stamp the interrupts waiting on the kernel before anything else runs
+    #[cfg(feature = "irq-latency")]
+    crate::irq::latency_trap_entered(irqs_asserted());
+
     let sc = scause::read();
 
     // If we were previously in Supervisor mode and we've just tried to write to
@@ -180,27 +234,6 @@ pub extern "C" fn trap_handler(
         // or returning from a handler or thread. If so, handle the exception
         // and return right away.
         match ex {
-            RiscvException::StorePageFault(_pc, addr)
-            | RiscvException::LoadPageFault(_pc, addr) => {
-                #[cfg(all(feature = "debug-print", feature = "print-panics"))]
-                print!(
-                    "KERNEL({}): RISC-V fault: {} @ {:08x}, addr {:08x} - ",
-                    pid, ex, _pc, addr
-                );
-                crate::arch::mem::ensure_page_exists_inner(addr)
-                    .map(|_new_page| {
-                        #[cfg(all(feature = "debug-print", feature = "print-panics"))]
-                        println!("Handing page {:08x} to process", _new_page);
-                        ArchProcess::with_current_mut(|process| {
-                            crate::arch::syscall::resume(
-                                current_pid().get() == 1,
-                                process.current_thread(),
-                            )
-                        });
-                    })
-                    .ok(); // If this fails, fall through.
-            }
-
             RiscvException::InstructionPageFault(RETURN_FROM_EXCEPTION_HANDLER, _offset) => {
                 // This address indicates the exception handler
                 SystemServices::with_mut(|ss| {
@@ -238,6 +271,8 @@ pub extern "C" fn trap_handler(
             }
 
             RiscvException::InstructionPageFault(RETURN_FROM_ISR, _offset) => {
+                #[cfg(feature = "irq-latency")]
+                crate::irq::latency_returned(latency_timestamp());
                 // If we hit this address, then an ISR has just returned.  Since
                 // we're in an interrupt context, it is safe to access this
                 // global variable.
@@ -264,6 +299,31 @@ pub extern "C" fn trap_handler(
                 });
             }
 
+            // A fetch can be the first touch of a page too, so instruction
+            // faults that aren't one of the addresses above are backed the
+            // same way as loads and stores.
+            RiscvException::StorePageFault(_pc, addr)
+            | RiscvException::LoadPageFault(_pc, addr)
+            | RiscvException::InstructionPageFault(_pc, addr) => {
+                #[cfg(all(feature = "debug-print", feature = "print-panics"))]
+                print!(
+                    "KERNEL({}): RISC-V fault: {} @ {:08x}, addr {:08x} - ",
+                    pid, ex, _pc, addr
+                );
+                crate::arch::mem::ensure_page_exists_inner(addr)
+                    .map(|_new_page| {
+                        #[cfg(all(feature = "debug-print", feature = "print-panics"))]
+                        println!("Handing page {:08x} to process", _new_page);
+                        ArchProcess::with_current_mut(|process| {
+                            crate::arch::syscall::resume(
+                                current_pid().get() == 1,
+                                process.current_thread(),
+                            )
+                        });
+                    })
+                    .ok(); // If this fails, fall through.
+            }
+
             _ => (),
         }
 
@@ -343,6 +403,18 @@ pub extern "C" fn trap_handler(
         ArchProcess::with_current_mut(|process| {
             crate::arch::syscall::resume(current_pid().get() == 1, process.current_thread())
         })
+    } else if sc.bits() == SUPERVISOR_TIMER_INTERRUPT {
+        // A sleeping thread's time is up, or a process missed its watchdog.
+        // Waking a thread only makes it ready, and the timer is pointed at the
+        // next deadline, so the interrupted thread carries on, unless its own
+        // process was the one terminated, in which case its parent runs.
+        SystemServices::with_mut(|ss| ss.wake_sleepers()).expect("couldn't wake sleeping threads");
+        if SystemServices::with_mut(|ss| ss.expire_watchdogs(Some(pid))) {
+            crate::syscall::reset_switchto_caller();
+        }
+        ArchProcess::with_current_mut(|process| {
+            crate::arch::syscall::resume(current_pid().get() == 1, process.current_thread())
+        })
     } else {
         let irqs_pending = sip::read();
         // println!("irqs: {:x}", irqs_pending);
@@ -0,0 +1,58 @@
+// SPDX-FileCopyrightText: 2020 Sean Cross <sean@xobs.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Leaf page table entries for pages of main memory that are zeroed when the
+//! process first touches them, rather than when they're mapped. These are
+//! plain operations on the entry, with nothing else of the MMU in them, so
+//! that they can be tested off the target.
+
+// The Sv32 entry bits these deal in; see `MMUFlags`.
+const VALID: usize = 0x1;
+const USER: usize = 0x10;
+const A: usize = 0x40;
+const D: usize = 0x80;
+const SHARED: usize = 0x100;
+
+/// The entry for a valid page handed to the user without being zeroed. It
+/// keeps pointing at its physical page, but isn't valid, so the first access
+/// faults. Until then, neither the process nor the stale data behind it can
+/// be reached through it. `None` if the page isn't valid to begin with.
+pub fn unzeroed(entry: usize) -> Option<usize> {
+    if entry & VALID == 0 {
+        return None;
+    }
+    Some((entry | USER) & !VALID)
+}
+
+/// Whether `entry` is a page handed over by `unzeroed()` that hasn't been
+/// touched yet. Pages that are lent out are also not valid, but are marked as
+/// shared; reserved pages have no physical page behind them yet.
+pub fn awaiting_zero(entry: usize) -> bool {
+    entry & (VALID | SHARED) == 0 && entry >> 10 != 0
+}
+
+/// The physical address of the page behind `entry`
+pub fn phys(entry: usize) -> usize {
+    (entry >> 10) << 12
+}
+
+/// Zeroes a page on the first touch after `unzeroed()`, and lets the process
+/// at it. The page is only mapped for the kernel while `zero` clears it, and
+/// `flush` makes each change to the entry take. Returns the physical address
+/// of the page, or `None` if the entry isn't awaiting a zero, in which case
+/// nothing is done.
+pub fn zero_on_first_touch(
+    entry: &mut usize,
+    flush: impl Fn(),
+    zero: impl FnOnce(),
+) -> Option<usize> {
+    if !awaiting_zero(*entry) {
+        return None;
+    }
+    *entry = (*entry | VALID | D | A) & !USER;
+    flush();
+    zero();
+    *entry |= USER;
+    flush();
+    Some(phys(*entry))
+}
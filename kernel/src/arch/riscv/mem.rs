@@ -1,9 +1,10 @@
 // SPDX-FileCopyrightText: 2020 Sean Cross <sean@xobs.io>
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::arch::lazy_zero;
 use crate::mem::MemoryManager;
 use core::fmt;
-use riscv::register::satp;
+use riscv::register::{satp, sstatus};
 use xous_kernel::{MemoryFlags, PID};
 
 // pub const DEFAULT_STACK_TOP: usize = 0x8000_0000;
@@ -447,6 +448,15 @@ pub fn hand_page_to_user(virt: *mut u8) -> Result<(), xous_kernel::Error> {
     Ok(())
 }
 
+/// Hand a page of main memory to the user without zeroing it first. The
+/// first access faults, and `ensure_page_exists_inner()` zeroes the page then.
+pub fn hand_page_to_user_unzeroed(virt: *mut u8) -> Result<(), xous_kernel::Error> {
+    let entry = pagetable_entry(virt as usize)?;
+    *entry = lazy_zero::unzeroed(*entry).ok_or(xous_kernel::Error::BadAddress)?;
+    unsafe { flush_mmu() };
+    Ok(())
+}
+
 pub fn peek_memory<T>(addr: *mut T) -> Result<T, xous_kernel::Error> {
     let virt = addr as usize;
     let vpn1 = (virt >> 22) & ((1 << 10) - 1);
@@ -544,6 +554,113 @@ pub fn poke_memory<T>(addr: *mut T, val: T) -> Result<(), xous_kernel::Error> {
     Ok(())
 }
 
+/// Check that the `len` bytes at `start` are memory belonging to the current
+/// process that it may read, and also write if `writable`, backing any pages
+/// that are only reserved so far.
+fn check_user_range(start: usize, len: usize, access: MMUFlags) -> Result<(), xous_kernel::Error> {
+    let end = start
+        .checked_add(len)
+        .ok_or(xous_kernel::Error::BadAddress)?;
+    if end > USER_AREA_END {
+        return Err(xous_kernel::Error::BadAddress);
+    }
+    let needed = (MMUFlags::VALID | MMUFlags::USER | access).bits();
+    let mut page = start & !(PAGE_SIZE - 1);
+    while page < end {
+        ensure_page_exists_inner(page)?;
+        let flags = *pagetable_entry(page)?;
+        // Lent pages belong to someone else until they come back
+        if flags & needed != needed || flags & MMUFlags::S.bits() != 0 {
+            return Err(xous_kernel::Error::BadAddress);
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Copy `dst.len()` bytes from address `src` of the current process into `dst`.
+///
+/// # Errors
+///
+/// * BadAddress - Some of the range isn't readable memory belonging to the
+///                process
+pub fn copy_from_user(src: usize, dst: &mut [u8]) -> Result<(), xous_kernel::Error> {
+    check_user_range(src, dst.len(), MMUFlags::R)?;
+    // Supervisor mode may only touch user pages while SUM is set
+    unsafe {
+        sstatus::set_sum();
+        dst.copy_from_slice(core::slice::from_raw_parts(src as *const u8, dst.len()));
+        sstatus::clear_sum();
+    }
+    Ok(())
+}
+
+/// Copy `src` to address `dst` of the current process.
+///
+/// # Errors
+///
+/// * BadAddress - Some of the range isn't writable memory belonging to the
+///                process
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), xous_kernel::Error> {
+    check_user_range(dst, src.len(), MMUFlags::R | MMUFlags::W)?;
+    unsafe {
+        sstatus::set_sum();
+        core::slice::from_raw_parts_mut(dst as *mut u8, src.len()).copy_from_slice(src);
+        sstatus::clear_sum();
+    }
+    Ok(())
+}
+
+/// Zero `len` bytes at address `dst` of the current process. This is the
+/// kernel clearing out memory it has just handed over, so the process needn't
+/// be able to write to it itself.
+///
+/// # Errors
+///
+/// * BadAddress - Some of the range isn't memory belonging to the process
+pub fn clear_user(dst: usize, len: usize) -> Result<(), xous_kernel::Error> {
+    check_user_range(dst, len, MMUFlags::NONE)?;
+    unsafe {
+        sstatus::set_sum();
+        (dst as *mut u8).write_bytes(0, len);
+        sstatus::clear_sum();
+    }
+    Ok(())
+}
+
+/// Check that `range` is word-aligned; the copies check the rest.
+fn check_user_words(range: &xous_kernel::MemoryRange) -> Result<(), xous_kernel::Error> {
+    let word = core::mem::size_of::<usize>();
+    if range.as_ptr() as usize & (word - 1) != 0 || range.len() & (word - 1) != 0 {
+        return Err(xous_kernel::Error::BadAlignment);
+    }
+    Ok(())
+}
+
+/// Copy the words in `range` of the current process's memory into `words`,
+/// for a syscall that hands the kernel a buffer.
+pub fn read_user_words(
+    range: &xous_kernel::MemoryRange,
+    words: &mut [usize],
+) -> Result<(), xous_kernel::Error> {
+    check_user_words(range)?;
+    assert_eq!(words.len() * core::mem::size_of::<usize>(), range.len());
+    let bytes =
+        unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, range.len()) };
+    copy_from_user(range.as_ptr() as usize, bytes)
+}
+
+/// Copy `words` back over `range` of the current process's memory.
+pub fn write_user_words(
+    range: &xous_kernel::MemoryRange,
+    words: &[usize],
+) -> Result<(), xous_kernel::Error> {
+    check_user_words(range)?;
+    assert_eq!(words.len() * core::mem::size_of::<usize>(), range.len());
+    let bytes = unsafe { core::slice::from_raw_parts(words.as_ptr() as *const u8, range.len()) };
+    copy_to_user(range.as_ptr() as usize, bytes)
+}
+
 /// Map the given page to the specified process table.  If necessary,
 /// allocate a new page.
 ///
@@ -673,6 +790,16 @@ pub fn unmap_page_inner(_mm: &mut MemoryManager, virt: usize) -> Result<usize, x
     Ok(phys)
 }
 
+/// Mark the page at `virt` as a guard page. The entry has neither the Valid bit
+/// nor any permissions, so accessing it faults instead of allocating a fresh page,
+/// but it is nonzero so the address is never handed out to anything else.
+pub fn guard_page_inner(_mm: &mut MemoryManager, virt: usize) -> Result<(), xous_kernel::Error> {
+    let entry = pagetable_entry(virt)?;
+    *entry = MMUFlags::P.bits();
+    unsafe { flush_mmu() };
+    Ok(())
+}
+
 /// Move a page from one address space to another.
 pub fn move_page_inner(
     mm: &mut MemoryManager,
@@ -860,6 +987,11 @@ pub fn virt_to_phys(virt: usize) -> Result<usize, xous_kernel::Error> {
 
     // Ensure the entry hasn't already been mapped.
     if l0_pt.entries[vpn0] & MMUFlags::VALID.bits() == 0 {
+        // The memory is backed, but won't be zeroed until it's first touched.
+        if lazy_zero::awaiting_zero(l0_pt.entries[vpn0]) {
+            return Ok(lazy_zero::phys(l0_pt.entries[vpn0]));
+        }
+
         // The memory has been reserved, but isn't pointing anywhere yet.
         if l0_pt.entries[vpn0] != 0 {
             return Err(xous_kernel::Error::MemoryInUse);
@@ -886,9 +1018,23 @@ pub fn ensure_page_exists_inner(address: usize) -> Result<usize, xous_kernel::Er
         return Ok(address);
     }
 
+    // A page that already has a physical page behind it, but isn't valid, was
+    // handed over by `hand_page_to_user_unzeroed()`. Zero it while only the
+    // kernel can see it, then let the process at it.
+    if let Some(phys) = lazy_zero::zero_on_first_touch(
+        entry,
+        || unsafe { flush_mmu() },
+        || unsafe {
+            memset(virt as *mut u8, 0, PAGE_SIZE);
+        },
+    ) {
+        return Ok(phys);
+    }
+
     // If the flags are nonzero, but the "Valid" bit is not 1 and
     // the page isn't shared, then this is a reserved page. Allocate
-    // a real page to back it and resume execution.
+    // a real page to back it and resume execution. Guard pages only
+    // carry the P bit, which is masked off above, so they fault here.
     if flags == 0 || flags & MMUFlags::S.bits() != 0 {
         return Err(xous_kernel::Error::BadAddress);
     }
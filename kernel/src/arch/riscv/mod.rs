@@ -5,6 +5,7 @@ use riscv::register::{satp, sie, sstatus};
 
 pub mod exception;
 pub mod irq;
+pub mod lazy_zero;
 pub mod mem;
 pub mod process;
 pub mod rand;
@@ -29,6 +30,43 @@ pub fn current_pid() -> PID {
     PID::new(satp::read().asid() as _).unwrap()
 }
 
+/// How many ticks of the `time` CSR make up a millisecond. It counts at the
+/// system clock, whose frequency comes from the SoC description.
+const TIME_TICKS_PER_MS: u64 = LITEX_CONFIG_CLOCK_FREQUENCY as u64 / 1_000;
+
+/// Return the number of milliseconds since the system started.
+pub fn uptime_ms() -> u64 {
+    riscv::register::time::read64() / TIME_TICKS_PER_MS
+}
+
+/// Raise a supervisor timer interrupt once `uptime_ms()` reaches `at_ms`, or
+/// stop raising them if `at_ms` is `None`. The compare register is
+/// `stimecmp`, which counts in the same ticks as `time`.
+pub fn set_wakeup(at_ms: Option<u64>) {
+    let ticks = at_ms.map_or(u64::MAX, |ms| ms.saturating_mul(TIME_TICKS_PER_MS));
+    unsafe {
+        // Park the low half at its maximum while the high half changes, so
+        // the compare value never passes through a time earlier than both
+        // the old and the new one.
+        #[cfg(target_arch = "riscv32")]
+        core::arch::asm!(
+            "csrw 0x14D, {max}",
+            "csrw 0x15D, {hi}",
+            "csrw 0x14D, {lo}",
+            max = in(reg) usize::MAX,
+            hi = in(reg) (ticks >> 32) as usize,
+            lo = in(reg) ticks as usize,
+        );
+        #[cfg(target_arch = "riscv64")]
+        core::arch::asm!("csrw 0x14D, {0}", in(reg) ticks as usize);
+        if at_ms.is_some() {
+            sie::set_stimer();
+        } else {
+            sie::clear_stimer();
+        }
+    }
+}
+
 pub fn init() {
     MemoryManager::with_mut(|memory_manager| {
         memory_manager
@@ -66,9 +66,13 @@ struct ProcessImpl {
     /// The last thread ID that was allocated
     last_tid_allocated: u8,
 
+    /// Bitmask of threads that have exited but whose return value
+    /// has not yet been collected with `JoinThread`.
+    finished_threads: u32,
+
     /// Pad everything to 128 bytes, so the Thread slice starts at
     /// offset 128.
-    _padding: [u32; 13],
+    _padding: [u32; 12],
 
     /// This enables the kernel to keep track of threads in the
     /// target process, and know which threads are ready to
@@ -91,6 +95,12 @@ static mut PROCESS_TABLE: ProcessTable = ProcessTable {
     table: [false; MAX_PROCESS_COUNT],
 };
 
+/// Time each process has spent as the current process, in `time` CSR ticks.
+static mut CPU_TIME: [u64; MAX_PROCESS_COUNT] = [0; MAX_PROCESS_COUNT];
+
+/// The `time` CSR value when the current process was last switched to.
+static mut SWITCHED_AT: u64 = 0;
+
 #[repr(C)]
 #[cfg(baremetal)]
 /// The stage1 bootloader sets up some initial processes.  These are reported
@@ -197,6 +207,17 @@ impl Process {
         self.thread(tid).sepc != 0
     }
 
+    /// If the given thread has exited and nobody has joined it yet, return its
+    /// return value. This may only be collected once.
+    pub fn take_thread_exit_value(&mut self, tid: TID) -> Option<usize> {
+        let process = unsafe { &mut *PROCESS };
+        if tid >= process.threads.len() || process.finished_threads & (1 << tid) == 0 {
+            return None;
+        }
+        process.finished_threads &= !(1 << tid);
+        Some(process.threads[tid].registers[9])
+    }
+
     /// Set the current thread number.
     pub fn set_tid(&mut self, thread: TID) -> Result<(), xous_kernel::Error> {
         let mut process = unsafe { &mut *PROCESS };
@@ -244,6 +265,13 @@ impl Process {
         }
     }
 
+    /// The number of threads that currently exist in this process.
+    pub fn thread_count(&self) -> usize {
+        let mut count = 0;
+        self.for_each_thread_mut(|_, _| count += 1);
+        count
+    }
+
     pub fn find_free_thread(&self) -> Option<TID> {
         let process = unsafe { &mut *PROCESS };
         let start_tid = process.last_tid_allocated as usize;
@@ -321,6 +349,7 @@ impl Process {
                 pid
             );
             PROCESS_TABLE.table[pid_idx] = true;
+            CPU_TIME[pid_idx] = 0;
         }
 
         // By convention, thread 0 is the trap thread. Therefore, thread 1 is
@@ -380,6 +409,8 @@ impl Process {
         setup: ThreadInit,
     ) -> Result<(), xous_kernel::Error> {
         let entrypoint = unsafe { core::mem::transmute::<_, usize>(setup.call) };
+        // This context may be reused from a thread that was never joined.
+        unsafe { (*PROCESS).finished_threads &= !(1 << new_tid) };
         // Create the new context and set it to run in the new address space.
         let pid = self.pid.get();
         let thread = self.thread_mut(new_tid);
@@ -388,6 +419,9 @@ impl Process {
         if sp <= 16 {
             return Err(xous_kernel::Error::BadAddress);
         }
+        // Leave the lowest page of the stack unmapped so an overflow faults.
+        let guard = crate::mem::stack_guard_page(setup.stack.as_ptr() as usize, setup.stack.len())?;
+        crate::mem::MemoryManager::with_mut(|memory_manager| memory_manager.guard_page(guard))?;
         crate::arch::syscall::invoke(
             thread,
             pid == 1,
@@ -399,7 +433,8 @@ impl Process {
         Ok(())
     }
 
-    /// Destroy a given thread and return its return value.
+    /// Destroy a given thread and return its return value. The return value is
+    /// kept around so it may later be collected with `take_thread_exit_value()`.
     ///
     /// # Returns
     ///     The return value of the function
@@ -427,6 +462,8 @@ impl Process {
             *val = 0;
         }
         thread.sepc = 0;
+        thread.registers[9] = return_value;
+        unsafe { (*PROCESS).finished_threads |= 1 << tid };
 
         Ok(return_value)
     }
@@ -527,7 +564,7 @@ impl Process {
             server_id_array[3] as _,
         );
 
-        Self::setup_process(pid, initial_thread).unwrap();
+        Self::setup_process(pid, initial_thread)?;
 
         services.create_server_with_address(pid, server_id, false)?;
 
@@ -596,10 +633,29 @@ pub fn set_current_pid(pid: PID) {
             None | Some(false) => panic!("PID {} does not exist", pid),
             _ => (),
         }
+
+        // Charge the time since the last switch to the outgoing process.
+        if pt.current != pid {
+            let now = riscv::register::time::read64();
+            CPU_TIME[pt.current.get() as usize - 1] += now.wrapping_sub(SWITCHED_AT);
+            SWITCHED_AT = now;
+        }
         pt.current = pid;
     }
 }
 
+/// Return how long the given process has spent running, in `time` CSR ticks.
+pub fn cpu_time(pid: PID) -> u64 {
+    let pid_idx = pid.get() as usize - 1;
+    unsafe {
+        let mut total = CPU_TIME[pid_idx];
+        if PROCESS_TABLE.current == pid {
+            total += riscv::register::time::read64().wrapping_sub(SWITCHED_AT);
+        }
+        total
+    }
+}
+
 pub fn current_pid() -> PID {
     unsafe { PROCESS_TABLE.current }
 }
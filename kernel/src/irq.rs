@@ -4,11 +4,153 @@
 use crate::arch;
 use xous_kernel::{MemoryAddress, PID};
 
-static mut IRQ_HANDLERS: [Option<(PID, MemoryAddress, Option<MemoryAddress>)>; 32] = [None; 32];
+/// The process, callback, argument, and priority of a claimed interrupt.
+pub type IrqHandler = (PID, MemoryAddress, Option<MemoryAddress>, usize);
 
-#[cfg(baremetal)]
+static mut IRQ_HANDLERS: [Option<IrqHandler>; 32] = [None; 32];
+
+/// A bitmask of claimed interrupts that their owners have masked with
+/// `SetInterruptEnabled`.
+static mut IRQS_DISABLED: usize = 0;
+
+/// How quickly one interrupt has been getting to its handler. Times are in
+/// ticks of `arch::irq::latency_timestamp()`.
+///
+/// The hardware doesn't note when an interrupt is asserted, so latency runs
+/// from the entry of the first trap the kernel takes with the interrupt
+/// asserted to the jump to its handler. Each trap looks, whether it's the
+/// interrupt's own or a syscall, fault, timer tick or handler return, so time
+/// the interrupt spends masked behind another handler is counted from that
+/// handler's next trap. Time between the assertion and the first trap isn't
+/// seen at all, so the figures are lower bounds.
+#[cfg(feature = "irq-latency")]
+#[derive(Copy, Clone)]
+struct IrqLatency {
+    /// Entry of the first trap that saw the interrupt asserted, if its handler
+    /// hasn't been jumped to since
+    pending_since: Option<u64>,
+    count: u64,
+    max_latency: u64,
+    total_latency: u64,
+    max_handler: u64,
+    total_handler: u64,
+}
+
+#[cfg(feature = "irq-latency")]
+impl IrqLatency {
+    const fn new() -> IrqLatency {
+        IrqLatency {
+            pending_since: None,
+            count: 0,
+            max_latency: 0,
+            total_latency: 0,
+            max_handler: 0,
+            total_handler: 0,
+        }
+    }
+}
+
+#[cfg(feature = "irq-latency")]
+static mut IRQ_LATENCY: [IrqLatency; 32] = [IrqLatency::new(); 32];
+
+/// The interrupt whose handler is running, and when it was jumped to. Only one
+/// handler runs at a time, as all interrupts are disabled until it returns.
+#[cfg(feature = "irq-latency")]
+static mut IRQ_IN_FLIGHT: Option<(usize, u64)> = None;
+
+/// Note the interrupts asserted at the entry of a trap, masked or not. Called
+/// first thing on every trap. Only the first trap to see an interrupt counts,
+/// so one left pending behind a higher priority one, or masked while another
+/// handler runs, keeps its original time. An interrupt that isn't asserted any
+/// more, or that its owner has masked, loses its time, as the owner's own
+/// masking isn't the kernel's latency.
+#[cfg(feature = "irq-latency")]
+pub fn latency_trap_entered(irqs_asserted: usize) {
+    let now = arch::irq::latency_timestamp();
+    unsafe {
+        for (irq_no, latency) in IRQ_LATENCY.iter_mut().enumerate() {
+            let waiting = irqs_asserted & (1 << irq_no) != 0
+                && IRQS_DISABLED & (1 << irq_no) == 0
+                && IRQ_HANDLERS[irq_no].is_some();
+            if !waiting {
+                latency.pending_since = None;
+            } else if latency.pending_since.is_none() {
+                latency.pending_since = Some(now);
+            }
+        }
+    }
+}
+
+/// Note that the handler for `irq_no` is being jumped to at `now`.
+#[cfg(feature = "irq-latency")]
+pub fn latency_dispatched(irq_no: usize, now: u64) {
+    unsafe {
+        let latency = &mut IRQ_LATENCY[irq_no];
+        let waited = now.saturating_sub(latency.pending_since.take().unwrap_or(now));
+        latency.count += 1;
+        latency.total_latency += waited;
+        latency.max_latency = latency.max_latency.max(waited);
+        IRQ_IN_FLIGHT = Some((irq_no, now));
+    }
+}
+
+/// Note that the running handler returned at `now`.
+#[cfg(feature = "irq-latency")]
+pub fn latency_returned(now: u64) {
+    unsafe {
+        if let Some((irq_no, dispatched)) = IRQ_IN_FLIGHT.take() {
+            let latency = &mut IRQ_LATENCY[irq_no];
+            let ran = now.saturating_sub(dispatched);
+            latency.total_handler += ran;
+            latency.max_handler = latency.max_handler.max(ran);
+        }
+    }
+}
+
+/// Return how quickly the kernel has been getting to the handler for `irq`
+/// since it was claimed.
+#[cfg(feature = "irq-latency")]
+pub fn interrupt_latency(irq: usize) -> Result<xous_kernel::InterruptLatency, xous_kernel::Error> {
+    unsafe {
+        let latency = IRQ_LATENCY
+            .get(irq)
+            .ok_or(xous_kernel::Error::InterruptNotFound)?;
+        let count = latency.count.max(1);
+        Ok(xous_kernel::InterruptLatency {
+            count: latency.count as usize,
+            max_latency: latency.max_latency as usize,
+            avg_latency: (latency.total_latency / count) as usize,
+            max_handler: latency.max_handler as usize,
+            avg_handler: (latency.total_handler / count) as usize,
+        })
+    }
+}
+
+/// Pick which of the pending interrupts to service next. Higher priorities
+/// win, and the lowest IRQ number breaks ties. Interrupts in `irqs_disabled`
+/// are left pending until they are enabled again.
+#[allow(dead_code)] // only called in baremetal mode
+pub fn next_pending_irq(
+    irqs_pending: usize,
+    irqs_disabled: usize,
+    handlers: &[Option<IrqHandler>],
+) -> Option<usize> {
+    let mut next: Option<(usize, usize)> = None;
+    for (irq_no, handler) in handlers.iter().enumerate() {
+        if irqs_pending & (1 << irq_no) == 0 || irqs_disabled & (1 << irq_no) != 0 {
+            continue;
+        }
+        if let Some((_, _, _, priority)) = handler {
+            if next.map(|(_, p)| *priority > p).unwrap_or(true) {
+                next = Some((irq_no, *priority));
+            }
+        }
+    }
+    next.map(|(irq_no, _)| irq_no)
+}
+
+#[allow(dead_code)] // only called in baremetal mode, and by tests in hosted mode
 pub fn handle(irqs_pending: usize) -> Result<xous_kernel::Result, xous_kernel::Error> {
-    use crate::services::SystemServices;
     // Unsafe is required here because we're accessing a static
     // mutable value, and it could be modified from various threads.
     // However, this is fine because this is run from an IRQ context
@@ -17,29 +159,32 @@ pub fn handle(irqs_pending: usize) -> Result<xous_kernel::Result, xous_kernel::E
     // so this should be protected by a mutex.
     unsafe {
         for (irq_no, handler) in IRQ_HANDLERS.iter().enumerate() {
-            if irqs_pending & (1 << irq_no) != 0 {
-                if let Some((pid, f, arg)) = handler {
-                    return SystemServices::with_mut(|ss| {
-                        // Disable all other IRQs and redirect into userspace
-                        arch::irq::disable_all_irqs();
-                        // println!("Making a callback to PID{}: {:x?} ({:08x}, {:x?})", pid, f, irq_no as usize, arg);
-                        ss.make_callback_to(
-                            *pid,
-                            f.get() as *mut usize,
-                            irq_no,
-                            arg.map(|x| x.get() as *mut usize)
-                                .unwrap_or(core::ptr::null_mut::<usize>()),
-                        )
-                        .map(|_| xous_kernel::Result::ResumeProcess)
-                    });
-                } else {
-                    // If there is no handler, mask this interrupt
-                    // to prevent an IRQ storm.  This is considered
-                    // an error.
-                    arch::irq::disable_irq(irq_no)?;
-                }
+            if irqs_pending & (1 << irq_no) != 0 && handler.is_none() {
+                // If there is no handler, mask this interrupt
+                // to prevent an IRQ storm.  This is considered
+                // an error.
+                arch::irq::disable_irq(irq_no)?;
             }
         }
+
+        // Service the highest-priority interrupt first. Any others remain
+        // pending and will fire again once the handler returns.
+        if let Some(irq_no) = next_pending_irq(irqs_pending, IRQS_DISABLED, &IRQ_HANDLERS) {
+            let (pid, f, arg, _priority) = IRQ_HANDLERS[irq_no].unwrap();
+            // println!("Making a callback to PID{}: {:x?} ({:08x}, {:x?})", pid, f, irq_no as usize, arg);
+            return arch::irq::make_callback(
+                pid,
+                f.get() as *mut usize,
+                irq_no,
+                arg.map(|x| x.get() as *mut usize)
+                    .unwrap_or(core::ptr::null_mut::<usize>()),
+            )
+            .map(|_| {
+                #[cfg(feature = "irq-latency")]
+                latency_dispatched(irq_no, arch::irq::latency_timestamp());
+                xous_kernel::Result::ResumeProcess
+            });
+        }
     }
     Ok(xous_kernel::Result::ResumeProcess)
 }
@@ -64,6 +209,7 @@ pub fn interrupt_claim(
     pid: PID,
     f: MemoryAddress,
     arg: Option<MemoryAddress>,
+    priority: usize,
 ) -> Result<(), xous_kernel::Error> {
     // Unsafe is required since we're accessing a static mut array.
     // However, we disable interrupts to prevent contention on this array.
@@ -73,13 +219,47 @@ pub fn interrupt_claim(
         } else if IRQ_HANDLERS[irq].is_some() {
             Err(xous_kernel::Error::InterruptInUse)
         } else {
-            IRQ_HANDLERS[irq] = Some((pid, f, arg));
+            IRQ_HANDLERS[irq] = Some((pid, f, arg, priority));
+            IRQS_DISABLED &= !(1 << irq);
+            // The new owner's handler starts with a clean slate
+            #[cfg(feature = "irq-latency")]
+            {
+                IRQ_LATENCY[irq] = IrqLatency::new();
+            }
             arch::irq::enable_irq(irq);
             Ok(())
         }
     }
 }
 
+/// Mask or unmask an interrupt that `pid` has claimed. A masked interrupt stays
+/// pending, and its handler runs once it is unmasked.
+pub fn interrupt_set_enabled(
+    irq: usize,
+    pid: PID,
+    enabled: bool,
+) -> Result<(), xous_kernel::Error> {
+    unsafe {
+        if irq >= IRQ_HANDLERS.len() {
+            return Err(xous_kernel::Error::InterruptNotFound);
+        }
+        match IRQ_HANDLERS[irq] {
+            Some((owner, _, _, _)) if owner == pid => (),
+            _ => return Err(xous_kernel::Error::AccessDenied),
+        }
+        if enabled {
+            IRQS_DISABLED &= !(1 << irq);
+            #[cfg(baremetal)]
+            arch::irq::enable_irq(irq);
+        } else {
+            IRQS_DISABLED |= 1 << irq;
+            #[cfg(baremetal)]
+            arch::irq::disable_irq(irq)?;
+        }
+    }
+    Ok(())
+}
+
 /// Iterate through the IRQ handlers and remove any handler that exists
 /// for the given PID.
 pub fn release_interrupts_for_pid(pid: PID) {
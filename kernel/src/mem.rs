@@ -4,9 +4,19 @@
 use core::fmt;
 
 pub use crate::arch::mem::{MemoryMapping, PAGE_SIZE};
-use crate::arch::process::Process;
+use crate::arch::process::{Process, MAX_PROCESS_COUNT};
 
-use xous_kernel::{MemoryFlags, MemoryRange, PID};
+use xous_kernel::{MemoryFlags, MemoryRange, PID, SID};
+
+/// How many named shared memory objects may exist at once.
+const MAX_SHARED_REGIONS: usize = 8;
+
+/// The largest shared memory object, in pages.
+const MAX_SHARED_PAGES: usize = 16;
+
+/// How many pages a process may have mapped or reserved at once, unless its
+/// parent sets a different limit. This is half of the RAM on a Precursor.
+pub const DEFAULT_PAGE_QUOTA: usize = 2048;
 
 #[derive(Debug)]
 enum ClaimReleaseMove {
@@ -40,6 +50,27 @@ impl fmt::Display for MemoryRangeExtra {
     }
 }
 
+/// A named block of memory that may be mapped into several processes at once.
+/// The physical pages belong to the kernel, and are only freed once the last
+/// process unmaps the region.
+#[derive(Copy, Clone)]
+struct SharedRegion {
+    name: SID,
+    size: usize,
+    #[allow(dead_code)]
+    pages: [usize; MAX_SHARED_PAGES],
+
+    /// The address the region is mapped at in each process, indexed by
+    /// `PID - 1`, or `0` if that process doesn't have it mapped.
+    mappers: [usize; MAX_PROCESS_COUNT],
+}
+
+impl SharedRegion {
+    fn mapper_count(&self) -> usize {
+        self.mappers.iter().filter(|&&virt| virt != 0).count()
+    }
+}
+
 pub struct MemoryManager {
     ram_start: usize,
     ram_size: usize,
@@ -47,6 +78,11 @@ pub struct MemoryManager {
     ram_name: u32,
     #[allow(dead_code)]
     last_ram_page: usize,
+    shared_regions: [Option<SharedRegion>; MAX_SHARED_REGIONS],
+    /// The most pages each process may have mapped or reserved, indexed by `PID - 1`.
+    page_quota: [usize; MAX_PROCESS_COUNT],
+    /// How many pages each process currently has mapped or reserved.
+    pages_charged: [usize; MAX_PROCESS_COUNT],
 }
 
 impl Default for MemoryManager {
@@ -77,6 +113,9 @@ impl MemoryManager {
             ram_size: 0,
             ram_name: 0,
             last_ram_page: 0,
+            shared_regions: [None; MAX_SHARED_REGIONS],
+            page_quota: [DEFAULT_PAGE_QUOTA; MAX_PROCESS_COUNT],
+            pages_charged: [0; MAX_PROCESS_COUNT],
         }
     }
 
@@ -173,6 +212,17 @@ impl MemoryManager {
         unsafe {
             MEMORY_ALLOCATIONS = slice::from_raw_parts_mut(base as *mut Option<PID>, mem_size)
         };
+
+        // The loader has already handed out memory to the initial processes, so
+        // charge it to them. Otherwise unmapping it later would refund pages that
+        // were never charged.
+        unsafe {
+            for owner in MEMORY_ALLOCATIONS.iter().flatten() {
+                if owner.get() != 1 {
+                    self.pages_charged[owner.get() as usize - 1] += 1;
+                }
+            }
+        }
         Ok(())
     }
 
@@ -303,6 +353,67 @@ impl MemoryManager {
         Err(xous_kernel::Error::OutOfMemory)
     }
 
+    /// Give a newly-created process the default page quota, with nothing
+    /// charged against it yet.
+    pub fn reset_page_quota(&mut self, pid: PID) {
+        let pid_idx = pid.get() as usize - 1;
+        self.page_quota[pid_idx] = DEFAULT_PAGE_QUOTA;
+        self.pages_charged[pid_idx] = 0;
+    }
+
+    /// Limit how many pages `pid` may have mapped or reserved at once. Pages it
+    /// already holds are kept, but no more may be mapped until it is under the
+    /// new limit.
+    pub fn set_page_quota(&mut self, pid: PID, pages: usize) {
+        self.page_quota[pid.get() as usize - 1] = pages;
+    }
+
+    /// Count `pages` against the quota of `pid`. PID 1 hosts the kernel and is
+    /// never limited.
+    ///
+    /// # Errors
+    ///
+    /// * OutOfMemory - Mapping this many pages would exceed the quota
+    pub fn charge_pages(&mut self, pid: PID, pages: usize) -> Result<(), xous_kernel::Error> {
+        if pid.get() == 1 {
+            return Ok(());
+        }
+        let pid_idx = pid.get() as usize - 1;
+        let charged = self.pages_charged[pid_idx] + pages;
+        if charged > self.page_quota[pid_idx] {
+            return Err(xous_kernel::Error::OutOfMemory);
+        }
+        self.pages_charged[pid_idx] = charged;
+        Ok(())
+    }
+
+    /// Return `pages` to the quota of `pid` once they have been unmapped.
+    pub fn refund_pages(&mut self, pid: PID, pages: usize) {
+        if pid.get() == 1 {
+            return;
+        }
+        let pid_idx = pid.get() as usize - 1;
+        assert!(
+            self.pages_charged[pid_idx] >= pages,
+            "PID {} refunded {} pages but only {} were charged",
+            pid,
+            pages,
+            self.pages_charged[pid_idx]
+        );
+        self.pages_charged[pid_idx] -= pages;
+    }
+
+    /// Move the charge for `pages` from `src_pid` to `dest_pid` along with the
+    /// pages themselves. The pages are already mapped, so the receiver may end
+    /// up over its quota, in which case it can't map anything more until it
+    /// frees some memory.
+    pub fn transfer_pages(&mut self, src_pid: PID, dest_pid: PID, pages: usize) {
+        self.refund_pages(src_pid, pages);
+        if dest_pid.get() != 1 {
+            self.pages_charged[dest_pid.get() as usize - 1] += pages;
+        }
+    }
+
     /// Find a virtual address in the current process that is big enough
     /// to fit `size` bytes.
     pub fn find_virtual_address(
@@ -390,7 +501,13 @@ impl MemoryManager {
 
     /// Reserve the given range without actually allocating memory.
     /// That way we can overpromise on stack size and heap size without
-    /// needing to actually have pages to back it.
+    /// needing to actually have pages to back it. Reserved pages still
+    /// count against the quota of the current process.
+    ///
+    /// # Errors
+    ///
+    /// * BadAlignment - The range does not lie on page boundaries
+    /// * OutOfMemory - The range would exceed the quota of the current process
     pub fn reserve_range(
         &mut self,
         virt_ptr: *mut u8,
@@ -410,10 +527,16 @@ impl MemoryManager {
             return Err(xous_kernel::Error::BadAlignment);
         }
 
+        let pid = crate::arch::process::current_pid();
+        self.charge_pages(pid, size / PAGE_SIZE)?;
+
         let mut mm = MemoryMapping::current();
         for virt in (virt..(virt + size)).step_by(PAGE_SIZE) {
             // FIXME: Un-reserve addresses if we encounter an error here
-            mm.reserve_address(self, virt, flags)?;
+            if let Err(e) = mm.reserve_address(self, virt, flags) {
+                self.refund_pages(pid, size / PAGE_SIZE);
+                return Err(e);
+            }
         }
         unsafe { xous_kernel::MemoryRange::new(virt_ptr as usize, size) }
     }
@@ -472,6 +595,7 @@ impl MemoryManager {
     /// # Errors
     ///
     /// * MemoryInUse - The specified page is already mapped
+    /// * OutOfMemory - The range would exceed the quota of the process
     pub fn map_range(
         &mut self,
         phys_ptr: *mut u8,
@@ -489,6 +613,8 @@ impl MemoryManager {
             return self.reserve_range(virt, size, flags);
         }
 
+        self.charge_pages(pid, size / PAGE_SIZE)?;
+
         // 1. Attempt to claim all physical pages in the range
         for claim_phys in (phys..(phys + size)).step_by(PAGE_SIZE) {
             if let Err(err) = self.claim_page(claim_phys as *mut usize, pid) {
@@ -496,6 +622,7 @@ impl MemoryManager {
                 for rel_phys in (phys..claim_phys).step_by(PAGE_SIZE) {
                     self.release_page(rel_phys as *mut usize, pid).ok();
                 }
+                self.refund_pages(pid, size / PAGE_SIZE);
                 return Err(err);
             }
         }
@@ -515,6 +642,7 @@ impl MemoryManager {
                     self.release_page((unmap_offset + phys) as *mut usize, pid)
                         .ok();
                 }
+                self.refund_pages(pid, size / PAGE_SIZE);
                 return Err(e);
             }
         }
@@ -532,13 +660,262 @@ impl MemoryManager {
         let pid = crate::arch::process::current_pid();
 
         // If the virtual address has an assigned physical address, release that
-        // address from this process.
-        if let Ok(phys) = crate::arch::mem::virt_to_phys(virt as usize) {
-            self.release_page(phys as *mut usize, pid).ok();
+        // address from this process. Only pages this process owns, or has
+        // reserved without touching yet, were charged against its quota.
+        let charged = match crate::arch::mem::virt_to_phys(virt as usize) {
+            Ok(phys) => {
+                let owned = self.owns_page(phys, pid);
+                self.release_page(phys as *mut usize, pid).ok();
+                owned
+            }
+            Err(xous_kernel::Error::MemoryInUse) => true,
+            Err(_) => false,
         };
 
         // Free the virtual address.
-        crate::arch::mem::unmap_page_inner(self, virt as usize)
+        let result = crate::arch::mem::unmap_page_inner(self, virt as usize)?;
+        if charged {
+            self.refund_pages(pid, 1);
+        }
+        Ok(result)
+    }
+
+    /// Unmap a range of pages from the current process. Every page in the range
+    /// must belong to the process, otherwise nothing is unmapped.
+    ///
+    /// # Errors
+    ///
+    /// * BadAlignment - The range does not lie on page boundaries
+    /// * BadAddress - One or more pages in the range are not mapped
+    /// * ShareViolation - One or more pages in the range are lent out
+    pub fn unmap_range(&mut self, virt: usize, size: usize) -> Result<(), xous_kernel::Error> {
+        if cfg!(baremetal) && (virt & (PAGE_SIZE - 1) != 0 || size & (PAGE_SIZE - 1) != 0) {
+            return Err(xous_kernel::Error::BadAlignment);
+        }
+
+        // Validate the whole range before touching anything. Pages that have been
+        // reserved but not yet touched report `MemoryInUse`, and still belong to us.
+        for addr in (virt..(virt + size)).step_by(PAGE_SIZE) {
+            match crate::arch::mem::virt_to_phys(addr) {
+                Ok(_) | Err(xous_kernel::Error::MemoryInUse) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        for addr in (virt..(virt + size)).step_by(PAGE_SIZE) {
+            self.unmap_page(addr as *mut usize)?;
+        }
+        Ok(())
+    }
+
+    /// Turn the page at `virt` in the current process into a guard page. Any
+    /// memory backing it is released, and the page is left unusable so that
+    /// touching it faults rather than getting lazily backed.
+    #[allow(dead_code)] // only called in baremetal mode
+    pub fn guard_page(&mut self, virt: usize) -> Result<(), xous_kernel::Error> {
+        let pid = crate::arch::process::current_pid();
+        if let Ok(phys) = crate::arch::mem::virt_to_phys(virt) {
+            self.release_page(phys as *mut usize, pid).ok();
+        }
+        crate::arch::mem::guard_page_inner(self, virt)
+    }
+
+    /// Map the shared memory object `name` into the current process, creating it
+    /// if this is the first process to ask for it.
+    ///
+    /// # Errors
+    ///
+    /// * BadAlignment - The size is not a multiple of the page size
+    /// * BadAddress - The region exists with a different size
+    /// * MemoryInUse - This process has already mapped the region
+    /// * OutOfMemory - The region is too big, or there are no free slots or pages
+    pub fn map_shared(
+        &mut self,
+        pid: PID,
+        name: SID,
+        size: usize,
+        flags: MemoryFlags,
+    ) -> Result<MemoryRange, xous_kernel::Error> {
+        if size == 0 || size & (PAGE_SIZE - 1) != 0 {
+            return Err(xous_kernel::Error::BadAlignment);
+        }
+        if size / PAGE_SIZE > MAX_SHARED_PAGES {
+            return Err(xous_kernel::Error::OutOfMemory);
+        }
+
+        let mapper_idx = pid.get() as usize - 1;
+        let (slot, is_new) = match self
+            .shared_regions
+            .iter()
+            .position(|r| r.map(|r| r.name == name).unwrap_or(false))
+        {
+            Some(slot) => {
+                let region = self.shared_regions[slot].as_ref().unwrap();
+                if region.size != size {
+                    return Err(xous_kernel::Error::BadAddress);
+                }
+                if region.mappers[mapper_idx] != 0 {
+                    return Err(xous_kernel::Error::MemoryInUse);
+                }
+                (slot, false)
+            }
+            None => {
+                let slot = self
+                    .shared_regions
+                    .iter()
+                    .position(|r| r.is_none())
+                    .ok_or(xous_kernel::Error::OutOfMemory)?;
+                #[allow(unused_mut)]
+                let mut region = SharedRegion {
+                    name,
+                    size,
+                    pages: [0; MAX_SHARED_PAGES],
+                    mappers: [0; MAX_PROCESS_COUNT],
+                };
+
+                // The backing pages belong to the kernel, so that they
+                // outlive any single process that has them mapped.
+                #[cfg(baremetal)]
+                for index in 0..size / PAGE_SIZE {
+                    match self.alloc_page(PID::new(1).unwrap()) {
+                        Ok(phys) => region.pages[index] = phys,
+                        Err(e) => {
+                            for phys in &region.pages[..index] {
+                                self.release_page(*phys as *mut usize, PID::new(1).unwrap())
+                                    .ok();
+                            }
+                            return Err(e);
+                        }
+                    }
+                }
+                self.shared_regions[slot] = Some(region);
+                (slot, true)
+            }
+        };
+
+        let virt = match self.find_virtual_address(
+            core::ptr::null_mut(),
+            size,
+            xous_kernel::MemoryType::Default,
+        ) {
+            Ok(virt) => virt as usize,
+            Err(e) => {
+                if is_new {
+                    self.free_shared_region(slot);
+                }
+                return Err(e);
+            }
+        };
+
+        #[cfg(baremetal)]
+        {
+            let pages = self.shared_regions[slot].unwrap().pages;
+            for (index, phys) in pages[..size / PAGE_SIZE].iter().enumerate() {
+                let page_virt = virt + index * PAGE_SIZE;
+                if let Err(e) =
+                    crate::arch::mem::map_page_inner(self, pid, *phys, page_virt, flags, false)
+                {
+                    self.abandon_map_shared(virt, index, slot, is_new);
+                    return Err(e);
+                }
+                if is_new {
+                    unsafe {
+                        (page_virt as *mut usize)
+                            .write_bytes(0, PAGE_SIZE / core::mem::size_of::<usize>())
+                    };
+                }
+                if let Err(e) = crate::arch::mem::hand_page_to_user(page_virt as _) {
+                    self.abandon_map_shared(virt, index + 1, slot, is_new);
+                    return Err(e);
+                }
+            }
+        }
+        #[cfg(not(baremetal))]
+        let _ = flags;
+
+        self.shared_regions[slot].as_mut().unwrap().mappers[mapper_idx] = virt;
+        unsafe { MemoryRange::new(virt, size) }
+    }
+
+    /// Undo a `map_shared()` that failed part of the way through: unmap the
+    /// first `mapped` pages at `virt`, and free the region if it was created
+    /// for this mapping.
+    #[cfg(baremetal)]
+    fn abandon_map_shared(&mut self, virt: usize, mapped: usize, slot: usize, is_new: bool) {
+        for index in 0..mapped {
+            crate::arch::mem::unmap_page_inner(self, virt + index * PAGE_SIZE).ok();
+        }
+        if is_new {
+            self.free_shared_region(slot);
+        }
+    }
+
+    /// Unmap the shared memory object `name` from the current process, and
+    /// free its pages if no other process has it mapped.
+    ///
+    /// # Returns
+    ///
+    /// The number of processes that still have the region mapped.
+    ///
+    /// # Errors
+    ///
+    /// * BadAddress - This process does not have the region mapped
+    pub fn unmap_shared(&mut self, pid: PID, name: SID) -> Result<usize, xous_kernel::Error> {
+        let mapper_idx = pid.get() as usize - 1;
+        let slot = self
+            .shared_regions
+            .iter()
+            .position(|r| {
+                r.map(|r| r.name == name && r.mappers[mapper_idx] != 0)
+                    .unwrap_or(false)
+            })
+            .ok_or(xous_kernel::Error::BadAddress)?;
+
+        let region = self.shared_regions[slot].as_mut().unwrap();
+        let _virt = region.mappers[mapper_idx];
+        let _size = region.size;
+        region.mappers[mapper_idx] = 0;
+        let remaining = region.mapper_count();
+
+        #[cfg(baremetal)]
+        for page_virt in (_virt..(_virt + _size)).step_by(PAGE_SIZE) {
+            crate::arch::mem::unmap_page_inner(self, page_virt)?;
+        }
+
+        if remaining == 0 {
+            self.free_shared_region(slot);
+        }
+        Ok(remaining)
+    }
+
+    /// Forget every shared region mapping held by a process that is being
+    /// destroyed, freeing any regions that nobody else has mapped. The
+    /// process' pagetables are torn down separately.
+    pub fn release_shared_for_process(&mut self, pid: PID) {
+        let mapper_idx = pid.get() as usize - 1;
+        for slot in 0..MAX_SHARED_REGIONS {
+            let remaining = match self.shared_regions[slot].as_mut() {
+                Some(region) if region.mappers[mapper_idx] != 0 => {
+                    region.mappers[mapper_idx] = 0;
+                    region.mapper_count()
+                }
+                _ => continue,
+            };
+            if remaining == 0 {
+                self.free_shared_region(slot);
+            }
+        }
+    }
+
+    /// Return the pages backing a shared region to the allocator and free its slot.
+    fn free_shared_region(&mut self, slot: usize) {
+        if let Some(_region) = self.shared_regions[slot].take() {
+            #[cfg(baremetal)]
+            for phys in &_region.pages[.._region.size / PAGE_SIZE] {
+                self.release_page(*phys as *mut usize, PID::new(1).unwrap())
+                    .ok();
+            }
+        }
     }
 
     /// Move a page from one process into another, keeping its permissions.
@@ -565,7 +942,9 @@ impl MemoryManager {
             phys_addr as *mut usize,
             dest_pid,
             ClaimReleaseMove::Move(src_pid),
-        )
+        )?;
+        self.transfer_pages(src_pid, dest_pid, 1);
+        Ok(())
     }
 
     #[allow(dead_code)]
@@ -718,32 +1097,53 @@ impl MemoryManager {
             return Err(xous_kernel::Error::BadAlignment);
         }
 
+        if let Some(offset) = self.address_to_allocation_offset(addr) {
+            return unsafe { action_inner(&mut MEMORY_ALLOCATIONS[offset], pid, action) };
+        }
+        // println!(
+        //     "mem: unable to claim or release physical address {:08x}",
+        //     addr
+        // );
+        Err(xous_kernel::Error::BadAddress)
+    }
+
+    /// Convert a physical address into its offset in the `MEMORY_ALLOCATIONS`
+    /// array, or `None` if it isn't in any memory region.
+    #[cfg(baremetal)]
+    fn address_to_allocation_offset(&self, addr: usize) -> Option<usize> {
         let mut offset = 0;
         // Happy path: The address is in main RAM
         if addr >= self.ram_start && addr < self.ram_start + self.ram_size {
-            offset += (addr - self.ram_start) / PAGE_SIZE;
-            return unsafe { action_inner(&mut MEMORY_ALLOCATIONS[offset], pid, action) };
+            return Some(offset + (addr - self.ram_start) / PAGE_SIZE);
         }
 
         offset += self.ram_size / PAGE_SIZE;
-        // Go through additional regions looking for this address, and claim it
-        // if it's not in use.
+        // Go through additional regions looking for this address.
         unsafe {
             for region in EXTRA_REGIONS {
                 if addr >= (region.mem_start as usize)
                     && addr < (region.mem_start + region.mem_size) as usize
                 {
-                    offset += (addr - (region.mem_start as usize)) / PAGE_SIZE;
-                    return action_inner(&mut MEMORY_ALLOCATIONS[offset], pid, action);
+                    return Some(offset + (addr - (region.mem_start as usize)) / PAGE_SIZE);
                 }
                 offset += region.mem_size as usize / PAGE_SIZE;
             }
         }
-        // println!(
-        //     "mem: unable to claim or release physical address {:08x}",
-        //     addr
-        // );
-        Err(xous_kernel::Error::BadAddress)
+        None
+    }
+
+    /// Determine whether the physical page at `addr` belongs to `pid`.
+    #[cfg(baremetal)]
+    fn owns_page(&self, addr: usize, pid: PID) -> bool {
+        self.address_to_allocation_offset(addr)
+            .map(|offset| unsafe { MEMORY_ALLOCATIONS[offset] == Some(pid) })
+            .unwrap_or(false)
+    }
+
+    /// Hosted processes keep their own memory, so every page they unmap was theirs.
+    #[cfg(not(baremetal))]
+    fn owns_page(&self, _addr: usize, _pid: PID) -> bool {
+        true
     }
 
     /// Mark a given address as being owned by the specified process ID
@@ -852,3 +1252,35 @@ impl MemoryManager {
         Ok(())
     }
 }
+
+/// Ensure the load address and stack of a new process lie on page
+/// boundaries, since both get mapped into the new address space page-by-page.
+#[allow(dead_code)] // only called in baremetal mode
+pub fn validate_process_layout(
+    text_destination: usize,
+    stack_addr: usize,
+    stack_size: usize,
+) -> Result<(), xous_kernel::Error> {
+    if text_destination & (PAGE_SIZE - 1) != 0
+        || stack_addr & (PAGE_SIZE - 1) != 0
+        || stack_size & (PAGE_SIZE - 1) != 0
+    {
+        return Err(xous_kernel::Error::BadAlignment);
+    }
+    Ok(())
+}
+
+/// Pick the page at the bottom of a new thread's stack to serve as its guard
+/// page, so that running off the end of the stack faults instead of corrupting
+/// whatever lies below it. The stack must lie on page boundaries and leave at
+/// least one usable page above the guard.
+#[allow(dead_code)] // only called in baremetal mode
+pub fn stack_guard_page(stack_addr: usize, stack_size: usize) -> Result<usize, xous_kernel::Error> {
+    if stack_addr & (PAGE_SIZE - 1) != 0 || stack_size & (PAGE_SIZE - 1) != 0 {
+        return Err(xous_kernel::Error::BadAlignment);
+    }
+    if stack_size < PAGE_SIZE * 2 {
+        return Err(xous_kernel::Error::BadAddress);
+    }
+    Ok(stack_addr)
+}
@@ -4,7 +4,10 @@
 pub use crate::arch::process::Thread;
 use crate::{mem::MemoryManager, services::SystemServices};
 use core::mem;
-use xous_kernel::{MemoryAddress, MemoryRange, MemorySize, Message, MessageSender, PID, SID, TID};
+use xous_kernel::{
+    ConnectToken, MemoryAddress, MemoryRange, MemorySize, Message, MessageSender, QueuePolicy, PID,
+    SID, TID,
+};
 
 /// A pointer to resolve a server ID to a particular process
 #[derive(PartialEq, Debug)]
@@ -15,6 +18,9 @@ pub struct Server {
     /// The process that owns this server
     pub pid: PID,
 
+    /// If set, other processes must present this token in order to connect
+    pub token: Option<ConnectToken>,
+
     /// Where messages should be inserted
     queue_head: usize,
 
@@ -27,6 +33,12 @@ pub struct Server {
     /// An increasing (but wrapping number) that indicates where clients are writing.
     tail_generation: u8,
 
+    /// The most messages that may be waiting for the server to receive them
+    queue_depth: usize,
+
+    /// What to do with a message that arrives when `queue_depth` messages are waiting
+    queue_policy: QueuePolicy,
+
     /// Where data will appear
     #[cfg(baremetal)]
     queue: &'static mut [QueuedMessage],
@@ -100,6 +112,9 @@ pub enum WaitingMessage {
 
     /// This memory should be returned to the system.
     ForgetMemory(MemoryRange),
+
+    /// The sender stopped waiting for a response, so the response should be dropped.
+    Cancelled,
 }
 
 /// Internal representation of a queued message for a server. This should be
@@ -238,6 +253,24 @@ enum QueuedMessage {
         u8,    /* message index */
         usize, /* server return address */
     ),
+
+    /// The sender of a blocking scalar message gave up before the server
+    /// received it. The slot keeps its place in the sequence so that later
+    /// messages are still delivered in order, but it is skipped over.
+    BlockingScalarCancelled(
+        u16, /* client PID */
+        u8,  /* client TID */
+        u8,  /* message index */
+    ),
+
+    /// The sender of a blocking scalar message gave up while the server was
+    /// working on it. The server's response will be dropped.
+    WaitingReturnScalarCancelled(
+        u16,   /* client PID */
+        u8,    /* client TID */
+        u8,    /* message index */
+        usize, /* server return address */
+    ),
 }
 
 impl QueuedMessage {
@@ -267,6 +300,7 @@ impl Server {
         new: &mut Option<Server>,
         pid: PID,
         sid: SID,
+        token: Option<ConnectToken>,
         _backing: MemoryRange,
     ) -> Result<(), xous_kernel::Error> {
         if new != &None {
@@ -295,16 +329,65 @@ impl Server {
         *new = Some(Server {
             sid,
             pid,
+            token,
             queue_head: 0,
             queue_tail: 0,
             head_generation: 0,
             tail_generation: 0,
+            queue_depth: Self::max_queue_depth(),
+            queue_policy: QueuePolicy::Reject,
             queue,
             ready_threads: 0,
         });
         Ok(())
     }
 
+    /// The most messages that can be waiting in a server's queue, which fills a
+    /// single page. `queue_message()` treats the queue as full once the tail
+    /// generation is one behind the head, so no more than 254 can be told apart.
+    pub fn max_queue_depth() -> usize {
+        (crate::arch::mem::PAGE_SIZE / mem::size_of::<QueuedMessage>()).min(u8::MAX as usize - 1)
+    }
+
+    /// Limit the queue to `depth` messages waiting to be received, and deal with
+    /// any more according to `policy`. `depth` must be between 1 and
+    /// `max_queue_depth()`.
+    pub fn set_queue_limit(&mut self, depth: usize, policy: QueuePolicy) {
+        assert!(depth > 0 && depth <= Self::max_queue_depth());
+        self.queue_depth = depth;
+        self.queue_policy = policy;
+    }
+
+    /// What this server does with messages sent to it while its queue is full
+    pub fn queue_policy(&self) -> QueuePolicy {
+        self.queue_policy
+    }
+
+    /// Discard the oldest message the server hasn't received, if it is a
+    /// non-blocking scalar message. Such a message has no sender waiting on it
+    /// and no memory attached, so it can go without anyone noticing.
+    ///
+    /// Returns `true` if a message was discarded.
+    fn drop_oldest_scalar(&mut self) -> bool {
+        let oldest = self.queue.iter().position(|entry| {
+            matches!(entry, QueuedMessage::ScalarMessage(_, _, idx, _, _, _, _, _, _)
+                if *idx == self.head_generation)
+        });
+        let queue_idx = match oldest {
+            Some(queue_idx) => queue_idx,
+            None => return false,
+        };
+        self.queue[queue_idx] = QueuedMessage::Empty;
+        if queue_idx == self.queue_tail {
+            self.queue_tail += 1;
+            if self.queue_tail >= self.queue.len() {
+                self.queue_tail = 0;
+            }
+        }
+        self.head_generation = self.head_generation.wrapping_add(1);
+        true
+    }
+
     /// Take a current slot and replace it with `None`, clearing out the contents of the queue.
     /// Returns an error if the queue has any waiting elements.
     /// Returns a list of threads that should be readied.
@@ -333,6 +416,11 @@ impl Server {
                 // and the server will never see them.
                 QueuedMessage::Empty | QueuedMessage::ScalarMessage(_, _, _, _, _, _, _, _, _) => {}
 
+                // Cancelled messages have already woken up their sender, and
+                // nobody is waiting on the result.
+                QueuedMessage::BlockingScalarCancelled(_, _, _)
+                | QueuedMessage::WaitingReturnScalarCancelled(_, _, _, _) => {}
+
                 // For `Send` messages, the Server has not yet seen these messages. Simply
                 // prevent this memory from getting mapped into the Server and free it.
                 QueuedMessage::MemoryMessageSend(
@@ -554,6 +642,39 @@ impl Server {
         }
     }
 
+    /// Cancel the blocking scalar message that the given thread is waiting on, if
+    /// it was sent to this server. A message that the server has not yet seen
+    /// will be skipped, and the response to a message the server is currently
+    /// working on will be dropped.
+    ///
+    /// Returns `true` if a message was cancelled. The caller is responsible for
+    /// waking up the sending thread.
+    pub fn cancel_blocking_scalar(&mut self, pid: PID, tid: TID) -> bool {
+        for entry in self.queue.iter_mut() {
+            match *entry {
+                QueuedMessage::BlockingScalarMessage(msg_pid, msg_tid, idx, _, _, _, _, _, _)
+                    if msg_pid == pid.get() as _ && msg_tid == tid as _ =>
+                {
+                    *entry = QueuedMessage::BlockingScalarCancelled(msg_pid, msg_tid, idx);
+                    return true;
+                }
+                QueuedMessage::WaitingReturnScalar(msg_pid, msg_tid, idx, return_address)
+                    if msg_pid == pid.get() as _ && msg_tid == tid as _ =>
+                {
+                    *entry = QueuedMessage::WaitingReturnScalarCancelled(
+                        msg_pid,
+                        msg_tid,
+                        idx,
+                        return_address,
+                    );
+                    return true;
+                }
+                _ => (),
+            }
+        }
+        false
+    }
+
     /// Convert a `QueuedMesage::WaitingReturnMemory` into `QueuedMessage::Empty`
     /// and return the pair.  Advance the tail.  Note that the `idx` could be
     /// somewhere other than the tail, but as long as it points to a valid
@@ -573,6 +694,10 @@ impl Server {
             .get_mut(message_index)
             .ok_or(xous_kernel::Error::BadAddress)?;
         // klog!("memory in queue[{}]: {:?}", message_index, current_val);
+        let cancelled = matches!(
+            *current_val,
+            QueuedMessage::WaitingReturnScalarCancelled(_, _, _, _)
+        );
         let (pid, tid, _idx, server_addr, client_addr, len, forget, is_memory) = match *current_val
         {
             QueuedMessage::WaitingReturnMemory(pid, tid, idx, server_addr, client_addr, len) => {
@@ -581,7 +706,8 @@ impl Server {
             QueuedMessage::WaitingForget(pid, tid, idx, server_addr, client_addr, len) => {
                 (pid, tid, idx, server_addr, client_addr, len, true, true)
             }
-            QueuedMessage::WaitingReturnScalar(pid, tid, idx, return_address) => {
+            QueuedMessage::WaitingReturnScalar(pid, tid, idx, return_address)
+            | QueuedMessage::WaitingReturnScalarCancelled(pid, tid, idx, return_address) => {
                 (pid, tid, idx, return_address, 0, 0, true, false)
             }
             _ => return Ok(WaitingMessage::None),
@@ -620,6 +746,10 @@ impl Server {
         //     tid
         // );
 
+        if cancelled {
+            return Ok(WaitingMessage::Cancelled);
+        }
+
         if !is_memory {
             return Ok(WaitingMessage::ScalarMessage(
                 PID::new(pid as _).unwrap(),
@@ -938,6 +1068,21 @@ impl Server {
                     self.head_generation = self.head_generation.wrapping_add(1);
                     return Some(msg);
                 }
+                // The sender has given up on this message, so consume its place in the
+                // sequence and look for the one after it.
+                QueuedMessage::BlockingScalarCancelled(_, _, idx)
+                    if idx == self.head_generation =>
+                {
+                    self.queue[queue_idx] = QueuedMessage::Empty;
+                    if queue_idx == self.queue_tail {
+                        self.queue_tail += 1;
+                        if self.queue_tail >= self.queue.len() {
+                            self.queue_tail = 0;
+                        }
+                    }
+                    self.head_generation = self.head_generation.wrapping_add(1);
+                    return self.take_next_message(sidx);
+                }
                 _ => {
                     queue_idx += 1;
                     if queue_idx >= self.queue.len() {
@@ -963,7 +1108,9 @@ impl Server {
         }
     }
 
-    /// Add the given message to this server's queue.
+    /// Add the given message to this server's queue. If the queue already has
+    /// as many messages waiting as it was limited to, a server with the
+    /// `DropOldest` policy discards the oldest one to make room, if it can.
     ///
     /// # Errors
     ///
@@ -987,6 +1134,14 @@ impl Server {
             return Err(xous_kernel::Error::ServerQueueFull);
         }
 
+        // The generations also count how many messages are waiting to be received.
+        let waiting = self.tail_generation.wrapping_sub(self.head_generation) as usize;
+        if waiting >= self.queue_depth
+            && !(self.queue_policy == QueuePolicy::DropOldest && self.drop_oldest_scalar())
+        {
+            return Err(xous_kernel::Error::ServerQueueFull);
+        }
+
         // Look through the queue, beginning at the queue head, for an empty slot.
         let mut discovered_index = None;
         for queue_idx in self.queue_head..self.queue.len() {
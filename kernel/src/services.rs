@@ -14,11 +14,27 @@ use crate::filled_array;
 use crate::server::Server;
 // use core::mem;
 use xous_kernel::{
-    pid_from_usize, Error, MemoryAddress, Message, ProcessInit, ThreadInit, CID, PID, SID, TID,
+    pid_from_usize, ConnectToken, Error, MemoryAddress, Message, ProcessInit, QueuePolicy,
+    ThreadInit, ThreadName, CID, PID, SID, TID,
 };
 
 const MAX_SERVER_COUNT: usize = 128;
 
+/// How many threads may be waiting in `ConnectWithTimeout` at once.
+const MAX_CONNECT_WAITERS: usize = 16;
+
+/// How many threads may be parked in `WaitForEvent` at once.
+const MAX_EVENT_WAITERS: usize = 32;
+
+/// How many events may be signaled with nobody waiting on them at once.
+const MAX_PENDING_EVENTS: usize = 32;
+
+/// How many threads may be parked in `SleepMs` at once.
+const MAX_SLEEPERS: usize = 32;
+
+/// How many threads may have a name set with `SetThreadName` at once.
+const MAX_THREAD_NAMES: usize = 64;
+
 pub use crate::arch::process::{INITIAL_TID, MAX_PROCESS_COUNT};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -44,6 +60,28 @@ pub struct SystemServices {
 
     /// A table of all servers in the system
     pub servers: [Option<Server>; MAX_SERVER_COUNT],
+
+    /// Threads waiting in `ConnectWithTimeout`, along with the time in
+    /// milliseconds at which they give up.
+    connect_deadlines: [Option<(PID, TID, u64)>; MAX_CONNECT_WAITERS],
+
+    /// Threads parked in `WaitForEvent`, along with the event they're waiting on.
+    event_waiters: [Option<(PID, TID, usize)>; MAX_EVENT_WAITERS],
+
+    /// Events signaled while no thread of the process was waiting on them,
+    /// kept for the next `WaitForEvent`.
+    pending_events: [Option<(PID, usize)>; MAX_PENDING_EVENTS],
+
+    /// Threads parked in `SleepMs`, along with the time in milliseconds at
+    /// which they wake up.
+    sleepers: [Option<(PID, TID, u64)>; MAX_SLEEPERS],
+
+    /// Names given to threads with `SetThreadName`, for debugging.
+    thread_names: [Option<(PID, TID, ThreadName)>; MAX_THREAD_NAMES],
+
+    /// Each process' armed `SetWatchdog`, indexed by PID - 1: the period in
+    /// milliseconds, and the time by which it must next be petted.
+    watchdogs: [Option<(usize, u64)>; MAX_PROCESS_COUNT],
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -220,6 +258,12 @@ impl Process {
         matches!(self.state, ProcessState::Free)
     }
 
+    /// Whether the process has a thread that could be making calls. Neither a
+    /// free slot nor a process that has only been allocated has one.
+    pub fn has_threads(&self) -> bool {
+        !matches!(self.state, ProcessState::Free | ProcessState::Allocated)
+    }
+
     pub fn activate(&self) -> Result<(), xous_kernel::Error> {
         crate::arch::process::set_current_pid(self.pid);
         self.mapping.activate()?;
@@ -236,6 +280,7 @@ impl Process {
         unsafe {
             crate::mem::MemoryManager::with_mut(|mm| mm.release_all_memory_for_process(self.pid))
         };
+        crate::mem::MemoryManager::with_mut(|mm| mm.release_shared_for_process(self.pid));
 
         // Free all claimed IRQs
         crate::irq::release_interrupts_for_pid(self.pid);
@@ -261,6 +306,12 @@ std::thread_local!(static SYSTEM_SERVICES: core::cell::RefCell<SystemServices> =
     // Note we can't use MAX_SERVER_COUNT here because of how Rust's
     // macro tokenization works
     servers: filled_array![None; 128],
+    connect_deadlines: [None; MAX_CONNECT_WAITERS],
+    event_waiters: [None; MAX_EVENT_WAITERS],
+    pending_events: [None; MAX_PENDING_EVENTS],
+    sleepers: [None; MAX_SLEEPERS],
+    thread_names: [None; MAX_THREAD_NAMES],
+    watchdogs: [None; MAX_PROCESS_COUNT],
 }));
 
 #[cfg(baremetal)]
@@ -277,6 +328,12 @@ static mut SYSTEM_SERVICES: SystemServices = SystemServices {
     // Note we can't use MAX_SERVER_COUNT here because of how Rust's
     // macro tokenization works
     servers: filled_array![None; 128],
+    connect_deadlines: [None; MAX_CONNECT_WAITERS],
+    event_waiters: [None; MAX_EVENT_WAITERS],
+    pending_events: [None; MAX_PENDING_EVENTS],
+    sleepers: [None; MAX_SLEEPERS],
+    thread_names: [None; MAX_THREAD_NAMES],
+    watchdogs: [None; MAX_PROCESS_COUNT],
 };
 
 impl core::fmt::Debug for Process {
@@ -291,6 +348,16 @@ impl core::fmt::Debug for Process {
     }
 }
 
+/// Pick the thread to run next out of the `ready` bitmask, starting with the
+/// one after `current` and wrapping around. Every ready thread gets a turn
+/// before `current` runs again, and if `current` is the only ready thread
+/// then it keeps running.
+pub fn next_ready_thread(ready: usize, current: TID) -> Option<TID> {
+    (1..=arch::process::MAX_THREAD + 1)
+        .map(|offset| (current + offset) % (arch::process::MAX_THREAD + 1))
+        .find(|&tid| ready & (1 << tid) != 0)
+}
+
 impl SystemServices {
     /// Calls the provided function with the current inner process state.
     pub fn with<F, R>(f: F) -> R
@@ -389,11 +456,19 @@ impl SystemServices {
     }
 
     /// Add a new entry to the process table. This results in a new address space
-    /// and a new PID, though the process is in the state `Setup()`.
+    /// and a new PID. The process is left `Allocated` and will not be scheduled
+    /// until its parent explicitly runs it with `SwitchTo`.
     pub fn create_process(
         &mut self,
         init_process: ProcessInit,
     ) -> Result<ProcessStartup, xous_kernel::Error> {
+        #[cfg(baremetal)]
+        crate::mem::validate_process_layout(
+            init_process.text_destination.get(),
+            init_process.stack.addr.get(),
+            init_process.stack.size.get(),
+        )?;
+
         let mut entry_idx = None;
         let mut new_pid = None;
         let _ppid = crate::arch::process::current_pid();
@@ -419,17 +494,18 @@ impl SystemServices {
             return Err(xous_kernel::Error::ProcessNotFound);
         }
         let new_pid = new_pid.unwrap();
-        let startup = arch::process::Process::create(new_pid, init_process, self).unwrap();
+        crate::mem::MemoryManager::with_mut(|mm| {
+            mm.reset_page_quota(new_pid);
+            if init_process.page_quota != 0 {
+                mm.set_page_quota(new_pid, init_process.page_quota);
+            }
+        });
+        let startup = arch::process::Process::create(new_pid, init_process, self)?;
 
-        #[cfg(baremetal)]
-        {
-            let mut entry = &mut self.processes[entry_idx.unwrap()];
-            // The `Process::create()` call above set up the process so that it will
-            // be ready to run right away, meaning we will not need to first set
-            // the state to `ProcessState::Allocated` and we can go straight to running
-            // this process.
-            entry.state = ProcessState::Ready(1 << INITIAL_TID);
-        }
+        // The `Process::create()` call above set up the initial thread, but the
+        // process stays `Allocated` so the scheduler won't pick it up on its own.
+        // `switch_to_thread()` will start it at `INITIAL_TID` once the parent
+        // calls `SwitchTo`.
         // entry.ppid = _ppid;
         klog!("created new process for PID {} with PPID {}", new_pid, ppid);
         return Ok(startup);
@@ -689,7 +765,13 @@ impl SystemServices {
             ),
         };
         // log_process_update(file!(), line!(), process, old_state);
-        klog!("Readying ({}:{}) -> {:?}", pid, tid, process.state);
+        klog!(
+            "Readying ({}:{} {}) -> {:?}",
+            pid,
+            tid,
+            self.thread_name(pid, tid),
+            self.processes[pid.get() as usize - 1].state
+        );
         Ok(())
     }
 
@@ -944,7 +1026,12 @@ impl SystemServices {
 
         #[cfg(feature = "debug-print")]
         if new_tid != 0 {
-            klog!("Activating process {} thread {}", new_pid, new_tid);
+            klog!(
+                "Activating process {} thread {} ({})",
+                new_pid,
+                new_tid,
+                self.thread_name(new_pid, new_tid)
+            );
         } else {
             klog!("Activating process {} thread ANY", new_pid);
         }
@@ -976,17 +1063,8 @@ impl SystemServices {
                         new.state
                     );
                     if new_tid == 0 {
-                        new_tid = (new.current_thread + 1) as usize;
-                        while x & (1 << new_tid) == 0 {
-                            new_tid += 1;
-                            if new_tid > arch::process::MAX_THREAD {
-                                new_tid = 0;
-                            } else if new_tid == (new.current_thread + 1) as usize {
-                                // If we've looped around, return an error.
-                                // println!("Looked through all contexts and couldn't find one that was ready");
-                                return Err(xous_kernel::Error::ProcessNotFound);
-                            }
-                        }
+                        new_tid = next_ready_thread(x, new.current_thread as usize)
+                            .ok_or(xous_kernel::Error::ProcessNotFound)?;
                         new.current_thread = new_tid as _;
                         klog!("picked thread ID {}", new_tid);
                     } else if x & (1 << new_tid) == 0 {
@@ -1121,16 +1199,8 @@ impl SystemServices {
                 // thread.  If that is not runnable, do a round-robin
                 // search for the next available thread.
                 if new_tid == 0 {
-                    new_tid = (new.current_thread + 1) as usize;
-                    while x & (1 << new_tid) == 0 {
-                        new_tid += 1;
-                        if new_tid > arch::process::MAX_THREAD {
-                            new_tid = 0;
-                        } else if new_tid == (new.current_thread + 1) as usize {
-                            // If we've looped around, return an error.
-                            return Err(xous_kernel::Error::ProcessNotFound);
-                        }
-                    }
+                    new_tid = next_ready_thread(x, new.current_thread as usize)
+                        .ok_or(xous_kernel::Error::ProcessNotFound)?;
                     new.current_thread = new_tid as _;
                 } else if x & (1 << new_tid) == 0 {
                     return Err(xous_kernel::Error::ProcessNotFound);
@@ -1184,8 +1254,8 @@ impl SystemServices {
     ///
     /// # Errors
     ///
-    /// * **ShareViolation**: Tried to mutably share a region that was already
-    ///   shared
+    /// * **ShareViolation**: Part of the region is currently lent to another
+    ///   process
     /// * **BadAddress**: The provided address was not valid
     /// * **BadAlignment**: The provided address or length was not page-aligned
     ///
@@ -1238,6 +1308,17 @@ impl SystemServices {
         let src_mapping = self.get_process(current_pid)?.mapping;
         let dest_mapping = self.get_process(dest_pid)?.mapping;
         crate::mem::MemoryManager::with_mut(|mm| {
+            // Check every page before moving any of them, so a bad page can't
+            // leave the message half-moved. A page that's lent out stays put
+            // until it's returned, since the borrower still refers to it.
+            for offset in (0..usize_len).step_by(usize_page) {
+                let page = src_virt.wrapping_add(offset);
+                mm.ensure_page_exists(page as usize)?;
+                if crate::arch::mem::page_is_lent(page as *mut u8) {
+                    return Err(xous_kernel::Error::ShareViolation);
+                }
+            }
+
             // Locate an address to fit the new memory.
             dest_mapping.activate()?;
             let dest_virt = mm
@@ -1256,7 +1337,6 @@ impl SystemServices {
             for offset in (0..usize_len).step_by(usize_page) {
                 assert!(((src_virt.wrapping_add(offset) as usize) & 0xfff) == 0);
                 assert!(((dest_virt.wrapping_add(offset) as usize) & 0xfff) == 0);
-                mm.ensure_page_exists(src_virt.wrapping_add(offset) as usize)?;
                 mm.move_page(
                     current_pid,
                     &src_mapping,
@@ -1276,10 +1356,23 @@ impl SystemServices {
     pub fn send_memory(
         &mut self,
         src_virt: *mut usize,
-        _dest_pid: PID,
+        dest_pid: PID,
         _dest_virt: *mut usize,
-        _len: usize,
+        len: usize,
     ) -> Result<*mut usize, xous_kernel::Error> {
+        // The sender frees its copy without telling the kernel, and the receiver
+        // unmaps the memory through the kernel once it is done, so the charge
+        // moves with the message.
+        let current_pid = self.current_pid();
+        if current_pid != dest_pid {
+            crate::mem::MemoryManager::with_mut(|mm| {
+                mm.transfer_pages(
+                    current_pid,
+                    dest_pid,
+                    (len + crate::mem::PAGE_SIZE - 1) / crate::mem::PAGE_SIZE,
+                )
+            });
+        }
         Ok(src_virt)
     }
 
@@ -1528,6 +1621,70 @@ impl SystemServices {
         Ok(src_virt as *mut usize)
     }
 
+    /// Read the bookkeeping information for a process. The process' memory
+    /// space is briefly activated in order to read its heap layout.
+    ///
+    /// # Errors
+    ///
+    /// * **ProcessNotFound**: The process doesn't exist
+    pub fn process_info(&self, pid: PID) -> Result<xous_kernel::ProcessInfo, xous_kernel::Error> {
+        let process = self.get_live_process(pid)?;
+        let ppid = process.ppid;
+
+        let current_pid = self.current_pid();
+        process.activate()?;
+        let (heap_base, heap_size, heap_max) = ArchProcess::with_inner(|process_inner| {
+            (
+                process_inner.mem_heap_base,
+                process_inner.mem_heap_size,
+                process_inner.mem_heap_max,
+            )
+        });
+        let thread_count = ArchProcess::current().thread_count();
+        self.get_process(current_pid)?.activate()?;
+
+        let name = self
+            .thread_names
+            .iter()
+            .flatten()
+            .filter(|(p, _, _)| *p == pid)
+            .min_by_key(|(_, t, _)| *t)
+            .map(|(_, _, name)| *name)
+            .unwrap_or_default();
+
+        Ok(xous_kernel::ProcessInfo {
+            pid,
+            ppid,
+            heap_base,
+            heap_size,
+            heap_max,
+            thread_count,
+            name,
+        })
+    }
+
+    /// Return how long a process has spent running, in platform timer ticks.
+    ///
+    /// # Errors
+    ///
+    /// * **ProcessNotFound**: The process doesn't exist
+    pub fn cpu_time(&self, pid: PID) -> Result<u64, xous_kernel::Error> {
+        self.get_live_process(pid)?;
+        Ok(crate::arch::process::cpu_time(pid))
+    }
+
+    /// Like `get_process()`, but rejects PIDs that are out of range or free.
+    fn get_live_process(&self, pid: PID) -> Result<&Process, xous_kernel::Error> {
+        if pid.get() as usize > self.processes.len() {
+            return Err(xous_kernel::Error::ProcessNotFound);
+        }
+        let process = self.get_process(pid)?;
+        if process.free() {
+            return Err(xous_kernel::Error::ProcessNotFound);
+        }
+        Ok(process)
+    }
+
     /// Create a new thread in the current process.  Execution begins at
     /// `entrypoint`, with the stack pointer set to `stack_pointer`.  A single
     /// argument will be passed to the new function.
@@ -1574,6 +1731,9 @@ impl SystemServices {
         };
         // log_process_update(file!(), line!(), process, old_state);
 
+        // Don't let the new thread inherit the name of one that used to have its ID
+        self.set_thread_name(pid, new_tid, ThreadName::default())?;
+
         Ok(new_tid)
     }
 
@@ -1605,6 +1765,8 @@ impl SystemServices {
             // Wake up the thread
             self.set_thread_result(pid, waiting_tid, xous_kernel::Result::Scalar1(return_value))?;
             waiting_threads |= 1 << waiting_tid;
+            // The return value has been delivered, so it can't be joined again.
+            arch_process.take_thread_exit_value(tid);
         }
 
         // Mark this process as `Ready` if there are waiting threads, or `Sleeping` if
@@ -1630,6 +1792,11 @@ impl SystemServices {
 
     /// Park this thread if the target thread is currently running. Otherwise,
     /// return the value of the given thread.
+    ///
+    /// # Errors
+    ///
+    /// * **ThreadNotAvailable**: The thread does not exist, is the calling thread,
+    ///   or has already been joined
     pub fn join_thread(
         &mut self,
         pid: PID,
@@ -1645,17 +1812,19 @@ impl SystemServices {
         }
 
         // If the target thread exists, put this thread to sleep.
-        let arch_process = ArchProcess::current();
+        let mut arch_process = ArchProcess::current();
         if arch_process.thread_exists(join_tid) {
             // The target thread exists -- put this thread to sleep
             let ppid = self.get_process(pid).unwrap().ppid;
             self.activate_process_thread(tid, ppid, 0, false)
                 .map(|_| Ok(xous_kernel::Result::ResumeProcess))
                 .unwrap_or(Err(xous_kernel::Error::ProcessNotFound))
+        } else if let Some(return_value) = arch_process.take_thread_exit_value(join_tid) {
+            // The thread already finished -- hand back its return value
+            Ok(xous_kernel::Result::Scalar1(return_value))
         } else {
-            // The thread does not exist -- continue execution
-            // Err(xous_kernel::Error::ThreadNotAvailable)
-            Ok(xous_kernel::Result::Scalar1(0))
+            // The thread does not exist
+            Err(xous_kernel::Error::ThreadNotAvailable)
         }
     }
 
@@ -1674,6 +1843,61 @@ impl SystemServices {
         pid: PID,
         sid: SID,
         connect: bool,
+    ) -> Result<(SID, CID), xous_kernel::Error> {
+        self.allocate_server(pid, sid, None, connect)
+    }
+
+    /// Like `create_server_with_address()`, except that other processes must
+    /// present `token` to `connect_to_server_with_token()` in order to connect.
+    pub fn create_server_with_token(
+        &mut self,
+        pid: PID,
+        sid: SID,
+        token: ConnectToken,
+        connect: bool,
+    ) -> Result<(SID, CID), xous_kernel::Error> {
+        self.allocate_server(pid, sid, Some(token), connect)
+    }
+
+    /// Like `create_server_with_address()`, except that the server's queue holds
+    /// at most `depth` messages that it hasn't received, and deals with any more
+    /// according to `policy`.
+    ///
+    /// # Errors
+    ///
+    /// * **InvalidSyscall**: `depth` is 0
+    /// * **OutOfMemory**: The queue can't hold `depth` messages
+    pub fn create_server_with_queue(
+        &mut self,
+        pid: PID,
+        sid: SID,
+        depth: usize,
+        policy: QueuePolicy,
+        connect: bool,
+    ) -> Result<(SID, CID), xous_kernel::Error> {
+        if depth == 0 {
+            return Err(xous_kernel::Error::InvalidSyscall);
+        }
+        if depth > Server::max_queue_depth() {
+            return Err(xous_kernel::Error::OutOfMemory);
+        }
+        let (sid, cid) = self.allocate_server(pid, sid, None, connect)?;
+        let sidx = self
+            .sidx_from_sid(sid, pid)
+            .expect("couldn't find the server that was just created");
+        self.servers[sidx]
+            .as_mut()
+            .unwrap()
+            .set_queue_limit(depth, policy);
+        Ok((sid, cid))
+    }
+
+    fn allocate_server(
+        &mut self,
+        pid: PID,
+        sid: SID,
+        token: Option<ConnectToken>,
+        connect: bool,
     ) -> Result<(SID, CID), xous_kernel::Error> {
         // klog!(
         //     "looking through server list for free server, connect? {}",
@@ -1692,12 +1916,16 @@ impl SystemServices {
         for entry in self.servers.iter_mut() {
             if *entry == None {
                 #[cfg(baremetal)]
-                // Allocate a single page for the server queue
+                // Allocate a single page for the server queue. It belongs to the
+                // process, so it counts against its quota until the server is
+                // destroyed.
                 let backing = crate::mem::MemoryManager::with_mut(|mm| unsafe {
-                    MemoryRange::new(
-                        mm.map_zeroed_page(pid, false)? as _,
-                        crate::arch::mem::PAGE_SIZE,
-                    )
+                    mm.charge_pages(pid, 1)?;
+                    let page = mm.map_zeroed_page(pid, false).map_err(|e| {
+                        mm.refund_pages(pid, 1);
+                        e
+                    })?;
+                    MemoryRange::new(page as _, crate::arch::mem::PAGE_SIZE)
                 })?;
 
                 #[cfg(not(baremetal))]
@@ -1705,7 +1933,7 @@ impl SystemServices {
 
                 // klog!("initializing new server with backing at {:x?} -- entry is {:?} (connect? {:?})", backing, *entry, connect);
                 // Initialize the server with the given memory page.
-                Server::init(entry, pid, sid, backing).unwrap();
+                Server::init(entry, pid, sid, token, backing).unwrap();
 
                 let cid = if connect {
                     self.connect_to_server(sid)?
@@ -1750,6 +1978,35 @@ impl SystemServices {
         Ok(sid)
     }
 
+    /// Abandon the blocking scalar message that thread `tid` of process `pid`
+    /// is waiting on, and wake the thread up with a `Timeout` error.
+    ///
+    /// Returns `Result::None` if the thread was not waiting on any server.
+    pub fn cancel_message(
+        &mut self,
+        pid: PID,
+        tid: TID,
+    ) -> Result<xous_kernel::Result, xous_kernel::Error> {
+        let cancelled = self
+            .servers
+            .iter_mut()
+            .flatten()
+            .any(|server| server.cancel_blocking_scalar(pid, tid));
+        if !cancelled {
+            return Ok(xous_kernel::Result::None);
+        }
+        self.set_thread_result(
+            pid,
+            tid,
+            xous_kernel::Result::Error(xous_kernel::Error::Timeout),
+        )?;
+        // As with `return_scalar()`, a hosted thread resumes as soon as its result is set.
+        if cfg!(baremetal) {
+            self.ready_thread(pid, tid)?;
+        }
+        Ok(xous_kernel::Result::Ok)
+    }
+
     /// Destroy the provided server ID and disconnect any processes that are
     /// connected.
     pub fn destroy_server(&mut self, pid: PID, sid: SID) -> Result<(), xous_kernel::Error> {
@@ -1818,9 +2075,343 @@ impl SystemServices {
 
         result
     }
+    /// Note that thread `tid` of `pid` failed to connect to a server and decide
+    /// whether it should keep waiting. The first failure starts the clock, and
+    /// once `timeout_ms` has passed this returns `true` and forgets the thread.
+    ///
+    /// # Errors
+    ///
+    /// * OutOfMemory - Too many threads are already waiting to connect
+    pub fn connect_timed_out(
+        &mut self,
+        pid: PID,
+        tid: TID,
+        timeout_ms: usize,
+    ) -> Result<bool, xous_kernel::Error> {
+        let now = arch::uptime_ms();
+        if let Some(waiter) = self
+            .connect_deadlines
+            .iter_mut()
+            .find(|waiter| matches!(waiter, Some((p, t, _)) if *p == pid && *t == tid))
+        {
+            let (_, _, deadline) = waiter.unwrap();
+            if now < deadline {
+                return Ok(false);
+            }
+            *waiter = None;
+            return Ok(true);
+        }
+
+        if timeout_ms == 0 {
+            return Ok(true);
+        }
+        let waiter = self
+            .connect_deadlines
+            .iter_mut()
+            .find(|waiter| waiter.is_none())
+            .ok_or(xous_kernel::Error::OutOfMemory)?;
+        *waiter = Some((pid, tid, now + timeout_ms as u64));
+        Ok(false)
+    }
+
+    /// Stop tracking a thread that was waiting in `ConnectWithTimeout`.
+    pub fn clear_connect_deadline(&mut self, pid: PID, tid: TID) {
+        for waiter in self.connect_deadlines.iter_mut() {
+            if matches!(waiter, Some((p, t, _)) if *p == pid && *t == tid) {
+                *waiter = None;
+            }
+        }
+    }
+
+    /// Record that thread `tid` of `pid` is parked until `event` is signaled,
+    /// returning `false`. If `event` was signaled while nobody was waiting on
+    /// it, the signal is consumed instead and this returns `true`: the thread
+    /// shouldn't park. Otherwise the caller is responsible for actually
+    /// parking the thread.
+    ///
+    /// # Errors
+    ///
+    /// * OutOfMemory - Too many threads are already waiting on events
+    pub fn wait_for_event(
+        &mut self,
+        pid: PID,
+        tid: TID,
+        event: usize,
+    ) -> Result<bool, xous_kernel::Error> {
+        if let Some(pending) = self
+            .pending_events
+            .iter_mut()
+            .find(|pending| **pending == Some((pid, event)))
+        {
+            *pending = None;
+            return Ok(true);
+        }
+        let waiter = self
+            .event_waiters
+            .iter_mut()
+            .find(|waiter| waiter.is_none())
+            .ok_or(xous_kernel::Error::OutOfMemory)?;
+        *waiter = Some((pid, tid, event));
+        Ok(false)
+    }
+
+    /// Wake every thread of `pid` that is parked on `event`, returning how many
+    /// were woken. Each one sees its `WaitForEvent` return `Ok`. If none was,
+    /// the event is kept pending for the next thread to wait on it; signals
+    /// that arrive while it is pending are folded into it.
+    ///
+    /// # Errors
+    ///
+    /// * OutOfMemory - Too many events are already pending
+    pub fn signal_event(&mut self, pid: PID, event: usize) -> Result<usize, xous_kernel::Error> {
+        let mut woken = 0;
+        for idx in 0..self.event_waiters.len() {
+            let (waiter_pid, waiter_tid, waiter_event) = match self.event_waiters[idx] {
+                Some(waiter) => waiter,
+                None => continue,
+            };
+            if waiter_pid != pid || waiter_event != event {
+                continue;
+            }
+            self.event_waiters[idx] = None;
+            self.set_thread_result(waiter_pid, waiter_tid, xous_kernel::Result::Ok)?;
+            // As with `cancel_message()`, a hosted thread resumes as soon as its result is set.
+            if cfg!(baremetal) {
+                self.ready_thread(waiter_pid, waiter_tid)?;
+            }
+            woken += 1;
+        }
+        if woken == 0 && !self.pending_events.contains(&Some((pid, event))) {
+            let pending = self
+                .pending_events
+                .iter_mut()
+                .find(|pending| pending.is_none())
+                .ok_or(xous_kernel::Error::OutOfMemory)?;
+            *pending = Some((pid, event));
+        }
+        Ok(woken)
+    }
+
+    /// Record that thread `tid` of `pid` is parked for `ms` milliseconds. The
+    /// caller is responsible for actually parking the thread.
+    ///
+    /// # Errors
+    ///
+    /// * OutOfMemory - Too many threads are already sleeping
+    pub fn sleep_thread(
+        &mut self,
+        pid: PID,
+        tid: TID,
+        ms: usize,
+    ) -> Result<(), xous_kernel::Error> {
+        let sleeper = self
+            .sleepers
+            .iter_mut()
+            .find(|sleeper| sleeper.is_none())
+            .ok_or(xous_kernel::Error::OutOfMemory)?;
+        *sleeper = Some((pid, tid, arch::uptime_ms() + ms as u64));
+        self.arm_wakeup();
+        Ok(())
+    }
+
+    /// Point the timer at the next sleeping thread's wakeup or watchdog
+    /// deadline. The hosted kernel waits for it in its message loop instead.
+    fn arm_wakeup(&self) {
+        #[cfg(baremetal)]
+        arch::set_wakeup(self.next_timer());
+    }
+
+    /// The earliest time in milliseconds at which the timer has to go off,
+    /// either to wake a sleeping thread or to catch a missed watchdog.
+    pub fn next_timer(&self) -> Option<u64> {
+        let watchdogs = self
+            .watchdogs
+            .iter()
+            .flatten()
+            .map(|(_, deadline)| *deadline);
+        self.next_wakeup().into_iter().chain(watchdogs).min()
+    }
+
+    /// The earliest time in milliseconds at which a sleeping thread is due to
+    /// wake up, if any are sleeping.
+    pub fn next_wakeup(&self) -> Option<u64> {
+        self.sleepers
+            .iter()
+            .flatten()
+            .map(|(_, _, wake_at)| *wake_at)
+            .min()
+    }
+
+    /// Wake every sleeping thread whose time has come. Each one sees its
+    /// `SleepMs` return `Ok`. On baremetal this runs from the timer interrupt
+    /// armed for the earliest wakeup, which is then re-armed for the next one.
+    pub fn wake_sleepers(&mut self) -> Result<(), xous_kernel::Error> {
+        let now = arch::uptime_ms();
+        for idx in 0..self.sleepers.len() {
+            let (pid, tid, wake_at) = match self.sleepers[idx] {
+                Some(sleeper) => sleeper,
+                None => continue,
+            };
+            if now < wake_at {
+                continue;
+            }
+            self.sleepers[idx] = None;
+            self.set_thread_result(pid, tid, xous_kernel::Result::Ok)?;
+            // As with `cancel_message()`, a hosted thread resumes as soon as its result is set.
+            if cfg!(baremetal) {
+                self.ready_thread(pid, tid)?;
+            }
+        }
+        self.arm_wakeup();
+        Ok(())
+    }
+
+    /// Arm the watchdog of `pid` with a period of `ms` milliseconds, starting
+    /// now, or disarm it if `ms` is 0.
+    ///
+    /// # Errors
+    ///
+    /// * AccessDenied - `pid` is PID 1, which can't be terminated
+    pub fn set_watchdog(&mut self, pid: PID, ms: usize) -> Result<(), xous_kernel::Error> {
+        if pid.get() == 1 {
+            return Err(xous_kernel::Error::AccessDenied);
+        }
+        self.watchdogs[pid.get() as usize - 1] = if ms == 0 {
+            None
+        } else {
+            Some((ms, arch::uptime_ms() + ms as u64))
+        };
+        self.arm_wakeup();
+        Ok(())
+    }
+
+    /// Move the watchdog deadline of `pid` to a whole period from now.
+    ///
+    /// # Errors
+    ///
+    /// * UseBeforeInit - The watchdog of `pid` isn't armed
+    pub fn pet_watchdog(&mut self, pid: PID) -> Result<(), xous_kernel::Error> {
+        let (period, deadline) = self.watchdogs[pid.get() as usize - 1]
+            .as_mut()
+            .ok_or(xous_kernel::Error::UseBeforeInit)?;
+        *deadline = arch::uptime_ms() + *period as u64;
+        self.arm_wakeup();
+        Ok(())
+    }
+
+    /// Terminate every process whose watchdog deadline has passed. This runs
+    /// from the timer interrupt armed for the earliest deadline, or from the
+    /// hosted kernel's message loop, and never from within a syscall.
+    ///
+    /// `current` is the process the timer interrupted, which carries on once
+    /// the others are torn down. If it's one of them, it's torn down last and
+    /// its parent is switched to in its place, and `true` is returned. A
+    /// process that can't be torn down has its watchdog dropped all the same.
+    pub fn expire_watchdogs(&mut self, current: Option<PID>) -> bool {
+        let now = arch::uptime_ms();
+        let mut current_expired = false;
+        let mut expired = false;
+        for idx in 0..self.watchdogs.len() {
+            let target_pid = PID::new(idx as u8 + 1).unwrap();
+            match self.watchdogs[idx] {
+                Some((_, deadline)) if now >= deadline => (),
+                _ => continue,
+            }
+            klog!(
+                "PID {} missed its watchdog deadline, terminating it",
+                target_pid
+            );
+            self.watchdogs[idx] = None;
+            if Some(target_pid) == current {
+                current_expired = true;
+                continue;
+            }
+            if let Err(_e) = self.release_process(target_pid) {
+                klog!("couldn't terminate PID {}: {:?}", target_pid, _e);
+            }
+            expired = true;
+        }
+        let mut switched = false;
+        if let Some(current) = current {
+            if current_expired {
+                match self.terminate_process(current) {
+                    Ok(_) => switched = true,
+                    Err(_e) => klog!("couldn't terminate PID {}: {:?}", current, _e),
+                }
+            }
+            // Tearing down a process activated other address spaces, so switch back.
+            if expired && !switched {
+                if let Err(_e) = self.get_process(current).and_then(|p| p.activate()) {
+                    klog!("couldn't reactivate PID {}: {:?}", current, _e);
+                }
+            }
+        }
+        self.arm_wakeup();
+        switched
+    }
+
+    /// Give thread `tid` of `pid` a name for debugging. An empty name removes
+    /// the thread's name.
+    ///
+    /// # Errors
+    ///
+    /// * OutOfMemory - Too many threads have names already
+    pub fn set_thread_name(
+        &mut self,
+        pid: PID,
+        tid: TID,
+        name: ThreadName,
+    ) -> Result<(), xous_kernel::Error> {
+        let existing = self
+            .thread_names
+            .iter_mut()
+            .find(|entry| matches!(entry, Some((p, t, _)) if *p == pid && *t == tid));
+        if name.is_empty() {
+            if let Some(entry) = existing {
+                *entry = None;
+            }
+            return Ok(());
+        }
+        let entry = match existing {
+            Some(entry) => entry,
+            None => self
+                .thread_names
+                .iter_mut()
+                .find(|entry| entry.is_none())
+                .ok_or(xous_kernel::Error::OutOfMemory)?,
+        };
+        *entry = Some((pid, tid, name));
+        Ok(())
+    }
+
+    /// The name of thread `tid` of `pid`, which is empty if it was never given one.
+    /// Only the scheduler's debug prints show it.
+    #[cfg(feature = "debug-print")]
+    pub fn thread_name(&self, pid: PID, tid: TID) -> ThreadName {
+        self.thread_names
+            .iter()
+            .flatten()
+            .find(|(p, t, _)| *p == pid && *t == tid)
+            .map(|(_, _, name)| *name)
+            .unwrap_or_default()
+    }
+
     /// Allocate a new server ID for this process and return the address. If the
-    /// server table is full, return an error.
+    /// server table is full, return an error. Servers that were created with a
+    /// token refuse the connection with `AccessDenied`.
     pub fn connect_to_server(&mut self, sid: SID) -> Result<CID, xous_kernel::Error> {
+        self.connect_to_server_with_token(sid, None)
+    }
+
+    /// Connect to a server, presenting `token`. If the server was created with a
+    /// token and this is a different one, fail with `AccessDenied`. A server's
+    /// own process, and any process that is already connected, is let through
+    /// regardless.
+    pub fn connect_to_server_with_token(
+        &mut self,
+        sid: SID,
+        token: Option<ConnectToken>,
+    ) -> Result<CID, xous_kernel::Error> {
         // Check to see if we've already connected to this server.
         // While doing this, find a free slot in case we haven't
         // yet connected.
@@ -1860,6 +2451,12 @@ impl SystemServices {
             for (server_idx, server) in self.servers.iter().enumerate() {
                 if let Some(allocated_server) = server {
                     if allocated_server.sid == sid {
+                        if allocated_server.token.is_some()
+                            && allocated_server.token != token
+                            && allocated_server.pid != pid
+                        {
+                            return Err(xous_kernel::Error::AccessDenied);
+                        }
                         process_inner.connection_map[slot_idx] =
                             Some(NonZeroU8::new((server_idx as u8) + 2).unwrap());
                         // println!(
@@ -1928,6 +2525,14 @@ impl SystemServices {
         None
     }
 
+    /// What the server behind connection `cid` of the current process does with
+    /// messages sent while its queue is full
+    pub fn queue_policy(&self, cid: CID) -> Option<QueuePolicy> {
+        let sidx = self.sidx_from_cid(cid)?;
+        self.server_from_sidx(sidx)
+            .map(|server| server.queue_policy())
+    }
+
     /// Return a server based on the connection id and the current process
     pub fn server_from_sidx(&self, sidx: usize) -> Option<&Server> {
         if sidx > self.servers.len() {
@@ -2061,6 +2666,63 @@ impl SystemServices {
 
     /// Terminate the given process. Returns the process' parent PID.
     pub fn terminate_process(&mut self, target_pid: PID) -> Result<PID, xous_kernel::Error> {
+        let parent_pid = self.release_process(target_pid)?;
+
+        self.switch_to_thread(parent_pid, None).unwrap();
+
+        Ok(parent_pid)
+    }
+
+    /// Terminate a child of `pid`. Unlike `terminate_process()`, the caller keeps
+    /// running once the child has been torn down.
+    pub fn terminate_child_process(
+        &mut self,
+        pid: PID,
+        target_pid: PID,
+    ) -> Result<(), xous_kernel::Error> {
+        if target_pid.get() as usize > self.processes.len() {
+            return Err(xous_kernel::Error::ProcessNotFound);
+        }
+        let target = self.get_process(target_pid)?;
+        if target.free() {
+            return Err(xous_kernel::Error::ProcessNotFound);
+        }
+        // PID 1 is its own parent, so also check that we're not terminating ourselves.
+        if target.ppid != pid || target_pid == pid {
+            return Err(xous_kernel::Error::ProcessNotChild);
+        }
+
+        self.release_process(target_pid)?;
+
+        // Tearing down the process activated other address spaces, so switch back.
+        self.get_process(pid)?.activate()
+    }
+
+    /// Limit how many pages the child `target_pid` of `pid` may have mapped or
+    /// reserved at once.
+    pub fn set_memory_quota(
+        &self,
+        pid: PID,
+        target_pid: PID,
+        pages: usize,
+    ) -> Result<(), xous_kernel::Error> {
+        if target_pid.get() as usize > self.processes.len() {
+            return Err(xous_kernel::Error::ProcessNotFound);
+        }
+        let target = self.get_process(target_pid)?;
+        if target.free() {
+            return Err(xous_kernel::Error::ProcessNotFound);
+        }
+        if target.ppid != pid || target_pid == pid {
+            return Err(xous_kernel::Error::ProcessNotChild);
+        }
+        crate::mem::MemoryManager::with_mut(|mm| mm.set_page_quota(target_pid, pages));
+        Ok(())
+    }
+
+    /// Remove all servers, connections, and memory belonging to `target_pid` and
+    /// mark its slot as free. Returns the PID of its parent.
+    fn release_process(&mut self, target_pid: PID) -> Result<PID, xous_kernel::Error> {
         // To terminate a process, we must perform the following:
         //
         // 1. If we have any client connections, remove them.
@@ -2112,13 +2774,42 @@ impl SystemServices {
             }
         }
 
+        // Forget any connections it was waiting on.
+        for deadline in self.connect_deadlines.iter_mut() {
+            if matches!(deadline, Some((pid, _, _)) if *pid == target_pid) {
+                *deadline = None;
+            }
+        }
+
+        // Its threads will never be woken from their events or sleeps.
+        for waiter in self.event_waiters.iter_mut() {
+            if matches!(waiter, Some((pid, _, _)) if *pid == target_pid) {
+                *waiter = None;
+            }
+        }
+        for pending in self.pending_events.iter_mut() {
+            if matches!(pending, Some((pid, _)) if *pid == target_pid) {
+                *pending = None;
+            }
+        }
+        for sleeper in self.sleepers.iter_mut() {
+            if matches!(sleeper, Some((pid, _, _)) if *pid == target_pid) {
+                *sleeper = None;
+            }
+        }
+        self.arm_wakeup();
+        for name in self.thread_names.iter_mut() {
+            if matches!(name, Some((pid, _, _)) if *pid == target_pid) {
+                *name = None;
+            }
+        }
+        self.watchdogs[target_pid.get() as usize - 1] = None;
+
         let process = self.get_process_mut(target_pid)?;
         process.activate()?;
         let parent_pid = process.ppid;
         process.terminate()?;
 
-        self.switch_to_thread(parent_pid, None).unwrap();
-
         Ok(parent_pid)
     }
 
@@ -3,7 +3,7 @@
 
 use crate::arch;
 use crate::arch::process::Process as ArchProcess;
-use crate::irq::interrupt_claim;
+use crate::irq::{interrupt_claim, interrupt_set_enabled};
 use crate::mem::{MemoryManager, PAGE_SIZE};
 use crate::server::{SenderID, WaitingMessage};
 use crate::services::SystemServices;
@@ -64,6 +64,46 @@ fn do_yield(_pid: PID, tid: TID) -> SysCallResult {
     })
 }
 
+/// Park the calling thread until something else sets its result and readies
+/// it, e.g. an event being signaled or a sleep running out.
+fn park_thread(ss: &mut SystemServices, pid: PID, tid: TID) -> SysCallResult {
+    if cfg!(baremetal) {
+        unsafe { SWITCHTO_CALLER = None };
+        let ppid = ss.get_process(pid)?.ppid;
+        ss.activate_process_thread(tid, ppid, 0, false)
+            .map(|_| Ok(xous_kernel::Result::ResumeProcess))
+            .unwrap_or(Err(xous_kernel::Error::ProcessNotFound))
+    } else {
+        ss.unschedule_thread(pid, tid)
+            .map(|_| xous_kernel::Result::BlockedProcess)
+    }
+}
+
+/// Hand the rest of this quantum directly to `target_pid`. If that process has
+/// nothing ready to run, this behaves like a normal `Yield`.
+fn do_yield_to(pid: PID, tid: TID, target_pid: PID) -> SysCallResult {
+    // If we're not running on bare metal, treat this as a no-op.
+    if !cfg!(baremetal) {
+        return Ok(xous_kernel::Result::Ok);
+    }
+
+    let runnable = SystemServices::with(|ss| {
+        ss.get_process(target_pid)
+            .map(|p| p.runnable())
+            .unwrap_or(false)
+    });
+    if target_pid == pid || !runnable {
+        return do_yield(pid, tid);
+    }
+
+    // Leave `SWITCHTO_CALLER` alone, so that when the target yields it goes
+    // back to whoever scheduled us.
+    SystemServices::with_mut(|ss| {
+        ss.activate_process_thread(tid, target_pid, 0, true)
+            .map(|_| xous_kernel::Result::ResumeProcess)
+    })
+}
+
 fn send_message(pid: PID, thread: TID, cid: CID, message: Message) -> SysCallResult {
     SystemServices::with_mut(|ss| {
         let sidx = ss
@@ -75,6 +115,13 @@ fn send_message(pid: PID, thread: TID, cid: CID, message: Message) -> SysCallRes
             .expect("server couldn't be located")
             .pid;
 
+        // A blocking message to a server in this very process can only ever be
+        // answered by another thread, so refuse it if there isn't one.
+        if message.is_blocking() && server_pid == pid && ArchProcess::current().thread_count() <= 1
+        {
+            return Err(xous_kernel::Error::WouldDeadlock);
+        }
+
         // Remember the address the message came from, in case we need to
         // return it after the borrow is through.
         let client_address = match &message {
@@ -187,11 +234,28 @@ fn send_message(pid: PID, thread: TID, cid: CID, message: Message) -> SysCallRes
                 e
             })?;
 
-            let runnable = ss
-                .runnable(server_pid, Some(server_tid))
-                .expect("server doesn't exist");
             // --- NOTE: Returning this value //
-            return if blocking && cfg!(baremetal) {
+            return if !blocking {
+                // Fast path: the message goes straight to the server thread as
+                // the result of its `ReceiveMessage`, and the client keeps running
+                // without any scheduling beyond readying that thread.
+                if !cfg!(baremetal) {
+                    // "Switch to" the server PID when not running on bare metal. This
+                    // ensures that it's "Running".
+                    ss.switch_to_thread(server_pid, Some(server_tid))?;
+                }
+                klog!("Setting the return value of the Server ({}:{}) to {:?} and returning to Client",
+                    server_pid, server_tid, envelope);
+                ss.set_thread_result(
+                    server_pid,
+                    server_tid,
+                    xous_kernel::Result::Message(envelope),
+                )
+                .map(|_| xous_kernel::Result::Ok)
+            } else if cfg!(baremetal) {
+                let runnable = ss
+                    .runnable(server_pid, Some(server_tid))
+                    .expect("server doesn't exist");
                 if !runnable {
                     // If it's not runnable (e.g. it's being debugged), switch to the parent.
                     let (ppid, ptid) = unsafe { SWITCHTO_CALLER.take().unwrap() };
@@ -212,7 +276,7 @@ fn send_message(pid: PID, thread: TID, cid: CID, message: Message) -> SysCallRes
                         .map(|_| Ok(xous_kernel::Result::Message(envelope)))
                         .unwrap_or(Err(xous_kernel::Error::ProcessNotFound))
                 }
-            } else if blocking && !cfg!(baremetal) {
+            } else {
                 klog!("Blocking client, since it sent a blocking message");
                 ss.unschedule_thread(pid, thread)?;
                 ss.switch_to_thread(server_pid, Some(server_tid))?;
@@ -222,29 +286,6 @@ fn send_message(pid: PID, thread: TID, cid: CID, message: Message) -> SysCallRes
                     xous_kernel::Result::Message(envelope),
                 )
                 .map(|_| xous_kernel::Result::BlockedProcess)
-            } else if cfg!(baremetal) {
-                klog!("Setting the return value of the Server ({}:{}) to {:?} and returning to Client",
-                    server_pid, server_tid, envelope);
-                ss.set_thread_result(
-                    server_pid,
-                    server_tid,
-                    xous_kernel::Result::Message(envelope),
-                )
-                .map(|_| xous_kernel::Result::Ok)
-            } else {
-                klog!(
-                    "setting the return value of the Server to {:?} and returning to Client",
-                    envelope
-                );
-                // "Switch to" the server PID when not running on bare metal. This ensures
-                // that it's "Running".
-                ss.switch_to_thread(server_pid, Some(server_tid))?;
-                ss.set_thread_result(
-                    server_pid,
-                    server_tid,
-                    xous_kernel::Result::Message(envelope),
-                )
-                .map(|_| xous_kernel::Result::Ok)
             };
         }
         klog!(
@@ -328,7 +369,7 @@ fn return_memory(
                     result
                 })
             }
-            WaitingMessage::ScalarMessage(_pid, _tid) => {
+            WaitingMessage::ScalarMessage(_, _) | WaitingMessage::Cancelled => {
                 println!("WARNING: Tried to wait on a message that was a scalar");
                 return Err(xous_kernel::Error::InternalError);
             }
@@ -411,6 +452,8 @@ fn return_scalar(
         let result = server.take_waiting_message(sender.idx, None)?;
         let (client_pid, client_tid) = match result {
             WaitingMessage::ScalarMessage(pid, tid) => (pid, tid),
+            // The client timed out and is no longer waiting for this response.
+            WaitingMessage::Cancelled => return Ok(xous_kernel::Result::Ok),
             WaitingMessage::ForgetMemory(_) => {
                 println!(
                     "WARNING: Tried to wait on a scalar message that was actually forgettingmemory"
@@ -463,13 +506,13 @@ fn return_scalar(
     })
 }
 
-fn return_scalar2(
+/// Answer a blocking scalar message with `result`, which holds several scalars
+fn return_scalars(
     server_pid: PID,
     server_tid: TID,
     in_irq: bool,
     sender: MessageSender,
-    arg1: usize,
-    arg2: usize,
+    result: xous_kernel::Result,
 ) -> SysCallResult {
     SystemServices::with_mut(|ss| {
         let sender = SenderID::from(sender);
@@ -481,9 +524,11 @@ fn return_scalar2(
         if server.pid != server_pid {
             return Err(xous_kernel::Error::ServerNotFound);
         }
-        let result = server.take_waiting_message(sender.idx, None)?;
-        let (client_pid, client_tid) = match result {
+        let waiting = server.take_waiting_message(sender.idx, None)?;
+        let (client_pid, client_tid) = match waiting {
             WaitingMessage::ScalarMessage(pid, tid) => (pid, tid),
+            // The client timed out and is no longer waiting for this response.
+            WaitingMessage::Cancelled => return Ok(xous_kernel::Result::Ok),
             WaitingMessage::ForgetMemory(_) => {
                 println!("WARNING: Tried to wait on a scalar message that was actually forgetting memory");
                 return Err(xous_kernel::Error::ProcessNotFound);
@@ -515,11 +560,7 @@ fn return_scalar2(
             // return to the server.
             // In a baremetal environment, the opposite is true -- we instruct
             // the server to resume and return to the client.
-            ss.set_thread_result(
-                client_pid,
-                client_tid,
-                xous_kernel::Result::Scalar2(arg1, arg2),
-            )?;
+            ss.set_thread_result(client_pid, client_tid, result)?;
             if cfg!(baremetal) {
                 ss.ready_thread(client_pid, client_tid)?;
             }
@@ -533,7 +574,7 @@ fn return_scalar2(
             // Switch to the client
             ss.ready_thread(client_pid, client_tid)?;
             ss.switch_to_thread(client_pid, Some(client_tid))?;
-            Ok(xous_kernel::Result::Scalar2(arg1, arg2))
+            Ok(result)
         }
     })
 }
@@ -597,13 +638,60 @@ fn receive_message(pid: PID, tid: TID, sid: SID, blocking: ExecutionType) -> Sys
     })
 }
 
+/// Make each of the calls in a `Batch`, writing their results over them.
+fn run_batch(pid: PID, tid: TID, in_irq: bool, range: MemoryRange) -> SysCallResult {
+    let entry_len = BATCH_CALL_WORDS * mem::size_of::<usize>();
+    if range.len() % entry_len != 0 {
+        return Err(xous_kernel::Error::BadAlignment);
+    }
+    let count = range.len() / entry_len;
+    if count > MAX_BATCH_CALLS {
+        return Err(xous_kernel::Error::InvalidSyscall);
+    }
+    let mut buffer = [0usize; BATCH_CALL_WORDS * MAX_BATCH_CALLS];
+    let words = &mut buffer[..count * BATCH_CALL_WORDS];
+    arch::mem::read_user_words(&range, words)?;
+
+    let decode = |entry: &[usize]| {
+        SysCall::from_args(
+            entry[0], entry[1], entry[2], entry[3], entry[4], entry[5], entry[6], entry[7],
+        )
+    };
+    // Check every call before making any, so a batch that's refused has no effect
+    for entry in words.chunks(BATCH_CALL_WORDS) {
+        if !decode(entry)?.can_batch() {
+            return Err(xous_kernel::Error::InvalidSyscall);
+        }
+    }
+    for entry in words.chunks_mut(BATCH_CALL_WORDS) {
+        let call = decode(entry).expect("batched call was checked above");
+        let result =
+            handle_inner(pid, tid, in_irq, call).unwrap_or_else(xous_kernel::Result::Error);
+        entry.copy_from_slice(&result.to_args());
+    }
+
+    arch::mem::write_user_words(&range, words)?;
+    Ok(xous_kernel::Result::Scalar1(count))
+}
+
 pub fn handle(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallResult {
-    #[cfg(feature = "debug-print")]
+    #[cfg(feature = "syscall-trace")]
     print!("KERNEL({}:{}): Syscall {:x?}", pid, tid, call);
     // let call_string = format!("{:x?}", call);
     // let start_time = std::time::Instant::now();
+
+    // A call can't come from a process that's gone, but in hosted mode one
+    // may still be on its way from a process that was terminated
+    let gone = SystemServices::with(|ss| {
+        ss.get_process(pid)
+            .map(|p| !p.has_threads())
+            .unwrap_or(true)
+    });
+
     #[allow(clippy::let_and_return)]
-    let result = if in_irq && !call.can_call_from_interrupt() {
+    let result = if gone {
+        Err(xous_kernel::Error::ProcessNotFound)
+    } else if in_irq && !call.can_call_from_interrupt() {
         Err(xous_kernel::Error::InvalidSyscall)
     } else {
         handle_inner(pid, tid, in_irq, call)
@@ -611,7 +699,7 @@ pub fn handle(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallResult
 
     // println!("KERNEL [{:2}:{:2}] Syscall took {:7} usec: {}", pid, tid, start_time.elapsed().as_micros(), call_string);
 
-    #[cfg(feature = "debug-print")]
+    #[cfg(feature = "syscall-trace")]
     println!(
         " -> ({}:{}) {:x?}",
         crate::arch::current_pid(),
@@ -661,27 +749,34 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
 
                 // If we're handing back an address in main RAM, zero it out. If
                 // phys is 0, then the page will be lazily allocated, so we
-                // don't need to do this.
+                // don't need to do this. With `LAZY_ZERO`, main RAM is zeroed
+                // a page at a time as it's first touched instead, so large maps
+                // don't pay for it all up front. That's only asked for where
+                // nothing else writes to the memory before then, as DMA would.
                 if phys.is_some() {
-                    if mm.is_main_memory(phys_ptr) {
-                        // println!(
-                        //     "Going to zero out {} bytes @ {:08x}",
-                        //     range.len(),
-                        //     range.as_ptr() as usize,
-                        // );
-                        unsafe {
-                            range
-                                .as_mut_ptr()
-                                .write_bytes(0, range.len() / mem::size_of::<usize>())
-                        };
-                        // println!("Done zeroing out");
-                    }
+                    let zero_on_fault = req_flags & MemoryFlags::LAZY_ZERO
+                        == MemoryFlags::LAZY_ZERO
+                        && mm.is_main_memory(phys_ptr);
                     for offset in (range.as_ptr() as usize..(range.as_ptr() as usize + range.len()))
                         .step_by(PAGE_SIZE)
                     {
                         // println!("Handing page to user");
-                        crate::arch::mem::hand_page_to_user(offset as *mut u8)
-                            .expect("couldn't hand page to user");
+                        if zero_on_fault {
+                            crate::arch::mem::hand_page_to_user_unzeroed(offset as *mut u8)
+                        } else {
+                            crate::arch::mem::hand_page_to_user(offset as *mut u8)
+                        }
+                        .expect("couldn't hand page to user");
+                    }
+                    // The process can't run until this call returns, so nothing
+                    // sees the pages between being handed over and zeroed.
+                    if mm.is_main_memory(phys_ptr) && !zero_on_fault {
+                        if let Err(e) =
+                            crate::arch::mem::clear_user(range.as_ptr() as usize, range.len())
+                        {
+                            mm.unmap_range(range.as_ptr() as usize, range.len()).ok();
+                            return Err(e);
+                        }
                     }
                 }
 
@@ -689,20 +784,8 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
             })
         }
         SysCall::UnmapMemory(range) => MemoryManager::with_mut(|mm| {
-            let mut result = Ok(xous_kernel::Result::Ok);
-            let virt = range.as_ptr() as usize;
-            let size = range.len();
-            if cfg!(baremetal) && virt & 0xfff != 0 {
-                return Err(xous_kernel::Error::BadAlignment);
-            }
-            for addr in (virt..(virt + size)).step_by(PAGE_SIZE) {
-                if let Err(e) = mm.unmap_page(addr as *mut usize) {
-                    if result.is_ok() {
-                        result = Err(e);
-                    }
-                }
-            }
-            result
+            mm.unmap_range(range.as_ptr() as usize, range.len())
+                .map(|_| xous_kernel::Result::Ok)
         }),
         SysCall::IncreaseHeap(delta, flags) => {
             if delta & 0xfff != 0 {
@@ -719,10 +802,17 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
                     Ok(start as *mut u8)
                 })?
             };
-            MemoryManager::with_mut(|mm| {
-                Ok(xous_kernel::Result::MemoryRange(
-                    mm.reserve_range(start, delta, flags)?,
-                ))
+            // The pages are only reserved here. Each is zeroed when a fault first
+            // backs it with memory, so there's nothing to clear now.
+            MemoryManager::with_mut(|mm| match mm.reserve_range(start, delta, flags) {
+                Ok(range) => Ok(xous_kernel::Result::MemoryRange(range)),
+                Err(e) => {
+                    // Give back the heap space, since nothing was reserved for it.
+                    ArchProcess::with_inner_mut(|process_inner| {
+                        process_inner.mem_heap_size -= delta
+                    });
+                    Err(e)
+                }
             })
         }
         SysCall::DecreaseHeap(delta) => {
@@ -730,8 +820,8 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
                 return Err(xous_kernel::Error::BadAlignment);
             }
             let start = ArchProcess::with_inner_mut(|process_inner| {
-                if process_inner.mem_heap_size + delta > process_inner.mem_heap_max {
-                    return Err(xous_kernel::Error::OutOfMemory);
+                if delta > process_inner.mem_heap_size {
+                    return Err(xous_kernel::Error::BadAddress);
                 }
 
                 let start = process_inner.mem_heap_base + process_inner.mem_heap_size;
@@ -762,11 +852,52 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
             ss.activate_process_thread(tid, new_pid, new_context, true)
                 .map(|_ctx| xous_kernel::Result::ResumeProcess)
         }),
-        SysCall::ClaimInterrupt(no, callback, arg) => {
-            interrupt_claim(no, pid as definitions::PID, callback, arg)
+        SysCall::ClaimInterrupt(no, callback, arg, priority) => {
+            interrupt_claim(no, pid as definitions::PID, callback, arg, priority)
                 .map(|_| xous_kernel::Result::Ok)
         }
+        SysCall::SetInterruptEnabled(no, enabled) => {
+            interrupt_set_enabled(no, pid, enabled).map(|_| xous_kernel::Result::Ok)
+        }
+        #[cfg(feature = "irq-latency")]
+        SysCall::GetInterruptLatency(no) => crate::irq::interrupt_latency(no).map(|latency| {
+            xous_kernel::Result::Scalar5(
+                latency.count,
+                latency.max_latency,
+                latency.avg_latency,
+                latency.max_handler,
+                latency.avg_handler,
+            )
+        }),
         SysCall::Yield => do_yield(pid, tid),
+        SysCall::YieldTo(target_pid) => do_yield_to(pid, tid, target_pid),
+        SysCall::GetProcessInfo(target_pid) => SystemServices::with(|ss| {
+            ss.process_info(target_pid)
+                .map(xous_kernel::Result::ProcessInfo)
+        }),
+        SysCall::GetCpuTime(target_pid) => SystemServices::with(|ss| {
+            ss.cpu_time(target_pid).map(|time| {
+                xous_kernel::Result::Scalar2(time as u32 as usize, (time >> 32) as u32 as usize)
+            })
+        }),
+        SysCall::SetThreadName(name) => SystemServices::with_mut(|ss| {
+            ss.set_thread_name(pid, tid, name)
+                .map(|_| xous_kernel::Result::Ok)
+        }),
+        SysCall::Batch(range) => run_batch(pid, tid, in_irq, range),
+        SysCall::SetWatchdog(ms) => {
+            SystemServices::with_mut(|ss| ss.set_watchdog(pid, ms).map(|_| xous_kernel::Result::Ok))
+        }
+        SysCall::PetWatchdog => {
+            SystemServices::with_mut(|ss| ss.pet_watchdog(pid).map(|_| xous_kernel::Result::Ok))
+        }
+        SysCall::GetMonotonicMs => {
+            let now = arch::uptime_ms();
+            Ok(xous_kernel::Result::Scalar2(
+                now as u32 as usize,
+                (now >> 32) as u32 as usize,
+            ))
+        }
         SysCall::ReturnToParent(_pid, _cpuid) => {
             unsafe {
                 if let Some((parent_pid, parent_ctx)) = SWITCHTO_CALLER.take() {
@@ -809,6 +940,14 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
             ss.create_server_with_address(pid, name, true)
                 .map(|(sid, cid)| xous_kernel::Result::NewServerID(sid, cid))
         }),
+        SysCall::CreateServerWithToken(name, token) => SystemServices::with_mut(|ss| {
+            ss.create_server_with_token(pid, name, token, true)
+                .map(|(sid, cid)| xous_kernel::Result::NewServerID(sid, cid))
+        }),
+        SysCall::CreateServerWithQueue(name, depth, policy) => SystemServices::with_mut(|ss| {
+            ss.create_server_with_queue(pid, name, depth, policy, true)
+                .map(|(sid, cid)| xous_kernel::Result::NewServerID(sid, cid))
+        }),
         SysCall::CreateServer => SystemServices::with_mut(|ss| {
             ss.create_server(pid, true)
                 .map(|(sid, cid)| xous_kernel::Result::NewServerID(sid, cid))
@@ -820,14 +959,43 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
             ss.connect_to_server(sid)
                 .map(xous_kernel::Result::ConnectionID)
         }),
+        SysCall::ConnectWithToken(sid, token) => SystemServices::with_mut(|ss| {
+            ss.connect_to_server_with_token(sid, Some(token))
+                .map(xous_kernel::Result::ConnectionID)
+        }),
         SysCall::ReturnMemory(sender, buf, offset, valid) => {
             return_memory(pid, tid, in_irq, sender, buf, offset, valid)
         }
         SysCall::ReturnScalar1(sender, arg) => return_scalar(pid, tid, in_irq, sender, arg),
-        SysCall::ReturnScalar2(sender, arg1, arg2) => {
-            return_scalar2(pid, tid, in_irq, sender, arg1, arg2)
+        SysCall::ReturnScalar2(sender, arg1, arg2) => return_scalars(
+            pid,
+            tid,
+            in_irq,
+            sender,
+            xous_kernel::Result::Scalar2(arg1, arg2),
+        ),
+        SysCall::ReturnScalar5(sender, arg1, arg2, arg3, arg4, arg5) => return_scalars(
+            pid,
+            tid,
+            in_irq,
+            sender,
+            xous_kernel::Result::Scalar5(arg1, arg2, arg3, arg4, arg5),
+        ),
+        SysCall::TrySendMessage(cid, message) => {
+            let result = send_message(pid, tid, cid, message);
+            match result {
+                // Servers that block their senders do so even here, unless the sender
+                // is an interrupt handler, which can't wait.
+                Err(xous_kernel::Error::ServerQueueFull)
+                    if !in_irq
+                        && SystemServices::with(|ss| ss.queue_policy(cid))
+                            == Some(xous_kernel::QueuePolicy::Block) =>
+                {
+                    retry_syscall(pid, tid)
+                }
+                result => result,
+            }
         }
-        SysCall::TrySendMessage(cid, message) => send_message(pid, tid, cid, message),
         SysCall::TerminateProcess(_ret) => SystemServices::with_mut(|ss| {
             ss.unschedule_thread(pid, tid)?;
             ss.terminate_process(pid)?;
@@ -835,6 +1003,14 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
             unsafe { SWITCHTO_CALLER = None };
             Ok(xous_kernel::Result::ResumeProcess)
         }),
+        SysCall::SetMemoryQuota(target_pid, pages) => SystemServices::with(|ss| {
+            ss.set_memory_quota(pid, target_pid, pages)
+                .map(|_| xous_kernel::Result::Ok)
+        }),
+        SysCall::TerminateChildProcess(target_pid) => SystemServices::with_mut(|ss| {
+            ss.terminate_child_process(pid, target_pid)
+                .map(|_| xous_kernel::Result::Ok)
+        }),
         SysCall::Shutdown => {
             SystemServices::with_mut(|ss| ss.shutdown().map(|_| xous_kernel::Result::Ok))
         }
@@ -852,6 +1028,43 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
                 Err(e) => Err(e),
             }
         }
+        SysCall::ConnectWithTimeout(sid, timeout_ms) => {
+            let result = SystemServices::with_mut(|ss| match ss.connect_to_server(sid) {
+                Err(xous_kernel::Error::ServerNotFound) => {
+                    if ss.connect_timed_out(pid, tid, timeout_ms)? {
+                        Err(xous_kernel::Error::ServerNotFound)
+                    } else {
+                        Ok(None)
+                    }
+                }
+                result => {
+                    ss.clear_connect_deadline(pid, tid);
+                    result.map(Some)
+                }
+            });
+            match result {
+                Ok(Some(cid)) => Ok(xous_kernel::Result::ConnectionID(cid)),
+                Ok(None) => retry_syscall(pid, tid),
+                Err(e) => Err(e),
+            }
+        }
+        SysCall::WaitForEvent(event) => SystemServices::with_mut(|ss| {
+            if ss.wait_for_event(pid, tid, event)? {
+                // It was signaled before we got here
+                Ok(xous_kernel::Result::Ok)
+            } else {
+                park_thread(ss, pid, tid)
+            }
+        }),
+        SysCall::SignalEvent(event) => SystemServices::with_mut(|ss| {
+            ss.signal_event(pid, event)
+                .map(xous_kernel::Result::Scalar1)
+        }),
+        SysCall::SleepMs(0) => do_yield(pid, tid),
+        SysCall::SleepMs(ms) => SystemServices::with_mut(|ss| {
+            ss.sleep_thread(pid, tid, ms)?;
+            park_thread(ss, pid, tid)
+        }),
         SysCall::ConnectForProcess(pid, sid) => {
             let result = SystemServices::with_mut(|ss| {
                 ss.connect_process_to_server(pid, sid)
@@ -884,6 +1097,9 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
                 ret
             })
         }
+        SysCall::CancelMessage(other_tid) => {
+            SystemServices::with_mut(|ss| ss.cancel_message(pid, other_tid))
+        }
         SysCall::UpdateMemoryFlags(range, flags, pid) => {
             // We do not yet support modifying flags for other processes.
             if pid.is_some() {
@@ -893,6 +1109,13 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
             MemoryManager::with_mut(|mm| mm.update_memory_flags(range, flags))?;
             Ok(xous_kernel::Result::Ok)
         }
+        SysCall::MapSharedMemory(name, size, flags) => MemoryManager::with_mut(|mm| {
+            mm.map_shared(pid, name, size.get(), flags)
+                .map(xous_kernel::Result::MemoryRange)
+        }),
+        SysCall::UnmapSharedMemory(name) => MemoryManager::with_mut(|mm| {
+            mm.unmap_shared(pid, name).map(xous_kernel::Result::Scalar1)
+        }),
         /* https://github.com/betrusted-io/xous-core/issues/90
         SysCall::SetExceptionHandler(pc, sp) => SystemServices::with_mut(|ss| {
             ss.set_exception_handler(pid, pc, sp)
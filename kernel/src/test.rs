@@ -126,6 +126,882 @@ fn shutdown() {
     main_thread.join().expect("couldn't join main thread");
 }
 
+#[test]
+fn create_process_layout() {
+    use crate::mem::{validate_process_layout, PAGE_SIZE};
+
+    // A new process must be loaded and have its stack on page boundaries
+    assert_eq!(
+        validate_process_layout(0x2050_1001, 0x8000_0000, PAGE_SIZE * 32),
+        Err(xous_kernel::Error::BadAlignment)
+    );
+    assert_eq!(
+        validate_process_layout(0x2050_1000, 0x8000_0800, PAGE_SIZE * 32),
+        Err(xous_kernel::Error::BadAlignment)
+    );
+    assert_eq!(
+        validate_process_layout(0x2050_1000, 0x8000_0000, PAGE_SIZE * 32 + 4),
+        Err(xous_kernel::Error::BadAlignment)
+    );
+    assert_eq!(
+        validate_process_layout(0x2050_1000, 0x8000_0000, PAGE_SIZE * 32),
+        Ok(())
+    );
+}
+
+#[test]
+fn thread_stack_guard_page() {
+    use crate::mem::{stack_guard_page, PAGE_SIZE};
+
+    // The guard is the lowest page of the stack, below where the thread starts
+    let stack = 0x4000_0000;
+    let size = PAGE_SIZE * 33;
+    let guard = stack_guard_page(stack, size).expect("couldn't pick a guard page");
+    assert_eq!(guard, stack);
+    assert!(guard + PAGE_SIZE <= stack + size - PAGE_SIZE);
+
+    // Stacks that aren't page-aligned can't have a page carved out of them
+    assert_eq!(
+        stack_guard_page(stack + 0x10, size),
+        Err(xous_kernel::Error::BadAlignment)
+    );
+    assert_eq!(
+        stack_guard_page(stack, size - 4),
+        Err(xous_kernel::Error::BadAlignment)
+    );
+
+    // A single-page stack would be nothing but guard
+    assert_eq!(
+        stack_guard_page(stack, PAGE_SIZE),
+        Err(xous_kernel::Error::BadAddress)
+    );
+}
+
+#[test]
+fn thread_round_robin() {
+    use crate::services::next_ready_thread;
+
+    // Three runnable contexts each get one turn per rotation
+    let ready = (1 << 2) | (1 << 3) | (1 << 5);
+    let mut current = 2;
+    let mut order = vec![];
+    for _ in 0..6 {
+        current = next_ready_thread(ready, current).expect("no thread was ready");
+        order.push(current);
+    }
+    assert_eq!(order, vec![3, 5, 2, 3, 5, 2]);
+
+    // A lone context keeps running, and an idle process has nothing to run
+    assert_eq!(next_ready_thread(1 << 2, 2), Some(2));
+    assert_eq!(next_ready_thread(1 << 2, 7), Some(2));
+    assert_eq!(next_ready_thread(0, 2), None);
+}
+
+#[test]
+fn create_process_fresh_pid() {
+    // Start the server in another thread
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (pid_send, pid_recv) = unbounded();
+
+    // Each spawn gets its own process table entry
+    let mut processes = vec![];
+    for _ in 0..2 {
+        let pid_send = pid_send.clone();
+        processes.push(
+            xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+                "create_process_fresh_pid child",
+                move || {
+                    pid_send.send(xous_kernel::current_pid().unwrap()).unwrap();
+                },
+            ))
+            .expect("couldn't spawn child process"),
+        );
+    }
+
+    let first = pid_recv.recv().unwrap();
+    let second = pid_recv.recv().unwrap();
+    assert_ne!(first, second);
+    assert_ne!(first.get(), 1);
+    assert_ne!(second.get(), 1);
+
+    for process in processes {
+        crate::wait_process_as_thread(process).expect("couldn't join child process");
+    }
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn terminate_child_process() {
+    // Start the server in another thread
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (pid_send, pid_recv) = unbounded();
+    let (checked_send, checked_recv) = unbounded();
+    let (release_send, release_recv) = unbounded::<()>();
+
+    // Both processes are children of the test harness, not of each other.
+    let xous_target = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "terminate_child_process target",
+        move || {
+            pid_send.send(xous_kernel::current_pid().unwrap()).unwrap();
+            release_recv.recv().ok();
+        },
+    ))
+    .expect("couldn't spawn target process");
+    let target_pid = pid_recv.recv().unwrap();
+
+    let xous_other = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "terminate_child_process other",
+        move || {
+            assert_eq!(
+                xous_kernel::terminate_child_process(target_pid),
+                Err(xous_kernel::Error::ProcessNotChild)
+            );
+            let own_pid = xous_kernel::current_pid().unwrap();
+            assert_eq!(
+                xous_kernel::terminate_child_process(own_pid),
+                Err(xous_kernel::Error::ProcessNotChild)
+            );
+            checked_send.send(()).unwrap();
+        },
+    ))
+    .expect("couldn't spawn other process");
+    checked_recv.recv().unwrap();
+    crate::wait_process_as_thread(xous_other).expect("couldn't join other process");
+
+    // The parent may terminate its child, after which the child is gone.
+    assert_eq!(xous_kernel::terminate_child_process(target_pid), Ok(()));
+    assert_eq!(
+        xous_kernel::terminate_child_process(target_pid),
+        Err(xous_kernel::Error::ProcessNotFound)
+    );
+    // Its connection has been shut down, so let its thread return without
+    // making another call, and wait for it to be done.
+    drop(release_send);
+    crate::wait_process_as_thread(xous_target).expect("couldn't join target process");
+
+    // The next process gets the PID, and isn't disturbed by what may still be
+    // on its way from the old one.
+    let (reused_send, reused_recv) = unbounded();
+    let xous_next = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "terminate_child_process next",
+        move || {
+            for _ in 0..10 {
+                xous_kernel::yield_slice();
+            }
+            reused_send
+                .send(xous_kernel::current_pid().unwrap())
+                .unwrap();
+        },
+    ))
+    .expect("couldn't spawn next process");
+    assert_eq!(reused_recv.recv().unwrap(), target_pid);
+    crate::wait_process_as_thread(xous_next).expect("couldn't join next process");
+
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn syscall_from_released_process() {
+    use crate::services::SystemServices;
+    // Nothing is set up on this thread, so no PID has a process behind it
+    let pid = xous_kernel::PID::new(5).unwrap();
+    assert_eq!(
+        crate::syscall::handle(pid, 1, false, SysCall::TerminateProcess(0)),
+        Err(xous_kernel::Error::ProcessNotFound)
+    );
+
+    // Nor does one that has only been allocated have a thread to call from
+    let init = xous_kernel::ProcessInit {
+        key: xous_kernel::ProcessKey::new([7; 16]),
+        page_quota: 0,
+    };
+    let allocated = SystemServices::with_mut(|ss| ss.create_process(init))
+        .unwrap()
+        .pid();
+    assert_eq!(
+        crate::syscall::handle(allocated, 1, false, SysCall::TerminateProcess(0)),
+        Err(xous_kernel::Error::ProcessNotFound)
+    );
+}
+
+#[test]
+fn irq_priority_order() {
+    use crate::irq::{next_pending_irq, IrqHandler};
+    let pid = xous_kernel::PID::new(2).unwrap();
+    let f = xous_kernel::MemoryAddress::new(0x1000).unwrap();
+    let mut handlers: [Option<IrqHandler>; 32] = [None; 32];
+
+    // With equal priorities, the lowest-numbered interrupt goes first
+    handlers[3] = Some((pid, f, None, xous_kernel::IRQ_PRIORITY_DEFAULT));
+    handlers[7] = Some((pid, f, None, xous_kernel::IRQ_PRIORITY_DEFAULT));
+    let pending = (1 << 3) | (1 << 7);
+    assert_eq!(next_pending_irq(pending, 0, &handlers), Some(3));
+
+    // A higher-priority interrupt is serviced first, and the other one
+    // follows once it is no longer pending
+    handlers[7] = Some((pid, f, None, 5));
+    assert_eq!(next_pending_irq(pending, 0, &handlers), Some(7));
+    assert_eq!(next_pending_irq(pending & !(1 << 7), 0, &handlers), Some(3));
+
+    // Pending interrupts that nobody has claimed are never dispatched
+    assert_eq!(next_pending_irq(1 << 4, 0, &handlers), None);
+}
+
+#[test]
+fn irq_set_enabled() {
+    use crate::irq::{next_pending_irq, IrqHandler};
+    let pid = xous_kernel::PID::new(2).unwrap();
+    let f = xous_kernel::MemoryAddress::new(0x1000).unwrap();
+    let mut handlers: [Option<IrqHandler>; 32] = [None; 32];
+    handlers[3] = Some((pid, f, None, xous_kernel::IRQ_PRIORITY_DEFAULT));
+
+    // A masked interrupt stays pending but its handler doesn't run
+    assert_eq!(next_pending_irq(1 << 3, 1 << 3, &handlers), None);
+    assert_eq!(next_pending_irq(1 << 3, 0, &handlers), Some(3));
+
+    // Only the process that claimed an interrupt may mask it
+    let main_thread = start_kernel(SERVER_SPEC);
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("irq_set_enabled", move || {
+            assert_eq!(
+                xous_kernel::set_interrupt_enabled(31, false),
+                Err(xous_kernel::Error::AccessDenied)
+            );
+            assert_eq!(
+                xous_kernel::set_interrupt_enabled(32, true),
+                Err(xous_kernel::Error::InterruptNotFound)
+            );
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn yield_to() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::Arc;
+
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    // A process that keeps count of how often it gets to run
+    let runs = Arc::new(AtomicUsize::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let (pid_tx, pid_rx) = unbounded();
+    let target_process = {
+        let runs = runs.clone();
+        let stop = stop.clone();
+        xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+            "yield_to target",
+            move || {
+                pid_tx.send(xous_kernel::current_pid().unwrap()).unwrap();
+                while !stop.load(Ordering::SeqCst) {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    xous_kernel::yield_slice();
+                }
+            },
+        ))
+        .expect("couldn't spawn target process")
+    };
+    let target_pid = pid_rx.recv().unwrap();
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("yield_to", move || {
+            // The target gets to run while we yield to it
+            let before = runs.load(Ordering::SeqCst);
+            for _ in 0..1000 {
+                assert_eq!(
+                    rsyscall(SysCall::YieldTo(target_pid)),
+                    Ok(xous_kernel::Result::Ok)
+                );
+                if runs.load(Ordering::SeqCst) > before {
+                    break;
+                }
+            }
+            assert!(runs.load(Ordering::SeqCst) > before);
+            stop.store(true, Ordering::SeqCst);
+
+            // Yielding to a process that can't run falls back to a regular yield
+            // rather than returning an error
+            let own_pid = xous_kernel::current_pid().unwrap();
+            assert_eq!(
+                rsyscall(SysCall::YieldTo(own_pid)),
+                Ok(xous_kernel::Result::Ok)
+            );
+            assert_eq!(
+                rsyscall(SysCall::YieldTo(xous_kernel::PID::new(30).unwrap())),
+                Ok(xous_kernel::Result::Ok)
+            );
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    xous_kernel::wait_process_as_thread(target_process).expect("couldn't join target process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn get_process_info() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("get_process_info", move || {
+            let pid = xous_kernel::current_pid().unwrap();
+            let before = xous_kernel::get_process_info(pid).expect("couldn't get process info");
+            assert_eq!(before.pid, pid);
+            assert_eq!(before.ppid.get(), 1);
+
+            rsyscall(SysCall::IncreaseHeap(
+                4096 * 2,
+                xous_kernel::MemoryFlags::R | xous_kernel::MemoryFlags::W,
+            ))
+            .expect("couldn't increase heap");
+            let after = xous_kernel::get_process_info(pid).expect("couldn't get process info");
+            assert_eq!(after.heap_base, before.heap_base);
+            assert_eq!(after.heap_size, before.heap_size + 4096 * 2);
+            assert_eq!(after.heap_max, before.heap_max);
+
+            assert_eq!(
+                xous_kernel::get_process_info(xous_kernel::PID::new(30).unwrap()),
+                Err(xous_kernel::Error::ProcessNotFound)
+            );
+            assert_eq!(
+                xous_kernel::get_process_info(xous_kernel::PID::new(255).unwrap()),
+                Err(xous_kernel::Error::ProcessNotFound)
+            );
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn set_thread_name() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("set_thread_name", move || {
+            let pid = xous_kernel::current_pid().unwrap();
+            let info = xous_kernel::get_process_info(pid).expect("couldn't get process info");
+            assert!(info.name.is_empty());
+
+            xous_kernel::set_thread_name("main").expect("couldn't set thread name");
+            let info = xous_kernel::get_process_info(pid).expect("couldn't get process info");
+            assert_eq!(info.name.as_str(), "main");
+            assert_eq!(info.pid, pid);
+            assert_eq!(info.ppid.get(), 1);
+
+            // Names are cut short at the limit, and never in the middle of a character
+            xous_kernel::set_thread_name("a-rather-long-name").expect("couldn't set thread name");
+            let info = xous_kernel::get_process_info(pid).expect("couldn't get process info");
+            assert_eq!(info.name.as_str(), "a-rather-lon");
+            xous_kernel::set_thread_name("naïveté-naïveté").expect("couldn't set thread name");
+            let info = xous_kernel::get_process_info(pid).expect("couldn't get process info");
+            assert_eq!(info.name.as_str(), "naïveté-na");
+
+            // Other threads' names don't replace the main thread's
+            let thr = xous_kernel::create_thread(move || {
+                xous_kernel::set_thread_name("worker").expect("couldn't set thread name");
+            })
+            .expect("couldn't create thread");
+            xous_kernel::wait_thread(thr).expect("couldn't join thread");
+            let info = xous_kernel::get_process_info(pid).expect("couldn't get process info");
+            assert_eq!(info.name.as_str(), "naïveté-na");
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn decrease_heap_bounds() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("decrease_heap_bounds", move || {
+            let pid = xous_kernel::current_pid().unwrap();
+            let initial = xous_kernel::get_process_info(pid).unwrap().heap_size;
+            rsyscall(SysCall::IncreaseHeap(
+                4096 * 2,
+                xous_kernel::MemoryFlags::R | xous_kernel::MemoryFlags::W,
+            ))
+            .expect("couldn't increase heap");
+
+            // Shrinking past the start of the heap is rejected and leaves it alone
+            assert_eq!(
+                rsyscall(SysCall::DecreaseHeap(initial + 4096 * 3)),
+                Err(xous_kernel::Error::BadAddress)
+            );
+            assert_eq!(
+                xous_kernel::get_process_info(pid).unwrap().heap_size,
+                initial + 4096 * 2
+            );
+
+            assert_eq!(
+                rsyscall(SysCall::DecreaseHeap(4096)),
+                Ok(xous_kernel::Result::Ok)
+            );
+            assert_eq!(
+                xous_kernel::get_process_info(pid).unwrap().heap_size,
+                initial + 4096
+            );
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn cpu_time() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (server_addr_send, server_addr_recv) = unbounded();
+    let (idle_time_send, idle_time_recv) = unbounded();
+    let (busy_time_send, busy_time_recv) = unbounded();
+
+    // This process spends almost all of its time blocked in the kernel
+    let xous_idle = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "cpu_time idle",
+        move || {
+            let sid = xous_kernel::create_server().expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+            xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            let pid = xous_kernel::current_pid().unwrap();
+            idle_time_send
+                .send(xous_kernel::get_cpu_time(pid).unwrap())
+                .unwrap();
+        },
+    ))
+    .expect("couldn't spawn idle process");
+
+    // This process spins for a while before waking the idle one up
+    let xous_busy = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "cpu_time busy",
+        move || {
+            let sid = server_addr_recv.recv().unwrap();
+            let conn = xous_kernel::connect(sid).expect("couldn't connect to server");
+            let start = std::time::Instant::now();
+            while start.elapsed() < std::time::Duration::from_millis(50) {
+                core::hint::spin_loop();
+            }
+            let pid = xous_kernel::current_pid().unwrap();
+            busy_time_send
+                .send(xous_kernel::get_cpu_time(pid).unwrap())
+                .unwrap();
+            xous_kernel::send_message(conn, xous_kernel::Message::new_scalar(1, 0, 0, 0, 0))
+                .expect("couldn't send message");
+        },
+    ))
+    .expect("couldn't spawn busy process");
+
+    let busy_time = busy_time_recv.recv().unwrap();
+    let idle_time = idle_time_recv.recv().unwrap();
+    assert!(
+        busy_time >= 50_000,
+        "busy process only ran for {} us",
+        busy_time
+    );
+    assert!(
+        idle_time < busy_time,
+        "idle process ran for {} us, busy for {} us",
+        idle_time,
+        busy_time
+    );
+    assert_eq!(
+        xous_kernel::get_cpu_time(xous_kernel::PID::new(30).unwrap()),
+        Err(xous_kernel::Error::ProcessNotFound)
+    );
+
+    xous_kernel::wait_process_as_thread(xous_busy).expect("couldn't join busy process");
+    xous_kernel::wait_process_as_thread(xous_idle).expect("couldn't join idle process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn connect_with_timeout() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("connect_with_timeout", move || {
+            let missing = xous_kernel::SID::from_bytes(b"timeout-missing!").unwrap();
+
+            // Nobody ever creates this server, so connecting gives up after the deadline
+            let start = std::time::Instant::now();
+            assert_eq!(
+                xous_kernel::connect_with_timeout(missing, 100),
+                Err(xous_kernel::Error::ServerNotFound)
+            );
+            assert!(start.elapsed() >= std::time::Duration::from_millis(100));
+
+            // A timeout of zero doesn't wait at all
+            assert_eq!(
+                xous_kernel::connect_with_timeout(missing, 0),
+                Err(xous_kernel::Error::ServerNotFound)
+            );
+
+            // Servers that already exist are connected to right away
+            let sid = xous_kernel::create_server_with_address(b"timeout-present!")
+                .expect("couldn't create server");
+            xous_kernel::connect_with_timeout(sid, 100).expect("couldn't connect to server");
+            xous_kernel::destroy_server(sid).expect("couldn't destroy server");
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn wait_for_event() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("wait_for_event", move || {
+            let woken = Arc::new(AtomicBool::new(false));
+            let waiter = {
+                let woken = woken.clone();
+                xous_kernel::create_thread(move || {
+                    xous_kernel::wait_for_event(7).expect("couldn't wait for event");
+                    woken.store(true, Ordering::SeqCst);
+                })
+                .expect("couldn't create waiter thread")
+            };
+
+            // The waiter stays parked until its own event is signaled
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            assert!(!woken.load(Ordering::SeqCst));
+            assert_eq!(xous_kernel::signal_event(8), Ok(0));
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(!woken.load(Ordering::SeqCst));
+
+            assert_eq!(xous_kernel::signal_event(7), Ok(1));
+            xous_kernel::wait_thread(waiter).expect("couldn't join waiter thread");
+            assert!(woken.load(Ordering::SeqCst));
+
+            // Nobody is waiting anymore, so the signal is kept for the next
+            // wait, and a second signal folds into it
+            assert_eq!(xous_kernel::signal_event(7), Ok(0));
+            assert_eq!(xous_kernel::signal_event(7), Ok(0));
+            xous_kernel::wait_for_event(7).expect("pending event wasn't delivered");
+
+            // That used it up, so the next wait parks until a new signal
+            let woken = Arc::new(AtomicBool::new(false));
+            let waiter = {
+                let woken = woken.clone();
+                xous_kernel::create_thread(move || {
+                    xous_kernel::wait_for_event(7).expect("couldn't wait for event");
+                    woken.store(true, Ordering::SeqCst);
+                })
+                .expect("couldn't create waiter thread")
+            };
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            assert!(!woken.load(Ordering::SeqCst));
+            assert_eq!(xous_kernel::signal_event(7), Ok(1));
+            xous_kernel::wait_thread(waiter).expect("couldn't join waiter thread");
+            assert!(woken.load(Ordering::SeqCst));
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn sleep_ms() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("sleep_ms", move || {
+            let start = std::time::Instant::now();
+            xous_kernel::sleep_ms(200).expect("couldn't sleep");
+            assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+
+            // A longer sleep in another thread doesn't hold up a shorter one here
+            let start = std::time::Instant::now();
+            let sleeper = xous_kernel::create_thread(move || {
+                xous_kernel::sleep_ms(1000).expect("couldn't sleep");
+                start.elapsed()
+            })
+            .expect("couldn't create sleeper thread");
+            xous_kernel::sleep_ms(100).expect("couldn't sleep");
+            let short = start.elapsed();
+            assert!(short >= std::time::Duration::from_millis(100));
+            assert!(short < std::time::Duration::from_millis(1000));
+            xous_kernel::wait_thread(sleeper).expect("couldn't join sleeper thread");
+            assert!(start.elapsed() >= std::time::Duration::from_millis(1000));
+
+            // Sleeping for no time at all is just a yield
+            xous_kernel::sleep_ms(0).expect("couldn't yield");
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn get_monotonic_ms() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("get_monotonic_ms", move || {
+            let first = xous_kernel::get_monotonic_ms().expect("couldn't get time");
+            let second = xous_kernel::get_monotonic_ms().expect("couldn't get time");
+            assert!(second >= first);
+
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            let third = xous_kernel::get_monotonic_ms().expect("couldn't get time");
+            assert!(third >= second + 100, "{} -> {}", second, third);
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn connect_with_token() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (server_addr_send, server_addr_recv) = unbounded();
+    let token = xous_kernel::ConnectToken::from_u32(0x1234_5678, 0x9abc_def0, 0x0fed_cba9);
+
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "connect_with_token server",
+        move || {
+            let sid = xous_kernel::create_server_with_token(b"token-protected!", token)
+                .expect("couldn't create server");
+            // The server's own process doesn't need the token
+            xous_kernel::try_connect(sid).expect("couldn't connect to own server");
+            server_addr_send.send(sid).unwrap();
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            assert_eq!(envelope.id(), 1);
+        },
+    ))
+    .expect("couldn't spawn server process");
+
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "connect_with_token client",
+        move || {
+            let sid = server_addr_recv.recv().unwrap();
+            assert_eq!(
+                xous_kernel::try_connect(sid),
+                Err(xous_kernel::Error::AccessDenied)
+            );
+            assert_eq!(
+                xous_kernel::connect(sid),
+                Err(xous_kernel::Error::AccessDenied)
+            );
+            assert_eq!(
+                xous_kernel::connect_with_token(
+                    sid,
+                    xous_kernel::ConnectToken::from_u32(0x1234_5678, 0x9abc_def0, 0)
+                ),
+                Err(xous_kernel::Error::AccessDenied)
+            );
+
+            let conn = xous_kernel::connect_with_token(sid, token).expect("couldn't connect");
+            xous_kernel::try_send_message(conn, xous_kernel::Message::new_scalar(1, 0, 0, 0, 0))
+                .expect("couldn't send message");
+        },
+    ))
+    .expect("couldn't spawn client process");
+
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn server_queue_policies() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use xous_kernel::QueuePolicy;
+
+    let main_thread = start_kernel(SERVER_SPEC);
+    let (server_addr_send, server_addr_recv) = unbounded();
+    let (full_send, full_recv) = unbounded();
+    let draining = Arc::new(AtomicBool::new(false));
+    let server_draining = draining.clone();
+
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "server_queue_policies server",
+        move || {
+            assert_eq!(
+                xous_kernel::create_server_with_queue(b"queue-depth-zero", 0, QueuePolicy::Reject),
+                Err(xous_kernel::Error::InvalidSyscall)
+            );
+            assert_eq!(
+                xous_kernel::create_server_with_queue(
+                    b"queue-too-deep!!",
+                    256,
+                    QueuePolicy::Reject
+                ),
+                Err(xous_kernel::Error::OutOfMemory)
+            );
+
+            let reject =
+                xous_kernel::create_server_with_queue(b"queue-rejecting!", 2, QueuePolicy::Reject)
+                    .expect("couldn't create rejecting server");
+            let drop_oldest = xous_kernel::create_server_with_queue(
+                b"queue-dropoldest",
+                2,
+                QueuePolicy::DropOldest,
+            )
+            .expect("couldn't create drop-oldest server");
+            let block =
+                xous_kernel::create_server_with_queue(b"queue-blocking!!", 2, QueuePolicy::Block)
+                    .expect("couldn't create blocking server");
+            server_addr_send.send((reject, drop_oldest, block)).unwrap();
+
+            // Give the client time to get stuck on the full blocking server
+            full_recv.recv().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            server_draining.store(true, Ordering::SeqCst);
+
+            let ids = |sid| {
+                let mut ids = vec![];
+                while let Some(envelope) =
+                    xous_kernel::try_receive_message(sid).expect("couldn't receive messages")
+                {
+                    ids.push(envelope.id());
+                }
+                ids
+            };
+            let mut blocked = vec![];
+            while blocked.len() < 3 {
+                let envelope =
+                    xous_kernel::receive_message(block).expect("couldn't receive messages");
+                blocked.push(envelope.id());
+            }
+            assert_eq!(blocked, vec![1, 2, 3]);
+            assert_eq!(ids(reject), vec![1, 2]);
+            assert_eq!(ids(drop_oldest), vec![2, 3]);
+        },
+    ))
+    .expect("couldn't spawn server process");
+
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "server_queue_policies client",
+        move || {
+            let (reject, drop_oldest, block) = server_addr_recv.recv().unwrap();
+            let send = |conn, id| {
+                xous_kernel::try_send_message(
+                    conn,
+                    xous_kernel::Message::new_scalar(id, 0, 0, 0, 0),
+                )
+            };
+
+            let conn = xous_kernel::connect(reject).expect("couldn't connect");
+            assert!(send(conn, 1).is_ok());
+            assert!(send(conn, 2).is_ok());
+            assert_eq!(send(conn, 3), Err(xous_kernel::Error::ServerQueueFull));
+
+            let conn = xous_kernel::connect(drop_oldest).expect("couldn't connect");
+            for id in 1..=3 {
+                assert!(send(conn, id).is_ok());
+            }
+
+            let conn = xous_kernel::connect(block).expect("couldn't connect");
+            assert!(send(conn, 1).is_ok());
+            assert!(send(conn, 2).is_ok());
+            full_send.send(()).unwrap();
+            assert!(send(conn, 3).is_ok());
+            assert!(
+                draining.load(Ordering::SeqCst),
+                "sent to a full queue without waiting"
+            );
+        },
+    ))
+    .expect("couldn't spawn client process");
+
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn batch_syscalls() {
+    use xous_kernel::{Result, SysCall};
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("batch_syscalls", move || {
+            let pid = xous_kernel::current_pid().expect("couldn't get pid");
+            let before = xous_kernel::get_monotonic_ms().expect("couldn't get time");
+
+            // Both results come back, in the order the calls were made
+            let results = xous_kernel::batch(&[SysCall::GetProcessId, SysCall::GetMonotonicMs])
+                .expect("couldn't run batch");
+            assert_eq!(results[0], Result::ProcessID(pid));
+            if let Result::Scalar2(low, high) = results[1] {
+                let now = (low as u32 as u64) | ((high as u32 as u64) << 32);
+                assert!(now >= before, "{} -> {}", before, now);
+            } else {
+                panic!("unexpected result for GetMonotonicMs: {:?}", results[1]);
+            }
+
+            // A call that can block is refused, and nothing in the batch runs
+            assert_eq!(
+                xous_kernel::batch(&[SysCall::GetProcessId, SysCall::SleepMs(1)]),
+                Err(xous_kernel::Error::InvalidSyscall)
+            );
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
 #[test]
 fn connect_for_process() {
     use xous_kernel::SID;
@@ -189,36 +1065,369 @@ fn connect_for_process() {
         "send_scalar_message server",
         move || {
             let sid = xous_kernel::create_server().expect("couldn't create test server");
-            // Wait for nameserver to start
-            nameserver_recv.recv().unwrap();
-
-            let conn =
-                xous_kernel::try_connect(nameserver_addr).expect("couldn't connect to server");
-            // Register our SID with the nameserver
-            let sid_u32 = sid.to_u32();
-            xous_kernel::send_message(
-                conn,
-                xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
-                    id: 1,
-                    arg1: sid_u32.0 as _,
-                    arg2: sid_u32.1 as _,
-                    arg3: sid_u32.2 as _,
-                    arg4: sid_u32.3 as _,
-                }),
-            )
-            .expect("couldn't send message");
-
-            server_addr_send.send(()).unwrap();
-
-            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            // Wait for nameserver to start
+            nameserver_recv.recv().unwrap();
+
+            let conn =
+                xous_kernel::try_connect(nameserver_addr).expect("couldn't connect to server");
+            // Register our SID with the nameserver
+            let sid_u32 = sid.to_u32();
+            xous_kernel::send_message(
+                conn,
+                xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
+                    id: 1,
+                    arg1: sid_u32.0 as _,
+                    arg2: sid_u32.1 as _,
+                    arg3: sid_u32.2 as _,
+                    arg4: sid_u32.3 as _,
+                }),
+            )
+            .expect("couldn't send message");
+
+            server_addr_send.send(()).unwrap();
+
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            assert_eq!(
+                envelope.body,
+                xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
+                    id: 15,
+                    arg1: 21,
+                    arg2: 31,
+                    arg3: 41,
+                    arg4: 51
+                })
+            );
+        },
+    ))
+    .expect("couldn't spawn server process");
+
+    // Spawn the client "process" and wait for the server address.
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "send_scalar_message client",
+        move || {
+            server_addr_recv.recv().unwrap();
+            let conn =
+                xous_kernel::try_connect(nameserver_addr).expect("couldn't connect to server");
+
+            // Attempt to resolve this address.
+            let other_conn_result = xous_kernel::try_send_message(
+                conn,
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
+                    id: 10,
+                    arg1: 52,
+                    arg2: 53,
+                    arg3: 54,
+                    arg4: 55,
+                }),
+            )
+            .expect("couldn't send message");
+            let other_conn = if let xous_kernel::Result::Scalar1(r) = other_conn_result {
+                r
+            } else {
+                panic!("unexpected return value");
+            };
+
+            // Send a message to the server we were just connected to.
+            xous_kernel::try_send_message(
+                other_conn as u32,
+                xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
+                    id: 15,
+                    arg1: 21,
+                    arg2: 31,
+                    arg3: 41,
+                    arg4: 51,
+                }),
+            )
+            .expect("couldn't send message");
+        },
+    ))
+    .expect("couldn't spawn client process");
+
+    // Wait for both processes to finish
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+    crate::wait_process_as_thread(nameserver_process).expect("couldn't join nameserver process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn send_scalar_message() {
+    // Start the server in another thread
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (server_addr_send, server_addr_recv) = unbounded();
+
+    // Spawn the server "process" (which just lives in a separate thread)
+    // and receive the message. Note that we need to communicate to the
+    // "Client" what our server ID is. Normally this would be done via
+    // an external nameserver.
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "send_scalar_message server",
+        move || {
+            let sid = xous_kernel::create_server().expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            assert_eq!(
+                envelope.body,
+                xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
+                    id: 1,
+                    arg1: 2,
+                    arg2: 3,
+                    arg3: 4,
+                    arg4: 5
+                })
+            );
+        },
+    ))
+    .expect("couldn't spawn server process");
+
+    // Spawn the client "process" and wait for the server address.
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "send_scalar_message client",
+        move || {
+            let sid = server_addr_recv.recv().unwrap();
+            let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
+            xous_kernel::try_send_message(
+                conn,
+                xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
+                    id: 1,
+                    arg1: 2,
+                    arg2: 3,
+                    arg3: 4,
+                    arg4: 5,
+                }),
+            )
+            .expect("couldn't send message");
+        },
+    ))
+    .expect("couldn't spawn client process");
+
+    // Wait for both processes to finish
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn message_sender_pid() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (server_addr_send, server_addr_recv) = unbounded();
+    let (client_pid_send, client_pid_recv) = unbounded();
+    let (server_waiting_send, server_waiting_recv) = unbounded();
+
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "message_sender_pid server",
+        move || {
+            let sid = xous_kernel::create_server().expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+            let client_pid = client_pid_recv.recv().unwrap();
+            assert_ne!(client_pid, xous_kernel::current_pid().unwrap());
+
+            // Give the first message time to land in the queue before picking it up
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            assert_eq!(envelope.id(), 1);
+            assert_eq!(envelope.sender.pid(), Some(client_pid));
+
+            // The second message is handed straight to this waiting thread
+            server_waiting_send.send(()).unwrap();
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            assert_eq!(envelope.id(), 2);
+            assert_eq!(envelope.sender.pid(), Some(client_pid));
+            xous_kernel::return_scalar(envelope.sender, 0).expect("couldn't return scalar");
+        },
+    ))
+    .expect("couldn't spawn server process");
+
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "message_sender_pid client",
+        move || {
+            let sid = server_addr_recv.recv().unwrap();
+            let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
+            client_pid_send
+                .send(xous_kernel::current_pid().unwrap())
+                .unwrap();
+            xous_kernel::try_send_message(conn, xous_kernel::Message::new_scalar(1, 0, 0, 0, 0))
+                .expect("couldn't send message");
+
+            server_waiting_recv.recv().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            xous_kernel::try_send_message(
+                conn,
+                xous_kernel::Message::new_blocking_scalar(2, 0, 0, 0, 0),
+            )
+            .expect("couldn't send message");
+        },
+    ))
+    .expect("couldn't spawn client process");
+
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn scalar_fast_path_matches_queued() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (server_addr_send, server_addr_recv) = unbounded();
+    let (server_waiting_send, server_waiting_recv) = unbounded();
+    let (envelope_send, envelope_recv) = unbounded();
+    let (client_pid_send, client_pid_recv) = unbounded();
+
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "scalar_fast_path_matches_queued server",
+        move || {
+            let sid = xous_kernel::create_server().expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+
+            // The first message arrives while nobody is receiving, so it's queued
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            envelope_send
+                .send((envelope.sender.pid(), envelope.body))
+                .unwrap();
+
+            // The second is handed straight to this waiting thread
+            server_waiting_send.send(()).unwrap();
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            envelope_send
+                .send((envelope.sender.pid(), envelope.body))
+                .unwrap();
+        },
+    ))
+    .expect("couldn't spawn server process");
+
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "scalar_fast_path_matches_queued client",
+        move || {
+            let sid = server_addr_recv.recv().unwrap();
+            let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
+            client_pid_send
+                .send(xous_kernel::current_pid().unwrap())
+                .unwrap();
+            assert_eq!(
+                xous_kernel::try_send_message(
+                    conn,
+                    xous_kernel::Message::new_scalar(7, 1, 2, 3, 4)
+                ),
+                Ok(xous_kernel::Result::Ok)
+            );
+
+            server_waiting_recv.recv().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert_eq!(
+                xous_kernel::try_send_message(
+                    conn,
+                    xous_kernel::Message::new_scalar(7, 1, 2, 3, 4)
+                ),
+                Ok(xous_kernel::Result::Ok)
+            );
+        },
+    ))
+    .expect("couldn't spawn client process");
+
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+
+    // Both paths deliver the same message from the same sender
+    let queued = envelope_recv.recv().unwrap();
+    let direct = envelope_recv.recv().unwrap();
+    assert_eq!(queued.0, Some(client_pid_recv.recv().unwrap()));
+    assert_eq!(queued, direct);
+    assert_eq!(queued.1, xous_kernel::Message::new_scalar(7, 1, 2, 3, 4));
+
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn blocking_send_to_self() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    // This test thread connects as PID1, and is the only thread in it
+    xous_kernel::arch::ensure_connection().expect("couldn't connect to kernel");
+    let sid = xous_kernel::create_server().expect("couldn't create server");
+    let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
+
+    // With nobody else to answer, a blocking send would never return
+    assert_eq!(
+        xous_kernel::try_send_message(
+            conn,
+            xous_kernel::Message::new_blocking_scalar(1, 0, 0, 0, 0)
+        ),
+        Err(xous_kernel::Error::WouldDeadlock)
+    );
+
+    // A process with a second thread to serve the server can send to it
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("blocking_send_to_self", move || {
+            let sid = xous_kernel::create_server().expect("couldn't create server");
+            let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
+            let server = xous_kernel::create_thread(move || {
+                let envelope = xous_kernel::receive_message(sid).expect("couldn't receive message");
+                xous_kernel::return_scalar(envelope.sender, envelope.id() + 1)
+                    .expect("couldn't return scalar");
+            })
+            .expect("couldn't create server thread");
+            assert_eq!(
+                xous_kernel::send_message(
+                    conn,
+                    xous_kernel::Message::new_blocking_scalar(3, 0, 0, 0, 0),
+                ),
+                Ok(xous_kernel::Result::Scalar1(4))
+            );
+            xous_kernel::wait_thread(server).expect("couldn't join server thread");
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn try_receive_message() {
+    // Start the server in another thread
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (server_addr_send, server_addr_recv) = unbounded();
+    let (client_sent_send, client_sent_recv) = unbounded();
+
+    // Spawn the server "process" (which just lives in a separate thread)
+    // and receive the message. Note that we need to communicate to the
+    // "Client" what our server ID is. Normally this would be done via
+    // an external nameserver.
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "send_scalar_message server",
+        move || {
+            let sid = xous_kernel::create_server().expect("couldn't create test server");
+            let maybe_envelope =
+                xous_kernel::try_receive_message(sid).expect("couldn't receive messages");
+            assert!(maybe_envelope.is_none(), "some message came back");
+            server_addr_send.send(sid).unwrap();
+            client_sent_recv.recv().unwrap();
+            let maybe_envelope =
+                xous_kernel::try_receive_message(sid).expect("couldn't receive messages");
+            let envelope = maybe_envelope.expect("got None as an envelope");
             assert_eq!(
                 envelope.body,
                 xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
-                    id: 15,
-                    arg1: 21,
-                    arg2: 31,
-                    arg3: 41,
-                    arg4: 51
+                    id: 11,
+                    arg1: 12,
+                    arg2: 13,
+                    arg3: 14,
+                    arg4: 15
                 })
             );
         },
@@ -229,40 +1438,22 @@ fn connect_for_process() {
     let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
         "send_scalar_message client",
         move || {
-            server_addr_recv.recv().unwrap();
-            let conn =
-                xous_kernel::try_connect(nameserver_addr).expect("couldn't connect to server");
-
-            // Attempt to resolve this address.
-            let other_conn_result = xous_kernel::try_send_message(
-                conn,
-                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
-                    id: 10,
-                    arg1: 52,
-                    arg2: 53,
-                    arg3: 54,
-                    arg4: 55,
-                }),
-            )
-            .expect("couldn't send message");
-            let other_conn = if let xous_kernel::Result::Scalar1(r) = other_conn_result {
-                r
-            } else {
-                panic!("unexpected return value");
-            };
-
-            // Send a message to the server we were just connected to.
+            let sid = server_addr_recv.recv().unwrap();
+            let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
             xous_kernel::try_send_message(
-                other_conn as u32,
+                conn,
                 xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
-                    id: 15,
-                    arg1: 21,
-                    arg2: 31,
-                    arg3: 41,
-                    arg4: 51,
+                    id: 11,
+                    arg1: 12,
+                    arg2: 13,
+                    arg3: 14,
+                    arg4: 15,
                 }),
             )
             .expect("couldn't send message");
+            client_sent_send
+                .send(())
+                .expect("couldn't notify them we sent a message");
         },
     ))
     .expect("couldn't spawn client process");
@@ -270,14 +1461,53 @@ fn connect_for_process() {
     // Wait for both processes to finish
     crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
     crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
-    crate::wait_process_as_thread(nameserver_process).expect("couldn't join nameserver process");
     shutdown_kernel();
 
     main_thread.join().expect("couldn't join kernel process");
 }
 
 #[test]
-fn send_scalar_message() {
+fn try_receive_message_wrong_process() {
+    // Start the server in another thread
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (server_addr_send, server_addr_recv) = unbounded();
+    let (checked_send, checked_recv) = unbounded();
+
+    // The server stays alive until the other process has tried to steal from its queue.
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "try_receive_message_wrong_process server",
+        move || {
+            let sid = xous_kernel::create_server().expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+            checked_recv.recv().unwrap();
+        },
+    ))
+    .expect("couldn't spawn server process");
+
+    // Only the process that owns a server may receive from it, whether or not it blocks.
+    let xous_other = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "try_receive_message_wrong_process other",
+        move || {
+            let sid = server_addr_recv.recv().unwrap();
+            assert_eq!(
+                xous_kernel::try_receive_message(sid),
+                Err(xous_kernel::Error::ServerNotFound)
+            );
+            checked_send.send(()).unwrap();
+        },
+    ))
+    .expect("couldn't spawn other process");
+
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_other).expect("couldn't join other process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn send_blocking_scalar_message() {
     // Start the server in another thread
     let main_thread = start_kernel(SERVER_SPEC);
 
@@ -290,12 +1520,13 @@ fn send_scalar_message() {
     let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
         "send_scalar_message server",
         move || {
-            let sid = xous_kernel::create_server().expect("couldn't create test server");
+            let sid = xous_kernel::create_server_with_address(b"send_scalar_mesg")
+                .expect("couldn't create test server");
             server_addr_send.send(sid).unwrap();
             let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
             assert_eq!(
                 envelope.body,
-                xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
                     id: 1,
                     arg1: 2,
                     arg2: 3,
@@ -303,6 +1534,20 @@ fn send_scalar_message() {
                     arg4: 5
                 })
             );
+            xous_kernel::return_scalar(envelope.sender, 42).expect("couldn't return scalar");
+
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            assert_eq!(
+                envelope.body,
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
+                    id: 6,
+                    arg1: 7,
+                    arg2: 8,
+                    arg3: 9,
+                    arg4: 10
+                })
+            );
+            xous_kernel::return_scalar2(envelope.sender, 56, 78).expect("couldn't return scalar");
         },
     ))
     .expect("couldn't spawn server process");
@@ -313,9 +1558,9 @@ fn send_scalar_message() {
         move || {
             let sid = server_addr_recv.recv().unwrap();
             let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
-            xous_kernel::try_send_message(
+            let result = xous_kernel::try_send_message(
                 conn,
-                xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
                     id: 1,
                     arg1: 2,
                     arg2: 3,
@@ -324,6 +1569,20 @@ fn send_scalar_message() {
                 }),
             )
             .expect("couldn't send message");
+            assert_eq!(result, xous_kernel::Result::Scalar1(42));
+
+            let result = xous_kernel::try_send_message(
+                conn,
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
+                    id: 6,
+                    arg1: 7,
+                    arg2: 8,
+                    arg3: 9,
+                    arg4: 10,
+                }),
+            )
+            .expect("couldn't send message");
+            assert_eq!(result, xous_kernel::Result::Scalar2(56, 78));
         },
     ))
     .expect("couldn't spawn client process");
@@ -337,32 +1596,113 @@ fn send_scalar_message() {
 }
 
 #[test]
-fn try_receive_message() {
+fn return_scalar5() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (server_addr_send, server_addr_recv) = unbounded();
+
+    // The server answers with the message id and all four arguments, for five values in all
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "return_scalar5 server",
+        move || {
+            let sid = xous_kernel::create_server().expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            let scalar = envelope
+                .body
+                .scalar_message()
+                .expect("message wasn't a scalar");
+            xous_kernel::return_scalar5(
+                envelope.sender,
+                scalar.id,
+                scalar.arg1,
+                scalar.arg2,
+                scalar.arg3,
+                scalar.arg4,
+            )
+            .expect("couldn't return scalar");
+        },
+    ))
+    .expect("couldn't spawn server process");
+
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "return_scalar5 client",
+        move || {
+            let sid = server_addr_recv.recv().unwrap();
+            let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
+            let result = xous_kernel::send_message(
+                conn,
+                xous_kernel::Message::new_blocking_scalar(11, 12, 13, 14, usize::MAX),
+            )
+            .expect("couldn't send message");
+            assert_eq!(
+                result,
+                xous_kernel::Result::Scalar5(11, 12, 13, 14, usize::MAX)
+            );
+        },
+    ))
+    .expect("couldn't spawn client process");
+
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn send_blocking_scalar_message_queued() {
     // Start the server in another thread
     let main_thread = start_kernel(SERVER_SPEC);
 
     let (server_addr_send, server_addr_recv) = unbounded();
-    let (client_sent_send, client_sent_recv) = unbounded();
 
-    // Spawn the server "process" (which just lives in a separate thread)
-    // and receive the message. Note that we need to communicate to the
-    // "Client" what our server ID is. Normally this would be done via
-    // an external nameserver.
+    // The server never parks in `receive_message()`, so it never has an available
+    // thread and every message it gets must have gone through the queue. The blocking
+    // client stays parked until the queued message is answered.
     let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
-        "send_scalar_message server",
+        "send_blocking_scalar_message_queued server",
         move || {
             let sid = xous_kernel::create_server().expect("couldn't create test server");
-            let maybe_envelope =
-                xous_kernel::try_receive_message(sid).expect("couldn't receive messages");
-            assert!(maybe_envelope.is_none(), "some message came back");
             server_addr_send.send(sid).unwrap();
-            client_sent_recv.recv().unwrap();
-            let maybe_envelope =
-                xous_kernel::try_receive_message(sid).expect("couldn't receive messages");
-            let envelope = maybe_envelope.expect("got None as an envelope");
+            let poll = || loop {
+                if let Some(envelope) =
+                    xous_kernel::try_receive_message(sid).expect("couldn't receive messages")
+                {
+                    return envelope;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            };
+
+            let envelope = poll();
             assert_eq!(
                 envelope.body,
                 xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
+                    id: 1,
+                    arg1: 2,
+                    arg2: 3,
+                    arg3: 4,
+                    arg4: 5
+                })
+            );
+
+            let envelope = poll();
+            assert_eq!(
+                envelope.body,
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
+                    id: 6,
+                    arg1: 7,
+                    arg2: 8,
+                    arg3: 9,
+                    arg4: 10
+                })
+            );
+            xous_kernel::return_scalar(envelope.sender, 99).expect("couldn't return scalar");
+
+            let envelope = poll();
+            assert_eq!(
+                envelope.body,
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
                     id: 11,
                     arg1: 12,
                     arg2: 13,
@@ -370,19 +1710,47 @@ fn try_receive_message() {
                     arg4: 15
                 })
             );
+            xous_kernel::return_scalar2(envelope.sender, 123, 456)
+                .expect("couldn't return scalar");
         },
     ))
     .expect("couldn't spawn server process");
 
     // Spawn the client "process" and wait for the server address.
     let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
-        "send_scalar_message client",
+        "send_blocking_scalar_message_queued client",
         move || {
             let sid = server_addr_recv.recv().unwrap();
             let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
-            xous_kernel::try_send_message(
+            let result = xous_kernel::try_send_message(
+                conn,
+                xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
+                    id: 1,
+                    arg1: 2,
+                    arg2: 3,
+                    arg3: 4,
+                    arg4: 5,
+                }),
+            )
+            .expect("couldn't send message");
+            assert_eq!(result, xous_kernel::Result::Ok);
+
+            let result = xous_kernel::try_send_message(
+                conn,
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
+                    id: 6,
+                    arg1: 7,
+                    arg2: 8,
+                    arg3: 9,
+                    arg4: 10,
+                }),
+            )
+            .expect("couldn't send message");
+            assert_eq!(result, xous_kernel::Result::Scalar1(99));
+
+            let result = xous_kernel::try_send_message(
                 conn,
-                xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
                     id: 11,
                     arg1: 12,
                     arg2: 13,
@@ -391,9 +1759,7 @@ fn try_receive_message() {
                 }),
             )
             .expect("couldn't send message");
-            client_sent_send
-                .send(())
-                .expect("couldn't notify them we sent a message");
+            assert_eq!(result, xous_kernel::Result::Scalar2(123, 456));
         },
     ))
     .expect("couldn't spawn client process");
@@ -407,82 +1773,100 @@ fn try_receive_message() {
 }
 
 #[test]
-fn send_blocking_scalar_message() {
+fn send_blocking_scalar_message_timeout() {
     // Start the server in another thread
     let main_thread = start_kernel(SERVER_SPEC);
 
     let (server_addr_send, server_addr_recv) = unbounded();
+    let (first_cancelled_send, first_cancelled_recv) = unbounded();
+    let (second_cancelled_send, second_cancelled_recv) = unbounded();
 
-    // Spawn the server "process" (which just lives in a separate thread)
-    // and receive the message. Note that we need to communicate to the
-    // "Client" what our server ID is. Normally this would be done via
-    // an external nameserver.
+    // This server never answers in time. The first message is cancelled while it is
+    // still queued, the second one after the server has received it.
     let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
-        "send_scalar_message server",
+        "send_blocking_scalar_message_timeout server",
         move || {
-            let sid = xous_kernel::create_server_with_address(b"send_scalar_mesg")
-                .expect("couldn't create test server");
+            let sid = xous_kernel::create_server().expect("couldn't create test server");
             server_addr_send.send(sid).unwrap();
+
+            // The cancelled message must never be delivered
+            first_cancelled_recv.recv().unwrap();
+            assert!(xous_kernel::try_receive_message(sid)
+                .expect("couldn't receive messages")
+                .is_none());
+
             let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
             assert_eq!(
                 envelope.body,
                 xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
-                    id: 1,
-                    arg1: 2,
-                    arg2: 3,
-                    arg3: 4,
-                    arg4: 5
+                    id: 2,
+                    arg1: 0,
+                    arg2: 0,
+                    arg3: 0,
+                    arg4: 0
                 })
             );
+            // Answering after the client has given up is not an error, the answer is dropped
+            second_cancelled_recv.recv().unwrap();
             xous_kernel::return_scalar(envelope.sender, 42).expect("couldn't return scalar");
 
             let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
             assert_eq!(
                 envelope.body,
                 xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
-                    id: 6,
-                    arg1: 7,
-                    arg2: 8,
-                    arg3: 9,
-                    arg4: 10
+                    id: 3,
+                    arg1: 0,
+                    arg2: 0,
+                    arg3: 0,
+                    arg4: 0
                 })
             );
-            xous_kernel::return_scalar2(envelope.sender, 56, 78).expect("couldn't return scalar");
+            xous_kernel::return_scalar(envelope.sender, 43).expect("couldn't return scalar");
         },
     ))
     .expect("couldn't spawn server process");
 
-    // Spawn the client "process" and wait for the server address.
     let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
-        "send_scalar_message client",
+        "send_blocking_scalar_message_timeout client",
         move || {
             let sid = server_addr_recv.recv().unwrap();
             let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
-            let result = xous_kernel::try_send_message(
-                conn,
-                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
-                    id: 1,
-                    arg1: 2,
-                    arg2: 3,
-                    arg3: 4,
-                    arg4: 5,
-                }),
-            )
-            .expect("couldn't send message");
-            assert_eq!(result, xous_kernel::Result::Scalar1(42));
+            let tid = xous_kernel::current_tid().expect("couldn't get thread id");
+            let send_with_timeout = |id, notify: crossbeam_channel::Sender<()>| {
+                let watchdog = xous_kernel::create_thread(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(200));
+                    assert!(xous_kernel::cancel_message(tid).expect("couldn't cancel message"));
+                    notify.send(()).unwrap();
+                })
+                .expect("couldn't spawn watchdog thread");
+                let result = xous_kernel::try_send_message(
+                    conn,
+                    xous_kernel::Message::new_blocking_scalar(id, 0, 0, 0, 0),
+                );
+                xous_kernel::wait_thread(watchdog).expect("couldn't join watchdog thread");
+                result
+            };
 
+            assert_eq!(
+                send_with_timeout(1, first_cancelled_send),
+                Err(xous_kernel::Error::Timeout)
+            );
+            assert_eq!(
+                send_with_timeout(2, second_cancelled_send),
+                Err(xous_kernel::Error::Timeout)
+            );
+
+            // Nothing is outstanding anymore, so there's nothing to cancel
+            assert!(!xous_kernel::cancel_message(tid).expect("couldn't cancel message"));
+
+            // The server is still usable, and the late answer to the cancelled
+            // message doesn't show up here
             let result = xous_kernel::try_send_message(
                 conn,
-                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
-                    id: 6,
-                    arg1: 7,
-                    arg2: 8,
-                    arg3: 9,
-                    arg4: 10,
-                }),
+                xous_kernel::Message::new_blocking_scalar(3, 0, 0, 0, 0),
             )
             .expect("couldn't send message");
-            assert_eq!(result, xous_kernel::Result::Scalar2(56, 78));
+            assert_eq!(result, xous_kernel::Result::Scalar1(43));
         },
     ))
     .expect("couldn't spawn client process");
@@ -600,103 +1984,462 @@ fn message_ordering() {
     let server_total = server_total_recv.recv().unwrap();
     let client_total = client_total_recv.recv().unwrap();
 
-    // Wait for both processes to finish
+    // Wait for both processes to finish
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+    shutdown_kernel();
+    assert_eq!(
+        client_total, server_total,
+        "client and server processed a different number of messages"
+    );
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn send_interleved_blocking_scalar_message() {
+    // Start the server in another thread
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (server_addr_send, server_addr_recv) = unbounded();
+
+    // Spawn the server "process" (which just lives in a separate thread)
+    // and receive the message. Note that we need to communicate to the
+    // "Client" what our server ID is. Normally this would be done via
+    // an external nameserver.
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "send_scalar_message server",
+        move || {
+            let sid = xous_kernel::create_server_with_address(b"send_scalar_mesg")
+                .expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+
+            let envelope1 = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            let envelope2 = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            let retval1 = if let xous_kernel::Message::BlockingScalar(bs) = envelope1.body {
+                bs.id + 1
+            } else {
+                panic!("unexpected value")
+            };
+            let retval2 = if let xous_kernel::Message::BlockingScalar(bs) = envelope2.body {
+                bs.id + 10
+            } else {
+                panic!("unexpected value")
+            };
+            xous_kernel::return_scalar(envelope2.sender, retval2).expect("couldn't return scalar");
+            xous_kernel::return_scalar(envelope1.sender, retval1).expect("couldn't return scalar");
+        },
+    ))
+    .expect("couldn't spawn server process");
+
+    let sid_client_1 = server_addr_recv.recv().unwrap();
+    let sid_client_2 = sid_client_1;
+
+    // Spawn the client "process" and wait for the server address. This one will have
+    // 1 added to the `id` field.
+    let xous_client_1 = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("send_scalar_message client 1", move || {
+            let conn = xous_kernel::try_connect(sid_client_1).expect("couldn't connect to server");
+            let result = xous_kernel::try_send_message(
+                conn,
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
+                    id: 1,
+                    arg1: 2,
+                    arg2: 3,
+                    arg3: 4,
+                    arg4: 5,
+                }),
+            )
+            .expect("couldn't send message");
+            assert_eq!(result, xous_kernel::Result::Scalar1(2));
+        }),
+    )
+    .expect("couldn't spawn client 1 process");
+
+    // Spawn the client "process" and wait for the server address. This one
+    // will have `10` added to the value when it is returned.
+    let xous_client_2 = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("send_scalar_message client 2", move || {
+            let conn = xous_kernel::try_connect(sid_client_2).expect("couldn't connect to server");
+            let result = xous_kernel::try_send_message(
+                conn,
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
+                    id: 10,
+                    arg1: 2,
+                    arg2: 3,
+                    arg3: 4,
+                    arg4: 5,
+                }),
+            )
+            .expect("couldn't send message");
+            assert_eq!(result, xous_kernel::Result::Scalar1(20));
+        }),
+    )
+    .expect("couldn't spawn client 2 process");
+
+    // Wait for both processes to finish
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client_1).expect("couldn't join client process");
+    crate::wait_process_as_thread(xous_client_2).expect("couldn't join client process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn mapped_memory_reads_as_zero() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("mapped_memory_reads_as_zero", move || {
+            let flags = xous_kernel::MemoryFlags::R | xous_kernel::MemoryFlags::W;
+            let range =
+                xous_kernel::map_memory(None, None, 4096 * 4, flags).expect("couldn't map memory");
+            // Nothing has touched the pages yet, so the first look at them finds zeroes
+            assert!(range.as_slice::<u8>().iter().all(|&b| b == 0));
+
+            // Leave something behind, and make sure the next mapping doesn't see it
+            let mut range = range;
+            range
+                .as_slice_mut::<u8>()
+                .iter_mut()
+                .for_each(|b| *b = 0xa5);
+            assert_eq!(xous_kernel::unmap_memory(range), Ok(()));
+            let range =
+                xous_kernel::map_memory(None, None, 4096 * 4, flags).expect("couldn't map memory");
+            assert!(range.as_slice::<u8>().iter().all(|&b| b == 0));
+            assert_eq!(xous_kernel::unmap_memory(range), Ok(()));
+
+            // Asking for the zeroing to wait for the first touch makes no difference to what's seen
+            let range = xous_kernel::map_memory(
+                None,
+                None,
+                4096 * 4,
+                flags | xous_kernel::MemoryFlags::LAZY_ZERO,
+            )
+            .expect("couldn't map memory");
+            assert!(range.as_slice::<u8>().iter().all(|&b| b == 0));
+            assert_eq!(xous_kernel::unmap_memory(range), Ok(()));
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[path = "arch/riscv/lazy_zero.rs"]
+mod riscv_lazy_zero;
+
+#[test]
+fn riscv_lazy_zero_on_first_touch() {
+    use riscv_lazy_zero::{awaiting_zero, phys, unzeroed, zero_on_first_touch};
+    use std::cell::RefCell;
+    const VALID: usize = 0x1;
+    const RW: usize = 0x2 | 0x4;
+    const USER: usize = 0x10;
+    const SHARED: usize = 0x100;
+
+    // A readable, writable page of main memory, mapped for the kernel, with
+    // whatever was left behind in it
+    let page_phys = 0x4010_2000;
+    let mapped = ((page_phys >> 12) << 10) | RW | VALID;
+    let page = RefCell::new(vec![0xa5u8; 4096]);
+    let steps = RefCell::new(vec![]);
+
+    // Handed over, it points at the same page, but can't be reached until it's touched
+    let mut entry = unzeroed(mapped).expect("couldn't hand over page");
+    assert_eq!(entry & VALID, 0);
+    assert!(awaiting_zero(entry));
+    assert_eq!(phys(entry), page_phys);
+    assert_eq!(unzeroed(entry), None);
+
+    // The first touch zeroes it, and only then lets the process at it
+    let touched = zero_on_first_touch(
+        &mut entry,
+        || steps.borrow_mut().push("flush"),
+        || {
+            steps.borrow_mut().push("zero");
+            page.borrow_mut().iter_mut().for_each(|b| *b = 0);
+        },
+    );
+    assert_eq!(touched, Some(page_phys));
+    assert_eq!(*steps.borrow(), vec!["flush", "zero", "flush"]);
+    assert!(page.borrow().iter().all(|&b| b == 0));
+    assert_eq!(entry & (VALID | USER | RW), VALID | USER | RW);
+    assert_eq!(phys(entry), page_phys);
+    assert!(!awaiting_zero(entry));
+
+    // Later touches, and pages that weren't handed over unzeroed, are left alone
+    let reserved = RW;
+    let lent = ((page_phys >> 12) << 10) | RW | USER | SHARED;
+    for &other in &[entry, 0, reserved, lent] {
+        let mut other_entry = other;
+        steps.borrow_mut().clear();
+        assert_eq!(
+            zero_on_first_touch(
+                &mut other_entry,
+                || steps.borrow_mut().push("flush"),
+                || steps.borrow_mut().push("zero")
+            ),
+            None
+        );
+        assert_eq!(other_entry, other);
+        assert!(steps.borrow().is_empty());
+    }
+}
+
+#[test]
+fn map_unmap_memory() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("map_unmap_memory", move || {
+            let range = xous_kernel::map_memory(
+                None,
+                None,
+                4096 * 2,
+                xous_kernel::MemoryFlags::R | xous_kernel::MemoryFlags::W,
+            )
+            .expect("couldn't map memory");
+            assert_eq!(xous_kernel::unmap_memory(range), Ok(()));
+
+            // The range no longer belongs to this process
+            assert_eq!(
+                xous_kernel::unmap_memory(range),
+                Err(xous_kernel::Error::BadAddress)
+            );
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn memory_quota() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (pid_send, pid_recv) = unbounded();
+    let (quota_send, quota_recv) = unbounded::<()>();
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("memory_quota", move || {
+            pid_send.send(xous_kernel::current_pid().unwrap()).unwrap();
+            quota_recv.recv().unwrap();
+
+            // The quota can only be set by a parent
+            let own_pid = xous_kernel::current_pid().unwrap();
+            assert_eq!(
+                xous_kernel::set_memory_quota(own_pid, 1024),
+                Err(xous_kernel::Error::ProcessNotChild)
+            );
+
+            let flags = xous_kernel::MemoryFlags::R | xous_kernel::MemoryFlags::W;
+            let range =
+                xous_kernel::map_memory(None, None, 4096 * 3, flags).expect("couldn't map memory");
+
+            // Only one page is left in the quota
+            assert_eq!(
+                xous_kernel::map_memory(None, None, 4096 * 2, flags),
+                Err(xous_kernel::Error::OutOfMemory)
+            );
+            let heap_size = xous_kernel::get_process_info(own_pid).unwrap().heap_size;
+            assert_eq!(
+                rsyscall(SysCall::IncreaseHeap(4096 * 2, flags)),
+                Err(xous_kernel::Error::OutOfMemory)
+            );
+            assert_eq!(
+                xous_kernel::get_process_info(own_pid).unwrap().heap_size,
+                heap_size
+            );
+            let last = xous_kernel::map_memory(None, None, 4096, flags)
+                .expect("couldn't map the last page");
+            assert_eq!(
+                xous_kernel::map_memory(None, None, 4096, flags),
+                Err(xous_kernel::Error::OutOfMemory)
+            );
+
+            // Unmapping memory returns it to the quota
+            assert_eq!(xous_kernel::unmap_memory(range), Ok(()));
+            let range = xous_kernel::map_memory(None, None, 4096 * 3, flags)
+                .expect("couldn't map memory after unmapping");
+            assert_eq!(xous_kernel::unmap_memory(range), Ok(()));
+            assert_eq!(xous_kernel::unmap_memory(last), Ok(()));
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    let child_pid = pid_recv.recv().unwrap();
+    assert_eq!(xous_kernel::set_memory_quota(child_pid, 4), Ok(()));
+    quota_send.send(()).unwrap();
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn moved_memory_moves_quota() {
+    let main_thread = start_kernel(SERVER_SPEC);
+    let (server_addr_send, server_addr_recv) = unbounded();
+    let flags = xous_kernel::MemoryFlags::R | xous_kernel::MemoryFlags::W;
+
+    let xous_server = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("moved_memory_moves_quota server", move || {
+            let sid = xous_kernel::create_server_with_address(b"moved_quota_test")
+                .expect("couldn't create test server");
+            let own = xous_kernel::map_memory(None, None, 4096, flags)
+                .expect("couldn't map the first page");
+            server_addr_send.send(sid).unwrap();
+
+            // The page that was moved in now counts against the server
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive message");
+            let buf = match envelope.body {
+                xous_kernel::Message::Move(ref m) => m.buf,
+                _ => panic!("unexpected message type"),
+            };
+            assert_eq!(
+                xous_kernel::map_memory(None, None, 4096, flags),
+                Err(xous_kernel::Error::OutOfMemory)
+            );
+
+            // Freeing the message returns the page to the server's quota
+            assert_eq!(xous_kernel::unmap_memory(buf), Ok(()));
+            let range = xous_kernel::map_memory(None, None, 4096, flags)
+                .expect("couldn't map memory after freeing the message");
+            assert_eq!(xous_kernel::unmap_memory(range), Ok(()));
+            assert_eq!(xous_kernel::unmap_memory(own), Ok(()));
+        })
+        .page_quota(2),
+    )
+    .expect("couldn't start server");
+
+    let xous_client = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("moved_memory_moves_quota client", move || {
+            let sid = server_addr_recv.recv().unwrap();
+            let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
+
+            // The quota given at spawn time holds a single page
+            let msg = xous_kernel::carton::Carton::from_bytes(b"moving");
+            assert_eq!(
+                xous_kernel::map_memory(None, None, 4096, flags),
+                Err(xous_kernel::Error::OutOfMemory)
+            );
+
+            // Once the page has been sent away, it no longer counts against us
+            xous_kernel::send_message(conn, xous_kernel::Message::Move(msg.into_message(0)))
+                .expect("couldn't send a message");
+            let range = xous_kernel::map_memory(None, None, 4096, flags)
+                .expect("couldn't map memory after sending it away");
+            assert_eq!(xous_kernel::unmap_memory(range), Ok(()));
+        })
+        .page_quota(1),
+    )
+    .expect("couldn't start client");
+
     crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
     crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
     shutdown_kernel();
-    assert_eq!(
-        client_total, server_total,
-        "client and server processed a different number of messages"
-    );
 
     main_thread.join().expect("couldn't join kernel process");
 }
 
 #[test]
-fn send_interleved_blocking_scalar_message() {
-    // Start the server in another thread
+fn update_memory_flags_alignment() {
     let main_thread = start_kernel(SERVER_SPEC);
 
-    let (server_addr_send, server_addr_recv) = unbounded();
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("update_memory_flags_alignment", move || {
+            // Flags are per-page, so only whole pages may be changed
+            let range = unsafe { xous_kernel::MemoryRange::new(0x2000_0800, 4096).unwrap() };
+            assert_eq!(
+                xous_kernel::update_memory_flags(range, xous_kernel::MemoryFlags::R),
+                Err(xous_kernel::Error::BadAlignment)
+            );
+            let range = unsafe { xous_kernel::MemoryRange::new(0x2000_0000, 4000).unwrap() };
+            assert_eq!(
+                xous_kernel::update_memory_flags(range, xous_kernel::MemoryFlags::R),
+                Err(xous_kernel::Error::BadAlignment)
+            );
+        }),
+    )
+    .expect("couldn't spawn process");
 
-    // Spawn the server "process" (which just lives in a separate thread)
-    // and receive the message. Note that we need to communicate to the
-    // "Client" what our server ID is. Normally this would be done via
-    // an external nameserver.
-    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
-        "send_scalar_message server",
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn shared_memory() {
+    let main_thread = start_kernel(SERVER_SPEC);
+    let name = xous_kernel::SID::from_bytes(b"shared-memory-01").unwrap();
+
+    let (written_send, written_recv) = unbounded();
+    let (read_send, read_recv) = unbounded();
+
+    let xous_writer = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "shared_memory writer",
         move || {
-            let sid = xous_kernel::create_server_with_address(b"send_scalar_mesg")
-                .expect("couldn't create test server");
-            server_addr_send.send(sid).unwrap();
+            let range = xous_kernel::map_shared_memory(
+                name,
+                4096,
+                xous_kernel::MemoryFlags::R | xous_kernel::MemoryFlags::W,
+            )
+            .expect("couldn't map shared memory");
+            // A process may only map a given region once
+            assert_eq!(
+                xous_kernel::map_shared_memory(name, 4096, xous_kernel::MemoryFlags::R),
+                Err(xous_kernel::Error::MemoryInUse)
+            );
+            unsafe { range.as_mut_ptr().write_volatile(0x5a) };
+            written_send.send(()).unwrap();
 
-            let envelope1 = xous_kernel::receive_message(sid).expect("couldn't receive messages");
-            let envelope2 = xous_kernel::receive_message(sid).expect("couldn't receive messages");
-            let retval1 = if let xous_kernel::Message::BlockingScalar(bs) = envelope1.body {
-                bs.id + 1
-            } else {
-                panic!("unexpected value")
-            };
-            let retval2 = if let xous_kernel::Message::BlockingScalar(bs) = envelope2.body {
-                bs.id + 10
-            } else {
-                panic!("unexpected value")
-            };
-            xous_kernel::return_scalar(envelope2.sender, retval2).expect("couldn't return scalar");
-            xous_kernel::return_scalar(envelope1.sender, retval1).expect("couldn't return scalar");
+            // Wait for the reader to map the region before letting go of it
+            read_recv.recv().unwrap();
+            assert_eq!(xous_kernel::unmap_shared_memory(name), Ok(()));
         },
     ))
-    .expect("couldn't spawn server process");
+    .expect("couldn't spawn writer process");
 
-    let sid_client_1 = server_addr_recv.recv().unwrap();
-    let sid_client_2 = sid_client_1;
+    let xous_reader = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "shared_memory reader",
+        move || {
+            written_recv.recv().unwrap();
+            assert_eq!(
+                xous_kernel::map_shared_memory(name, 8192, xous_kernel::MemoryFlags::R),
+                Err(xous_kernel::Error::BadAddress)
+            );
+            let range = xous_kernel::map_shared_memory(name, 4096, xous_kernel::MemoryFlags::R)
+                .expect("couldn't map shared memory");
+            assert_eq!(unsafe { range.as_ptr().read_volatile() }, 0x5a);
+            read_send.send(()).unwrap();
+            assert_eq!(xous_kernel::unmap_shared_memory(name), Ok(()));
+        },
+    ))
+    .expect("couldn't spawn reader process");
 
-    // Spawn the client "process" and wait for the server address. This one will have
-    // 1 added to the `id` field.
-    let xous_client_1 = xous_kernel::create_process_as_thread(
-        xous_kernel::ProcessArgsAsThread::new("send_scalar_message client 1", move || {
-            let conn = xous_kernel::try_connect(sid_client_1).expect("couldn't connect to server");
-            let result = xous_kernel::try_send_message(
-                conn,
-                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
-                    id: 1,
-                    arg1: 2,
-                    arg2: 3,
-                    arg3: 4,
-                    arg4: 5,
-                }),
-            )
-            .expect("couldn't send message");
-            assert_eq!(result, xous_kernel::Result::Scalar1(2));
-        }),
-    )
-    .expect("couldn't spawn client 1 process");
+    xous_kernel::wait_process_as_thread(xous_writer).expect("couldn't join writer process");
+    xous_kernel::wait_process_as_thread(xous_reader).expect("couldn't join reader process");
 
-    // Spawn the client "process" and wait for the server address. This one
-    // will have `10` added to the value when it is returned.
-    let xous_client_2 = xous_kernel::create_process_as_thread(
-        xous_kernel::ProcessArgsAsThread::new("send_scalar_message client 2", move || {
-            let conn = xous_kernel::try_connect(sid_client_2).expect("couldn't connect to server");
-            let result = xous_kernel::try_send_message(
-                conn,
-                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
-                    id: 10,
-                    arg1: 2,
-                    arg2: 3,
-                    arg3: 4,
-                    arg4: 5,
-                }),
-            )
-            .expect("couldn't send message");
-            assert_eq!(result, xous_kernel::Result::Scalar1(20));
-        }),
-    )
-    .expect("couldn't spawn client 2 process");
+    // Both processes let go of the region, so mapping it again creates a fresh one
+    let range = xous_kernel::map_shared_memory(name, 4096, xous_kernel::MemoryFlags::R)
+        .expect("couldn't map shared memory");
+    assert_eq!(unsafe { range.as_ptr().read_volatile() }, 0);
+    assert_eq!(xous_kernel::unmap_shared_memory(name), Ok(()));
+    assert_eq!(
+        xous_kernel::unmap_shared_memory(name),
+        Err(xous_kernel::Error::BadAddress)
+    );
 
-    // Wait for both processes to finish
-    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
-    crate::wait_process_as_thread(xous_client_1).expect("couldn't join client process");
-    crate::wait_process_as_thread(xous_client_2).expect("couldn't join client process");
     shutdown_kernel();
 
     main_thread.join().expect("couldn't join kernel process");
@@ -761,6 +2504,67 @@ fn send_move_message() {
     main_thread.join().expect("couldn't join kernel process");
 }
 
+#[test]
+fn moved_memory_leaves_sender() {
+    let main_thread = start_kernel(SERVER_SPEC);
+    let (server_addr_send, server_addr_recv) = unbounded();
+
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "moved_memory_leaves_sender server",
+        move || {
+            let sid = xous_kernel::create_server_with_address(b"moved_mem_sender")
+                .expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive message");
+            let buf = match envelope.body {
+                xous_kernel::Message::Move(ref m) => m.buf,
+                _ => panic!("unexpected message type"),
+            };
+
+            // The page now belongs to the server, which can read and write it
+            let bytes = unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr(), buf.len()) };
+            assert!(bytes.iter().all(|&b| b == 0xa5));
+            bytes.fill(0x5a);
+            assert_eq!(xous_kernel::unmap_memory(buf), Ok(()));
+        },
+    ))
+    .expect("couldn't start server");
+
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "moved_memory_leaves_sender client",
+        move || {
+            let sid = server_addr_recv.recv().unwrap();
+            let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
+            let flags = xous_kernel::MemoryFlags::R | xous_kernel::MemoryFlags::W;
+            let range =
+                xous_kernel::map_memory(None, None, 4096, flags).expect("couldn't map memory");
+            unsafe { core::slice::from_raw_parts_mut(range.as_mut_ptr(), range.len()) }.fill(0xa5);
+
+            let msg = xous_kernel::MemoryMessage {
+                id: 0,
+                buf: range,
+                offset: None,
+                valid: None,
+            };
+            xous_kernel::send_message(conn, xous_kernel::Message::Move(msg))
+                .expect("couldn't send a message");
+
+            // Once moved, the memory is no longer the client's to unmap
+            assert_eq!(
+                xous_kernel::unmap_memory(range),
+                Err(xous_kernel::Error::BadAddress)
+            );
+        },
+    ))
+    .expect("couldn't start client");
+
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
 #[test]
 fn send_borrow_message() {
     let main_thread = start_kernel(SERVER_SPEC);
@@ -909,6 +2713,76 @@ fn send_mutableborrow_message() {
     main_thread.join().expect("couldn't join kernel process");
 }
 
+#[test]
+fn mutableborrow_returns_server_writes() {
+    let main_thread = start_kernel(SERVER_SPEC);
+    let (server_addr_send, server_addr_recv) = unbounded();
+
+    // Laid out like a `Buffer`-backed struct: a count the server fills in, followed by a payload
+    // it only reads
+    let mut request = [0u8; 16];
+    request[4..].copy_from_slice(b"Hello, world");
+
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "mutableborrow_returns_server_writes server",
+        move || {
+            let sid = xous_kernel::create_server_with_address(b"mutborrow_writes")
+                .expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            if let xous_kernel::Message::MutableBorrow(m) = envelope.body {
+                let bt =
+                    unsafe { core::slice::from_raw_parts_mut(m.buf.as_mut_ptr(), m.buf.len()) };
+                let sent = bt[4..].iter().filter(|c| c.is_ascii_alphabetic()).count() as u32;
+                bt[..4].copy_from_slice(&sent.to_le_bytes());
+                xous_kernel::return_memory_offset_valid(
+                    envelope.sender,
+                    m.buf,
+                    xous_kernel::MemorySize::new(4),
+                    xous_kernel::MemorySize::new(sent as usize),
+                )
+                .unwrap();
+            } else {
+                panic!("unexpected message type");
+            }
+        },
+    ))
+    .expect("couldn't start server");
+
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "mutableborrow_returns_server_writes client",
+        move || {
+            let sid = server_addr_recv.recv().unwrap();
+            let conn = xous_kernel::connect(sid).expect("couldn't connect to server");
+
+            let mut carton = xous_kernel::carton::Carton::from_bytes(&request);
+            let result = carton
+                .lend_mut(conn, 0)
+                .expect("couldn't mutably lend data");
+            assert_eq!(
+                result,
+                xous_kernel::Result::MemoryReturned(
+                    xous_kernel::MemorySize::new(4),
+                    xous_kernel::MemorySize::new(10)
+                )
+            );
+
+            // The server's write came back, and the rest of the buffer is as it was lent
+            let returned: &[u8] = carton.as_ref();
+            assert_eq!(&returned[..4], &10u32.to_le_bytes());
+            assert_eq!(&returned[4..], &request[4..]);
+        },
+    ))
+    .expect("couldn't start client");
+
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
 #[test]
 fn send_repeat_mutableborrow_message() {
     let main_thread = start_kernel(SERVER_SPEC);
@@ -1112,6 +2986,39 @@ fn multiple_multiple_contexts() {
     }
 }
 
+#[test]
+fn join_thread_return_value() {
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("join_thread_return_value", move || {
+            let thr = xous_kernel::create_thread(move || 0x1234_5678usize)
+                .expect("couldn't create thread");
+            assert_eq!(
+                xous_kernel::wait_thread(thr),
+                Ok(xous_kernel::Result::Scalar1(0x1234_5678))
+            );
+
+            // Joining a thread that doesn't exist, or ourselves, is an error.
+            assert_eq!(
+                xous_kernel::join_thread(20),
+                Err(xous_kernel::Error::ThreadNotAvailable)
+            );
+            let tid = xous_kernel::current_tid().unwrap();
+            assert_eq!(
+                xous_kernel::join_thread(tid),
+                Err(xous_kernel::Error::ThreadNotAvailable)
+            );
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
 /// Test that a server can be restarted and the kernel doesn't crash
 #[test]
 fn process_restart_server() {
@@ -1177,3 +3084,202 @@ fn process_restart_server() {
 
     main_thread.join().expect("couldn't join kernel process");
 }
+
+#[test]
+fn watchdog_terminates_hung_process() {
+    // Start the server in another thread
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (pid_send, pid_recv) = unbounded();
+    let (release_send, release_recv) = unbounded::<()>();
+
+    // Stops petting its watchdog as soon as it's armed.
+    let hung_pid_send = pid_send.clone();
+    let hung_release = release_recv.clone();
+    let xous_hung = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "watchdog hung",
+        move || {
+            assert_eq!(
+                xous_kernel::pet_watchdog(),
+                Err(xous_kernel::Error::UseBeforeInit)
+            );
+            xous_kernel::set_watchdog(100).unwrap();
+            xous_kernel::pet_watchdog().unwrap();
+            hung_pid_send
+                .send(xous_kernel::current_pid().unwrap())
+                .unwrap();
+            hung_release.recv().ok();
+        },
+    ))
+    .expect("couldn't spawn hung process");
+    let hung_pid = pid_recv.recv().unwrap();
+
+    // Arms its watchdog, then thinks better of it.
+    let xous_disarmed = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("watchdog disarmed", move || {
+            xous_kernel::set_watchdog(100).unwrap();
+            xous_kernel::set_watchdog(0).unwrap();
+            assert_eq!(
+                xous_kernel::pet_watchdog(),
+                Err(xous_kernel::Error::UseBeforeInit)
+            );
+            pid_send.send(xous_kernel::current_pid().unwrap()).unwrap();
+            release_recv.recv().ok();
+        }),
+    )
+    .expect("couldn't spawn disarmed process");
+    let disarmed_pid = pid_recv.recv().unwrap();
+
+    // Once the deadline has passed, the kernel's timer catches it, without
+    // any process having to enter the kernel.
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    assert_eq!(
+        xous_kernel::terminate_child_process(hung_pid),
+        Err(xous_kernel::Error::ProcessNotFound)
+    );
+    // The disarmed one was left alone.
+    assert_eq!(xous_kernel::terminate_child_process(disarmed_pid), Ok(()));
+
+    drop(release_send);
+    crate::wait_process_as_thread(xous_hung).ok();
+    crate::wait_process_as_thread(xous_disarmed).ok();
+
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
+#[test]
+fn copy_user_bad_address() {
+    use crate::arch::mem::{copy_from_user, copy_to_user};
+    let mut buf = [0u8; 16];
+
+    // Nothing has been lent to the kernel outside of a syscall, so no user
+    // address is valid.
+    assert_eq!(
+        copy_from_user(0x1000, &mut buf),
+        Err(xous_kernel::Error::BadAddress)
+    );
+    assert_eq!(
+        copy_to_user(0x1000, &buf),
+        Err(xous_kernel::Error::BadAddress)
+    );
+
+    // Nor is a range that wraps around the address space.
+    assert_eq!(
+        copy_from_user(usize::MAX, &mut buf),
+        Err(xous_kernel::Error::BadAddress)
+    );
+}
+
+#[test]
+fn copy_user_past_end_of_call_buffer() {
+    use crate::arch::mem::{clear_user, copy_from_user, copy_to_user, with_call_memory};
+    // A buffer sent along with a call, as the kernel sees it while handling it
+    let mut backing = vec![0x5au8; 64];
+    let base = backing.as_mut_ptr() as usize;
+    let range = unsafe { xous_kernel::MemoryRange::new(base, backing.len()) }.unwrap();
+
+    with_call_memory(Some(range), || {
+        let mut buf = [0u8; 16];
+        assert_eq!(copy_from_user(base + 48, &mut buf), Ok(()));
+        assert_eq!(buf, [0x5a; 16]);
+        assert_eq!(copy_to_user(base, &[1, 2, 3, 4]), Ok(()));
+        assert_eq!(clear_user(base + 60, 4), Ok(()));
+
+        // Past the end, or running over it, is refused, and nothing is copied
+        buf = [0u8; 16];
+        assert_eq!(
+            copy_from_user(base + 64, &mut buf),
+            Err(xous_kernel::Error::BadAddress)
+        );
+        assert_eq!(
+            copy_from_user(base + 49, &mut buf),
+            Err(xous_kernel::Error::BadAddress)
+        );
+        assert_eq!(buf, [0u8; 16]);
+        assert_eq!(
+            copy_to_user(base + 62, &[0xff; 4]),
+            Err(xous_kernel::Error::BadAddress)
+        );
+        assert_eq!(
+            clear_user(base + 32, 33),
+            Err(xous_kernel::Error::BadAddress)
+        );
+        // As is anything before the start
+        assert_eq!(
+            copy_from_user(base - 1, &mut buf[..1]),
+            Err(xous_kernel::Error::BadAddress)
+        );
+    });
+    assert_eq!(&backing[..4], &[1, 2, 3, 4]);
+    assert_eq!(&backing[4..60], &[0x5a; 56][..]);
+    assert_eq!(&backing[60..], &[0; 4]);
+
+    // Once the call has been handled, the buffer is out of reach again
+    let mut buf = [0u8; 16];
+    assert_eq!(
+        copy_from_user(base, &mut buf),
+        Err(xous_kernel::Error::BadAddress)
+    );
+}
+
+#[cfg(feature = "irq-latency")]
+#[test]
+fn interrupt_latency_recorded() {
+    use crate::arch::irq::{latency_timestamp, MOCK_CALLBACKS};
+    use xous_kernel::{MemoryAddress, PID};
+    // Nothing asserts interrupts in hosted mode, so stand in for the
+    // interrupt controller and the trap entry with a mock interrupt.
+    const MOCK_IRQ: usize = 7;
+    let main_thread = start_kernel(SERVER_SPEC);
+    let owner = PID::new(2).unwrap();
+    crate::irq::interrupt_claim(
+        MOCK_IRQ,
+        owner,
+        MemoryAddress::new(0x1000).unwrap(),
+        None,
+        0,
+    )
+    .expect("couldn't claim interrupt");
+
+    // The interrupt is first seen on a trap taken while it's masked behind
+    // another handler, and is only handled on a later trap of its own
+    let asserted = 1 << MOCK_IRQ;
+    crate::irq::latency_trap_entered(asserted);
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    crate::irq::latency_trap_entered(asserted);
+    crate::irq::handle(asserted).expect("couldn't handle interrupt");
+    assert_eq!(*MOCK_CALLBACKS.lock().unwrap(), vec![(owner, MOCK_IRQ)]);
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    crate::irq::latency_returned(latency_timestamp());
+
+    let xous_process = xous_kernel::create_process_as_thread(
+        xous_kernel::ProcessArgsAsThread::new("interrupt_latency_recorded", move || {
+            let latency =
+                xous_kernel::get_interrupt_latency(MOCK_IRQ).expect("couldn't get latency");
+            assert_eq!(latency.count, 1);
+            // counted from the first trap, in microseconds
+            assert!(latency.max_latency >= 2_000, "{:?}", latency);
+            assert_eq!(latency.avg_latency, latency.max_latency);
+            assert!(latency.max_handler >= 2_000, "{:?}", latency);
+            assert_eq!(latency.avg_handler, latency.max_handler);
+
+            // An interrupt that has never fired has nothing to show
+            assert_eq!(
+                xous_kernel::get_interrupt_latency(MOCK_IRQ + 1),
+                Ok(xous_kernel::InterruptLatency::default())
+            );
+            assert_eq!(
+                xous_kernel::get_interrupt_latency(32),
+                Err(xous_kernel::Error::InterruptNotFound)
+            );
+        }),
+    )
+    .expect("couldn't spawn process");
+
+    xous_kernel::wait_process_as_thread(xous_process).expect("couldn't join process");
+    crate::irq::release_interrupts_for_pid(owner);
+    shutdown_kernel();
+    main_thread.join().expect("couldn't join kernel process");
+}
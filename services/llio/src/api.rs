@@ -16,6 +16,15 @@ pub enum UartType {
     Application = 2,
     Invalid = 3,
 }
+impl UartType {
+    /// The mux setting for a UART selector; anything past the application UART is rejected
+    pub fn from_selector(sel: usize) -> Result<UartType, xous::Error> {
+        match UartType::from(sel) {
+            UartType::Invalid => Err(xous::Error::InvalidSyscall),
+            mux => Ok(mux),
+        }
+    }
+}
 // from/to for Xous messages
 impl From<usize> for UartType {
     fn from(code: usize) -> Self {
@@ -66,7 +75,7 @@ pub(crate) enum Opcode {
     GpioIntSubscribe, //(String<64>), //
     GpioIntHappened,
 
-    /// set UART mux
+    /// set UART mux; returns 0, or the `xous::Error` code if the selector is invalid
     UartMux, //(UartType),
 
     // InfoLitexId, //(String<64>), // TODO: returns the ASCII string baked into the FPGA that describes the FPGA build, inside Registration
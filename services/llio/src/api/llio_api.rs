@@ -1,4 +1,5 @@
 pub(crate) const SERVER_NAME_LLIO: &str      = "_Low Level I/O manager_";
+pub(crate) const SERVER_NAME_UART_MUX: &str  = "_UART mux_";
 // //////////////////////////////// VIBE
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
 pub enum VibePattern {
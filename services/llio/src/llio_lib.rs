@@ -9,6 +9,7 @@ static REFCOUNT: AtomicU32 = AtomicU32::new(0);
 #[derive(Debug)]
 pub struct Llio {
     conn: CID,
+    uart_mux_conn: core::cell::Cell<Option<CID>>,
     com_sid: Option<xous::SID>,
     usb_sid: Option<xous::SID>,
     gpio_sid: Option<xous::SID>,
@@ -20,6 +21,7 @@ impl Llio {
         let conn = xns.request_connection_blocking(SERVER_NAME_LLIO).expect("Can't connect to LLIO");
         Llio {
           conn,
+          uart_mux_conn: core::cell::Cell::new(None),
           com_sid: None,
           usb_sid: None,
           gpio_sid: None,
@@ -343,16 +345,42 @@ impl Llio {
             Err(xous::Error::InternalError)
         }
     }
+    /// Switches the console UART. `UartType::Invalid` is refused with `InvalidSyscall`.
+    ///
+    /// Switching is privileged: llio serves it to a single connection, which goes to the first
+    /// process to switch the UART (shellchat, for its `console` command). Any other process is
+    /// refused with `AccessDenied`. There's no kernel call for this, as the mux picks the console
+    /// UART for the whole SoC, and its register sits in llio's GPIO block.
     pub fn set_uart_mux(&self, setting: UartType) -> Result<(), xous::Error> {
+        if setting == UartType::Invalid {
+            return Err(xous::Error::InvalidSyscall);
+        }
         if setting == UartType::Application {
             log::warn!("Application UART has aggressive power settings, so you will have trouble using it for console input.");
             log::warn!("If this UART is critictal, recompile the SoC with the app UART in the always-on power domain.");
             log::warn!("It will consume more power but it will make this UART suitable for input via serial.");
         }
         let arg = setting.into();
-        send_message(self.conn,
-            Message::new_scalar(Opcode::UartMux.to_usize().unwrap(), arg, 0, 0, 0)
-        ).map(|_| ())
+        let response = send_message(self.uart_mux_conn()?,
+            Message::new_blocking_scalar(Opcode::UartMux.to_usize().unwrap(), arg, 0, 0, 0))?;
+        match response {
+            xous::Result::Scalar1(0) => Ok(()),
+            xous::Result::Scalar1(code) => Err(xous::Error::from_usize(code)),
+            _ => {
+                log::error!("LLIO: unexpected return value: {:#?}", response);
+                Err(xous::Error::InternalError)
+            }
+        }
+    }
+    fn uart_mux_conn(&self) -> Result<CID, xous::Error> {
+        if let Some(conn) = self.uart_mux_conn.get() {
+            return Ok(conn);
+        }
+        let xns = xous_names::XousNames::new()?;
+        // the server registers at boot, so not finding it means its one connection is spoken for
+        let conn = xns.request_connection(SERVER_NAME_UART_MUX).or(Err(xous::Error::AccessDenied))?;
+        self.uart_mux_conn.set(Some(conn));
+        Ok(conn)
     }
     /// wakeup alarm will force the system on if it is off, but does not trigger an interrupt on the CPU
     pub fn set_wakeup_alarm(&self, seconds_from_now: u8) -> Result<(), xous::Error> {
@@ -418,6 +446,9 @@ impl Drop for Llio {
         if let Some(sid) = self.rtc_sid.take() {
             drop_conn(sid);
         }
+        if let Some(conn) = self.uart_mux_conn.take() {
+            unsafe{xous::disconnect(conn).ok();}
+        }
         if REFCOUNT.fetch_sub(1, Ordering::Relaxed) == 1 {
             unsafe{xous::disconnect(self.conn).unwrap();}
         }
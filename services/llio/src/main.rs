@@ -87,6 +87,30 @@ fn i2c_thread(i2c_sid: xous::SID) {
     xous::destroy_server(i2c_sid).unwrap();
 }
 
+/// Takes UART mux requests from the one connection `SERVER_NAME_UART_MUX` allows, and hands them
+/// on to the main loop, which only switches the mux for requests coming from within llio.
+fn uart_mux_thread(uart_mux_sid: xous::SID, llio_conn: CID) {
+    loop {
+        let msg = xous::receive_message(uart_mux_sid).unwrap();
+        match FromPrimitive::from_usize(msg.body.id()) {
+            Some(Opcode::UartMux) => msg_blocking_scalar_unpack!(msg, sel, _, _, _, {
+                let result = match xous::send_message(llio_conn,
+                    xous::Message::new_blocking_scalar(Opcode::UartMux.to_usize().unwrap(), sel, 0, 0, 0)
+                ) {
+                    Ok(xous::Result::Scalar1(code)) => code,
+                    _ => xous::Error::InternalError.to_usize(),
+                };
+                xous::return_scalar(msg.sender, result).expect("couldn't return UartMux");
+            }),
+            _ => log::error!("UART mux server got an unexpected message: {:?}", msg),
+        }
+    }
+}
+
+/// Whether a message came from within llio itself, i.e. by way of `uart_mux_thread()`
+fn from_llio(sender: Option<xous::PID>, llio_pid: u32) -> bool {
+    sender.map(|pid| pid.get() as u32) == Some(llio_pid)
+}
 
 #[derive(Copy, Clone, Debug)]
 struct ScalarCallback {
@@ -134,6 +158,21 @@ fn main() -> ! {
         }
     });
 
+    // create the UART mux thread
+    // - shellchat (for the `console` command)
+    // The mux picks which UART is the console for the whole SoC, so switching it is privileged: the
+    // name only allows the one connection, and the main loop only takes UartMux from this thread.
+    // There's no kernel call for it, as the mux register lives in the GPIO block mapped here.
+    let uart_mux_sid = xns.register_name(api::SERVER_NAME_UART_MUX, Some(1)).expect("can't register UART mux thread");
+    log::trace!("registered UART mux thread with NS -- {:?}", uart_mux_sid);
+    let _ = thread::spawn({
+        let llio_conn = xous::connect(llio_sid).expect("can't connect UART mux thread to llio");
+        move || {
+            uart_mux_thread(uart_mux_sid, llio_conn);
+        }
+    });
+    let llio_pid = xous::process::id();
+
     // Create a new llio object
     let handler_conn = xous::connect(llio_sid).expect("can't create IRQ handler connection");
     let mut llio = Llio::new(handler_conn, gpio_base);
@@ -206,8 +245,22 @@ fn main() -> ! {
                 let ena = if arg == 0 {false} else {true};
                 llio.debug_wakeup(ena);
             }),
-            Some(Opcode::UartMux) => msg_scalar_unpack!(msg, mux, _, _, _, {
-                llio.set_uart_mux(mux.into());
+            Some(Opcode::UartMux) => msg_blocking_scalar_unpack!(msg, sel, _, _, _, {
+                if !from_llio(msg.sender.pid(), llio_pid) {
+                    log::warn!("refusing UART mux request that didn't come through {}", api::SERVER_NAME_UART_MUX);
+                    xous::return_scalar(msg.sender, xous::Error::AccessDenied.to_usize()).expect("couldn't return UartMux");
+                    continue;
+                }
+                match UartType::from_selector(sel) {
+                    Ok(mux) => {
+                        llio.set_uart_mux(mux);
+                        xous::return_scalar(msg.sender, 0).expect("couldn't return UartMux");
+                    }
+                    Err(e) => {
+                        log::warn!("rejecting invalid UART selector {}", sel);
+                        xous::return_scalar(msg.sender, e.to_usize()).expect("couldn't return UartMux");
+                    }
+                }
             }),
             Some(Opcode::InfoDna) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
                 let (val1, val2) = llio.get_info_dna();
@@ -643,6 +696,23 @@ mod tests {
         (msd << 4) | lsd
     }
 
+    #[test]
+    fn test_uart_selector() {
+        assert_eq!(UartType::from_selector(0), Ok(UartType::Kernel));
+        assert_eq!(UartType::from_selector(1), Ok(UartType::Log));
+        assert_eq!(UartType::from_selector(2), Ok(UartType::Application));
+        assert_eq!(UartType::from_selector(3), Err(xous::Error::InvalidSyscall));
+        assert_eq!(UartType::from_selector(usize::MAX), Err(xous::Error::InvalidSyscall));
+    }
+
+    #[test]
+    fn test_uart_mux_from_llio() {
+        let pid = |p: u8| xous::PID::new(p);
+        assert!(from_llio(pid(5), 5));
+        assert!(!from_llio(pid(6), 5));
+        assert!(!from_llio(None, 5));
+    }
+
     #[test]
     fn test_rtc_to_secs() {
         let mut rng = rand::thread_rng();
@@ -72,6 +72,7 @@ impl<'a> ShellCmdApi<'a> for Usb {
                 "status" => {
                     match self.usb_dev.get_current_core() {
                         Ok(UsbDeviceType::Debug) => write!(ret, "Debug core connected").unwrap(),
+                        Ok(UsbDeviceType::Dfu) => write!(ret, "Device core connected in DFU mode").unwrap(),
                         Ok(UsbDeviceType::Hid) => {
                             match self.usb_dev.status() {
                                 UsbDeviceState::Configured => write!(ret, "HID core connected to host").unwrap(),
@@ -13,6 +13,8 @@ fn main() {
         "Connected to process. PID: {:?}, CID: {:?}",
         process.pid, process.cid
     );
+    // New processes are parked until their parent runs them
+    xous::switch_to(process.pid, 0).unwrap();
     let result = xous::send_message(
         process.cid,
         xous::Message::new_blocking_scalar(4, 1, 2, 3, 4),
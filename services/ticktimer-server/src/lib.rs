@@ -53,6 +53,54 @@ impl Ticktimer {
         .map(|_| ())
     }
 
+    /// Send a blocking scalar message, giving up if the server hasn't responded within
+    /// `timeout_ms` milliseconds. On timeout this returns `Err(Error::Timeout)`, and any
+    /// response the server sends later is discarded.
+    ///
+    /// The deadlines of all calls in the process are kept by one thread, started by the first
+    /// call. The deadline counts from when this is called, but is only acted on once the message
+    /// is pending in the kernel.
+    ///
+    /// Only `BlockingScalar` messages can time out, because lent memory can't be taken
+    /// back from a server that is still working on it. Other messages return
+    /// `Err(Error::ShareViolation)` without being sent.
+    pub fn send_message_timeout(
+        &self,
+        cid: CID,
+        message: xous::Message,
+        timeout_ms: usize,
+    ) -> Result<xous::Result, Error> {
+        if !matches!(message, xous::Message::BlockingScalar(_)) {
+            return Err(Error::ShareViolation);
+        }
+        let timeout = Arc::new(PendingTimeout {
+            deadline: self.elapsed_ms() + timeout_ms as u64,
+            tid: xous::current_tid()?,
+            state: AtomicU32::new(TIMEOUT_WAITING),
+            returned: AtomicBool::new(false),
+        });
+        let queue = timeout_queue();
+        queue.arm(timeout.clone());
+
+        let result = send_message(cid, message);
+        timeout.returned.store(true, Ordering::SeqCst);
+        // Whichever of this thread and the timeout thread moves `state` out of WAITING first
+        // wins. If it's the timeout thread, wait for it to finish, so it can never cancel a
+        // message this thread sends after this call has returned.
+        if timeout
+            .state
+            .compare_exchange(TIMEOUT_WAITING, TIMEOUT_RESPONDED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            queue.disarm(&timeout);
+        } else {
+            while timeout.state.load(Ordering::SeqCst) != TIMEOUT_CANCELLED {
+                xous::yield_slice();
+            }
+        }
+        result
+    }
+
     pub fn ping_wdt(&self) {
         send_message(
             self.conn,
@@ -169,8 +217,109 @@ impl Ticktimer {
     }
 }
 
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 static REFCOUNT: AtomicU32 = AtomicU32::new(0);
+
+const TIMEOUT_WAITING: u32 = 0;
+const TIMEOUT_RESPONDED: u32 = 1;
+const TIMEOUT_CANCELLING: u32 = 2;
+const TIMEOUT_CANCELLED: u32 = 3;
+
+/// A `send_message_timeout()` that is waiting on its server
+struct PendingTimeout {
+    /// In the ticktimer's `elapsed_ms()`
+    deadline: u64,
+    /// The thread blocked on the send
+    tid: xous::TID,
+    state: AtomicU32,
+    /// Set once the send has returned, whether with the response or with `Timeout`
+    returned: AtomicBool,
+}
+
+/// The deadlines of all of the process's `send_message_timeout()` calls, served by a single
+/// thread that is started by the first call
+struct TimeoutQueue {
+    pending: Mutex<Vec<Arc<PendingTimeout>>>,
+    changed: Condvar,
+}
+impl TimeoutQueue {
+    fn arm(&self, timeout: Arc<PendingTimeout>) {
+        self.pending.lock().unwrap().push(timeout);
+        self.changed.notify_one();
+    }
+    fn disarm(&self, timeout: &Arc<PendingTimeout>) {
+        self.pending.lock().unwrap().retain(|t| !Arc::ptr_eq(t, timeout));
+    }
+    fn run(&self) {
+        let tt = Ticktimer::new().unwrap();
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            let now = tt.elapsed_ms();
+            let next = pending.iter().enumerate().min_by_key(|(_, t)| t.deadline).map(|(i, t)| (i, t.deadline));
+            pending = match next {
+                None => self.changed.wait(pending).unwrap(),
+                Some((i, deadline)) if deadline <= now => {
+                    let expired = pending.swap_remove(i);
+                    drop(pending);
+                    expire(&expired);
+                    self.pending.lock().unwrap()
+                }
+                Some((_, deadline)) => self
+                    .changed
+                    .wait_timeout(pending, core::time::Duration::from_millis(deadline - now))
+                    .unwrap()
+                    .0,
+            };
+        }
+    }
+}
+
+/// Cancels the send of a timeout whose deadline has passed, unless the response beat it
+fn expire(timeout: &PendingTimeout) {
+    if timeout
+        .state
+        .compare_exchange(TIMEOUT_WAITING, TIMEOUT_CANCELLING, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return;
+    }
+    // Only a message that is pending in the kernel can be cancelled. With a short enough
+    // timeout the sender may not have got that far yet, so keep at it until it has, or until
+    // the send returns by itself.
+    while !timeout.returned.load(Ordering::SeqCst) {
+        if xous::cancel_message(timeout.tid).unwrap_or(false) {
+            break;
+        }
+        xous::yield_slice();
+    }
+    timeout.state.store(TIMEOUT_CANCELLED, Ordering::SeqCst);
+}
+
+fn timeout_queue() -> &'static TimeoutQueue {
+    static QUEUE: AtomicPtr<TimeoutQueue> = AtomicPtr::new(core::ptr::null_mut());
+    let queue = QUEUE.load(Ordering::SeqCst);
+    if !queue.is_null() {
+        // Safety: once set, `QUEUE` points to a leaked `TimeoutQueue` that is never freed
+        return unsafe { &*queue };
+    }
+    let new = Box::into_raw(Box::new(TimeoutQueue {
+        pending: Mutex::new(Vec::new()),
+        changed: Condvar::new(),
+    }));
+    match QUEUE.compare_exchange(core::ptr::null_mut(), new, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => {
+            let queue: &'static TimeoutQueue = unsafe { &*new };
+            std::thread::spawn(move || queue.run());
+            queue
+        }
+        Err(current) => {
+            // another thread got there first
+            drop(unsafe { Box::from_raw(new) });
+            unsafe { &*current }
+        }
+    }
+}
 impl Drop for Ticktimer {
     fn drop(&mut self) {
         // de-allocate myself. It's unsafe because we are responsible to make sure nobody else is using the connection.
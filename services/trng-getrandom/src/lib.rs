@@ -0,0 +1,100 @@
+//! A `getrandom` backend that draws from the TRNG server's bulk fill.
+//!
+//! Crates that get their entropy through `getrandom` have nothing to call on a bare-metal
+//! target. Linking this crate registers the TRNG as their source:
+//!
+//! ```ignore
+//! use trng_getrandom as _;
+//! ```
+//!
+//! In hosted mode nothing is registered, and `getrandom` uses the host OS as usual.
+
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use std::sync::Mutex;
+
+/// Most words the TRNG server hands back per bulk fill
+const FILL_WORDS: usize = 1024;
+
+/// Fills `dest` from `fill_buf`, which fills a slice of up to `FILL_WORDS` words the way
+/// `trng::Trng::fill_buf()` does. Words are laid down little-endian; any bytes left over
+/// from the last word are discarded.
+pub fn fill_bytes_with<F>(dest: &mut [u8], mut fill_buf: F) -> Result<(), xous::Error>
+where
+    F: FnMut(&mut [u32]) -> Result<(), xous::Error>,
+{
+    let mut words = [0u32; FILL_WORDS];
+    for chunk in dest.chunks_mut(FILL_WORDS * 4) {
+        let count = (chunk.len() + 3) / 4;
+        fill_buf(&mut words[..count])?;
+        for (dst, word) in chunk.chunks_mut(4).zip(words.iter()) {
+            dst.copy_from_slice(&word.to_le_bytes()[..dst.len()]);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "none", target_os = "xous"))]
+static TRNG: Mutex<Option<trng::Trng>> = Mutex::new(None);
+
+#[cfg(any(target_os = "none", target_os = "xous"))]
+fn trng_getrandom(dest: &mut [u8]) -> Result<(), getrandom::Error> {
+    let mut trng = TRNG.lock().unwrap();
+    if trng.is_none() {
+        let xns = xous_names::XousNames::new().unwrap();
+        *trng = Some(trng::Trng::new(&xns).or(Err(getrandom::Error::UNSUPPORTED))?);
+    }
+    let trng = trng.as_ref().unwrap();
+    fill_bytes_with(dest, |words| trng.fill_buf(words)).or(Err(getrandom::Error::UNSUPPORTED))
+}
+
+#[cfg(any(target_os = "none", target_os = "xous"))]
+getrandom::register_custom_getrandom!(trng_getrandom);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_getrandom_fills_via_bulk() {
+        // a stand-in for the TRNG server, which counts up from 1, one word at a time
+        let mut next = 0u32;
+        let mut calls = 0;
+        let mut counter = |words: &mut [u32]| {
+            assert!(words.len() <= FILL_WORDS);
+            calls += 1;
+            for w in words.iter_mut() {
+                next += 1;
+                *w = next;
+            }
+            Ok(())
+        };
+        // two full bulk fills, and a part word in a third
+        let mut buf = vec![0u8; FILL_WORDS * 4 * 2 + 6];
+        fill_bytes_with(&mut buf, &mut counter).unwrap();
+        assert_eq!(calls, 3);
+        for (i, word) in buf.chunks(4).enumerate() {
+            let expected = (i as u32 + 1).to_le_bytes();
+            assert_eq!(word, &expected[..word.len()]);
+        }
+
+        // an empty buffer doesn't go to the server at all
+        calls = 0;
+        fill_bytes_with(&mut [], |_| {
+            calls += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(calls, 0);
+
+        // a failed fill is passed back
+        assert_eq!(
+            fill_bytes_with(&mut [0u8; 8], |_| Err(xous::Error::ServerNotFound)),
+            Err(xous::Error::ServerNotFound)
+        );
+
+        // the crate's users see all of this through getrandom, which fills the whole buffer
+        let mut buf = [0u8; 64];
+        getrandom::getrandom(&mut buf).unwrap();
+        assert!(buf.iter().any(|&b| b != 0));
+    }
+}
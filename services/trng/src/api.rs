@@ -24,6 +24,21 @@ pub struct HealthTests {
     pub ro_nist: [NistTests; 4],
 }
 
+/// How one generator fared over a `characterize_sources()` window: of `samples` words drawn
+/// with only that generator enabled, `rejections` failed its health tests.
+#[derive(Debug, Copy, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Default)]
+pub struct SourceHealth {
+    pub samples: u32,
+    pub rejections: u32,
+}
+#[derive(Debug, Copy, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Default)]
+pub struct SourceCharacterization {
+    /// ring oscillator alone
+    pub ro: SourceHealth,
+    /// avalanche generator alone
+    pub av: SourceHealth,
+}
+
 #[derive(Debug, Copy, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Default)]
 pub struct TrngErrors {
     pub excursion_errs: [Option<ExcursionTest>; 2],
@@ -76,6 +91,42 @@ pub(crate) enum Opcode {
     ErrorStats = 6,
 
     Quit = 7,
+
+    /// Block until the generator has warmed up after power-on
+    WaitReady = 8,
+
+    /// Serve raw generator data through a Von Neumann debiaser instead of urandom
+    SetDebias = 9,
+
+    /// Turn the avalanche generator's powersave on or off
+    SetPowersave = 10,
+
+    /// Whether powersave is on
+    GetPowersave = 11,
+
+    /// Sample each generator on its own and count its health test failures
+    CharacterizeSources = 12,
+
+    /// Subscribe to notifications that the raw pool behind the debiaser has run dry, or recovered
+    RegisterObserver = 13,
+
+    /// Look at the raw pool again, after it was found empty
+    PoolCheck = 14,
+
+    /// Fill a lent page of 32-bit words with random data; `valid` gives the number of bytes
+    FillTrngWords = 15,
+
+    /// Look at the generator again on behalf of clients waiting for it to warm up
+    WarmupCheck = 16,
+}
+
+/// Passed to pool observers as the first argument of their scalar message
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PoolEvent {
+    /// The raw pool ran dry, and draws from it will block until the generators catch up
+    Empty = 0,
+    /// The raw pool has filled back up
+    Recovered = 1,
 }
 
 #[derive(Debug, num_derive::FromPrimitive, num_derive::ToPrimitive)]
@@ -0,0 +1,36 @@
+use crate::api::{SourceCharacterization, SourceHealth};
+
+/// Words drawn from each generator by `characterize_sources()`
+pub(crate) const CHARACTERIZE_WINDOW: u32 = 1024;
+
+/// One of the two entropy sources feeding the TRNG
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Source {
+    RingOsc,
+    Avalanche,
+}
+
+/// Samples a window of `CHARACTERIZE_WINDOW` words from each source on its own, the ring
+/// oscillator first. `isolate` switches `trng` over to the given source alone, and `sample`
+/// draws one word from it and says whether the source's health tests passed. Putting the
+/// original configuration back is left to the caller.
+pub(crate) fn characterize<T>(
+    trng: &mut T,
+    mut isolate: impl FnMut(&mut T, Source),
+    mut sample: impl FnMut(&mut T, Source) -> bool,
+) -> SourceCharacterization {
+    let mut window = |trng: &mut T, source: Source| {
+        isolate(trng, source);
+        let mut health = SourceHealth::default();
+        for _ in 0..CHARACTERIZE_WINDOW {
+            health.samples += 1;
+            if !sample(trng, source) {
+                health.rejections += 1;
+            }
+        }
+        health
+    };
+    let ro = window(trng, Source::RingOsc);
+    let av = window(trng, Source::Avalanche);
+    SourceCharacterization { ro, av }
+}
@@ -0,0 +1,134 @@
+// Only applied on real hardware, but kept free of hardware dependencies so it can be tested in
+// hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+/// Von Neumann debiaser. Raw bits are taken in pairs: `01` yields a 0, `10` yields a 1, and
+/// `00` and `11` yield nothing. For independent bits this removes any bias, at the cost of
+/// at least three quarters of the input; the more biased the source, the more raw data it
+/// takes to fill a word.
+#[derive(Default)]
+pub(crate) struct VonNeumann {
+    /// debiased bits waiting to fill a word, in the low `count` bits
+    acc: u32,
+    count: u32,
+}
+
+impl VonNeumann {
+    pub fn new() -> VonNeumann {
+        VonNeumann::default()
+    }
+    /// Takes in a raw word. Returns a debiased word once enough bits have come through; the
+    /// bits beyond it are kept for the next one.
+    pub fn feed(&mut self, raw: u32) -> Option<u32> {
+        let mut out = None;
+        for pair in 0..16 {
+            let bits = (raw >> (pair * 2)) & 0b11;
+            if bits == 0b01 || bits == 0b10 {
+                self.acc = (self.acc << 1) | (bits >> 1);
+                self.count += 1;
+                if self.count == 32 {
+                    // a word has at most 16 pairs, so only one word can complete per feed
+                    out = Some(self.acc);
+                    self.acc = 0;
+                    self.count = 0;
+                }
+            }
+        }
+        out
+    }
+}
+
+/// The clients that asked for debiased data. Each client makes its own choice, so one client
+/// switching to the slower debiased stream doesn't slow down the others.
+pub(crate) struct DebiasClients<T> {
+    clients: Vec<T>,
+}
+
+impl<T: PartialEq> DebiasClients<T> {
+    pub fn new() -> DebiasClients<T> {
+        DebiasClients {
+            clients: Vec::new(),
+        }
+    }
+    pub fn set(&mut self, client: T, enabled: bool) {
+        let known = self.clients.iter().position(|c| *c == client);
+        match (known, enabled) {
+            (None, true) => self.clients.push(client),
+            (Some(index), false) => {
+                self.clients.remove(index);
+            }
+            _ => (),
+        }
+    }
+    pub fn wants(&self, client: &T) -> bool {
+        self.clients.contains(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// raw words whose bits are each 1 with probability `ones` out of 256
+    fn biased_words(count: usize, ones: u32) -> Vec<u32> {
+        let mut state = 0x1afe_cafe_u32;
+        (0..count)
+            .map(|_| {
+                let mut word = 0;
+                for bit in 0..32 {
+                    state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    if (state >> 24) < ones {
+                        word |= 1 << bit;
+                    }
+                }
+                word
+            })
+            .collect()
+    }
+
+    fn ones_fraction(words: &[u32]) -> f64 {
+        let ones: u32 = words.iter().map(|w| w.count_ones()).sum();
+        ones as f64 / (words.len() * 32) as f64
+    }
+
+    #[test]
+    fn test_debias() {
+        // a source stuck at 80% ones
+        let raw = biased_words(20_000, 205);
+        assert!((ones_fraction(&raw) - 0.8).abs() < 0.01);
+
+        let mut vn = VonNeumann::new();
+        let debiased: Vec<u32> = raw.iter().filter_map(|&w| vn.feed(w)).collect();
+        // 2 * 0.8 * 0.2 of the pairs survive, so it takes about 6 raw words per output word
+        assert!(debiased.len() > raw.len() / 8, "only {} words out", debiased.len());
+        assert!((ones_fraction(&debiased) - 0.5).abs() < 0.01, "{}", ones_fraction(&debiased));
+
+        // a constant source never yields anything, however much of it goes in
+        let mut vn = VonNeumann::new();
+        assert!((0..1000).all(|_| vn.feed(0xFFFF_FFFF).is_none() && vn.feed(0).is_none()));
+
+        // pairs map 01 -> 0 and 10 -> 1, leaving unfinished words in the accumulator
+        let mut vn = VonNeumann::new();
+        assert_eq!(vn.feed(0xAAAA_AAAA), None); // sixteen 10 pairs
+        assert_eq!(vn.feed(0x5555_5555), Some(0xFFFF_0000)); // sixteen 01 pairs
+    }
+
+    #[test]
+    fn test_debias_clients() {
+        let mut clients = DebiasClients::new();
+        assert!(!clients.wants(&1));
+        clients.set(1, true);
+        clients.set(1, true);
+        // one client's choice leaves the others on whitened data
+        assert!(clients.wants(&1));
+        assert!(!clients.wants(&2));
+        clients.set(2, true);
+        clients.set(1, false);
+        assert!(!clients.wants(&1));
+        assert!(clients.wants(&2));
+        // turning it off twice, or for a client that never turned it on, is harmless
+        clients.set(1, false);
+        clients.set(3, false);
+        assert!(clients.wants(&2));
+    }
+}
@@ -1,6 +1,8 @@
 #![cfg_attr(target_os = "none", no_std)]
 
 pub mod api;
+pub mod seeded;
+pub use seeded::SeededTrng;
 use num_traits::*;
 use xous::{send_message, CID};
 use xous_ipc::Buffer;
@@ -9,6 +11,7 @@ use xous_ipc::Buffer;
 pub struct Trng {
     conn: CID,
     error_sid: Option<xous::SID>,
+    pool_sid: Option<xous::SID>,
 }
 impl Trng {
     pub fn new(xns: &xous_names::XousNames) -> Result<Self, xous::Error> {
@@ -19,6 +22,7 @@ impl Trng {
         Ok(Trng {
             conn,
             error_sid: None,
+            pool_sid: None,
         })
     }
     pub fn get_u32(&self) -> Result<u32, xous::Error> {
@@ -78,13 +82,37 @@ impl Trng {
         }
         Ok(())
     }
+    /// Fills `words` straight from the generator, a word at a time, for consumers that want
+    /// 32-bit words rather than bytes. Unlike `fill_buf()`, the words are lent as a bare page of
+    /// `u32`s rather than a fixed-size `TrngBuf`, so only what was asked for is drawn and copied,
+    /// and any length is accepted; longer requests go a page at a time.
+    pub fn fill_words(&self, words: &mut [u32]) -> Result<(), xous::Error> {
+        if words.is_empty() {
+            return Ok(());
+        }
+        let page = xous::map_memory(None, None, 4096, xous::MemoryFlags::R | xous::MemoryFlags::W)?;
+        let per_page = page.len() / core::mem::size_of::<u32>();
+        let result = words.chunks_mut(per_page).try_for_each(|chunk| {
+            let msg = xous::MemoryMessage {
+                id: api::Opcode::FillTrngWords.to_usize().unwrap(),
+                buf: page,
+                offset: None,
+                valid: xous::MemorySize::new(core::mem::size_of_val(chunk)),
+            };
+            send_message(self.conn, xous::Message::MutableBorrow(msg))?;
+            chunk.copy_from_slice(&page.as_slice::<u32>()[..chunk.len()]);
+            Ok(())
+        });
+        xous::unmap_memory(page)?;
+        result
+    }
     pub fn hook_error_callback(&mut self, id: u32, cid: CID) -> Result<(), xous::Error> {
         if self.error_sid.is_none() {
             let sid = xous::create_server().unwrap();
             self.error_sid = Some(sid);
             let sid_tuple = sid.to_u32();
             xous::create_thread_4(
-                error_cb_server,
+                event_cb_server,
                 sid_tuple.0 as usize,
                 sid_tuple.1 as usize,
                 sid_tuple.2 as usize,
@@ -103,6 +131,103 @@ impl Trng {
             Err(xous::Error::MemoryInUse) // can't hook it twice
         }
     }
+    /// Has a scalar message `id` sent to `cid` each time the raw pool behind the debiaser runs
+    /// dry, and again when it recovers, with the `api::PoolEvent` as its first argument. Each
+    /// transition is sent once, so a client drawing large fills can back off on `Empty` and
+    /// pick up again on `Recovered`.
+    pub fn hook_pool_observer(&mut self, id: u32, cid: CID) -> Result<(), xous::Error> {
+        if self.pool_sid.is_none() {
+            let sid = xous::create_server().unwrap();
+            self.pool_sid = Some(sid);
+            let sid_tuple = sid.to_u32();
+            xous::create_thread_4(
+                event_cb_server,
+                sid_tuple.0 as usize,
+                sid_tuple.1 as usize,
+                sid_tuple.2 as usize,
+                sid_tuple.3 as usize,
+            )
+            .unwrap();
+            let hookdata = api::ScalarHook {
+                sid: sid_tuple,
+                id,
+                cid,
+            };
+            let buf = Buffer::into_buf(hookdata).or(Err(xous::Error::InternalError))?;
+            buf.lend(self.conn, api::Opcode::RegisterObserver.to_u32().unwrap())
+                .map(|_| ())
+        } else {
+            Err(xous::Error::MemoryInUse) // can't hook it twice
+        }
+    }
+    /// Blocks until the generator has finished powering up after boot or resume and passed a
+    /// health check, so that key generation doesn't use cold entropy. Returns `Timeout` if it
+    /// doesn't become ready within a couple of seconds.
+    pub fn wait_ready(&self) -> Result<(), xous::Error> {
+        let response = send_message(
+            self.conn,
+            xous::Message::new_blocking_scalar(
+                api::Opcode::WaitReady.to_usize().unwrap(),
+                0,
+                0,
+                0,
+                0,
+            ),
+        )?;
+        match response {
+            xous::Result::Scalar1(1) => Ok(()),
+            xous::Result::Scalar1(_) => Err(xous::Error::Timeout),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Powersave shuts the avalanche generator down between draws, at the cost of waiting for
+    /// it to power back up on each one. It is on by default; a caller about to generate a burst
+    /// of keys can turn it off for lower latency, and should turn it back on afterwards.
+    pub fn set_powersave(&self, enabled: bool) -> Result<(), xous::Error> {
+        send_message(
+            self.conn,
+            xous::Message::new_blocking_scalar(
+                api::Opcode::SetPowersave.to_usize().unwrap(),
+                if enabled { 1 } else { 0 },
+                0,
+                0,
+                0,
+            ),
+        )
+        .map(|_| ())
+    }
+    pub fn powersave(&self) -> Result<bool, xous::Error> {
+        match send_message(
+            self.conn,
+            xous::Message::new_blocking_scalar(
+                api::Opcode::GetPowersave.to_usize().unwrap(),
+                0,
+                0,
+                0,
+                0,
+            ),
+        )? {
+            xous::Result::Scalar1(enabled) => Ok(enabled != 0),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Switches the data this process gets between whitened data (the default) and raw
+    /// generator data passed through a Von Neumann debiaser. The debiaser doesn't need the
+    /// whitening hardware, but throws away at least three quarters of the raw bits, so data
+    /// comes out more slowly. Other processes keep getting whitened data.
+    pub fn set_debias(&self, enabled: bool) -> Result<(), xous::Error> {
+        send_message(
+            self.conn,
+            xous::Message::new_blocking_scalar(
+                api::Opcode::SetDebias.to_usize().unwrap(),
+                if enabled { 1 } else { 0 },
+                0,
+                0,
+                0,
+            ),
+        )
+        .map(|_| ())
+    }
     pub fn get_health_tests(&self) -> Result<api::HealthTests, xous::Error> {
         let ht = api::HealthTests::default();
         let mut buf = Buffer::into_buf(ht).or(Err(xous::Error::InternalError))?;
@@ -117,6 +242,16 @@ impl Trng {
             .or(Err(xous::Error::InternalError))?;
         Ok(buf.to_original().unwrap())
     }
+    /// For hardware validation: samples the ring oscillator and the avalanche generator each on
+    /// its own for a fixed window and reports how many of each one's words failed the health
+    /// tests, which points out a failing generator. Both are enabled again when it's done.
+    pub fn characterize_sources(&self) -> Result<api::SourceCharacterization, xous::Error> {
+        let sc = api::SourceCharacterization::default();
+        let mut buf = Buffer::into_buf(sc).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, api::Opcode::CharacterizeSources.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+        Ok(buf.to_original().unwrap())
+    }
 
     // legacy (0.5) trng apis
     pub fn next_u32(&mut self) -> u32 {
@@ -224,14 +359,14 @@ impl Drop for Trng {
     }
 }
 
-fn error_cb_server(sid0: usize, sid1: usize, sid2: usize, sid3: usize) {
+fn event_cb_server(sid0: usize, sid1: usize, sid2: usize, sid3: usize) {
     let sid = xous::SID::from_u32(sid0 as u32, sid1 as u32, sid2 as u32, sid3 as u32);
     loop {
         let msg = xous::receive_message(sid).unwrap();
         match FromPrimitive::from_usize(msg.body.id()) {
-            Some(api::EventCallback::Event) => xous::msg_scalar_unpack!(msg, cid, id, _, _, {
+            Some(api::EventCallback::Event) => xous::msg_scalar_unpack!(msg, cid, id, which, _, {
                 // directly pass the scalar message onto the CID with the ID memorized in the original hook
-                send_message(cid as u32, xous::Message::new_scalar(id, 0, 0, 0, 0)).unwrap();
+                send_message(cid as u32, xous::Message::new_scalar(id, which, 0, 0, 0)).unwrap();
             }),
             Some(api::EventCallback::Drop) => {
                 break; // this exits the loop and kills the thread
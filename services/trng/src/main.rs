@@ -3,6 +3,12 @@
 
 mod api;
 use api::*;
+mod warmup;
+mod debias;
+mod characterize;
+mod pool;
+use warmup::Waiters;
+use debias::DebiasClients;
 
 use num_traits::*;
 use xous::CID;
@@ -10,6 +16,13 @@ use xous_ipc::Buffer;
 
 use log::info;
 
+/// how often a drained raw pool is checked on until it recovers
+const POOL_CHECK_MS: usize = 10;
+/// how often the generator is checked on while clients wait for it to warm up
+const WARMUP_CHECK_MS: usize = 10;
+/// how long a client waits for the generator to warm up before giving up
+const WARMUP_TIMEOUT_MS: u64 = 2000;
+
 #[derive(Copy, Clone, Debug)]
 struct ScalarCallback {
     server_to_cb_cid: CID,
@@ -19,10 +32,17 @@ struct ScalarCallback {
 
 #[cfg(any(target_os = "none", target_os = "xous"))]
 mod implementation {
-    use crate::api::{ExcursionTest, HealthTests, MiniRunsTest, NistTests, TrngBuf, TrngErrors};
+    use crate::api::{ExcursionTest, HealthTests, MiniRunsTest, NistTests, PoolEvent, SourceCharacterization, TrngBuf, TrngErrors};
     use num_traits::*;
     use susres::{RegManager, RegOrField, SuspendResume};
     use utralib::generated::*;
+    use crate::warmup::Warmup;
+    use crate::debias::VonNeumann;
+    use crate::characterize::{characterize, Source};
+    use crate::pool::{PoolLevel, PoolWatch};
+
+    /// delay in microseconds for avalanche poweron after powersave
+    const AV_POWERDELAY_US: u32 = 50_000;
 
     pub struct Trng {
         csr: utralib::CSR<u32>,
@@ -30,6 +50,12 @@ mod implementation {
         conn: xous::CID,
         errors: TrngErrors,
         err_stat: HealthTests,
+        ticktimer: ticktimer_server::Ticktimer,
+        warmup: Warmup,
+        /// when set, data is served from the raw generators through this instead of from urandom
+        debias: Option<VonNeumann>,
+        /// whether draws of raw data are outrunning the generators
+        pool: PoolWatch,
     }
 
     fn trng_handler(_irq_no: usize, arg: *mut usize) {
@@ -110,6 +136,8 @@ mod implementation {
                 xous::MemoryFlags::R | xous::MemoryFlags::W,
             )
             .expect("couldn't map TRNG CSR range");
+            let ticktimer = ticktimer_server::Ticktimer::new().unwrap();
+            let now = ticktimer.elapsed_ms();
 
             let mut trng = Trng {
                 csr: CSR::new(csr.as_mut_ptr() as *mut u32),
@@ -129,6 +157,10 @@ mod implementation {
                     pending_mask: 0,
                 },
                 err_stat: HealthTests::default(),
+                ticktimer,
+                warmup: Warmup::new(now, (AV_POWERDELAY_US / 1000) as u64),
+                debias: None,
+                pool: PoolWatch::new(),
             };
 
             ///// configure power settings and which generator to use
@@ -190,7 +222,7 @@ mod implementation {
             // delay in microseconds for avalanche poweron after powersave
             trng.csr.wo(
                 utra::trng_server::AV_CONFIG,
-                trng.csr.ms(utra::trng_server::AV_CONFIG_POWERDELAY, 50_000)
+                trng.csr.ms(utra::trng_server::AV_CONFIG_POWERDELAY, AV_POWERDELAY_US)
                     | trng.csr.ms(utra::trng_server::AV_CONFIG_SAMPLES, 32),
             );
             trng.susres_manager
@@ -387,30 +419,40 @@ mod implementation {
             }
         }
 
+        /// With powersave on, the avalanche generator is powered down between draws, and each
+        /// draw waits out its power-on delay.
+        pub fn set_powersave(&mut self, enabled: bool) {
+            self.csr.rmwf(utra::trng_server::CONTROL_POWERSAVE, if enabled { 1 } else { 0 });
+        }
+        pub fn powersave(&self) -> bool {
+            self.csr.rf(utra::trng_server::CONTROL_POWERSAVE) != 0
+        }
+
+        pub fn set_debias(&mut self, enabled: bool) {
+            if enabled != self.debias.is_some() {
+                self.debias = if enabled { Some(VonNeumann::new()) } else { None };
+            }
+        }
+
         pub fn get_data_eager(&mut self) -> u32 {
-            let mut timeout = 0;
-            if false {
-                // raw random
-                while self.csr.rf(utra::trng_server::STATUS_AVAIL) == 0 {
-                    if timeout > 100 {
-                        log::debug!(
-                            "TRNG ran out of data, blocked on READY: 0x{:x}",
-                            self.csr.r(utra::trng_server::READY)
-                        );
-                        log::debug!(
-                            "ROstats: 0x{:x} 0x{:x} 0x{:x} 0x{:x}",
-                            self.csr.r(utra::trng_server::NIST_RO_STAT0),
-                            self.csr.r(utra::trng_server::NIST_RO_STAT1),
-                            self.csr.r(utra::trng_server::NIST_RO_STAT2),
-                            self.csr.r(utra::trng_server::NIST_RO_STAT3)
-                        );
-                        self.csr.rmwf(utra::trng_server::CONTROL_CLR_ERR, 1);
-                        timeout = 0;
+            if self.debias.is_some() {
+                // keep pulling raw data until enough pairs survive; a heavily biased source
+                // costs throughput, but still makes progress
+                let mut pulled = 0;
+                loop {
+                    let raw = self.get_raw_eager();
+                    if let Some(word) = self.debias.as_mut().unwrap().feed(raw) {
+                        return word;
+                    }
+                    pulled += 1;
+                    if pulled % 1024 == 0 {
+                        log::warn!("debiaser has consumed {} raw words without output; is the source stuck?", pulled);
                     }
-                    xous::yield_slice();
-                    timeout += 1;
                 }
-                self.csr.rf(utra::trng_server::DATA_DATA)
+            }
+            if false {
+                // raw random
+                self.get_raw_eager()
             } else {
                 // urandom
                 // in practice, urandom generates data fast enough that we could skip this check
@@ -421,6 +463,121 @@ mod implementation {
             }
         }
 
+        /// Samples each generator on its own, with the other disabled, and counts its words that
+        /// failed the health tests. The original configuration is put back afterwards. Health
+        /// interrupts are held off meanwhile, as the errors are counted here instead.
+        pub fn characterize_sources(&mut self) -> SourceCharacterization {
+            let control = self.csr.r(utra::trng_server::CONTROL);
+            let ev_enable = self.csr.r(utra::trng_server::EV_ENABLE);
+            self.csr.wo(utra::trng_server::EV_ENABLE, 0);
+            // these draws are the server's own, and say nothing about clients running short
+            self.pool.hold();
+            let sources = self.csr.ms(utra::trng_server::CONTROL_RO_DIS, 1)
+                | self.csr.ms(utra::trng_server::CONTROL_AV_DIS, 1);
+            let result = characterize(
+                self,
+                |trng, source| {
+                    let disable = match source {
+                        Source::RingOsc => trng.csr.ms(utra::trng_server::CONTROL_AV_DIS, 1),
+                        Source::Avalanche => trng.csr.ms(utra::trng_server::CONTROL_RO_DIS, 1),
+                    };
+                    trng.csr.wo(utra::trng_server::CONTROL, (control & !sources) | disable);
+                    // don't charge data from before the switch to the source being measured
+                    trng.flush_raw();
+                    trng.csr.rmwf(utra::trng_server::CONTROL_CLR_ERR, 1);
+                },
+                |trng, source| {
+                    trng.get_raw_eager();
+                    let errors = match source {
+                        Source::RingOsc => {
+                            trng.csr.rf(utra::trng_server::NIST_ERRORS_RO_REPCOUNT)
+                                + trng.csr.rf(utra::trng_server::NIST_ERRORS_RO_ADAPTIVE)
+                        }
+                        Source::Avalanche => {
+                            let excursions = trng.csr.r(utra::trng_server::EV_PENDING)
+                                & (trng.csr.ms(utra::trng_server::EV_PENDING_EXCURSION0, 1)
+                                    | trng.csr.ms(utra::trng_server::EV_PENDING_EXCURSION1, 1));
+                            if excursions != 0 {
+                                trng.csr.rmwf(utra::trng_server::AV_EXCURSION0_CTRL_RESET, 1);
+                                trng.csr.rmwf(utra::trng_server::AV_EXCURSION1_CTRL_RESET, 1);
+                                trng.csr.wo(utra::trng_server::EV_PENDING, excursions);
+                            }
+                            trng.csr.rf(utra::trng_server::NIST_ERRORS_AV_REPCOUNT)
+                                + trng.csr.rf(utra::trng_server::NIST_ERRORS_AV_ADAPTIVE)
+                                + excursions.count_ones()
+                        }
+                    };
+                    if errors != 0 {
+                        trng.csr.rmwf(utra::trng_server::CONTROL_CLR_ERR, 1);
+                    }
+                    errors == 0
+                },
+            );
+            self.csr.wo(utra::trng_server::CONTROL, control);
+            self.flush_raw();
+            self.csr.rmwf(utra::trng_server::CONTROL_CLR_ERR, 1);
+            self.csr.wo(utra::trng_server::EV_PENDING, 0xFFFF_FFFF);
+            self.csr.wo(utra::trng_server::EV_ENABLE, ev_enable);
+            self.pool.release();
+            result
+        }
+
+        /// Discards the raw data waiting in the FIFO
+        fn flush_raw(&mut self) {
+            while self.csr.rf(utra::trng_server::STATUS_AVAIL) != 0 {
+                self.csr.rf(utra::trng_server::DATA_DATA);
+            }
+        }
+
+        fn pool_level(&self) -> PoolLevel {
+            if self.csr.rf(utra::trng_server::STATUS_AVAIL) == 0 {
+                PoolLevel::Empty
+            } else if self.csr.rf(utra::trng_server::STATUS_FULL) != 0 {
+                PoolLevel::Full
+            } else {
+                PoolLevel::Partial
+            }
+        }
+        /// Looks at the raw FIFO between draws, so a drained pool is seen to recover even if
+        /// nobody is drawing from it
+        pub fn check_pool(&mut self) {
+            let level = self.pool_level();
+            self.pool.observe(level);
+        }
+        pub fn pool_needs_check(&self) -> bool {
+            self.pool.needs_check()
+        }
+        /// Pool transitions since the last call, for observers
+        pub fn take_pool_events(&mut self) -> Vec<PoolEvent> {
+            self.pool.take_events()
+        }
+
+        /// Raw data from the generators, without whitening
+        fn get_raw_eager(&mut self) -> u32 {
+            self.check_pool();
+            let mut timeout = 0;
+            while self.csr.rf(utra::trng_server::STATUS_AVAIL) == 0 {
+                if timeout > 100 {
+                    log::debug!(
+                        "TRNG ran out of data, blocked on READY: 0x{:x}",
+                        self.csr.r(utra::trng_server::READY)
+                    );
+                    log::debug!(
+                        "ROstats: 0x{:x} 0x{:x} 0x{:x} 0x{:x}",
+                        self.csr.r(utra::trng_server::NIST_RO_STAT0),
+                        self.csr.r(utra::trng_server::NIST_RO_STAT1),
+                        self.csr.r(utra::trng_server::NIST_RO_STAT2),
+                        self.csr.r(utra::trng_server::NIST_RO_STAT3)
+                    );
+                    self.csr.rmwf(utra::trng_server::CONTROL_CLR_ERR, 1);
+                    timeout = 0;
+                }
+                xous::yield_slice();
+                timeout += 1;
+            }
+            self.csr.rf(utra::trng_server::DATA_DATA)
+        }
+
         #[allow(dead_code)]
         pub fn wait_full(&self) {
             while self.csr.rf(utra::trng_server::STATUS_FULL) == 0 {
@@ -439,6 +596,12 @@ mod implementation {
             tb
         }
 
+        pub fn fill_words(&mut self, words: &mut [u32]) {
+            for word in words.iter_mut() {
+                *word = self.get_data_eager();
+            }
+        }
+
         pub fn get_trng(&mut self, count: usize) -> [u32; 2] {
             let mut ret: [u32; 2] = [0, 0];
 
@@ -453,11 +616,22 @@ mod implementation {
             ret
         }
 
+        /// Whether the generator has powered up and passed a health check. Doesn't wait.
+        pub fn poll_ready(&mut self) -> bool {
+            let now = self.ticktimer.elapsed_ms();
+            let csr = &self.csr;
+            self.warmup.poll(now, || {
+                csr.r(utra::trng_server::NIST_ERRORS) == 0
+                    && csr.rf(utra::trng_server::STATUS_CHACHA_READY) != 0
+            })
+        }
+
         pub fn suspend(&mut self) {
             self.susres_manager.suspend();
         }
         pub fn resume(&mut self) {
             self.susres_manager.resume();
+            self.warmup.power_on(self.ticktimer.elapsed_ms());
             // pump the engine to discard the initial 0's in the execution pipeline
             self.get_trng(2);
         }
@@ -470,20 +644,32 @@ mod implementation {
     use rand_chacha::ChaCha8Rng;
     use rand_chacha::rand_core::SeedableRng;
     use rand_chacha::rand_core::RngCore;
-    use crate::api::{HealthTests, TrngBuf, TrngErrors};
+    use crate::api::{HealthTests, PoolEvent, SourceCharacterization, TrngBuf, TrngErrors};
+    use crate::characterize::{characterize, Source};
+
+    // the fields of the hardware CONTROL register that the stub models
+    const CONTROL_ENABLE: u32 = 1 << 0;
+    const CONTROL_RO_DIS: u32 = 1 << 1;
+    const CONTROL_AV_DIS: u32 = 1 << 2;
+    const CONTROL_POWERSAVE: u32 = 1 << 3;
 
     pub struct Trng {
         rng: ChaCha8Rng,
         seed: u32,
         msgcount: u16, // re-print the message every time we rollover
+        control: u32,
     }
 
     impl Trng {
         pub fn new(_xns: &xous_names::XousNames) -> Trng {
+            Trng::stub()
+        }
+        fn stub() -> Trng {
             Trng {
                 rng: ChaCha8Rng::seed_from_u64(xous::TESTING_RNG_SEED.load(core::sync::atomic::Ordering::SeqCst)),
                 seed: 0x1afe_cafe,
                 msgcount: 0,
+                control: CONTROL_ENABLE | CONTROL_POWERSAVE,
             }
         }
 
@@ -512,6 +698,14 @@ mod implementation {
             }
         }
 
+        /// Fills from the same LFSR as `get_trng()`, so the words are repeatable
+        pub fn fill_words(&mut self, words: &mut [u32]) {
+            for word in words.iter_mut() {
+                self.seed = self.move_lfsr(self.seed);
+                *word = self.seed;
+            }
+        }
+
         pub fn get_trng(&mut self, _count: usize) -> [u32; 2] {
             if self.msgcount < 3 {
                 log::info!("hosted mode TRNG is *not* random, it is a deterministic LFSR");
@@ -525,6 +719,52 @@ mod implementation {
 
             ret
         }
+        pub fn poll_ready(&mut self) -> bool {
+            true
+        }
+        pub fn set_powersave(&mut self, enabled: bool) {
+            if enabled {
+                self.control |= CONTROL_POWERSAVE;
+            } else {
+                self.control &= !CONTROL_POWERSAVE;
+            }
+        }
+        pub fn powersave(&self) -> bool {
+            self.control & CONTROL_POWERSAVE != 0
+        }
+        // the hosted sources only fail their health tests if sampled with the wrong one enabled
+        pub fn characterize_sources(&mut self) -> SourceCharacterization {
+            let control = self.control;
+            let result = characterize(
+                self,
+                |trng, source| {
+                    trng.control &= !(CONTROL_RO_DIS | CONTROL_AV_DIS);
+                    trng.control |= match source {
+                        Source::RingOsc => CONTROL_AV_DIS,
+                        Source::Avalanche => CONTROL_RO_DIS,
+                    };
+                },
+                |trng, source| {
+                    let disabled = match source {
+                        Source::RingOsc => CONTROL_AV_DIS,
+                        Source::Avalanche => CONTROL_RO_DIS,
+                    };
+                    trng.seed = trng.move_lfsr(trng.seed);
+                    trng.control & (CONTROL_RO_DIS | CONTROL_AV_DIS) == disabled
+                },
+            );
+            self.control = control;
+            result
+        }
+        // the hosted generator has no raw source to debias, or to drain
+        pub fn set_debias(&mut self, _enabled: bool) {}
+        pub fn check_pool(&mut self) {}
+        pub fn pool_needs_check(&self) -> bool {
+            false
+        }
+        pub fn take_pool_events(&mut self) -> Vec<PoolEvent> {
+            Vec::new()
+        }
         pub fn suspend(&self) {}
         pub fn resume(&self) {}
         pub fn get_tests(&self) -> HealthTests {
@@ -547,6 +787,66 @@ mod implementation {
             HealthTests::default()
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_fill_words() {
+            let mut words = [0u32; 16];
+            Trng::stub().fill_words(&mut words);
+
+            // the same seed gives the same words every time
+            let mut again = [0u32; 16];
+            Trng::stub().fill_words(&mut again);
+            assert_eq!(words, again);
+
+            // and they're the LFSR's sequence from the seed, continuing where `get_trng()` leaves off
+            let mut trng = Trng::stub();
+            assert_eq!(trng.get_trng(2), [words[0], words[1]]);
+            let mut rest = [0u32; 14];
+            trng.fill_words(&mut rest);
+            assert_eq!(rest, words[2..]);
+            assert!(words.windows(2).all(|pair| pair[0] != pair[1]));
+
+            // nothing to fill is fine, and draws nothing
+            let mut trng = Trng::stub();
+            trng.fill_words(&mut []);
+            assert_eq!(trng.seed, 0x1afe_cafe);
+        }
+
+        #[test]
+        fn test_powersave() {
+            let mut trng = Trng::stub();
+            // on by default, as on hardware
+            assert!(trng.powersave());
+            assert_eq!(trng.control, CONTROL_ENABLE | CONTROL_POWERSAVE);
+            // only the powersave field moves
+            trng.set_powersave(false);
+            assert!(!trng.powersave());
+            assert_eq!(trng.control, CONTROL_ENABLE);
+            trng.set_powersave(false);
+            assert_eq!(trng.control, CONTROL_ENABLE);
+            trng.set_powersave(true);
+            assert!(trng.powersave());
+            assert_eq!(trng.control, CONTROL_ENABLE | CONTROL_POWERSAVE);
+        }
+
+        #[test]
+        fn test_characterize_sources() {
+            let mut trng = Trng::stub();
+            trng.set_powersave(false);
+            let sc = trng.characterize_sources();
+            // each source gets a full window, sampled with only that source enabled
+            assert_eq!(sc.ro.samples, crate::characterize::CHARACTERIZE_WINDOW);
+            assert_eq!(sc.av.samples, crate::characterize::CHARACTERIZE_WINDOW);
+            assert_eq!(sc.ro.rejections, 0);
+            assert_eq!(sc.av.rejections, 0);
+            // and both are enabled again afterwards, with the rest of the configuration as it was
+            assert_eq!(trng.control, CONTROL_ENABLE);
+        }
+    }
 }
 
 #[cfg(any(
@@ -792,10 +1092,21 @@ fn main() -> ! {
         .expect("couldn't create suspend/resume object");
 
     let mut error_cb_conns: [Option<ScalarCallback>; 32] = [None; 32];
+    let mut pool_cb_conns: [Option<ScalarCallback>; 32] = [None; 32];
+    // whether a `PoolCheck` is on its way
+    let mut pool_check_armed = false;
+    // the processes that get debiased data; everyone else gets whitened data
+    let mut debias_clients = DebiasClients::<Option<xous::PID>>::new();
+    // clients blocked in `wait_ready()`, answered once the generator warms up or they time out
+    let mut ready_waiters = Waiters::<xous::MessageSender>::new();
+    let tt = ticktimer_server::Ticktimer::new().unwrap();
+    // the suspend/resume callback connection is as good a way back to ourselves as any
+    let wakeups = spawn_wakeups(sr_cid);
     loop {
         let mut msg = xous::receive_message(trng_sid).unwrap();
         match FromPrimitive::from_usize(msg.body.id()) {
             Some(api::Opcode::GetTrng) => xous::msg_blocking_scalar_unpack!(msg, count, _, _, _, {
+                trng.set_debias(debias_clients.wants(&msg.sender.pid()));
                 let val: [u32; 2] = trng.get_trng(count);
                 xous::return_scalar2(msg.sender, val[0] as _, val[1] as _)
                     .expect("couldn't return GetTrng request");
@@ -819,7 +1130,7 @@ fn main() -> ! {
                     trng.get_errors()
                 );
                 log::error!("Stats: {:?}", trng.get_err_stats());
-                send_event(&error_cb_conns);
+                send_event(&error_cb_conns, 0);
             }
             Some(api::Opcode::HealthStats) => {
                 let mut buffer = unsafe {
@@ -838,23 +1149,118 @@ fn main() -> ! {
                     Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
                 };
                 let len = buffer.as_flat::<TrngBuf, _>().unwrap().len;
+                trng.set_debias(debias_clients.wants(&msg.sender.pid()));
                 buffer.replace(trng.get_buf(len)).unwrap();
             }
+            Some(api::Opcode::FillTrngWords) => {
+                let mem = msg.body.memory_message_mut().unwrap();
+                let count = mem.valid.map(|v| v.get()).unwrap_or(0) / core::mem::size_of::<u32>();
+                let words = mem.buf.as_slice_mut::<u32>();
+                // `valid` comes from the client, so don't let it run past the page
+                let count = count.min(words.len());
+                trng.set_debias(debias_clients.wants(&msg.sender.pid()));
+                trng.fill_words(&mut words[..count]);
+            }
+            Some(api::Opcode::WaitReady) => xous::msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                if trng.poll_ready() {
+                    xous::return_scalar(msg.sender, 1).expect("couldn't return WaitReady request");
+                } else {
+                    // the reply waits for the generator, so the server can keep serving others;
+                    // one that never passes its health check gets a couple of seconds
+                    if ready_waiters.is_empty() {
+                        wakeups
+                            .send((api::Opcode::WarmupCheck, WARMUP_CHECK_MS))
+                            .unwrap();
+                    }
+                    ready_waiters.add(msg.sender, tt.elapsed_ms() + WARMUP_TIMEOUT_MS);
+                }
+            }),
+            Some(api::Opcode::WarmupCheck) => {
+                let ready = trng.poll_ready();
+                for sender in ready_waiters.take_due(tt.elapsed_ms(), ready) {
+                    xous::return_scalar(sender, if ready { 1 } else { 0 })
+                        .expect("couldn't return WaitReady request");
+                }
+                if !ready_waiters.is_empty() {
+                    wakeups
+                        .send((api::Opcode::WarmupCheck, WARMUP_CHECK_MS))
+                        .unwrap();
+                }
+            }
+            Some(api::Opcode::SetDebias) => xous::msg_blocking_scalar_unpack!(msg, enabled, _, _, _, {
+                debias_clients.set(msg.sender.pid(), enabled != 0);
+                xous::return_scalar(msg.sender, 0).expect("couldn't return SetDebias request");
+            }),
+            Some(api::Opcode::SetPowersave) => xous::msg_blocking_scalar_unpack!(msg, enabled, _, _, _, {
+                trng.set_powersave(enabled != 0);
+                xous::return_scalar(msg.sender, 0).expect("couldn't return SetPowersave request");
+            }),
+            Some(api::Opcode::GetPowersave) => xous::msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                xous::return_scalar(msg.sender, if trng.powersave() { 1 } else { 0 })
+                    .expect("couldn't return GetPowersave request");
+            }),
+            Some(api::Opcode::CharacterizeSources) => {
+                let mut buffer = unsafe {
+                    Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap())
+                };
+                let sc = trng.characterize_sources();
+                log::info!("TRNG source characterization: {:?}", sc);
+                buffer.replace(sc).unwrap();
+            }
+            Some(api::Opcode::RegisterObserver) => {
+                let buffer =
+                    unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let hookdata = buffer.to_original::<api::ScalarHook, _>().unwrap();
+                do_hook(hookdata, &mut pool_cb_conns);
+            }
+            Some(api::Opcode::PoolCheck) => {
+                pool_check_armed = false;
+                trng.check_pool();
+            }
             Some(api::Opcode::Quit) => break,
             None => {
                 log::error!("couldn't convert opcode, ignoring");
             }
         }
+        for event in trng.take_pool_events() {
+            log::debug!("raw pool: {:?}", event);
+            send_event(&pool_cb_conns, event.to_usize().unwrap());
+        }
+        // keep an eye on a drained pool until it recovers, as a client that has backed off
+        // won't be drawing from it
+        if trng.pool_needs_check() && !pool_check_armed {
+            pool_check_armed = true;
+            wakeups.send((api::Opcode::PoolCheck, POOL_CHECK_MS)).unwrap();
+        }
     }
     // clean up our program
     log::trace!("main loop exit, destroying servers");
     unhook(&mut error_cb_conns);
+    unhook(&mut pool_cb_conns);
     xns.unregister_server(trng_sid).unwrap();
     xous::destroy_server(trng_sid).unwrap();
     log::trace!("quitting");
     xous::terminate_process(0)
 }
 
+/// Starts the thread that sends `(opcode, ms)` back to the server on `cid` as a scalar once `ms`
+/// have gone by. One long-lived thread serves every delay the server asks for, in order.
+fn spawn_wakeups(cid: CID) -> std::sync::mpsc::Sender<(api::Opcode, usize)> {
+    let (tx, rx) = std::sync::mpsc::channel::<(api::Opcode, usize)>();
+    std::thread::spawn(move || {
+        let tt = ticktimer_server::Ticktimer::new().unwrap();
+        while let Ok((opcode, ms)) = rx.recv() {
+            tt.sleep_ms(ms).unwrap();
+            xous::send_message(
+                cid,
+                xous::Message::new_scalar(opcode.to_usize().unwrap(), 0, 0, 0, 0),
+            )
+            .ok();
+        }
+    });
+    tx
+}
+
 fn do_hook(hookdata: ScalarHook, cb_conns: &mut [Option<ScalarCallback>; 32]) {
     let (s0, s1, s2, s3) = hookdata.sid;
     let sid = xous::SID::from_u32(s0, s1, s2, s3);
@@ -897,17 +1303,18 @@ fn unhook(cb_conns: &mut [Option<ScalarCallback>; 32]) {
         *entry = None;
     }
 }
-fn send_event(cb_conns: &[Option<ScalarCallback>; 32]) {
+fn send_event(cb_conns: &[Option<ScalarCallback>; 32], which: usize) {
     for entry in cb_conns.iter() {
         if let Some(scb) = entry {
-            // note that the "which" argument is only used for GPIO events, to indicate which pin had the event
+            // "which" is handed on to the client as the first argument; error events leave it 0,
+            // pool events say which `PoolEvent` it was
             xous::send_message(
                 scb.server_to_cb_cid,
                 xous::Message::new_scalar(
                     api::EventCallback::Event.to_usize().unwrap(),
                     scb.cb_to_client_cid as usize,
                     scb.cb_to_client_id as usize,
-                    0,
+                    which,
                     0,
                 ),
             )
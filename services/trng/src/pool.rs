@@ -0,0 +1,193 @@
+// Only fed on real hardware, but kept free of hardware dependencies so it can be tested in
+// hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use crate::api::PoolEvent;
+
+/// How full the raw FIFO is, as far as the status register can tell
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PoolLevel {
+    Empty,
+    Partial,
+    Full,
+}
+
+/// Watches the raw FIFO behind the debiaser, which a large fill can drain faster than the
+/// generators refill it. Edge-triggered: `Empty` is reported once when it runs dry, and
+/// `Recovered` once when it has filled all the way back up. Levels in between change nothing,
+/// so a pool hovering around empty under load doesn't repeat itself.
+///
+/// Draws the server makes for itself, rather than for a client, don't count: see `hold()`.
+pub(crate) struct PoolWatch {
+    drained: bool,
+    /// the server is drawing for itself, so levels are ignored
+    held: bool,
+    /// the server has flushed the pool for itself, and it hasn't filled back up yet
+    refilling: bool,
+    /// transitions not yet passed on to observers, oldest first
+    events: Vec<PoolEvent>,
+}
+
+impl PoolWatch {
+    pub fn new() -> PoolWatch {
+        PoolWatch {
+            drained: false,
+            held: false,
+            refilling: false,
+            events: Vec::new(),
+        }
+    }
+    pub fn observe(&mut self, level: PoolLevel) {
+        if self.held {
+            return;
+        }
+        match level {
+            PoolLevel::Empty if !self.drained && !self.refilling => {
+                self.drained = true;
+                self.events.push(PoolEvent::Empty);
+            }
+            PoolLevel::Full => {
+                self.refilling = false;
+                if self.drained {
+                    self.drained = false;
+                    self.events.push(PoolEvent::Recovered);
+                }
+            }
+            _ => (),
+        }
+    }
+    /// Ignores the pool while the server draws from it for itself, as when characterizing the
+    /// sources, which empties it without any client being short of data
+    pub fn hold(&mut self) {
+        self.held = true;
+    }
+    /// Picks the pool back up after `hold()`. The server's draws will have left it empty, so
+    /// that only counts once it has filled back up.
+    pub fn release(&mut self) {
+        self.held = false;
+        self.refilling = true;
+    }
+    /// Whether the pool has run dry and not yet recovered
+    pub fn drained(&self) -> bool {
+        self.drained
+    }
+    /// Whether the pool has to be checked on even with nobody drawing from it: it has run dry,
+    /// or is refilling after `release()`
+    pub fn needs_check(&self) -> bool {
+        self.drained() || self.refilling
+    }
+    pub fn take_events(&mut self) -> Vec<PoolEvent> {
+        core::mem::take(&mut self.events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A FIFO of `depth` words that the generators add one word to per tick
+    struct Fifo {
+        depth: u32,
+        words: u32,
+    }
+    impl Fifo {
+        fn level(&self) -> PoolLevel {
+            match self.words {
+                0 => PoolLevel::Empty,
+                w if w == self.depth => PoolLevel::Full,
+                _ => PoolLevel::Partial,
+            }
+        }
+        fn tick(&mut self) {
+            self.words = (self.words + 1).min(self.depth);
+        }
+        /// draws up to `count` words, looking at the level before each one as the server does
+        fn draw(&mut self, count: u32, watch: &mut PoolWatch) {
+            for _ in 0..count {
+                watch.observe(self.level());
+                self.words = self.words.saturating_sub(1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pool_drain() {
+        let mut fifo = Fifo {
+            depth: 512,
+            words: 512,
+        };
+        let mut watch = PoolWatch::new();
+
+        // a light load keeps up with the generators
+        for _ in 0..100 {
+            fifo.draw(1, &mut watch);
+            fifo.tick();
+        }
+        assert!(watch.take_events().is_empty());
+
+        // a bulk fill outruns them; the pool runs dry once, and keeps running dry while the
+        // fill goes on, but that's only reported the once
+        for _ in 0..1000 {
+            fifo.draw(4, &mut watch);
+            fifo.tick();
+        }
+        assert!(watch.drained());
+        assert_eq!(watch.take_events(), vec![PoolEvent::Empty]);
+
+        // once the client backs off, the server keeps checking until it has refilled
+        while watch.drained() {
+            assert!(watch.take_events().is_empty());
+            fifo.tick();
+            watch.observe(fifo.level());
+        }
+        // not before it had filled all the way
+        assert_eq!(fifo.level(), PoolLevel::Full);
+        assert_eq!(watch.take_events(), vec![PoolEvent::Recovered]);
+        // nothing more while it stays full
+        watch.observe(fifo.level());
+        assert!(watch.take_events().is_empty());
+
+        // and it can run dry again
+        fifo.draw(600, &mut watch);
+        assert_eq!(watch.take_events(), vec![PoolEvent::Empty]);
+    }
+
+    #[test]
+    fn test_pool_hold() {
+        let mut fifo = Fifo {
+            depth: 512,
+            words: 512,
+        };
+        let mut watch = PoolWatch::new();
+
+        // the server drawing the pool dry for itself, then flushing it, isn't reported
+        watch.hold();
+        fifo.draw(600, &mut watch);
+        assert!(!watch.needs_check());
+        watch.release();
+        assert!(watch.needs_check());
+        while fifo.level() != PoolLevel::Full {
+            watch.observe(fifo.level());
+            fifo.tick();
+        }
+        assert!(watch.take_events().is_empty());
+        assert!(watch.needs_check());
+        watch.observe(fifo.level());
+        assert!(!watch.needs_check());
+        assert!(watch.take_events().is_empty());
+
+        // once refilled, a client running it dry is
+        fifo.draw(600, &mut watch);
+        assert_eq!(watch.take_events(), vec![PoolEvent::Empty]);
+
+        // and a pool that was already dry still recovers across the server's own draws
+        watch.hold();
+        fifo.draw(10, &mut watch);
+        watch.release();
+        while watch.needs_check() {
+            fifo.tick();
+            watch.observe(fifo.level());
+        }
+        assert_eq!(watch.take_events(), vec![PoolEvent::Recovered]);
+    }
+}
@@ -0,0 +1,264 @@
+use rand_chacha::rand_core::{CryptoRng, Error, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// Bytes served between reseeds, unless set otherwise with `set_reseed_interval()`
+pub const DEFAULT_RESEED_INTERVAL: usize = 1024 * 1024;
+
+/// Something that can provide a fresh 256-bit seed
+pub trait SeedSource {
+    fn fill_seed(&mut self, seed: &mut [u8; 32]) -> Result<(), xous::Error>;
+}
+
+impl SeedSource for crate::Trng {
+    fn fill_seed(&mut self, seed: &mut [u8; 32]) -> Result<(), xous::Error> {
+        let mut words = [0u32; 8];
+        self.fill_buf(&mut words)?;
+        for (dst, word) in seed.chunks_exact_mut(4).zip(words.iter()) {
+            dst.copy_from_slice(&word.to_le_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// A ChaCha20 CSPRNG seeded from the TRNG, for callers that need more random data than is
+/// polite to draw straight from the hardware. Only 32 bytes are taken from the TRNG at a time:
+/// once at construction, and again each time the reseed interval's worth of output has been
+/// served.
+pub struct SeededTrng<S: SeedSource = crate::Trng> {
+    source: S,
+    rng: ChaCha20Rng,
+    reseed_interval: usize,
+    /// bytes served since the last reseed
+    served: usize,
+    reseeds: u32,
+}
+
+impl SeededTrng<crate::Trng> {
+    pub fn from_hardware(xns: &xous_names::XousNames) -> Result<Self, xous::Error> {
+        SeededTrng::from_source(crate::Trng::new(xns)?)
+    }
+}
+
+impl<S: SeedSource> SeededTrng<S> {
+    pub fn from_source(mut source: S) -> Result<Self, xous::Error> {
+        let mut seed = [0u8; 32];
+        source.fill_seed(&mut seed)?;
+        Ok(SeededTrng {
+            source,
+            rng: ChaCha20Rng::from_seed(seed),
+            reseed_interval: DEFAULT_RESEED_INTERVAL,
+            served: 0,
+            reseeds: 0,
+        })
+    }
+    /// Sets the number of bytes served between reseeds. The count restarts from the last
+    /// reseed, so a shorter interval may cause one on the next request.
+    pub fn set_reseed_interval(&mut self, bytes: usize) {
+        assert!(bytes > 0, "reseed interval must be at least one byte");
+        self.reseed_interval = bytes;
+    }
+    pub fn reseed_interval(&self) -> usize {
+        self.reseed_interval
+    }
+    /// Number of times the generator has been reseeded since it was created
+    pub fn reseed_count(&self) -> u32 {
+        self.reseeds
+    }
+    fn reseed_from_source(&mut self) -> Result<(), xous::Error> {
+        let mut seed = [0u8; 32];
+        self.source.fill_seed(&mut seed)?;
+        self.rng = ChaCha20Rng::from_seed(seed);
+        self.served = 0;
+        self.reseeds += 1;
+        Ok(())
+    }
+    /// Reseeds now, mixing the caller's `additional` entropy into a fresh seed from the source,
+    /// for policies that combine the TRNG with other entropy. The additional input only ever
+    /// adds to the seed from the source, which is drawn in full regardless; a caller that knows
+    /// or chooses `additional` learns nothing of the resulting seed from it.
+    ///
+    /// The new seed is derived by `mix_additional()`. This counts as a reseed, and restarts the
+    /// reseed interval.
+    pub fn reseed(&mut self, additional: &[u8]) -> Result<(), xous::Error> {
+        let mut seed = [0u8; 32];
+        self.source.fill_seed(&mut seed)?;
+        self.rng = ChaCha20Rng::from_seed(mix_additional(seed, additional));
+        self.served = 0;
+        self.reseeds += 1;
+        Ok(())
+    }
+    /// Serves `dest` in pieces that stop at each reseed boundary, so that no more than the
+    /// interval is ever drawn from one seed.
+    fn fill(&mut self, dest: &mut [u8]) -> Result<(), xous::Error> {
+        let mut left = dest;
+        while !left.is_empty() {
+            if self.served >= self.reseed_interval {
+                self.reseed_from_source()?;
+            }
+            let count = left.len().min(self.reseed_interval - self.served);
+            let (now, rest) = left.split_at_mut(count);
+            self.rng.fill_bytes(now);
+            self.served += count;
+            left = rest;
+        }
+        Ok(())
+    }
+}
+
+/// Mixes `additional` into a `seed` from the source, 32 bytes at a time: each block of the input,
+/// zero padded, is XORed into the first 32 bytes of the ChaCha20 keystream keyed by the seed so
+/// far, and the result keys the next step. A last step keyed by that, on the stream numbered by
+/// the length of the input, gives the new seed. With no additional input, that's just the last
+/// step on stream 0.
+///
+/// Each step is ChaCha20 keyed by the seed so far, so none of the source's entropy is lost to
+/// the additional input, whatever it is.
+pub fn mix_additional(seed: [u8; 32], additional: &[u8]) -> [u8; 32] {
+    let mut seed = seed;
+    for block in additional.chunks(32) {
+        let mut next = [0u8; 32];
+        ChaCha20Rng::from_seed(seed).fill_bytes(&mut next);
+        for (dst, src) in next.iter_mut().zip(block.iter()) {
+            *dst ^= src;
+        }
+        seed = next;
+    }
+    let mut last = ChaCha20Rng::from_seed(seed);
+    last.set_stream(additional.len() as u64);
+    last.fill_bytes(&mut seed);
+    seed
+}
+
+impl<S: SeedSource> RngCore for SeededTrng<S> {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.fill(dest).expect("couldn't reseed from the TRNG")
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill(dest).or(Err(Error::from(
+            core::num::NonZeroU32::new(Error::CUSTOM_START).unwrap(),
+        )))
+    }
+}
+
+impl<S: SeedSource> CryptoRng for SeededTrng<S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// hands out the seeds 1, 2, 3... as 32 repeats of that byte
+    struct CountingSource(u8);
+    impl SeedSource for CountingSource {
+        fn fill_seed(&mut self, seed: &mut [u8; 32]) -> Result<(), xous::Error> {
+            self.0 += 1;
+            *seed = [self.0; 32];
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reseed_boundary() {
+        let mut rng = SeededTrng::from_source(CountingSource(0)).unwrap();
+        rng.set_reseed_interval(100);
+
+        // a fixed seed gives the same stream as plain ChaCha20 would
+        let mut out = [0u8; 100];
+        rng.fill_bytes(&mut out);
+        let mut expected = [0u8; 100];
+        ChaCha20Rng::from_seed([1; 32]).fill_bytes(&mut expected);
+        assert_eq!(out, expected);
+        assert_eq!(rng.reseed_count(), 0);
+
+        // the very next byte comes from the next seed
+        let mut byte = [0u8; 1];
+        rng.fill_bytes(&mut byte);
+        assert_eq!(rng.reseed_count(), 1);
+        let mut reference = ChaCha20Rng::from_seed([2; 32]);
+        let mut expected = [0u8; 1];
+        reference.fill_bytes(&mut expected);
+        assert_eq!(byte, expected);
+
+        // a request spanning the boundary is split across the two seeds
+        let mut out = [0u8; 150];
+        rng.fill_bytes(&mut out);
+        assert_eq!(rng.reseed_count(), 2);
+        let mut expected = [0u8; 150];
+        reference.fill_bytes(&mut expected[..99]);
+        ChaCha20Rng::from_seed([3; 32]).fill_bytes(&mut expected[99..]);
+        assert_eq!(out[..], expected[..]);
+
+        // two generators from the same seeds agree
+        let mut a = SeededTrng::from_source(CountingSource(7)).unwrap();
+        let mut b = SeededTrng::from_source(CountingSource(7)).unwrap();
+        a.set_reseed_interval(16);
+        b.set_reseed_interval(16);
+        for _ in 0..10 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        assert_eq!(a.reseed_count(), 4);
+    }
+
+    #[test]
+    fn test_reseed_additional() {
+        let additional = b"forty bytes of entropy from elsewhere...";
+        let mut rng = SeededTrng::from_source(CountingSource(0)).unwrap();
+        rng.set_reseed_interval(64);
+        let mut out = [0u8; 8];
+        rng.fill_bytes(&mut out);
+        rng.reseed(additional).unwrap();
+        assert_eq!(rng.reseed_count(), 1);
+
+        // the second seed from the source, with the input mixed in as `mix_additional()` says
+        let mut seed = [2u8; 32];
+        for block in additional.chunks(32) {
+            let mut next = [0u8; 32];
+            ChaCha20Rng::from_seed(seed).fill_bytes(&mut next);
+            for (dst, src) in next.iter_mut().zip(block.iter()) {
+                *dst ^= src;
+            }
+            seed = next;
+        }
+        let mut last = ChaCha20Rng::from_seed(seed);
+        last.set_stream(40);
+        last.fill_bytes(&mut seed);
+        assert_eq!(mix_additional([2; 32], additional), seed);
+        let mut expected = [0u8; 64];
+        ChaCha20Rng::from_seed(seed).fill_bytes(&mut expected);
+        // and the reseed restarted the interval, so all 64 bytes come from it
+        let mut out = [0u8; 64];
+        rng.fill_bytes(&mut out);
+        assert_eq!(out[..], expected[..]);
+        assert_eq!(rng.reseed_count(), 1);
+        // it's reproducible: the same source and input give the same stream
+        let mut again = SeededTrng::from_source(CountingSource(0)).unwrap();
+        again.reseed(additional).unwrap();
+        let mut out_again = [0u8; 64];
+        again.fill_bytes(&mut out_again);
+        assert_eq!(out_again[..], out[..]);
+        // and it starts with these, for checking other implementations of the mixing against
+        assert_eq!(&out[..8], &[0x21, 0x28, 0xfe, 0x17, 0x6a, 0x7a, 0x09, 0x06]);
+
+        // the input augments the seed from the source, and never stands in for it
+        assert_ne!(
+            mix_additional([3; 32], additional),
+            mix_additional([2; 32], additional)
+        );
+        assert_ne!(mix_additional([2; 32], additional), [2; 32]);
+        assert_ne!(mix_additional([2; 32], b""), [2; 32]);
+        // and the length of the input counts, not just its padded blocks
+        assert_ne!(
+            mix_additional([2; 32], b"ab"),
+            mix_additional([2; 32], b"ab\0")
+        );
+    }
+}
@@ -0,0 +1,111 @@
+// Only consulted on real hardware; hosted mode is always ready.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+/// Tracks whether the generator has warmed up since it was last powered on. Right after boot or
+/// resume the avalanche generator is still inside its power-on delay, and its first samples can
+/// be of lower quality, so the generator only counts as ready once that delay has passed and a
+/// health check has come back clean.
+pub(crate) struct Warmup {
+    /// time of the last power-on, in ms
+    powered_at: u64,
+    delay_ms: u64,
+    ready: bool,
+}
+
+impl Warmup {
+    pub fn new(now: u64, delay_ms: u64) -> Warmup {
+        Warmup {
+            powered_at: now,
+            delay_ms,
+            ready: false,
+        }
+    }
+    /// The generator was powered on again, e.g. on resume
+    pub fn power_on(&mut self, now: u64) {
+        self.powered_at = now;
+        self.ready = false;
+    }
+    /// Checks for readiness at `now`. `healthy` is only called once the power-on delay has
+    /// passed, and the generator stays ready from the first time it returns true.
+    pub fn poll(&mut self, now: u64, healthy: impl FnOnce() -> bool) -> bool {
+        if !self.ready && now.saturating_sub(self.powered_at) >= self.delay_ms {
+            self.ready = healthy();
+        }
+        self.ready
+    }
+    pub fn ready(&self) -> bool {
+        self.ready
+    }
+}
+
+/// Clients waiting for the generator to warm up, each with the time it gives up at
+pub(crate) struct Waiters<T> {
+    waiting: Vec<(T, u64)>,
+}
+
+impl<T> Waiters<T> {
+    pub fn new() -> Waiters<T> {
+        Waiters {
+            waiting: Vec::new(),
+        }
+    }
+    pub fn add(&mut self, waiter: T, deadline: u64) {
+        self.waiting.push((waiter, deadline));
+    }
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+    /// Takes the waiters to answer at `now`: all of them once the generator is `ready`,
+    /// otherwise those that have reached their deadline.
+    pub fn take_due(&mut self, now: u64, ready: bool) -> Vec<T> {
+        let (due, waiting) = self
+            .waiting
+            .drain(..)
+            .partition(|&(_, deadline)| ready || now >= deadline);
+        self.waiting = waiting;
+        due.into_iter().map(|(waiter, _)| waiter).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait_ready() {
+        let mut warmup = Warmup::new(1000, 50);
+        assert!(!warmup.ready());
+        // still powering up: the health check isn't consulted
+        assert!(!warmup.poll(1049, || panic!("checked health during the power-on delay")));
+        // powered up, but the first check fails
+        assert!(!warmup.poll(1050, || false));
+        assert!(!warmup.ready());
+        assert!(warmup.poll(1060, || true));
+        assert!(warmup.ready());
+        // once ready, it stays ready without further checks
+        assert!(warmup.poll(1070, || panic!("checked health after becoming ready")));
+
+        // a resume starts the delay over
+        warmup.power_on(5000);
+        assert!(!warmup.ready());
+        assert!(!warmup.poll(5010, || true));
+        assert!(warmup.poll(5050, || true));
+    }
+
+    #[test]
+    fn test_waiters() {
+        let mut waiters = Waiters::new();
+        assert!(waiters.is_empty());
+        waiters.add('a', 2000);
+        waiters.add('b', 2500);
+        // not ready yet, and nobody has waited long enough
+        assert!(waiters.take_due(1000, false).is_empty());
+        // the first waiter times out, the second keeps waiting
+        assert_eq!(waiters.take_due(2000, false), vec!['a']);
+        assert!(!waiters.is_empty());
+        // everyone still waiting is answered once the generator is ready
+        waiters.add('c', 4000);
+        assert_eq!(waiters.take_due(2100, true), vec!['b', 'c']);
+        assert!(waiters.is_empty());
+    }
+}
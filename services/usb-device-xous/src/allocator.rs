@@ -0,0 +1,371 @@
+// The allocator is only used on real hardware, but it is kept free of hardware dependencies
+// so that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use std::collections::BTreeMap;
+use usb_device::UsbError;
+
+pub(crate) const START_OFFSET: u32 = 0x0048 + 8 + 16; // align spinal free space to 16-byte boundary + 16 bytes for EP0 read
+pub(crate) const END_OFFSET: u32 = 0x1000; // derived from RAMSIZE parameter: this could be a dynamically read out constant, but, in practice, it's part of the hardware
+/// USB endpoint allocator. The SpinalHDL USB controller appears as a block of
+/// unstructured memory to the host. You can specify pointers into the memory with
+/// an offset and length to define where various USB descriptors should be placed.
+/// This allocator manages that space.
+///
+/// Note that all allocations must be aligned to 16-byte boundaries. This is a restriction
+/// of the USB core.
+///
+/// Returns a full memory address as the pointer. Must be shifted left by 4 to get the
+/// aligned representation used by the SpinalHDL block.
+pub(crate) fn alloc_inner(allocs: &mut BTreeMap<u32, u32>, requested: u32) -> Option<u32> {
+    if requested == 0 {
+        return None;
+    }
+    let with_descriptor = requested + 16; // the descriptor takes 3 words; add 4 because of the alignment requirement
+    let mut alloc_offset = START_OFFSET;
+    for (&offset, &length) in allocs.iter() {
+        // round length up to the nearest 16-byte increment
+        let length = if length & 0xF == 0 { length } else { (length + 16) & !0xF };
+        // println!("aoff: {}, cur: {}+{}", alloc_offset, offset, length);
+        assert!(offset >= alloc_offset, "allocated regions overlap");
+        if offset > alloc_offset {
+            if offset - alloc_offset >= with_descriptor {
+                // there's a hole in the list, insert the element here
+                break;
+            }
+        }
+        alloc_offset = offset + length;
+    }
+    if alloc_offset + with_descriptor <= END_OFFSET {
+        allocs.insert(alloc_offset, with_descriptor);
+        Some(alloc_offset)
+    } else {
+        None
+    }
+}
+pub(crate) fn dealloc_inner(allocs: &mut BTreeMap<u32, u32>, offset: u32) -> bool {
+    allocs.remove(&offset).is_some()
+}
+
+/// Checks a `max_packet_size` against a UDC RAM of `ramsize` bytes, as read from the hardware,
+/// before anything is allocated for it. A packet that wouldn't fit with its descriptor even in an
+/// otherwise empty RAM is an `EndpointMemoryOverflow`.
+pub(crate) fn check_max_packet_size(ramsize: u32, max_packet_size: u32) -> Result<(), UsbError> {
+    if max_packet_size + 16 > ramsize.saturating_sub(START_OFFSET) {
+        Err(UsbError::EndpointMemoryOverflow)
+    } else {
+        Ok(())
+    }
+}
+
+/// Picks the endpoint slot for `alloc_ep()` from `slots`, the endpoints allocated so far, and
+/// allocates its packet memory, plus a second buffer if it's `double_buffered`. A `requested`
+/// index must exist and be free, or the request is an `InvalidEndpoint`; without one, the first
+/// free slot after ep0 is taken, and there being none is an `EndpointOverflow`. A slot without
+/// the memory for its buffers is an `EndpointMemoryOverflow`. On any error, `allocs` is left as
+/// it was.
+///
+/// Returns the index, the offset of its packet memory, and the offset of the second buffer.
+pub(crate) fn alloc_ep_slot<T>(
+    slots: &[Option<T>],
+    requested: Option<usize>,
+    allocs: &mut BTreeMap<u32, u32>,
+    max_packet_size: u32,
+    double_buffered: bool,
+) -> Result<(usize, u32, Option<u32>), UsbError> {
+    let index = match requested {
+        Some(index) => match slots.get(index) {
+            Some(None) => index,
+            _ => return Err(UsbError::InvalidEndpoint),
+        },
+        None => (1..slots.len())
+            .find(|&index| slots[index].is_none())
+            .ok_or(UsbError::EndpointOverflow)?,
+    };
+    let offset = alloc_inner(allocs, max_packet_size).ok_or(UsbError::EndpointMemoryOverflow)?;
+    let alt_offset = if double_buffered {
+        match alloc_inner(allocs, max_packet_size) {
+            Some(alt_offset) => Some(alt_offset),
+            None => {
+                dealloc_inner(allocs, offset);
+                return Err(UsbError::EndpointMemoryOverflow);
+            }
+        }
+    } else {
+        None
+    };
+    Ok((index, offset, alt_offset))
+}
+
+/// Undoes `alloc_ep_slot()` for endpoint `index`: the packet memory of the endpoint, and of its
+/// second buffer at `alt` if it has one, goes back to `allocs`, and the slot is freed for the
+/// next `alloc_ep()`. Slots hold the offset of the packet memory in 16-byte units, as
+/// `alloc_ep()` records it. An endpoint that isn't allocated is left alone.
+///
+/// Returns `true` if the endpoint was allocated.
+pub(crate) fn free_ep_slot(
+    slots: &mut [Option<(usize, usize)>],
+    index: usize,
+    alt: Option<usize>,
+    allocs: &mut BTreeMap<u32, u32>,
+) -> bool {
+    match slots.get_mut(index).and_then(|slot| slot.take()) {
+        Some((head_offset, _max_len)) => {
+            dealloc_inner(allocs, head_offset as u32 * 16);
+            if let Some(alt) = alt {
+                dealloc_inner(allocs, alt as u32 * 16);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+// run with `cargo test -- --nocapture --test-threads=1`:
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_chacha::ChaCha8Rng;
+    use rand_chacha::rand_core::SeedableRng;
+    use rand_chacha::rand_core::RngCore;
+
+    /// space actually taken up by a request: the data, plus the descriptor header, rounded up to the 16-byte alignment
+    fn footprint(requested: u32) -> u32 {
+        (requested + 16 + 0xF) & !0xF
+    }
+    /// checks that every allocation is aligned, inside the descriptor space, and doesn't overlap its neighbor
+    fn check_consistency(allocs: &BTreeMap<u32, u32>) {
+        let mut last_end = START_OFFSET;
+        for (&offset, &len) in allocs.iter() {
+            assert!(offset & 0xF == 0, "misaligned allocation detected");
+            assert!(offset >= last_end, "new offset is inside last allocation!");
+            assert!(offset + len <= END_OFFSET, "allocation runs past the end of descriptor space");
+            last_end = offset + ((len + 0xF) & !0xF);
+        }
+        // the register bank lives at 0xFF00; descriptor space ends well before it
+        assert!(last_end <= END_OFFSET && END_OFFSET <= 0xFF00);
+    }
+    /// reference check for whether there is a hole anywhere that could fit `requested`
+    fn has_room(allocs: &BTreeMap<u32, u32>, requested: u32) -> bool {
+        let mut last_end = START_OFFSET;
+        for (&offset, &len) in allocs.iter() {
+            if offset - last_end >= requested + 16 {
+                return true;
+            }
+            last_end = offset + ((len + 0xF) & !0xF);
+        }
+        END_OFFSET - last_end >= requested + 16
+    }
+
+    #[test]
+    fn test_alloc() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+
+        let mut allocs = BTreeMap::<u32, u32>::new();
+        let s = START_OFFSET;
+        assert_eq!(alloc_inner(&mut allocs, 128), Some(s));
+        assert_eq!(alloc_inner(&mut allocs, 64), Some(s + footprint(128)));
+        let hole = s + footprint(128) + footprint(64);
+        assert_eq!(alloc_inner(&mut allocs, 256), Some(hole));
+        assert_eq!(alloc_inner(&mut allocs, 128), Some(hole + footprint(256)));
+        let tail = hole + footprint(256) + footprint(128);
+        assert_eq!(alloc_inner(&mut allocs, 128), Some(tail));
+        assert_eq!(alloc_inner(&mut allocs, 0xFF00), None);
+        assert_eq!(alloc_inner(&mut allocs, 0), None);
+
+        // create a hole and partially fill it
+        assert_eq!(dealloc_inner(&mut allocs, hole), true);
+        assert_eq!(dealloc_inner(&mut allocs, hole), false);
+        check_consistency(&allocs);
+        assert_eq!(alloc_inner(&mut allocs, 128), Some(hole));
+        // what's left of the hole is too small for another one of those, so it goes to the end
+        assert_eq!(alloc_inner(&mut allocs, 128), Some(tail + footprint(128)));
+        // but something smaller fills the rest of the hole
+        assert_eq!(alloc_inner(&mut allocs, 64), Some(hole + footprint(128)));
+
+        check_consistency(&allocs);
+        // every region is the request plus its descriptor header
+        assert_eq!(
+            allocs
+                .iter()
+                .map(|(&offset, &len)| (offset, len))
+                .collect::<Vec<_>>(),
+            vec![
+                (s, 128 + 16),
+                (s + footprint(128), 64 + 16),
+                (hole, 128 + 16),
+                (hole + footprint(128), 64 + 16),
+                (hole + footprint(256), 128 + 16),
+                (tail, 128 + 16),
+                (tail + footprint(128), 128 + 16),
+            ]
+        );
+        let structured: Vec<u32> = allocs.keys().copied().collect();
+
+        // random alloc/dealloc and check for overlapping regions
+        let mut tracker = Vec::<u32>::new();
+        for _ in 0..10240 {
+            if rng.next_u32() % 2 == 0 {
+                if tracker.len() > 0 {
+                    let index = tracker.remove((rng.next_u32() % tracker.len() as u32) as usize);
+                    assert_eq!(dealloc_inner(&mut allocs, index), true);
+                }
+            } else {
+                let req = rng.next_u32() % 256;
+                if let Some(offset) = alloc_inner(&mut allocs, req) {
+                    tracker.push(offset);
+                }
+            }
+        }
+
+        check_consistency(&allocs);
+        // the structured allocations are untouched, and the rest are exactly the ones still held
+        let mut expected = structured;
+        expected.extend(&tracker);
+        expected.sort_unstable();
+        assert_eq!(allocs.keys().copied().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_alloc_random_invariants() {
+        for seed in 0..16 {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            let mut allocs = BTreeMap::<u32, u32>::new();
+            let mut live = Vec::<u32>::new();
+            for _ in 0..4096 {
+                // bias towards allocating, so the space regularly runs out
+                if rng.next_u32() % 3 == 0 && live.len() > 0 {
+                    let offset = live.swap_remove((rng.next_u32() % live.len() as u32) as usize);
+                    assert!(dealloc_inner(&mut allocs, offset));
+                } else {
+                    let req = 1 + rng.next_u32() % 600;
+                    let room = has_room(&allocs, req);
+                    match alloc_inner(&mut allocs, req) {
+                        Some(offset) => {
+                            assert!(room, "allocated {} bytes when there was no room", req);
+                            live.push(offset);
+                        }
+                        None => assert!(!room, "failed to allocate {} bytes even though there was room", req),
+                    }
+                }
+                check_consistency(&allocs);
+            }
+        }
+    }
+
+    #[test]
+    fn test_alloc_alignment() {
+        // odd sizes must not knock subsequent allocations off the 16-byte alignment
+        let mut allocs = BTreeMap::<u32, u32>::new();
+        let mut expected = START_OFFSET;
+        for &req in [1u32, 5, 17, 33, 63, 8, 2].iter() {
+            let offset = alloc_inner(&mut allocs, req).unwrap();
+            assert_eq!(offset & 0xF, 0, "allocation of {} bytes is misaligned", req);
+            assert_eq!(offset, expected);
+            expected += footprint(req);
+        }
+        // a hole left by an odd-sized region is reused at its aligned start
+        let third = START_OFFSET + footprint(1) + footprint(5);
+        assert!(dealloc_inner(&mut allocs, third));
+        assert_eq!(alloc_inner(&mut allocs, 3), Some(third));
+        check_consistency(&allocs);
+    }
+
+    #[test]
+    fn test_alloc_ep_errors() {
+        let mut allocs = BTreeMap::<u32, u32>::new();
+        let mut slots = [None; 4];
+        slots[0] = Some(());
+        slots[2] = Some(());
+
+        // the first free slot after ep0, or the one asked for
+        assert_eq!(alloc_ep_slot(&slots, None, &mut allocs, 64, false), Ok((1, START_OFFSET, None)));
+        assert!(matches!(alloc_ep_slot(&slots, Some(3), &mut allocs, 64, false), Ok((3, _, None))));
+
+        // a taken or nonexistent endpoint can't be asked for, whether or not there's memory
+        assert_eq!(alloc_ep_slot(&slots, Some(2), &mut allocs, 64, false), Err(UsbError::InvalidEndpoint));
+        assert_eq!(alloc_ep_slot(&slots, Some(4), &mut allocs, 64, false), Err(UsbError::InvalidEndpoint));
+        assert_eq!(alloc_ep_slot(&slots, Some(2), &mut allocs, 0xFF00, false), Err(UsbError::InvalidEndpoint));
+
+        // a free endpoint without the memory for its packets
+        assert_eq!(alloc_ep_slot(&slots, Some(1), &mut allocs, 0xFF00, false), Err(UsbError::EndpointMemoryOverflow));
+        assert_eq!(alloc_ep_slot(&slots, None, &mut allocs, 0xFF00, false), Err(UsbError::EndpointMemoryOverflow));
+
+        // no endpoints left at all
+        let slots = [Some(()); 4];
+        assert_eq!(alloc_ep_slot(&slots, None, &mut allocs, 64, false), Err(UsbError::EndpointOverflow));
+        assert_eq!(alloc_ep_slot(&slots, None, &mut allocs, 0xFF00, false), Err(UsbError::EndpointOverflow));
+    }
+
+    #[test]
+    fn test_check_max_packet_size() {
+        // the largest packet that fits is the largest the allocator can place in an empty RAM
+        let largest = END_OFFSET - START_OFFSET - 16;
+        assert_eq!(check_max_packet_size(END_OFFSET, largest), Ok(()));
+        assert!(alloc_inner(&mut BTreeMap::new(), largest).is_some());
+        assert_eq!(check_max_packet_size(END_OFFSET, largest + 1), Err(UsbError::EndpointMemoryOverflow));
+        assert!(alloc_inner(&mut BTreeMap::new(), largest + 1).is_none());
+
+        // the widest the descriptor's length field can express, against a smaller RAM
+        assert_eq!(check_max_packet_size(END_OFFSET, 1023), Ok(()));
+        assert_eq!(check_max_packet_size(1 << 10, 1023), Err(UsbError::EndpointMemoryOverflow));
+        // and a RAM too small to hold even the control area
+        assert_eq!(check_max_packet_size(1 << 4, 8), Err(UsbError::EndpointMemoryOverflow));
+    }
+
+    #[test]
+    fn test_free_ep_slot() {
+        let mut slots = [None::<(usize, usize)>; 4];
+        let mut allocs = BTreeMap::<u32, u32>::new();
+        let (first, offset, _) = alloc_ep_slot(&slots, None, &mut allocs, 64, false).unwrap();
+        slots[first] = Some((offset as usize / 16, 64));
+        let before = allocs.clone();
+        let (index, offset, alt) = alloc_ep_slot(&slots, None, &mut allocs, 64, true).unwrap();
+        slots[index] = Some((offset as usize / 16, 64));
+        assert_eq!(allocs.len(), before.len() + 2);
+
+        // disabling gives back both buffers, and leaves the other endpoint be
+        assert!(free_ep_slot(&mut slots, index, alt.map(|a| a as usize / 16), &mut allocs));
+        assert_eq!(allocs, before);
+        assert_eq!(slots[index], None);
+        assert!(slots[first].is_some());
+        check_consistency(&allocs);
+
+        // again, or on an endpoint that was never allocated, does nothing
+        assert!(!free_ep_slot(&mut slots, index, alt.map(|a| a as usize / 16), &mut allocs));
+        assert!(!free_ep_slot(&mut slots, 3, None, &mut allocs));
+        assert!(!free_ep_slot(&mut slots, 7, None, &mut allocs));
+        assert_eq!(allocs, before);
+
+        // the endpoint has to be allocated afresh, and can be, into the memory it gave back
+        assert_eq!(alloc_ep_slot(&slots, Some(index), &mut allocs, 64, true), Ok((index, offset, alt)));
+    }
+
+    #[test]
+    fn test_alloc_ep_rollback() {
+        let slots = [None::<()>; 4];
+        let mut allocs = BTreeMap::<u32, u32>::new();
+        // leave room for one more 512-byte buffer, but not two
+        while has_room(&allocs, 2 * 512 + 16) {
+            alloc_inner(&mut allocs, 64).unwrap();
+        }
+        assert!(has_room(&allocs, 512));
+        let before = allocs.clone();
+
+        // every failure leaves the allocator as it found it
+        assert_eq!(alloc_ep_slot(&slots, Some(4), &mut allocs, 512, true), Err(UsbError::InvalidEndpoint));
+        assert_eq!(allocs, before);
+        assert_eq!(alloc_ep_slot(&slots, None, &mut allocs, 0xFF00, false), Err(UsbError::EndpointMemoryOverflow));
+        assert_eq!(allocs, before);
+        // including when the first buffer fits and the second doesn't
+        assert_eq!(alloc_ep_slot(&slots, None, &mut allocs, 512, true), Err(UsbError::EndpointMemoryOverflow));
+        assert_eq!(allocs, before);
+
+        // and what it found is still there to be had
+        let (_, offset, alt) = alloc_ep_slot(&slots, None, &mut allocs, 512, false).unwrap();
+        assert_eq!(alt, None);
+        assert_eq!(allocs.len(), before.len() + 1);
+        assert!(allocs.contains_key(&offset));
+        check_consistency(&allocs);
+    }
+}
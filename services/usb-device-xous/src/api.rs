@@ -1,4 +1,13 @@
 pub(crate) const SERVER_NAME_USB_DEVICE: &'static str = "_Xous USB device driver_";
+/// Number of keyboard reports the server holds for the host before senders see backpressure.
+/// Each keystroke takes two: the key press, and the key-up after it.
+pub const HID_REPORT_QUEUE_LEN: usize = 64;
+
+/// The `ListCores` answer is a bitmask with a bit for each core that can be switched to,
+/// numbered by its `UsbDeviceType`. The debug and HID cores are always there.
+pub(crate) const CORES_ALWAYS: usize = (1 << 0) | (1 << 1);
+/// The device core in DFU mode, once a server has hooked the firmware downloads
+pub(crate) const CORE_DFU: usize = 1 << 2;
 
 #[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
 pub(crate) enum Opcode {
@@ -25,21 +34,328 @@ pub(crate) enum Opcode {
     U2fTx,
     /// Blocks the caller, waiting for a U2F message
     U2fRxDeferred,
+    /// Send a report on the vendor-defined raw HID interface
+    RawHidTx,
+    /// Blocks the caller, waiting for a report on the raw HID interface
+    RawHidRxDeferred,
+
+    /// Read out the USB event statistics
+    GetStats,
+    /// Clear the USB event statistics
+    ResetStats,
+    /// Number of keyboard reports waiting to be sent to the host
+    KeyQueueDepth,
+    /// Characters sent and total characters for the current or last `send_str()`
+    TypingProgress,
+    /// Abandon the rest of the current `send_str()`
+    CancelTyping,
+    /// Register a server to be told when the host suspends or resumes the bus
+    HookPowerEvents,
+    /// Register a server to be told when the host changes the keyboard LEDs
+    HookLedChanges,
+    /// Re-enumerate with the identity of a `KeyboardProfile`
+    SetProfile,
+    /// "Type" a run of bytes through the byte table
+    SendBytes,
+    /// Select the `ByteMap` used by `send_bytes()`
+    SetByteMap,
+    /// Route digits and operators in `send_str()` through the numeric keypad
+    SetNumericMode,
+    /// Start recording the reports queued by `send_keycode()` and `send_str()` into a macro
+    StartRecording,
+    /// Finish the recording, and keep the macro
+    StopRecording,
+    /// Fetch the frames of a macro, for `play_macro()`
+    GetMacro,
+    /// Read the USB frame counter
+    GetFrameNumber,
+    /// Read back a descriptor as it was sent to the host
+    GetDescriptorBytes,
+    /// Report the device's battery level to the host
+    ReportBattery,
+    /// Choose the interface that Windows binds WinUSB to, if any
+    SetWinUsbInterface,
+    /// Give the device's strings in another language
+    AddStringLanguage,
+    /// Say whether the device has its own power, in the configuration descriptor
+    SetSelfPowered,
+    /// Register a server to take the firmware downloaded over DFU
+    HookDfuBlocks,
+    /// Unlock debug access until a deadline
+    UnlockDebugFor,
+    /// Read back the recent changes to debug access
+    GetDebugAuditLog,
+    /// Which device cores can be switched to
+    ListCores,
+    /// Register the server to be sent the output reports the host writes to an interface
+    HookOutputReports,
+    /// Read out a decoded copy of the UDC registers
+    GetUdcRegs,
+    /// Take an endpoint out of service, or put it back
+    SetEndpointEnabled,
+    /// Hold the host off on an OUT endpoint, or stop holding it off
+    SetEndpointNack,
+    /// Register a server to take the answers to `AsyncUsbHid`'s requests
+    HookAsyncReplies,
+    /// Stop sending answers to a server registered with `HookAsyncReplies`
+    UnhookAsyncReplies,
+    /// Answer with the next U2F packet from the host, through the async reply server
+    U2fRxAsync,
+    /// Answer with the link status, through the async reply server
+    LinkStatusAsync,
+    /// Answer once the keyboard report queue has room, through the async reply server
+    KeyQueueRoomAsync,
 
     /// Handle the USB interrupt
     UsbIrqHandler,
     /// Suspend/resume callback
     SuspendResume,
+    /// Lock debug access again if `unlock_debug_for()`'s time is up
+    RelockTimeout,
+    /// Send the cached reports again if the host's idle rate says they're due
+    IdleResend,
+    /// The DFU block listener has answered a block
+    DfuBlockAnswered,
     /// Exits the server
     Quit,
 }
 
+/// Identities the device can present to the host, for host software that only enables
+/// features for keyboards it recognizes
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum KeyboardProfile {
+    /// The device's own identity
+    Generic,
+    AppleKeyboard,
+    MicrosoftKeyboard,
+}
+
+/// How `send_bytes()` turns each byte into keystrokes
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ByteMap {
+    /// Printable ASCII in the user's keyboard layout, and control codes as a terminal reads
+    /// them (Ctrl-A..Ctrl-Z, Escape, Backspace). The default.
+    Terminal,
+    /// Each byte is the HID usage ID of the key to press
+    UsageId,
+}
+
+/// Descriptors `get_descriptor()` can read back, numbered as in a `GET_DESCRIPTOR` request
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum DescriptorType {
+    Device = 1,
+    Configuration = 2,
+    String = 3,
+}
+
+/// The id of the scalar message sent to the servers registered with `hook_power_events()`
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, PartialEq, Eq, Copy, Clone)]
+pub enum PowerEvent {
+    /// The host suspended the bus; nothing sent until it resumes will arrive
+    Suspend,
+    /// The host resumed the bus after a suspend
+    Resume,
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub(crate) struct LedHook {
+    pub sid: (u32, u32, u32, u32),
+    pub id: u32, // ID of the scalar message to send through (e.g. the discriminant of the Enum on the caller's side API)
+    /// Filled in by the server: `true` once it has connected back to `sid`
+    pub registered: bool,
+}
+
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
 pub struct UsbString {
     pub s: xous_ipc::String::<4000>,
     pub sent: Option<u32>,
+    /// Set on the first chunk of a send to the length of the whole string, in characters, so the
+    /// server can report progress across chunks. `None` on the chunks that follow.
+    pub total: Option<u32>,
+    /// Set by the server when the send this chunk continues was cancelled with `cancel_typing()`
+    pub cancelled: bool,
+}
+
+/// The most bytes `send_bytes()` hands to the server at a time
+pub const USB_BYTES_LEN: usize = 3968;
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub struct UsbBytes {
+    pub data: [u8; USB_BYTES_LEN],
+    pub len: u32,
+    /// Set by the server to the number of bytes consumed, whether typed or skipped. `None` if
+    /// there is no host to type at.
+    pub sent: Option<u32>,
+    /// Set by the server to the number of bytes consumed that had no mapping
+    pub skipped: u32,
+}
+
+/// Most macros the server keeps at a time
+pub const MAX_MACROS: usize = 8;
+/// Most reports in one macro. A keystroke takes two: the key press, and the key-up after it.
+pub const MAX_MACRO_FRAMES: usize = 256;
+/// Most keys held down in one report of a macro, as for `send_keycode()`
+pub const MACRO_REPORT_KEYS: usize = 3;
+
+/// One keyboard report of a macro, and the delay before it's sent
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone, Default, Eq, PartialEq)]
+pub struct MacroFrame {
+    /// Usage IDs of the keys held down; unused entries are 0. All zero is a key-up.
+    pub keys: [u8; MACRO_REPORT_KEYS],
+    pub delay_ms: u32,
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub(crate) struct UsbMacro {
+    pub name: xous_ipc::String::<64>,
+    /// Filled in by the server with the macro's frames. `None` if there's no such macro.
+    pub len: Option<u32>,
+    pub frames: [MacroFrame; MAX_MACRO_FRAMES],
+}
+
+/// Longest descriptor `get_descriptor()` returns; anything past this is cut off
+pub const MAX_DESCRIPTOR_LEN: usize = 512;
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub(crate) struct UsbDescriptor {
+    /// A `DescriptorType`, as a number so the server can turn away ones it doesn't know
+    pub kind: u8,
+    pub index: u8,
+    /// Set by the server if it doesn't know `kind`
+    pub unknown_kind: bool,
+    /// Filled in by the server with the descriptor. `None` if the host hasn't asked for it.
+    pub len: Option<u32>,
+    pub data: [u8; MAX_DESCRIPTOR_LEN],
+}
+
+/// Most languages the device's strings can be given in, counting the profile's own
+pub const MAX_STRING_LANGUAGES: usize = 16;
+
+/// The device's manufacturer and product names in one language, for `add_string_language()`
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub struct DeviceStrings {
+    pub manufacturer: xous_ipc::String::<64>,
+    pub product: xous_ipc::String::<64>,
+}
+
+impl DeviceStrings {
+    pub fn new(manufacturer: &str, product: &str) -> DeviceStrings {
+        DeviceStrings {
+            manufacturer: xous_ipc::String::<64>::from_str(manufacturer),
+            product: xous_ipc::String::<64>::from_str(product),
+        }
+    }
 }
 
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub(crate) struct StringLanguage {
+    pub langid: u16,
+    pub strings: DeviceStrings,
+    /// Set by the server if there was no room for another language
+    pub full: bool,
+}
+
+/// Most firmware bytes the host sends in one DFU block
+pub const DFU_TRANSFER_SIZE: usize = 64;
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub(crate) struct DfuHook {
+    pub sid: (u32, u32, u32, u32),
+    pub id: u32,
+    /// Filled in by the server: 0 once it has registered `sid`, 1 if it couldn't connect back
+    /// to it, 2 if it turned the registration down, 3 if it has no DFU interface
+    pub result: u32,
+}
+
+/// A block of firmware downloaded over DFU, as lent to the server registered with
+/// `hook_dfu_blocks()`
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub struct DfuBlock {
+    /// Where the block goes in the image. For the manifest, the length of the whole image.
+    pub offset: u32,
+    pub len: u32,
+    pub data: [u8; DFU_TRANSFER_SIZE],
+    /// Set on the empty block that ends the download, once the host has sent the whole image
+    pub manifest: bool,
+    /// Set by the listener once it has written the block, or checked the whole image. Left
+    /// clear, the host is told the write failed.
+    pub accepted: bool,
+}
+
+/// The id of the memory message each `AsyncReply` is sent in
+pub const ASYNC_REPLY_ID: u32 = 0;
+
+/// The server's answer to one of `AsyncUsbHid`'s requests, as sent to the reply server the
+/// client registered
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub struct AsyncReply {
+    /// The token the request was made with
+    pub token: u32,
+    /// For `U2fRxAsync`, 0 with the packet in `data`, or 1 if turned away, or if another
+    /// request took its place. For
+    /// `LinkStatusAsync`, the `UsbDeviceState`. For `KeyQueueRoomAsync`, 0 once there's room,
+    /// or 1 if the server is quitting.
+    pub code: u32,
+    pub data: [u8; 64],
+}
+
+/// Longest output report passed on to the server registered with `hook_output_reports()`;
+/// anything past this is cut off
+pub const MAX_OUTPUT_REPORT_LEN: usize = 64;
+/// The id of the memory message each `OutputReport` is sent in
+pub const OUTPUT_REPORT_ID: u32 = 0;
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub(crate) struct OutputReportHook {
+    pub sid: (u32, u32, u32, u32),
+    /// The HID interface whose output reports go to `sid`
+    pub interface: u8,
+    /// Filled in by the server: 0 once it has registered `sid`, 1 if it couldn't connect back
+    /// to it, 2 if another server already takes the interface's reports, 3 if the interface
+    /// isn't one whose reports are passed on
+    pub result: u32,
+}
+
+/// An output report written by the host, as sent to the server registered with
+/// `hook_output_reports()`
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone, Eq, PartialEq)]
+pub struct OutputReport {
+    /// The HID interface the report was written to
+    pub interface: u8,
+    pub len: u32,
+    pub data: [u8; MAX_OUTPUT_REPORT_LEN],
+}
+
+impl OutputReport {
+    /// The report as the host wrote it
+    pub fn report(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+/// Most changes to debug access `get_debug_audit_log()` keeps; older ones are dropped
+pub const MAX_DEBUG_AUDIT_ENTRIES: usize = 16;
+
+/// A change to debug access
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone, Default, Eq, PartialEq)]
+pub struct DebugAuditEntry {
+    /// When it changed, as the ticktimer's `elapsed_ms()`
+    pub timestamp_ms: u64,
+    pub was_locked: bool,
+    pub locked: bool,
+    /// The process that asked for the change, or 0 if the server made it itself, as when an
+    /// `unlock_debug_for()` runs out
+    pub pid: u8,
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone, Default)]
+pub(crate) struct DebugAuditLogIpc {
+    /// Filled in by the server, oldest first
+    pub entries: [DebugAuditEntry; MAX_DEBUG_AUDIT_ENTRIES],
+    pub len: u32,
+}
+
+/// Also carries raw HID reports, which follow the same send/deferred-receive protocol as U2F.
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
 pub struct U2fMsgIpc {
     /// All U2F protocol messages are 64 bytes
@@ -48,6 +364,46 @@ pub struct U2fMsgIpc {
     pub code: U2fCode,
 }
 
+/// Counters of USB bus events, for diagnosing enumeration problems. All counts are since
+/// boot or since the last call to `reset_stats()`, and wrap on overflow.
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone, Default, Eq, PartialEq)]
+pub struct UsbStats {
+    pub resets: u32,
+    pub suspends: u32,
+    pub resumes: u32,
+    pub disconnects: u32,
+    pub setup_packets: u32,
+    /// Number of times each endpoint was stalled, indexed by endpoint number
+    pub stalls: [u32; 16],
+}
+
+/// A decoded copy of the UDC register bank, taken in one pass so it can be logged or handed
+/// to diagnostic tooling without reference to the bitfield layouts.
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone, Default, Eq, PartialEq)]
+pub struct SpinalUdcRegsSnapshot {
+    pub frame: u32,
+    /// device address, bits 6:0 of the address register
+    pub address: u8,
+    pub address_enable: bool,
+    pub address_trigger: bool,
+    /// bitmask of endpoints with a pending completion interrupt
+    pub int_endpoints: u16,
+    pub int_reset: bool,
+    pub int_ep0_setup: bool,
+    pub int_suspend: bool,
+    pub int_resume: bool,
+    pub int_disconnect: bool,
+    pub halt_endpoint: u8,
+    pub halt_enable_req: bool,
+    pub halt_enable_ack: bool,
+    pub pullup_on: bool,
+    pub pullup_off: bool,
+    pub enable_ints: bool,
+    pub disable_ints: bool,
+    /// size of the descriptor RAM in bytes
+    pub ramsize: u32,
+}
+
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone, Eq, PartialEq)]
 pub enum U2fCode {
     Tx,
@@ -0,0 +1,526 @@
+use crate::api::{AsyncReply, Opcode, ASYNC_REPLY_ID};
+use crate::{FidoMsg, UsbDeviceState, UsbError, UsbHid};
+use num_traits::*;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use xous::{send_message, Message};
+use xous_ipc::Buffer;
+
+/// Sent to the reply server by its own `AsyncUsbHid` once the last clone is dropped
+const REPLY_SERVER_QUIT: usize = 1;
+
+/// An answer on its way to a future, and the waker of the task polling it
+#[derive(Default)]
+struct Slot {
+    reply: Option<AsyncReply>,
+    waker: Option<Waker>,
+}
+
+/// The futures waiting on an answer from the USB device server, by the token their request was
+/// made with. The reply server's thread hands each answer in as it arrives.
+#[derive(Default)]
+pub(crate) struct Replies {
+    next_token: AtomicU32,
+    slots: Mutex<HashMap<u32, Slot>>,
+}
+
+impl Replies {
+    /// A token for a new request, with its slot waiting on the answer
+    fn expect(&self, waker: &Waker) -> u32 {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        self.slots.lock().unwrap().insert(
+            token,
+            Slot {
+                reply: None,
+                waker: Some(waker.clone()),
+            },
+        );
+        token
+    }
+    /// The answer to `token`, if it has come; otherwise `waker` is woken when it does
+    fn take(&self, token: u32, waker: &Waker) -> Option<AsyncReply> {
+        let mut slots = self.slots.lock().unwrap();
+        let slot = slots.get_mut(&token)?;
+        match slot.reply.take() {
+            Some(reply) => {
+                slots.remove(&token);
+                Some(reply)
+            }
+            None => {
+                // the task may have moved to another waker since it was last polled
+                slot.waker = Some(waker.clone());
+                None
+            }
+        }
+    }
+    /// Drops the slot of a request nobody is waiting on any more
+    fn forget(&self, token: u32) {
+        self.slots.lock().unwrap().remove(&token);
+    }
+    /// Hands an answer from the server to the future waiting on it, and wakes its task.
+    /// Answers to futures that have since been dropped are thrown away.
+    pub(crate) fn complete(&self, reply: AsyncReply) {
+        let waker = match self.slots.lock().unwrap().get_mut(&reply.token) {
+            Some(slot) => {
+                slot.reply = Some(reply);
+                slot.waker.take()
+            }
+            None => None,
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// How `AsyncUsbHid` reaches the USB device server
+pub(crate) trait Requests: Send + Sync + 'static {
+    /// Asks the server to answer `token` through the reply server, sending `opcode`
+    fn request(&self, opcode: Opcode, token: u32) -> Result<(), UsbError>;
+    /// As `UsbHid::send_str_chunk()`, which the server answers straight away
+    fn send_str_chunk(&self, s: &str, total_chars: Option<u32>) -> Result<(usize, bool), UsbError>;
+}
+
+/// The reply server of one `AsyncUsbHid` and its clones, and its handle with the USB device
+/// server. One thread serves it, however many requests are outstanding.
+struct ReplyServer {
+    hid: Arc<UsbHid>,
+    handle: u32,
+    /// a connection to the reply server itself, to tell its thread to quit
+    cid: xous::CID,
+}
+
+impl Requests for ReplyServer {
+    fn request(&self, opcode: Opcode, token: u32) -> Result<(), UsbError> {
+        send_message(
+            self.hid.conn,
+            Message::new_scalar(
+                opcode.to_usize().unwrap(),
+                self.handle as usize,
+                token as usize,
+                0,
+                0,
+            ),
+        )
+        .map(|_| ())
+        .map_err(UsbError::from)
+    }
+    fn send_str_chunk(&self, s: &str, total_chars: Option<u32>) -> Result<(usize, bool), UsbError> {
+        self.hid.send_str_chunk(s, total_chars)
+    }
+}
+
+impl Drop for ReplyServer {
+    fn drop(&mut self) {
+        // no more answers once the server has let go of the handle, so the thread can go
+        send_message(
+            self.hid.conn,
+            Message::new_blocking_scalar(
+                Opcode::UnhookAsyncReplies.to_usize().unwrap(),
+                self.handle as usize,
+                0,
+                0,
+                0,
+            ),
+        )
+        .ok();
+        send_message(self.cid, Message::new_scalar(REPLY_SERVER_QUIT, 0, 0, 0, 0)).ok();
+        unsafe { xous::disconnect(self.cid).ok() };
+    }
+}
+
+/// Starts the reply server for `hid`, and registers it with the USB device server
+fn start_reply_server(hid: Arc<UsbHid>, replies: Arc<Replies>) -> Result<ReplyServer, UsbError> {
+    let sid = xous::create_server()?;
+    let cid = xous::connect(sid)?;
+    std::thread::spawn(move || {
+        loop {
+            let msg = xous::receive_message(sid).unwrap();
+            match msg.body.id() {
+                REPLY_SERVER_QUIT => break,
+                id if id == ASYNC_REPLY_ID as usize => {
+                    let buffer =
+                        unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                    match buffer.to_original::<AsyncReply, _>() {
+                        Ok(reply) => replies.complete(reply),
+                        Err(_) => log::error!("couldn't deserialize async reply"),
+                    }
+                }
+                _ => log::warn!("unexpected message to the async reply server: {:?}", msg),
+            }
+        }
+        xous::destroy_server(sid).ok();
+    });
+    let (s0, s1, s2, s3) = sid.to_u32();
+    let hooked = match send_message(
+        hid.conn,
+        Message::new_blocking_scalar(
+            Opcode::HookAsyncReplies.to_usize().unwrap(),
+            s0 as usize,
+            s1 as usize,
+            s2 as usize,
+            s3 as usize,
+        ),
+    ) {
+        Ok(xous::Result::Scalar2(0, handle)) => Ok(handle as u32),
+        // the server couldn't connect back to the reply server
+        Ok(xous::Result::Scalar2(_, _)) => Err(UsbError::NoListener),
+        Ok(_) => Err(UsbError::ProtocolMismatch),
+        Err(e) => Err(UsbError::from(e)),
+    };
+    match hooked {
+        Ok(handle) => Ok(ReplyServer { hid, handle, cid }),
+        Err(e) => {
+            send_message(cid, Message::new_scalar(REPLY_SERVER_QUIT, 0, 0, 0, 0)).ok();
+            unsafe { xous::disconnect(cid).ok() };
+            Err(e)
+        }
+    }
+}
+
+/// A request to the USB device server as a future. The request is sent on first poll; the
+/// server defers its answer, e.g. holding a U2F receive until the host sends a packet, and then
+/// sends it to the reply server, whose thread wakes the task. The executor never blocks.
+pub struct UsbFuture<T> {
+    requests: Arc<dyn Requests>,
+    replies: Arc<Replies>,
+    opcode: Option<Opcode>,
+    token: Option<u32>,
+    answer: fn(Result<AsyncReply, UsbError>) -> T,
+}
+
+impl<T> UsbFuture<T> {
+    fn new(
+        requests: Arc<dyn Requests>,
+        replies: Arc<Replies>,
+        opcode: Opcode,
+        answer: fn(Result<AsyncReply, UsbError>) -> T,
+    ) -> UsbFuture<T> {
+        UsbFuture {
+            requests,
+            replies,
+            opcode: Some(opcode),
+            token: None,
+            answer,
+        }
+    }
+}
+
+impl<T> Future for UsbFuture<T> {
+    type Output = T;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        if let Some(opcode) = self.opcode.take() {
+            // the slot goes in first, as the answer can come before `request()` returns
+            let token = self.replies.expect(cx.waker());
+            self.token = Some(token);
+            if let Err(e) = self.requests.request(opcode, token) {
+                self.replies.forget(token);
+                self.token = None;
+                return Poll::Ready((self.answer)(Err(e)));
+            }
+        }
+        match self.token {
+            Some(token) => match self.replies.take(token, cx.waker()) {
+                Some(reply) => {
+                    self.token = None;
+                    Poll::Ready((self.answer)(Ok(reply)))
+                }
+                None => Poll::Pending,
+            },
+            None => panic!("UsbFuture polled after it completed"),
+        }
+    }
+}
+
+impl<T> Drop for UsbFuture<T> {
+    fn drop(&mut self) {
+        if let Some(token) = self.token {
+            self.replies.forget(token);
+        }
+    }
+}
+
+/// `AsyncUsbHid::send_str()`: queues as much of the string as fits, and waits on the server to
+/// say there's room in the keyboard report queue before queueing more
+pub struct SendStr {
+    requests: Arc<dyn Requests>,
+    replies: Arc<Replies>,
+    remaining: String,
+    total: usize,
+    total_chars: Option<u32>,
+    room: Option<UsbFuture<Result<(), UsbError>>>,
+}
+
+impl Future for SendStr {
+    type Output = Result<usize, UsbError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<usize, UsbError>> {
+        let this = &mut *self;
+        loop {
+            if let Some(room) = this.room.as_mut() {
+                match Pin::new(room).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Ready(Ok(())) => this.room = None,
+                }
+            }
+            if this.remaining.is_empty() {
+                return Poll::Ready(Ok(this.total));
+            }
+            // only the first chunk starts the progress count, even if none of it fit
+            match this
+                .requests
+                .send_str_chunk(&this.remaining, this.total_chars.take())
+            {
+                // `cancel_typing()` was called from elsewhere; report how much made it out
+                Ok((typed, true)) => return Poll::Ready(Ok(typed)),
+                Ok((sent, false)) => {
+                    this.total += sent;
+                    this.remaining = match this.remaining.char_indices().nth(sent) {
+                        Some((index, _)) => this.remaining.split_off(index),
+                        None => String::new(),
+                    };
+                }
+                Err(UsbError::QueueFull) => {
+                    this.room = Some(UsbFuture::new(
+                        this.requests.clone(),
+                        this.replies.clone(),
+                        Opcode::KeyQueueRoomAsync,
+                        |reply| match reply?.code {
+                            0 => Ok(()),
+                            // the server is quitting
+                            _ => Err(UsbError::NotConnected),
+                        },
+                    ));
+                }
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+/// `UsbHid` for async executors: `u2f_wait_incoming()`, `send_str()` and `status()` return
+/// futures instead of blocking. The server sends its answers to a reply server of this
+/// `AsyncUsbHid`'s own, served by one thread; clones share the connection and the reply server.
+#[derive(Clone)]
+pub struct AsyncUsbHid {
+    hid: Arc<UsbHid>,
+    requests: Arc<dyn Requests>,
+    replies: Arc<Replies>,
+}
+
+impl AsyncUsbHid {
+    /// Connects to the USB device server, and panics if the reply server can't be registered
+    pub fn new() -> AsyncUsbHid {
+        AsyncUsbHid::from(UsbHid::new())
+    }
+    /// Like `new()`, but returns the error rather than panicking
+    pub fn try_from(hid: UsbHid) -> Result<AsyncUsbHid, UsbError> {
+        let hid = Arc::new(hid);
+        let replies = Arc::new(Replies::default());
+        let server = start_reply_server(hid.clone(), replies.clone())?;
+        Ok(AsyncUsbHid {
+            hid,
+            requests: Arc::new(server),
+            replies,
+        })
+    }
+    /// The `UsbHid` behind the futures, for the calls that don't have async versions
+    pub fn blocking(&self) -> &UsbHid {
+        &self.hid
+    }
+    /// Resolves to the next U2F packet from the host, as `UsbHid::u2f_wait_incoming()` returns it
+    pub fn u2f_wait_incoming(&self) -> UsbFuture<Result<FidoMsg, UsbError>> {
+        UsbFuture::new(
+            self.requests.clone(),
+            self.replies.clone(),
+            Opcode::U2fRxAsync,
+            |reply| {
+                let reply = reply?;
+                // turned away: the interface is locked to another process, or the server is quitting
+                if reply.code != 0 {
+                    return Err(UsbError::AccessDenied);
+                }
+                let mut msg = FidoMsg::default();
+                msg.packet.copy_from_slice(&reply.data);
+                Ok(msg)
+            },
+        )
+    }
+    /// Resolves once the string has been typed at the host, as `UsbHid::send_str()` returns
+    pub fn send_str(&self, s: &str) -> SendStr {
+        SendStr {
+            requests: self.requests.clone(),
+            replies: self.replies.clone(),
+            remaining: s.to_string(),
+            total: 0,
+            total_chars: Some(s.chars().count() as u32),
+            room: None,
+        }
+    }
+    pub fn status(&self) -> UsbFuture<UsbDeviceState> {
+        UsbFuture::new(
+            self.requests.clone(),
+            self.replies.clone(),
+            Opcode::LinkStatusAsync,
+            |reply| match reply
+                .expect("Internal error: couldn't ask for the link status")
+                .code
+            {
+                0 => UsbDeviceState::Default,
+                1 => UsbDeviceState::Addressed,
+                2 => UsbDeviceState::Configured,
+                3 => UsbDeviceState::Suspend,
+                _ => panic!("Internal error: illegal status code"),
+            },
+        )
+    }
+}
+
+impl From<UsbHid> for AsyncUsbHid {
+    /// Panics if the reply server can't be registered; see `try_from()`
+    fn from(hid: UsbHid) -> AsyncUsbHid {
+        AsyncUsbHid::try_from(hid).expect("couldn't register the async reply server")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+    use std::task::Wake;
+    use std::thread::Thread;
+
+    /// Wakes the executor's thread, and counts how often it's been woken
+    struct ThreadWaker {
+        thread: Thread,
+        wakes: AtomicUsize,
+    }
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.wakes.fetch_add(1, Ordering::SeqCst);
+            self.thread.unpark();
+        }
+    }
+
+    /// Just enough executor to run one future to completion
+    fn block_on<F: Future + Unpin>(mut future: F, waker: &Arc<ThreadWaker>) -> F::Output {
+        let waker = Waker::from(waker.clone());
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = Pin::new(&mut future).poll(&mut cx) {
+                return output;
+            }
+            std::thread::park();
+        }
+    }
+
+    /// The USB device server, as far as the futures can tell: requests are passed on to the
+    /// test, which answers them as the server would, through `Replies::complete()`
+    struct Server {
+        requests: Mutex<mpsc::Sender<(Opcode, u32)>>,
+    }
+    impl Requests for Server {
+        fn request(&self, opcode: Opcode, token: u32) -> Result<(), UsbError> {
+            self.requests.lock().unwrap().send((opcode, token)).unwrap();
+            Ok(())
+        }
+        fn send_str_chunk(
+            &self,
+            _s: &str,
+            _total_chars: Option<u32>,
+        ) -> Result<(usize, bool), UsbError> {
+            Err(UsbError::NotConnected)
+        }
+    }
+
+    #[test]
+    fn test_u2f_wait_incoming() {
+        let (sender, requests) = mpsc::channel();
+        let replies = Arc::new(Replies::default());
+        let usb = AsyncUsbHid {
+            hid: Arc::new(UsbHid {
+                conn: 0,
+                last_leds: Default::default(),
+            }),
+            requests: Arc::new(Server {
+                requests: Mutex::new(sender),
+            }),
+            replies: replies.clone(),
+        };
+        let mut incoming = usb.u2f_wait_incoming();
+        // nothing is asked of the server until the future is polled
+        assert!(requests.try_recv().is_err());
+
+        let waker = Arc::new(ThreadWaker {
+            thread: std::thread::current(),
+            wakes: AtomicUsize::new(0),
+        });
+        let first = Waker::from(waker.clone());
+        // nothing from the host yet, so the first poll can't finish, and polling doesn't block
+        assert!(Pin::new(&mut incoming)
+            .poll(&mut Context::from_waker(&first))
+            .is_pending());
+        let (opcode, token) = requests.try_recv().unwrap();
+        assert!(matches!(opcode, Opcode::U2fRxAsync));
+        // polling again doesn't ask again
+        assert!(Pin::new(&mut incoming)
+            .poll(&mut Context::from_waker(&first))
+            .is_pending());
+        assert!(requests.try_recv().is_err());
+
+        // the server answers once the host sends a packet, from the reply server's thread
+        let mut packet = [0u8; 64];
+        packet[..4].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        packet[4] = 0x86; // U2FHID_INIT
+        let reply_thread = std::thread::spawn(move || {
+            // an answer to a request nobody is waiting on goes nowhere
+            replies.complete(AsyncReply {
+                token: token + 1,
+                code: 0,
+                data: [0; 64],
+            });
+            replies.complete(AsyncReply {
+                token,
+                code: 0,
+                data: packet,
+            });
+        });
+        let msg = block_on(incoming, &waker).unwrap();
+        reply_thread.join().unwrap();
+        assert_eq!(&msg.packet[..], &packet[..]);
+        // the reply server's thread woke the task when the packet came in
+        assert!(waker.wakes.load(Ordering::SeqCst) >= 1);
+        assert!(usb.replies.slots.lock().unwrap().is_empty());
+
+        // a process that doesn't hold the interface is turned away
+        let mut refused = usb.u2f_wait_incoming();
+        assert!(Pin::new(&mut refused)
+            .poll(&mut Context::from_waker(&first))
+            .is_pending());
+        let (_, token) = requests.try_recv().unwrap();
+        usb.replies.complete(AsyncReply {
+            token,
+            code: 1,
+            data: [0; 64],
+        });
+        assert_eq!(
+            block_on(refused, &waker).unwrap_err(),
+            UsbError::AccessDenied
+        );
+
+        // and a future dropped before its answer leaves nothing behind
+        let mut dropped = usb.u2f_wait_incoming();
+        assert!(Pin::new(&mut dropped)
+            .poll(&mut Context::from_waker(&first))
+            .is_pending());
+        drop(dropped);
+        assert!(usb.replies.slots.lock().unwrap().is_empty());
+        // the stand-in connection was never counted, so dropping it would throw the count off
+        std::mem::forget(usb);
+    }
+}
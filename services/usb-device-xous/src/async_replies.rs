@@ -0,0 +1,118 @@
+// Requests for queue room only wait on real hardware with the emulated keyboard, but the
+// bookkeeping is kept free of hardware dependencies so that it can be tested in hosted mode.
+#![cfg_attr(
+    not(all(any(target_os = "none", target_os = "xous"), feature = "emukbd")),
+    allow(dead_code)
+)]
+
+use std::num::NonZeroU8;
+
+/// Where the answers to `AsyncUsbHid`'s requests go: the reply servers clients have registered,
+/// and the requests for keyboard queue room still waiting on the host to drain it. Requests are
+/// answered with an `AsyncReply` sent to the reply server, rather than by returning a lend, so
+/// the client's task can wait on them without a thread of its own.
+///
+/// Each reply server is known by the handle `register()` gives out, and only answers the
+/// process that registered it.
+pub(crate) struct AsyncReplies<C> {
+    servers: Vec<Option<(Option<NonZeroU8>, C)>>,
+    room_waiters: Vec<(C, u32)>,
+}
+
+impl<C: Copy + PartialEq> AsyncReplies<C> {
+    pub fn new() -> AsyncReplies<C> {
+        AsyncReplies {
+            servers: Vec::new(),
+            room_waiters: Vec::new(),
+        }
+    }
+    /// Registers `cid` as a reply server of process `pid`, and returns its handle
+    pub fn register(&mut self, pid: Option<NonZeroU8>, cid: C) -> u32 {
+        match self.servers.iter().position(|slot| slot.is_none()) {
+            Some(free) => {
+                self.servers[free] = Some((pid, cid));
+                free as u32
+            }
+            None => {
+                self.servers.push(Some((pid, cid)));
+                (self.servers.len() - 1) as u32
+            }
+        }
+    }
+    /// Takes out the reply server `handle`, if `pid` registered it, and hands it back so that
+    /// its connection can be closed. Requests still waiting to be answered through it are dropped.
+    pub fn unregister(&mut self, pid: Option<NonZeroU8>, handle: u32) -> Option<C> {
+        let cid = self.server(pid, handle)?;
+        self.servers[handle as usize] = None;
+        self.room_waiters.retain(|&(waiting, _)| waiting != cid);
+        Some(cid)
+    }
+    /// The reply server `handle`, if `pid` registered it
+    pub fn server(&self, pid: Option<NonZeroU8>, handle: u32) -> Option<C> {
+        match self.servers.get(handle as usize) {
+            Some(Some((registered, cid))) if *registered == pid => Some(*cid),
+            _ => None,
+        }
+    }
+    /// Parks the request `token` until the keyboard report queue has room
+    pub fn wait_for_room(&mut self, cid: C, token: u32) {
+        self.room_waiters.push((cid, token));
+    }
+    /// Hands back the requests waiting for queue room, to be answered now that there is some
+    pub fn take_room_waiters(&mut self) -> Vec<(C, u32)> {
+        std::mem::take(&mut self.room_waiters)
+    }
+    /// The server is going away: hands back the reply servers, to be disconnected
+    pub fn release(&mut self) -> Vec<C> {
+        self.room_waiters.clear();
+        self.servers
+            .drain(..)
+            .flatten()
+            .map(|(_, cid)| cid)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_async_replies() {
+        let pid = |p: u8| NonZeroU8::new(p);
+        let mut replies = AsyncReplies::<u32>::new();
+        assert_eq!(replies.server(pid(2), 0), None);
+
+        // a process can have more than one reply server, and only it can use them
+        let first = replies.register(pid(2), 10);
+        let second = replies.register(pid(2), 11);
+        let other = replies.register(pid(3), 12);
+        assert_eq!(replies.server(pid(2), first), Some(10));
+        assert_eq!(replies.server(pid(2), second), Some(11));
+        assert_eq!(replies.server(pid(3), other), Some(12));
+        assert_eq!(replies.server(pid(3), first), None);
+        assert_eq!(replies.server(pid(2), 99), None);
+
+        // requests for room are answered once, in order
+        replies.wait_for_room(10, 1);
+        replies.wait_for_room(12, 7);
+        replies.wait_for_room(10, 2);
+        assert_eq!(replies.take_room_waiters(), vec![(10, 1), (12, 7), (10, 2)]);
+        assert!(replies.take_room_waiters().is_empty());
+
+        // taking a server out drops what was waiting to go to it, and frees its handle
+        replies.wait_for_room(10, 3);
+        replies.wait_for_room(12, 8);
+        assert_eq!(replies.unregister(pid(3), first), None);
+        assert_eq!(replies.unregister(pid(2), first), Some(10));
+        assert_eq!(replies.server(pid(2), first), None);
+        assert_eq!(replies.unregister(pid(2), first), None);
+        assert_eq!(replies.take_room_waiters(), vec![(12, 8)]);
+        assert_eq!(replies.register(pid(4), 13), first);
+
+        replies.wait_for_room(11, 4);
+        assert_eq!(replies.release(), vec![13, 11, 12]);
+        assert!(replies.take_room_waiters().is_empty());
+        assert_eq!(replies.server(pid(3), other), None);
+    }
+}
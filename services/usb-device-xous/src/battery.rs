@@ -0,0 +1,130 @@
+// The interface only exists on real hardware built with the "battery" feature, but the report is
+// kept free of hardware dependencies so that it can be tested in hosted mode.
+#![cfg_attr(
+    not(all(any(target_os = "none", target_os = "xous"), feature = "battery")),
+    allow(dead_code)
+)]
+
+use usb_device::bus::{InterfaceNumber, StringIndex, UsbBus};
+use usb_device::class_prelude::DescriptorWriter;
+use usbd_human_interface_device::hid_class::prelude::*;
+use usbd_human_interface_device::interface::raw::{RawInterface, RawInterfaceBuilder, RawInterfaceConfig};
+use usbd_human_interface_device::interface::{InterfaceClass, WrappedInterface, WrappedInterfaceConfig};
+use usbd_human_interface_device::UsbHidError;
+use embedded_time::duration::Milliseconds;
+
+/// Length of the battery report: the charge, in percent
+pub const BATTERY_REPORT_LEN: usize = 1;
+
+/// The charge of the device's own battery, in percent, as both the Battery Strength of the
+/// Generic Device Controls page and the Absolute State Of Charge of the Battery System page, so
+/// that hosts that look for either will show it.
+pub const BATTERY_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x06,       // Usage Page (Generic Device Controls)
+    0x09, 0x20,       // Usage (Battery Strength)
+    0xA1, 0x01,       // Collection (Application)
+    0x05, 0x85,       //   Usage Page (Battery System)
+    0x09, 0x65,       //   Usage (Absolute State Of Charge)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x64,       //   Logical Maximum (100)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0xC0,             // End Collection
+];
+
+/// The report for a charge of `percent`, which is clamped to 100
+pub fn battery_report(percent: u8) -> [u8; BATTERY_REPORT_LEN] {
+    [percent.min(100)]
+}
+
+/// Reports the device's battery level to the host. Input only, and separate from the keyboard,
+/// so it's there whichever of the other interfaces are.
+pub struct BatteryInterface<'a, B: UsbBus> {
+    inner: RawInterface<'a, B>,
+}
+
+impl<'a, B: UsbBus> BatteryInterface<'a, B> {
+    pub fn write_report(&self, report: &[u8; BATTERY_REPORT_LEN]) -> Result<(), UsbHidError> {
+        self.inner.write_report(report).map(|_| ()).map_err(UsbHidError::from)
+    }
+    pub fn default_config() -> WrappedInterfaceConfig<Self, RawInterfaceConfig<'a>> {
+        WrappedInterfaceConfig::new(
+            RawInterfaceBuilder::new(BATTERY_REPORT_DESCRIPTOR)
+                .description("Precursor battery")
+                // the charge changes slowly; the host needn't ask often
+                .in_endpoint(UsbPacketSize::Bytes8, Milliseconds(255))
+                .unwrap()
+                .without_out_endpoint()
+                .build(),
+            (),
+        )
+    }
+}
+
+impl<'a, B: UsbBus> InterfaceClass<'a> for BatteryInterface<'a, B> {
+    fn report_descriptor(&self) -> &'_ [u8] {
+        self.inner.report_descriptor()
+    }
+    fn id(&self) -> InterfaceNumber {
+        self.inner.id()
+    }
+    fn write_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        self.inner.write_descriptors(writer)
+    }
+    fn get_string(&self, index: StringIndex, lang_id: u16) -> Option<&'_ str> {
+        self.inner.get_string(index, lang_id)
+    }
+    fn reset(&mut self) {
+        self.inner.reset()
+    }
+    fn set_report(&mut self, data: &[u8]) -> usb_device::Result<()> {
+        self.inner.set_report(data)
+    }
+    fn get_report(&mut self, data: &mut [u8]) -> usb_device::Result<usize> {
+        self.inner.get_report(data)
+    }
+    fn get_report_ack(&mut self) -> usb_device::Result<()> {
+        self.inner.get_report_ack()
+    }
+    fn set_idle(&mut self, report_id: u8, value: u8) {
+        self.inner.set_idle(report_id, value)
+    }
+    fn get_idle(&self, report_id: u8) -> u8 {
+        self.inner.get_idle(report_id)
+    }
+    fn set_protocol(&mut self, protocol: HidProtocol) {
+        self.inner.set_protocol(protocol)
+    }
+    fn get_protocol(&self) -> HidProtocol {
+        self.inner.get_protocol()
+    }
+}
+
+impl<'a, B: UsbBus> WrappedInterface<'a, B, RawInterface<'a, B>> for BatteryInterface<'a, B> {
+    fn new(interface: RawInterface<'a, B>, _: ()) -> Self {
+        Self { inner: interface }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_battery_report() {
+        assert_eq!(battery_report(0), [0]);
+        assert_eq!(battery_report(57), [57]);
+        assert_eq!(battery_report(100), [100]);
+        // nothing reads more than full
+        assert_eq!(battery_report(101), [100]);
+        assert_eq!(battery_report(255), [100]);
+
+        // and the report is what the descriptor says it is: one byte, 0 to 100
+        let logical_max = BATTERY_REPORT_DESCRIPTOR.windows(2).find(|item| item[0] == 0x25).unwrap()[1];
+        assert_eq!(logical_max, 100);
+        let report_count = BATTERY_REPORT_DESCRIPTOR.windows(2).find(|item| item[0] == 0x95).unwrap()[1];
+        let report_size = BATTERY_REPORT_DESCRIPTOR.windows(2).find(|item| item[0] == 0x75).unwrap()[1];
+        assert_eq!(report_count as usize * report_size as usize, BATTERY_REPORT_LEN * 8);
+    }
+}
@@ -0,0 +1,105 @@
+// Only used on real hardware, but the table is kept free of hardware dependencies so that it
+// can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use crate::api::ByteMap;
+use num_enum::FromPrimitive;
+use usbd_human_interface_device::page::Keyboard;
+
+/// The keys pressed to type each byte handed to `send_bytes()`. A byte with no keys has no
+/// mapping, and is skipped.
+pub(crate) struct ByteTable {
+    keys: Vec<Vec<Keyboard>>,
+}
+
+impl ByteTable {
+    /// Builds the table for `map`. `char_map` gives the keys for a character in the user's
+    /// keyboard layout, as used by `send_str()`.
+    pub fn new(map: ByteMap, char_map: impl Fn(char) -> Vec<Keyboard>) -> ByteTable {
+        let keys = (0..=255u8)
+            .map(|byte| match map {
+                ByteMap::Terminal => terminal_keys(byte, &char_map),
+                ByteMap::UsageId => usage_id_keys(byte),
+            })
+            .collect();
+        ByteTable { keys }
+    }
+    /// The keys for `byte`, or `None` if it has no mapping
+    pub fn keys(&self, byte: u8) -> Option<&Vec<Keyboard>> {
+        let keys = &self.keys[byte as usize];
+        if keys.is_empty() { None } else { Some(keys) }
+    }
+}
+
+/// Printable ASCII types as itself. The C0 control codes are sent the way a terminal expects
+/// to receive them: 0x01-0x1A as Ctrl-A to Ctrl-Z, 0x1B as Escape and 0x7F as Backspace.
+fn terminal_keys(byte: u8, char_map: &impl Fn(char) -> Vec<Keyboard>) -> Vec<Keyboard> {
+    match byte {
+        0x01..=0x1A => {
+            let mut keys = char_map((b'a' + byte - 1) as char);
+            if !keys.is_empty() {
+                keys.push(Keyboard::LeftControl);
+            }
+            keys
+        }
+        0x1B => vec![Keyboard::Escape],
+        0x20..=0x7E => char_map(byte as char),
+        0x7F => vec![Keyboard::DeleteBackspace],
+        _ => vec![],
+    }
+}
+
+/// The byte is the HID usage ID of a single key. The error codes below 0x04 and IDs the
+/// keyboard page doesn't define have no mapping.
+fn usage_id_keys(byte: u8) -> Vec<Keyboard> {
+    let key = Keyboard::from_primitive(byte);
+    if byte >= 0x04 && key as u8 == byte {
+        vec![key]
+    } else {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// stands in for the US101 layout, for the characters the test types
+    fn us101(ch: char) -> Vec<Keyboard> {
+        match ch {
+            'a'..='z' => vec![Keyboard::from_primitive(Keyboard::A as u8 + (ch as u8 - b'a'))],
+            'A'..='Z' => vec![Keyboard::from_primitive(Keyboard::A as u8 + (ch as u8 - b'A')), Keyboard::LeftShift],
+            '[' => vec![Keyboard::LeftBrace],
+            ' ' => vec![Keyboard::Space],
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn test_send_bytes() {
+        let table = ByteTable::new(ByteMap::Terminal, us101);
+        // an ANSI escape sequence, a control code and some unmappable bytes
+        let data = b"\x1b[A\x03\x00\x80 z";
+        let typed: Vec<&Vec<Keyboard>> = data.iter().filter_map(|&b| table.keys(b)).collect();
+        assert_eq!(typed, vec![
+            &vec![Keyboard::Escape],
+            &vec![Keyboard::LeftBrace],
+            &vec![Keyboard::A, Keyboard::LeftShift],
+            &vec![Keyboard::C, Keyboard::LeftControl],
+            &vec![Keyboard::Space],
+            &vec![Keyboard::Z],
+        ]);
+        assert_eq!(data.len() - typed.len(), 2);
+
+        // usage IDs go straight through, except for the error codes
+        let table = ByteTable::new(ByteMap::UsageId, us101);
+        for byte in 0x00..0x04 {
+            assert_eq!(table.keys(byte), None);
+        }
+        for byte in 0x04..=0x27 {
+            assert_eq!(table.keys(byte).map(|keys| keys[0] as u8), Some(byte));
+        }
+        assert_eq!(table.keys(0x04), Some(&vec![Keyboard::A]));
+        assert_eq!(table.keys(0xE1), Some(&vec![Keyboard::LeftShift]));
+    }
+}
@@ -0,0 +1,168 @@
+// Only used on real hardware, where descriptors go out on ep0, but kept free of hardware
+// dependencies so that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+/// `bmRequestType` of a standard request from the host to the device, with data coming back
+const REQUEST_TYPE_STANDARD_DEVICE_IN: u8 = 0x80;
+/// `bRequest` of `GET_DESCRIPTOR`
+const GET_DESCRIPTOR: u8 = 6;
+/// `bDescriptorType` of the configuration descriptor, which the interface descriptors follow
+const CONFIGURATION: u8 = 2;
+/// `bDescriptorType` of an interface descriptor
+const INTERFACE: u8 = 4;
+/// `bDescriptorType` of the HID class descriptor that follows a HID interface descriptor
+const HID: u8 = 0x21;
+/// Offset of `bInterfaceNumber` in an interface descriptor
+const INTERFACE_NUMBER_OFFSET: usize = 2;
+/// Offset of `bCountryCode` in a HID descriptor
+const COUNTRY_CODE_OFFSET: usize = 4;
+
+/// Where the walk through a configuration descriptor going out on ep0 has got to
+#[derive(Default)]
+struct Walk {
+    /// bytes of the response sent so far
+    offset: usize,
+    /// where the descriptor being sent started
+    start: usize,
+    /// where the next descriptor starts
+    next: usize,
+    kind: u8,
+    /// whether the descriptors being sent belong to the interface being patched
+    in_interface: bool,
+}
+
+/// Sets the `bCountryCode` of one HID interface as its configuration descriptor goes out to the
+/// host. The HID classes write a country code of 0 ("not supported"), and are built once, so
+/// this is how a `KeyboardProfile` gets its country code across.
+pub(crate) struct CountryCodePatch {
+    /// the interface to patch and the code to give it, if any
+    target: Option<(u8, u8)>,
+    walk: Option<Walk>,
+}
+
+impl CountryCodePatch {
+    pub fn new() -> CountryCodePatch {
+        CountryCodePatch {
+            target: None,
+            walk: None,
+        }
+    }
+    /// Gives `interface` the country code `code` from the next time the host reads the
+    /// configuration. A code of 0 leaves the descriptors as the classes wrote them.
+    pub fn set(&mut self, interface: u8, code: u8) {
+        self.target = if code == 0 {
+            None
+        } else {
+            Some((interface, code))
+        };
+    }
+    /// Notes a setup packet from the host. A `GET_DESCRIPTOR` of the configuration starts a
+    /// walk through the response; any other request ends one left unfinished.
+    pub fn setup(&mut self, packet: &[u8; 8]) {
+        self.walk = None;
+        if self.target.is_some()
+            && packet[0] == REQUEST_TYPE_STANDARD_DEVICE_IN
+            && packet[1] == GET_DESCRIPTOR
+            && packet[3] == CONFIGURATION
+        {
+            self.walk = Some(Walk::default());
+        }
+    }
+    /// Patches a packet about to go out on ep0 IN. Descriptors may straddle packets, so the
+    /// walk goes a byte at a time.
+    pub fn data_in(&mut self, packet: &mut [u8]) {
+        let (interface, code) = match self.target {
+            Some(target) => target,
+            None => return,
+        };
+        let walk = match self.walk.as_mut() {
+            Some(walk) => walk,
+            None => return,
+        };
+        for byte in packet.iter_mut() {
+            let at = walk.offset;
+            walk.offset += 1;
+            if at == walk.next {
+                if *byte == 0 {
+                    // not a descriptor; leave the rest alone
+                    self.walk = None;
+                    return;
+                }
+                walk.start = at;
+                walk.next = at + *byte as usize;
+            } else if at == walk.start + 1 {
+                walk.kind = *byte;
+            } else if walk.kind == INTERFACE && at == walk.start + INTERFACE_NUMBER_OFFSET {
+                walk.in_interface = *byte == interface;
+            } else if walk.kind == HID
+                && walk.in_interface
+                && at == walk.start + COUNTRY_CODE_OFFSET
+            {
+                *byte = code;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A configuration with a vendor interface 0 and a HID keyboard interface 1, as the stack
+    /// sends it
+    fn configuration() -> Vec<u8> {
+        let mut config = vec![9u8, 2, 43, 0, 2, 1, 0, 0x80, 50];
+        config.extend_from_slice(&[9, INTERFACE, 0, 0, 0, 0xff, 0, 0, 0]);
+        config.extend_from_slice(&[9, INTERFACE, 1, 0, 1, 3, 1, 1, 0]);
+        config.extend_from_slice(&[9, HID, 0x11, 0x01, 0, 1, 0x22, 63, 0]);
+        config.extend_from_slice(&[7, 5, 0x81, 3, 8, 0, 10]);
+        config
+    }
+
+    fn send(patch: &mut CountryCodePatch, data: &[u8], max_packet_size: usize) -> Vec<u8> {
+        let mut sent = Vec::new();
+        for chunk in data.chunks(max_packet_size) {
+            let mut packet = chunk.to_vec();
+            patch.data_in(&mut packet);
+            sent.extend_from_slice(&packet);
+        }
+        sent
+    }
+
+    #[test]
+    fn test_country_code_patch() {
+        let get_config = [0x80, 6, 0, CONFIGURATION, 0, 0, 255, 0];
+        let config = configuration();
+        let country_code_at = 9 + 9 + 9 + COUNTRY_CODE_OFFSET;
+        let mut patch = CountryCodePatch::new();
+
+        // nothing to patch until a code is set
+        patch.setup(&get_config);
+        assert_eq!(send(&mut patch, &config, 8), config);
+
+        // only the keyboard's HID descriptor changes, even with it split across packets
+        patch.set(1, 33);
+        patch.setup(&get_config);
+        let sent = send(&mut patch, &config, 8);
+        assert_eq!(sent[country_code_at], 33);
+        assert_eq!(sent[..country_code_at], config[..country_code_at]);
+        assert_eq!(sent[country_code_at + 1..], config[country_code_at + 1..]);
+
+        // a HID descriptor of another interface keeps its code
+        patch.set(0, 33);
+        patch.setup(&get_config);
+        assert_eq!(send(&mut patch, &config, 64), config);
+
+        // nor are other descriptors, or responses to other requests, touched
+        patch.set(1, 33);
+        patch.setup(&[0x80, 6, 0, 1, 0, 0, 64, 0]);
+        assert_eq!(send(&mut patch, &config, 64), config);
+        patch.setup(&[0x81, 6, 0, HID, 1, 0, 9, 0]);
+        assert_eq!(send(&mut patch, &config[27..36], 64), &config[27..36]);
+
+        // setting the code back to 0 stops the patching
+        patch.set(1, 0);
+        patch.setup(&get_config);
+        assert_eq!(send(&mut patch, &config, 64), config);
+    }
+}
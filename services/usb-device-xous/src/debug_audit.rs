@@ -0,0 +1,73 @@
+use crate::api::{DebugAuditEntry, MAX_DEBUG_AUDIT_ENTRIES};
+use core::num::NonZeroU8;
+use std::collections::VecDeque;
+
+/// The last `MAX_DEBUG_AUDIT_ENTRIES` changes to debug access, oldest first, for
+/// `get_debug_audit_log()`. Once it's full, each change drops the oldest one kept.
+pub(crate) struct DebugAuditLog {
+    entries: VecDeque<DebugAuditEntry>,
+}
+
+impl DebugAuditLog {
+    pub fn new() -> DebugAuditLog {
+        DebugAuditLog {
+            entries: VecDeque::with_capacity(MAX_DEBUG_AUDIT_ENTRIES),
+        }
+    }
+    /// Notes a request that left debug access `locked` at `timestamp_ms`, having been
+    /// `was_locked`, on behalf of `pid`. Requests that change nothing aren't kept.
+    pub fn record(&mut self, timestamp_ms: u64, was_locked: bool, locked: bool, pid: Option<NonZeroU8>) {
+        if was_locked == locked {
+            return;
+        }
+        if self.entries.len() == MAX_DEBUG_AUDIT_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(DebugAuditEntry {
+            timestamp_ms,
+            was_locked,
+            locked,
+            pid: pid.map(|pid| pid.get()).unwrap_or(0),
+        });
+    }
+    pub fn entries(&self) -> impl Iterator<Item = &DebugAuditEntry> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_audit_log() {
+        let shellchat = NonZeroU8::new(5);
+        let status = NonZeroU8::new(9);
+        let mut log = DebugAuditLog::new();
+        assert_eq!(log.entries().count(), 0);
+
+        // unlocking then locking again gives two entries, in order
+        log.record(1_000, true, false, shellchat);
+        log.record(1_000, false, false, status); // already unlocked: nothing to note
+        log.record(2_500, false, true, status);
+        let entries: Vec<DebugAuditEntry> = log.entries().cloned().collect();
+        assert_eq!(
+            entries,
+            vec![
+                DebugAuditEntry { timestamp_ms: 1_000, was_locked: true, locked: false, pid: 5 },
+                DebugAuditEntry { timestamp_ms: 2_500, was_locked: false, locked: true, pid: 9 },
+            ]
+        );
+
+        // the oldest are dropped to make room
+        for i in 0..MAX_DEBUG_AUDIT_ENTRIES as u64 {
+            let locked = i % 2 == 0;
+            log.record(3_000 + i, !locked, locked, None);
+        }
+        let entries: Vec<DebugAuditEntry> = log.entries().cloned().collect();
+        assert_eq!(entries.len(), MAX_DEBUG_AUDIT_ENTRIES);
+        assert_eq!(entries[0].timestamp_ms, 3_000);
+        assert_eq!(entries[0].pid, 0); // the server itself
+        assert!(entries.windows(2).all(|pair| pair[0].timestamp_ms < pair[1].timestamp_ms));
+    }
+}
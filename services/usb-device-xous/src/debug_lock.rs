@@ -0,0 +1,79 @@
+use crate::SpinalUsbMgmt;
+
+/// What the server keeps of the debug lock beyond the USBDISABLE register: the lock to write back
+/// on resume, as the register doesn't hold its value while the SoC is powered down, and whether the
+/// status bar has to redraw the lock even if it hasn't changed since it last asked.
+pub(crate) struct DebugLock {
+    saved: bool,
+    force_update: bool,
+}
+
+impl DebugLock {
+    pub fn new() -> DebugLock {
+        DebugLock {
+            saved: false,
+            // the status bar hasn't drawn anything yet after a restart-from-cold
+            force_update: true,
+        }
+    }
+    /// Notes the lock as it stands going into a suspend
+    pub fn suspend(&mut self, usbmgmt: &SpinalUsbMgmt) {
+        self.saved = usbmgmt.get_disable_debug();
+    }
+    /// Puts back the lock noted by `suspend()`, so that a lock engaged right before the suspend
+    /// stays engaged, and has the status bar redraw it
+    pub fn resume(&mut self, usbmgmt: &mut SpinalUsbMgmt) {
+        usbmgmt.disable_debug(self.saved);
+        self.force_update = true;
+    }
+    /// Has the status bar redraw the lock the next time it asks
+    pub fn force_update(&mut self) {
+        self.force_update = true;
+    }
+    /// The lock as read back from the register, and whether the status bar has to redraw it
+    /// regardless; the redraw is only forced once.
+    pub fn status(&mut self, usbmgmt: &SpinalUsbMgmt) -> (bool, bool) {
+        let force_update = std::mem::replace(&mut self.force_update, false);
+        (usbmgmt.get_disable_debug(), force_update)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The server's `SuspendResume` handling, less the wait for the resume
+    fn suspend_resume(lock: &mut DebugLock, usbmgmt: &mut SpinalUsbMgmt) {
+        lock.suspend(usbmgmt);
+        usbmgmt.xous_suspend();
+        usbmgmt.xous_resume();
+        lock.resume(usbmgmt);
+    }
+
+    #[test]
+    fn test_debug_lock_survives_suspend() {
+        let mut usbmgmt = SpinalUsbMgmt::default();
+        let mut lock = DebugLock::new();
+        assert_eq!(lock.status(&usbmgmt), (false, true));
+        assert_eq!(lock.status(&usbmgmt), (false, false));
+
+        // the register on its own comes back unlocked
+        usbmgmt.disable_debug(true);
+        usbmgmt.xous_suspend();
+        usbmgmt.xous_resume();
+        assert!(!usbmgmt.get_disable_debug());
+
+        // locked right before the suspend, and still locked after, with a redraw forced once
+        usbmgmt.disable_debug(true);
+        lock.force_update();
+        assert_eq!(lock.status(&usbmgmt), (true, true));
+        suspend_resume(&mut lock, &mut usbmgmt);
+        assert_eq!(lock.status(&usbmgmt), (true, true));
+        assert_eq!(lock.status(&usbmgmt), (true, false));
+
+        // and an unlocked port stays unlocked
+        usbmgmt.disable_debug(false);
+        suspend_resume(&mut lock, &mut usbmgmt);
+        assert_eq!(lock.status(&usbmgmt), (false, true));
+    }
+}
@@ -0,0 +1,149 @@
+// Descriptors are only captured on real hardware, but the log is kept free of hardware
+// dependencies so that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use crate::api::{DescriptorType, MAX_DESCRIPTOR_LEN};
+use num_traits::FromPrimitive;
+
+/// `bmRequestType` of a standard request from the host to the device, with data coming back
+const REQUEST_TYPE_STANDARD_DEVICE_IN: u8 = 0x80;
+/// `bRequest` of `GET_DESCRIPTOR`
+const GET_DESCRIPTOR: u8 = 6;
+
+struct Capture {
+    kind: u8,
+    index: u8,
+    /// the most the host asked for, in `wLength`
+    limit: usize,
+    data: Vec<u8>,
+}
+
+/// The descriptors the stack last sent the host, captured off ep0 as they go out, for
+/// `get_descriptor()`. What's kept is exactly what the host saw, so it can be compared against
+/// what the host rejected.
+pub(crate) struct DescriptorLog {
+    descriptors: Vec<((u8, u8), Vec<u8>)>,
+    capture: Option<Capture>,
+}
+
+impl DescriptorLog {
+    pub fn new() -> DescriptorLog {
+        DescriptorLog {
+            descriptors: Vec::new(),
+            capture: None,
+        }
+    }
+    /// Notes a setup packet from the host. A `GET_DESCRIPTOR` of one of the `DescriptorType`s
+    /// starts capturing the response; any other request abandons a capture left unfinished.
+    pub fn setup(&mut self, packet: &[u8; 8]) {
+        self.capture = None;
+        if packet[0] == REQUEST_TYPE_STANDARD_DEVICE_IN
+            && packet[1] == GET_DESCRIPTOR
+            && DescriptorType::from_u8(packet[3]).is_some()
+        {
+            self.capture = Some(Capture {
+                kind: packet[3],
+                index: packet[2],
+                limit: (u16::from_le_bytes([packet[6], packet[7]]) as usize).min(MAX_DESCRIPTOR_LEN),
+                data: Vec::new(),
+            });
+        }
+    }
+    /// Adds a packet sent on ep0 IN to the response being captured. The response is over once
+    /// the host has all it asked for, or a packet shorter than `max_packet_size` goes out.
+    pub fn data_in(&mut self, packet: &[u8], max_packet_size: usize) {
+        let done = match self.capture.as_mut() {
+            Some(capture) => {
+                let take = packet.len().min(capture.limit - capture.data.len());
+                capture.data.extend_from_slice(&packet[..take]);
+                capture.data.len() == capture.limit || packet.len() < max_packet_size
+            }
+            None => return,
+        };
+        if done {
+            let capture = self.capture.take().unwrap();
+            let key = (capture.kind, capture.index);
+            match self.descriptors.iter_mut().find(|(k, _)| *k == key) {
+                // hosts often read just the start of a descriptor to learn its length; that
+                // doesn't replace the whole of it read before
+                Some((_, kept)) if kept.starts_with(&capture.data) => (),
+                Some((_, kept)) => *kept = capture.data,
+                None => self.descriptors.push((key, capture.data)),
+            }
+        }
+    }
+    /// The bytes of descriptor `index` of `kind` as last sent to the host.
+    ///
+    /// Returns `Err(xous::Error::InvalidSyscall)` if `kind` isn't a `DescriptorType`, and
+    /// `Err(xous::Error::ServerNotFound)` if the host hasn't asked for the descriptor.
+    pub fn get(&self, kind: u8, index: u8) -> Result<&[u8], xous::Error> {
+        if DescriptorType::from_u8(kind).is_none() {
+            return Err(xous::Error::InvalidSyscall);
+        }
+        self.descriptors
+            .iter()
+            .find(|(k, _)| *k == (kind, index))
+            .map(|(_, data)| &data[..])
+            .ok_or(xous::Error::ServerNotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_descriptor(kind: DescriptorType, index: u8, length: u16) -> [u8; 8] {
+        let length = length.to_le_bytes();
+        [0x80, 6, index, kind as u8, 0, 0, length[0], length[1]]
+    }
+
+    #[test]
+    fn test_descriptor_capture() {
+        // a device descriptor as the stack sends it, over an 8-byte ep0
+        let device: [u8; 18] = [
+            18, 1, 0x10, 0x02, 0, 0, 0, 8, 0x09, 0x12, 0x11, 0x36, 0x10, 0x01, 1, 2, 3, 1,
+        ];
+        let mut log = DescriptorLog::new();
+        assert_eq!(log.get(DescriptorType::Device as u8, 0), Err(xous::Error::ServerNotFound));
+
+        // the host reads the first packet to learn the ep0 packet size, then the whole thing
+        log.setup(&get_descriptor(DescriptorType::Device, 0, 64));
+        log.data_in(&device[..8], 8);
+        log.setup(&[0x00, 5, 7, 0, 0, 0, 0, 0]); // SET_ADDRESS, abandoning the rest
+        log.setup(&get_descriptor(DescriptorType::Device, 0, 18));
+        for packet in device.chunks(8) {
+            log.data_in(packet, 8);
+        }
+        let bytes = log.get(DescriptorType::Device as u8, 0).unwrap();
+        assert_eq!(bytes, &device[..]);
+        assert_eq!(bytes[0] as usize, bytes.len()); // bLength
+        assert_eq!(bytes[1], DescriptorType::Device as u8); // bDescriptorType
+
+        // a short read of the configuration to learn wTotalLength doesn't hide the full one
+        let config: Vec<u8> = [9u8, 2, 13, 0, 1, 1, 0, 0x80, 50]
+            .iter()
+            .chain([4u8, 4, 0, 0].iter())
+            .cloned()
+            .collect();
+        log.setup(&get_descriptor(DescriptorType::Configuration, 0, 255));
+        log.data_in(&config[..8], 8);
+        log.data_in(&config[8..], 8);
+        log.setup(&get_descriptor(DescriptorType::Configuration, 0, 9));
+        log.data_in(&config[..8], 8);
+        log.data_in(&config[8..], 8);
+        assert_eq!(log.get(DescriptorType::Configuration as u8, 0).unwrap(), &config[..]);
+
+        // strings are kept by index
+        log.setup(&get_descriptor(DescriptorType::String, 1, 255));
+        log.data_in(&[4, 3, b'A', 0], 8);
+        assert_eq!(log.get(DescriptorType::String as u8, 1).unwrap(), &[4, 3, b'A', 0]);
+        assert_eq!(log.get(DescriptorType::String as u8, 2), Err(xous::Error::ServerNotFound));
+
+        // other requests and kinds aren't captured, and unknown kinds can't be asked for
+        log.setup(&[0x81, 6, 0, 0x22, 0, 0, 64, 0]); // a HID report descriptor
+        log.data_in(&[0x05, 0x01], 8);
+        assert_eq!(log.get(0x22, 0), Err(xous::Error::InvalidSyscall));
+        assert_eq!(log.get(0, 0), Err(xous::Error::InvalidSyscall));
+        assert_eq!(log.get(DescriptorType::Device as u8, 0).unwrap(), &device[..]);
+    }
+}
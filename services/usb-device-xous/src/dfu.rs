@@ -0,0 +1,708 @@
+// Only polled on real hardware, but the state machine is kept free of hardware dependencies so
+// that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use crate::api::{DfuBlock, Opcode, DFU_TRANSFER_SIZE};
+use core::task::Poll;
+use num_traits::ToPrimitive;
+use std::sync::mpsc::Sender;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+use xous_ipc::Buffer;
+
+// class requests, from the DFU 1.1 spec
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+
+const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const DFU_SUBCLASS: u8 = 0x01;
+const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+const DFU_PROTOCOL_DFU_MODE: u8 = 0x02;
+/// `bDescriptorType` of the DFU functional descriptor
+const DFU_FUNCTIONAL: u8 = 0x21;
+/// `bmAttributes` of the functional descriptor: bitCanDnload, bitManifestationTolerant, and
+/// bitWillDetach, as the device re-enumerates by itself on a `DFU_DETACH`
+const DFU_ATTRIBUTES: u8 = 0x01 | 0x04 | 0x08;
+/// `wDetachTimeOut`, in ms
+const DFU_DETACH_TIMEOUT: u16 = 1000;
+/// `bwPollTimeout` while a block is being written, in ms: how long the host waits before it
+/// asks again
+const DFU_POLL_TIMEOUT: u32 = 10;
+const DFU_VERSION: u16 = 0x0110;
+
+/// `bState` of a DFU interface
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum DfuState {
+    AppIdle = 0,
+    AppDetach = 1,
+    DfuIdle = 2,
+    DnloadSync = 3,
+    /// Only reported, while a block is being written; the machine stays in `DnloadSync`
+    DnBusy = 4,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    /// Only reported, while the image is being checked; the machine stays in `ManifestSync`
+    Manifest = 7,
+    Error = 10,
+}
+
+/// `bStatus` of a DFU interface
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum DfuStatus {
+    Ok = 0x00,
+    /// The image isn't for this device; here, there's nothing to take it
+    ErrTarget = 0x01,
+    ErrWrite = 0x03,
+    ErrVerify = 0x07,
+    /// A block came out of sequence
+    ErrAddress = 0x08,
+    /// The host ended the download before sending anything
+    ErrNotDone = 0x09,
+    ErrUnknown = 0x0E,
+    ErrStalledPkt = 0x0F,
+}
+
+/// Where the firmware the host downloads lands. A write can take longer than the host should be
+/// kept waiting, so it may come back `Pending`; it's then called again, with the same block,
+/// until it's done.
+pub(crate) trait BlockWrite {
+    /// Writes `data`, which goes `offset` bytes into the image. Blocks arrive in order, and
+    /// are at most `DFU_TRANSFER_SIZE` long.
+    fn write_block(&mut self, offset: usize, data: &[u8]) -> Poll<Result<(), DfuStatus>>;
+    /// Called once the host has sent the whole image, `len` bytes of it
+    fn manifest(&mut self, len: usize) -> Poll<Result<(), DfuStatus>>;
+    /// Forgets any write that is still `Pending`, as the download it was part of is over
+    fn cancel(&mut self) {}
+}
+
+/// The DFU state machine, for both the runtime interface and DFU mode. Blocks are written
+/// when the host asks for the status after sending them, so that it hears how the write went;
+/// until a write is done, the host is told to wait and ask again.
+pub(crate) struct DfuMachine<W: BlockWrite> {
+    writer: W,
+    state: DfuState,
+    status: DfuStatus,
+    next_block: u16,
+    offset: usize,
+    pending: Vec<u8>,
+}
+
+impl<W: BlockWrite> DfuMachine<W> {
+    pub fn new(writer: W) -> DfuMachine<W> {
+        DfuMachine {
+            writer,
+            state: DfuState::AppIdle,
+            status: DfuStatus::Ok,
+            next_block: 0,
+            offset: 0,
+            pending: Vec::new(),
+        }
+    }
+    pub fn state(&self) -> DfuState {
+        self.state
+    }
+    pub fn writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+    fn restart(&mut self, state: DfuState) {
+        self.writer.cancel();
+        self.state = state;
+        self.status = DfuStatus::Ok;
+        self.next_block = 0;
+        self.offset = 0;
+        self.pending.clear();
+    }
+    pub fn enter_dfu_mode(&mut self) {
+        self.restart(DfuState::DfuIdle);
+    }
+    pub fn leave_dfu_mode(&mut self) {
+        self.restart(DfuState::AppIdle);
+    }
+    fn in_dfu_mode(&self) -> bool {
+        !matches!(self.state, DfuState::AppIdle | DfuState::AppDetach)
+    }
+    /// A request that isn't allowed in the current state. The host is stalled, and in DFU mode
+    /// the interface goes to the error state until it's cleared.
+    fn stall(&mut self, status: DfuStatus) -> Result<(), DfuStatus> {
+        if self.in_dfu_mode() {
+            self.state = DfuState::Error;
+            self.status = status;
+        }
+        Err(status)
+    }
+    pub fn detach(&mut self) -> Result<(), DfuStatus> {
+        match self.state {
+            DfuState::AppIdle => {
+                self.state = DfuState::AppDetach;
+                Ok(())
+            }
+            _ => self.stall(DfuStatus::ErrStalledPkt),
+        }
+    }
+    /// A `DFU_DNLOAD` of block `block`. An empty block ends the download.
+    pub fn dnload(&mut self, block: u16, data: &[u8]) -> Result<(), DfuStatus> {
+        match self.state {
+            DfuState::DfuIdle | DfuState::DnloadIdle if !data.is_empty() => {
+                if data.len() > DFU_TRANSFER_SIZE {
+                    return self.stall(DfuStatus::ErrStalledPkt);
+                }
+                if block != self.next_block {
+                    return self.stall(DfuStatus::ErrAddress);
+                }
+                self.pending.clear();
+                self.pending.extend_from_slice(data);
+                self.state = DfuState::DnloadSync;
+                Ok(())
+            }
+            DfuState::DnloadIdle => {
+                self.state = DfuState::ManifestSync;
+                Ok(())
+            }
+            DfuState::DfuIdle => self.stall(DfuStatus::ErrNotDone),
+            _ => self.stall(DfuStatus::ErrStalledPkt),
+        }
+    }
+    /// The response to `DFU_GETSTATUS`, after finishing whatever the host is waiting on, or
+    /// telling it to wait if that isn't done yet
+    pub fn get_status(&mut self) -> [u8; 6] {
+        match self.state {
+            DfuState::DnloadSync => match self.writer.write_block(self.offset, &self.pending) {
+                Poll::Pending => return busy_status(DfuState::DnBusy),
+                Poll::Ready(Ok(())) => {
+                    self.offset += self.pending.len();
+                    self.next_block = self.next_block.wrapping_add(1);
+                    self.state = DfuState::DnloadIdle;
+                }
+                Poll::Ready(Err(status)) => {
+                    self.state = DfuState::Error;
+                    self.status = status;
+                }
+            },
+            DfuState::ManifestSync => match self.writer.manifest(self.offset) {
+                Poll::Pending => return busy_status(DfuState::Manifest),
+                // manifestation tolerant, so ready for another download straight away
+                Poll::Ready(Ok(())) => self.restart(DfuState::DfuIdle),
+                Poll::Ready(Err(status)) => {
+                    self.state = DfuState::Error;
+                    self.status = status;
+                }
+            },
+            _ => (),
+        }
+        // no bwPollTimeout, as the work is done by the time the host hears back, and no iString
+        [self.status as u8, 0, 0, 0, self.state as u8, 0]
+    }
+    pub fn clear_status(&mut self) -> Result<(), DfuStatus> {
+        match self.state {
+            DfuState::Error => {
+                self.restart(DfuState::DfuIdle);
+                Ok(())
+            }
+            _ => self.stall(DfuStatus::ErrStalledPkt),
+        }
+    }
+    pub fn abort(&mut self) -> Result<(), DfuStatus> {
+        match self.state {
+            DfuState::DfuIdle | DfuState::DnloadSync | DfuState::DnloadIdle | DfuState::ManifestSync => {
+                self.restart(DfuState::DfuIdle);
+                Ok(())
+            }
+            _ => self.stall(DfuStatus::ErrStalledPkt),
+        }
+    }
+}
+
+/// The response to `DFU_GETSTATUS` while a write is under way: the host waits
+/// `DFU_POLL_TIMEOUT` before asking again
+fn busy_status(state: DfuState) -> [u8; 6] {
+    let timeout = DFU_POLL_TIMEOUT.to_le_bytes();
+    [DfuStatus::Ok as u8, timeout[0], timeout[1], timeout[2], state as u8, 0]
+}
+
+/// What the DFU interface presents to the host
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum DfuMode {
+    /// No interface at all, until something is there to take the firmware
+    Off,
+    /// The runtime interface, through which a host tool asks the device to detach
+    Runtime,
+    /// DFU mode, taking firmware downloads
+    Dfu,
+}
+
+/// The DFU interface. Its interface number is allocated after the HID interfaces, so leaving
+/// it out of the configuration while it's `Off` doesn't leave a gap in the numbering.
+pub(crate) struct DfuClass<W: BlockWrite> {
+    interface: InterfaceNumber,
+    mode: DfuMode,
+    machine: DfuMachine<W>,
+    detach_requested: bool,
+}
+
+impl<W: BlockWrite> DfuClass<W> {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>, writer: W) -> DfuClass<W> {
+        DfuClass {
+            interface: alloc.interface(),
+            mode: DfuMode::Off,
+            machine: DfuMachine::new(writer),
+            detach_requested: false,
+        }
+    }
+    pub fn mode(&self) -> DfuMode {
+        self.mode
+    }
+    /// Takes effect the next time the host enumerates the device
+    pub fn set_mode(&mut self, mode: DfuMode) {
+        self.mode = mode;
+        self.detach_requested = false;
+        match mode {
+            DfuMode::Dfu => self.machine.enter_dfu_mode(),
+            _ => self.machine.leave_dfu_mode(),
+        }
+    }
+    /// Whether the host has asked the runtime interface to detach since the last call
+    pub fn take_detach(&mut self) -> bool {
+        core::mem::replace(&mut self.detach_requested, false)
+    }
+    pub fn writer_mut(&mut self) -> &mut W {
+        self.machine.writer_mut()
+    }
+    fn is_for_us(&self, request_type: RequestType, recipient: Recipient, index: u16) -> bool {
+        self.mode != DfuMode::Off
+            && request_type == RequestType::Class
+            && recipient == Recipient::Interface
+            && index == u8::from(self.interface) as u16
+    }
+}
+
+impl<B: UsbBus, W: BlockWrite> UsbClass<B> for DfuClass<W> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        let protocol = match self.mode {
+            DfuMode::Off => return Ok(()),
+            DfuMode::Runtime => DFU_PROTOCOL_RUNTIME,
+            DfuMode::Dfu => DFU_PROTOCOL_DFU_MODE,
+        };
+        writer.interface(self.interface, USB_CLASS_APPLICATION_SPECIFIC, DFU_SUBCLASS, protocol)?;
+        let timeout = DFU_DETACH_TIMEOUT.to_le_bytes();
+        let transfer_size = (DFU_TRANSFER_SIZE as u16).to_le_bytes();
+        let version = DFU_VERSION.to_le_bytes();
+        writer.write(
+            DFU_FUNCTIONAL,
+            &[DFU_ATTRIBUTES, timeout[0], timeout[1], transfer_size[0], transfer_size[1], version[0], version[1]],
+        )
+    }
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_for_us(req.request_type, req.recipient, req.index) {
+            return;
+        }
+        match req.request {
+            DFU_GETSTATUS => {
+                let status = self.machine.get_status();
+                xfer.accept_with(&status).ok();
+            }
+            DFU_GETSTATE => {
+                xfer.accept_with(&[self.machine.state() as u8]).ok();
+            }
+            // uploads aren't offered
+            _ => {
+                self.machine.stall(DfuStatus::ErrStalledPkt).ok();
+                xfer.reject().ok();
+            }
+        }
+    }
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_for_us(req.request_type, req.recipient, req.index) {
+            return;
+        }
+        let result = match req.request {
+            DFU_DETACH => self.machine.detach().map(|_| self.detach_requested = true),
+            DFU_DNLOAD => self.machine.dnload(req.value, xfer.data()),
+            DFU_CLRSTATUS => self.machine.clear_status(),
+            DFU_ABORT => self.machine.abort(),
+            _ => self.machine.stall(DfuStatus::ErrStalledPkt),
+        };
+        match result {
+            Ok(()) => xfer.accept().ok(),
+            Err(_) => xfer.reject().ok(),
+        };
+    }
+}
+
+/// How the lending thread found the listener's answer to a block, as passed back in a
+/// `DfuBlockAnswered` message
+const ANSWER_REJECTED: usize = 0;
+const ANSWER_ACCEPTED: usize = 1;
+const ANSWER_UNDELIVERED: usize = 2;
+
+/// A block on its way to the listener, with the sequence number its answer comes back with
+pub(crate) struct ForwardedBlock {
+    pub cid: xous::CID,
+    pub id: u32,
+    pub seq: u32,
+    pub block: DfuBlock,
+}
+
+/// Hands each block to the server registered with `hook_dfu_blocks()`. The blocks are lent from
+/// a thread of their own, started by `spawn_block_lender()`, so that the USB server carries on
+/// answering the host while the listener writes them.
+pub(crate) struct BlockForwarder {
+    listener: Option<(xous::CID, u32)>,
+    lender: Sender<ForwardedBlock>,
+    seq: u32,
+    /// The block out with the listener, if any: its sequence number, offset, and whether it's
+    /// the manifest
+    in_flight: Option<(u32, usize, bool)>,
+    /// The listener's answer to the block in flight, once the lending thread has passed it on
+    answer: Option<Result<bool, DfuStatus>>,
+}
+
+impl BlockForwarder {
+    pub fn new(lender: Sender<ForwardedBlock>) -> BlockForwarder {
+        BlockForwarder {
+            listener: None,
+            lender,
+            seq: 0,
+            in_flight: None,
+            answer: None,
+        }
+    }
+    pub fn has_listener(&self) -> bool {
+        self.listener.is_some()
+    }
+    pub fn set_listener(&mut self, cid: xous::CID, id: u32) {
+        self.listener = Some((cid, id));
+    }
+    pub fn take_listener(&mut self) -> Option<xous::CID> {
+        self.listener.take().map(|(cid, _)| cid)
+    }
+    /// Takes the answer to block `seq` from a `DfuBlockAnswered` message. Answers to blocks
+    /// that have since been cancelled are dropped.
+    pub fn answered(&mut self, seq: u32, answer: usize) {
+        if matches!(self.in_flight, Some((in_flight, _, _)) if in_flight == seq) {
+            self.answer = Some(match answer {
+                ANSWER_ACCEPTED => Ok(true),
+                ANSWER_REJECTED => Ok(false),
+                _ => Err(DfuStatus::ErrUnknown),
+            });
+        }
+    }
+    fn forward(&mut self, offset: usize, data: &[u8], manifest: bool) -> Poll<Result<bool, DfuStatus>> {
+        if let Some((_, in_flight_offset, in_flight_manifest)) = self.in_flight {
+            if (in_flight_offset, in_flight_manifest) == (offset, manifest) {
+                return match self.answer.take() {
+                    Some(answer) => {
+                        self.in_flight = None;
+                        Poll::Ready(answer)
+                    }
+                    None => Poll::Pending,
+                };
+            }
+        }
+        let (cid, id) = match self.listener {
+            Some(listener) => listener,
+            None => return Poll::Ready(Err(DfuStatus::ErrTarget)),
+        };
+        let mut block = DfuBlock {
+            offset: offset as u32,
+            len: data.len() as u32,
+            data: [0; DFU_TRANSFER_SIZE],
+            manifest,
+            accepted: false,
+        };
+        block.data[..data.len()].copy_from_slice(data);
+        self.seq = self.seq.wrapping_add(1);
+        if self.lender.send(ForwardedBlock { cid, id, seq: self.seq, block }).is_err() {
+            return Poll::Ready(Err(DfuStatus::ErrUnknown));
+        }
+        self.in_flight = Some((self.seq, offset, manifest));
+        self.answer = None;
+        Poll::Pending
+    }
+}
+
+impl BlockWrite for BlockForwarder {
+    fn write_block(&mut self, offset: usize, data: &[u8]) -> Poll<Result<(), DfuStatus>> {
+        self.forward(offset, data, false).map(|answer| match answer? {
+            true => Ok(()),
+            false => Err(DfuStatus::ErrWrite),
+        })
+    }
+    fn manifest(&mut self, len: usize) -> Poll<Result<(), DfuStatus>> {
+        self.forward(len, &[], true).map(|answer| match answer? {
+            true => Ok(()),
+            false => Err(DfuStatus::ErrVerify),
+        })
+    }
+    fn cancel(&mut self) {
+        self.in_flight = None;
+        self.answer = None;
+    }
+}
+
+/// Starts the thread that lends the blocks a `BlockForwarder` is given to the listener. It waits
+/// on each answer, and passes it on to the USB server through `server` in a `DfuBlockAnswered`
+/// message.
+pub(crate) fn spawn_block_lender(server: xous::CID) -> Sender<ForwardedBlock> {
+    let (lender, blocks) = std::sync::mpsc::channel::<ForwardedBlock>();
+    std::thread::spawn(move || {
+        for forwarded in blocks {
+            let answer = match lend_block(&forwarded) {
+                Ok(true) => ANSWER_ACCEPTED,
+                Ok(false) => ANSWER_REJECTED,
+                Err(e) => {
+                    log::warn!("couldn't lend DFU block to the listener: {:?}", e);
+                    ANSWER_UNDELIVERED
+                }
+            };
+            xous::send_message(
+                server,
+                xous::Message::new_scalar(
+                    Opcode::DfuBlockAnswered.to_usize().unwrap(),
+                    forwarded.seq as usize,
+                    answer,
+                    0,
+                    0,
+                ),
+            )
+            .ok();
+        }
+    });
+    lender
+}
+
+fn lend_block(forwarded: &ForwardedBlock) -> Result<bool, xous::Error> {
+    let mut buf = Buffer::into_buf(forwarded.block).or(Err(xous::Error::InternalError))?;
+    buf.lend_mut(forwarded.cid, forwarded.id)?;
+    let block = buf.to_original::<DfuBlock, _>().or(Err(xous::Error::InternalError))?;
+    Ok(block.accepted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Image {
+        data: Vec<u8>,
+        manifested: Option<usize>,
+        fail_writes: bool,
+        /// how many more times each write comes back `Pending` before it's done
+        busy_polls: usize,
+        busy_left: usize,
+    }
+
+    impl Image {
+        fn busy(&mut self) -> bool {
+            if self.busy_left > 0 {
+                self.busy_left -= 1;
+                return true;
+            }
+            self.busy_left = self.busy_polls;
+            false
+        }
+    }
+
+    impl BlockWrite for Image {
+        fn write_block(&mut self, offset: usize, data: &[u8]) -> Poll<Result<(), DfuStatus>> {
+            if self.busy() {
+                return Poll::Pending;
+            }
+            if self.fail_writes {
+                return Poll::Ready(Err(DfuStatus::ErrWrite));
+            }
+            assert_eq!(offset, self.data.len());
+            self.data.extend_from_slice(data);
+            Poll::Ready(Ok(()))
+        }
+        fn manifest(&mut self, len: usize) -> Poll<Result<(), DfuStatus>> {
+            if self.busy() {
+                return Poll::Pending;
+            }
+            self.manifested = Some(len);
+            Poll::Ready(Ok(()))
+        }
+        fn cancel(&mut self) {
+            self.busy_left = self.busy_polls;
+        }
+    }
+
+    fn state_of(status: [u8; 6]) -> (u8, u8) {
+        (status[0], status[4])
+    }
+
+    #[test]
+    fn test_dfu_download() {
+        let mut dfu = DfuMachine::new(Image::default());
+        // the runtime interface only detaches; downloads wait for DFU mode
+        assert_eq!(dfu.dnload(0, &[1, 2, 3]), Err(DfuStatus::ErrStalledPkt));
+        assert_eq!(dfu.state(), DfuState::AppIdle);
+        dfu.detach().unwrap();
+        assert_eq!(dfu.state(), DfuState::AppDetach);
+
+        dfu.enter_dfu_mode();
+        // ending a download that never started is an error, cleared by DFU_CLRSTATUS
+        assert_eq!(dfu.dnload(0, &[]), Err(DfuStatus::ErrNotDone));
+        assert_eq!(state_of(dfu.get_status()), (DfuStatus::ErrNotDone as u8, DfuState::Error as u8));
+        dfu.clear_status().unwrap();
+        assert_eq!(state_of(dfu.get_status()), (DfuStatus::Ok as u8, DfuState::DfuIdle as u8));
+
+        // each block is written when the host asks for the status after sending it
+        let first = [0xA5u8; DFU_TRANSFER_SIZE];
+        dfu.dnload(0, &first).unwrap();
+        assert_eq!(dfu.state(), DfuState::DnloadSync);
+        assert!(dfu.writer_mut().data.is_empty());
+        assert_eq!(state_of(dfu.get_status()), (DfuStatus::Ok as u8, DfuState::DnloadIdle as u8));
+        assert_eq!(dfu.writer_mut().data, &first[..]);
+        dfu.dnload(1, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(state_of(dfu.get_status()), (DfuStatus::Ok as u8, DfuState::DnloadIdle as u8));
+        assert_eq!(dfu.writer_mut().data.len(), DFU_TRANSFER_SIZE + 4);
+
+        // a block out of sequence, or too long, is refused without being written
+        assert_eq!(dfu.dnload(3, &[5]), Err(DfuStatus::ErrAddress));
+        assert_eq!(state_of(dfu.get_status()), (DfuStatus::ErrAddress as u8, DfuState::Error as u8));
+        assert_eq!(dfu.writer_mut().data.len(), DFU_TRANSFER_SIZE + 4);
+        dfu.clear_status().unwrap();
+        assert_eq!(dfu.dnload(0, &[0; DFU_TRANSFER_SIZE + 1]), Err(DfuStatus::ErrStalledPkt));
+        dfu.clear_status().unwrap();
+
+        // clearing the error starts the download over; an empty block ends it
+        dfu.writer_mut().data.clear();
+        dfu.dnload(0, &first).unwrap();
+        dfu.get_status();
+        dfu.dnload(1, &[1, 2, 3, 4]).unwrap();
+        dfu.get_status();
+        dfu.dnload(2, &[]).unwrap();
+        assert_eq!(dfu.state(), DfuState::ManifestSync);
+        assert_eq!(state_of(dfu.get_status()), (DfuStatus::Ok as u8, DfuState::DfuIdle as u8));
+        assert_eq!(dfu.writer_mut().manifested, Some(DFU_TRANSFER_SIZE + 4));
+
+        // a failed write is reported in the status, and DFU_ABORT isn't allowed from there
+        dfu.writer_mut().fail_writes = true;
+        dfu.dnload(0, &[9]).unwrap();
+        assert_eq!(state_of(dfu.get_status()), (DfuStatus::ErrWrite as u8, DfuState::Error as u8));
+        assert!(dfu.abort().is_err());
+        dfu.clear_status().unwrap();
+        // but it is from the middle of a download
+        dfu.writer_mut().fail_writes = false;
+        dfu.writer_mut().data.clear();
+        dfu.dnload(0, &[9]).unwrap();
+        dfu.get_status();
+        dfu.abort().unwrap();
+        assert_eq!(dfu.state(), DfuState::DfuIdle);
+        dfu.dnload(0, &[9]).unwrap();
+
+        // back in the application, the state machine starts over
+        dfu.leave_dfu_mode();
+        assert_eq!(dfu.state(), DfuState::AppIdle);
+        assert_eq!(dfu.clear_status(), Err(DfuStatus::ErrStalledPkt));
+        assert_eq!(dfu.state(), DfuState::AppIdle);
+    }
+
+    #[test]
+    fn test_dfu_busy_write() {
+        let mut dfu = DfuMachine::new(Image {
+            busy_polls: 2,
+            busy_left: 2,
+            ..Default::default()
+        });
+        dfu.enter_dfu_mode();
+
+        // while the write is going, the host is told to wait and ask again
+        dfu.dnload(0, &[1, 2, 3]).unwrap();
+        let busy = dfu.get_status();
+        assert_eq!(state_of(busy), (DfuStatus::Ok as u8, DfuState::DnBusy as u8));
+        assert_eq!(u32::from_le_bytes([busy[1], busy[2], busy[3], 0]), DFU_POLL_TIMEOUT);
+        assert_eq!(dfu.state(), DfuState::DnloadSync);
+        assert_eq!(state_of(dfu.get_status()), (DfuStatus::Ok as u8, DfuState::DnBusy as u8));
+        // another block can't be sent until it's done
+        assert_eq!(dfu.dnload(1, &[4]), Err(DfuStatus::ErrStalledPkt));
+        dfu.clear_status().unwrap();
+        dfu.dnload(0, &[1, 2, 3]).unwrap();
+        dfu.get_status();
+        dfu.get_status();
+        let done = dfu.get_status();
+        assert_eq!(state_of(done), (DfuStatus::Ok as u8, DfuState::DnloadIdle as u8));
+        assert_eq!(done[1..4], [0, 0, 0]);
+        assert_eq!(dfu.writer_mut().data, &[1, 2, 3]);
+
+        // the same goes for the manifest
+        dfu.dnload(1, &[]).unwrap();
+        assert_eq!(state_of(dfu.get_status()), (DfuStatus::Ok as u8, DfuState::Manifest as u8));
+        assert_eq!(state_of(dfu.get_status()), (DfuStatus::Ok as u8, DfuState::Manifest as u8));
+        assert_eq!(state_of(dfu.get_status()), (DfuStatus::Ok as u8, DfuState::DfuIdle as u8));
+        assert_eq!(dfu.writer_mut().manifested, Some(3));
+
+        // aborting cancels a write that hasn't finished
+        dfu.writer_mut().data.clear();
+        dfu.dnload(0, &[5]).unwrap();
+        dfu.get_status();
+        dfu.abort().unwrap();
+        assert_eq!(dfu.writer_mut().busy_left, 2);
+        assert!(dfu.writer_mut().data.is_empty());
+    }
+
+    #[test]
+    fn test_block_forwarder() {
+        let (lender, blocks) = std::sync::mpsc::channel();
+        let mut forwarder = BlockForwarder::new(lender);
+        // with nobody to write the blocks, the download fails straight away
+        assert_eq!(forwarder.write_block(0, &[1]), Poll::Ready(Err(DfuStatus::ErrTarget)));
+        assert!(blocks.try_recv().is_err());
+
+        // a block goes to the lending thread once, and is pending until its answer comes back
+        assert!(!forwarder.has_listener());
+        forwarder.set_listener(5, 7);
+        assert!(forwarder.has_listener());
+        assert_eq!(forwarder.write_block(0, &[1, 2, 3]), Poll::Pending);
+        let sent = blocks.try_recv().unwrap();
+        assert_eq!((sent.cid, sent.id), (5, 7));
+        assert_eq!((sent.block.offset, sent.block.len, sent.block.manifest), (0, 3, false));
+        assert_eq!(sent.block.data[..3], [1, 2, 3]);
+        assert_eq!(forwarder.write_block(0, &[1, 2, 3]), Poll::Pending);
+        assert!(blocks.try_recv().is_err());
+        // answers to other blocks are dropped
+        forwarder.answered(sent.seq.wrapping_add(1), ANSWER_ACCEPTED);
+        assert_eq!(forwarder.write_block(0, &[1, 2, 3]), Poll::Pending);
+        forwarder.answered(sent.seq, ANSWER_ACCEPTED);
+        assert_eq!(forwarder.write_block(0, &[1, 2, 3]), Poll::Ready(Ok(())));
+
+        // the next block is sent fresh; a rejection fails the write
+        assert_eq!(forwarder.write_block(3, &[4]), Poll::Pending);
+        let sent = blocks.try_recv().unwrap();
+        assert_eq!(sent.block.offset, 3);
+        forwarder.answered(sent.seq, ANSWER_REJECTED);
+        assert_eq!(forwarder.write_block(3, &[4]), Poll::Ready(Err(DfuStatus::ErrWrite)));
+
+        // once cancelled, a late answer doesn't count for the block sent again
+        assert_eq!(forwarder.write_block(0, &[1]), Poll::Pending);
+        let cancelled = blocks.try_recv().unwrap();
+        forwarder.cancel();
+        assert_eq!(forwarder.write_block(0, &[1]), Poll::Pending);
+        let resent = blocks.try_recv().unwrap();
+        assert_ne!(resent.seq, cancelled.seq);
+        forwarder.answered(cancelled.seq, ANSWER_ACCEPTED);
+        assert_eq!(forwarder.write_block(0, &[1]), Poll::Pending);
+
+        // a listener that couldn't be reached, or turns the image down, fails the manifest
+        forwarder.answered(resent.seq, ANSWER_UNDELIVERED);
+        assert_eq!(forwarder.write_block(0, &[1]), Poll::Ready(Err(DfuStatus::ErrUnknown)));
+        assert_eq!(forwarder.manifest(1), Poll::Pending);
+        let sent = blocks.try_recv().unwrap();
+        assert!(sent.block.manifest);
+        assert_eq!(sent.block.offset, 1);
+        forwarder.answered(sent.seq, ANSWER_REJECTED);
+        assert_eq!(forwarder.manifest(1), Poll::Ready(Err(DfuStatus::ErrVerify)));
+
+        // and with the lending thread gone, there's nobody to hand blocks to
+        assert_eq!(forwarder.take_listener(), Some(5));
+        forwarder.set_listener(5, 7);
+        drop(blocks);
+        assert_eq!(forwarder.write_block(0, &[1]), Poll::Ready(Err(DfuStatus::ErrUnknown)));
+    }
+}
@@ -0,0 +1,105 @@
+// Only polled on real hardware, but the report cache is kept free of hardware dependencies so
+// that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+use usbd_human_interface_device::page::Keyboard;
+
+/// HID class request code for GET_REPORT
+const HID_GET_REPORT: u8 = 0x01;
+/// Longest report the cache will hand back; the same as the largest endpoint packet
+const MAX_REPORT_LEN: usize = 64;
+
+/// The last input report sent on each interface, for answering GET_REPORT
+pub(crate) struct ReportCache {
+    reports: Vec<(u8, Vec<u8>)>,
+}
+
+impl ReportCache {
+    /// Answers for the interfaces in `interfaces`, each starting out with no report sent
+    pub fn new(interfaces: &[u8]) -> ReportCache {
+        ReportCache {
+            reports: interfaces.iter().map(|&iface| (iface, Vec::new())).collect(),
+        }
+    }
+    pub fn record(&mut self, interface: u8, report: &[u8]) {
+        if let Some((_, cached)) = self.reports.iter_mut().find(|(iface, _)| *iface == interface) {
+            cached.clear();
+            cached.extend_from_slice(&report[..report.len().min(MAX_REPORT_LEN)]);
+        }
+    }
+    /// Remembers the keys in a keyboard report, in the boot protocol layout that leads every
+    /// keyboard input report: a byte of modifier bits, a reserved byte, then up to six keys.
+    pub fn record_keys(&mut self, interface: u8, keys: &[Keyboard]) {
+        let mut report = [0u8; 8];
+        let mut slot = 2;
+        for &key in keys {
+            let code = key as u8;
+            if (Keyboard::LeftControl as u8..=Keyboard::RightGUI as u8).contains(&code) {
+                report[0] |= 1 << (code - Keyboard::LeftControl as u8);
+            } else if slot < report.len() {
+                report[slot] = code;
+                slot += 1;
+            }
+        }
+        self.record(interface, &report);
+    }
+    /// The response to a GET_REPORT for `interface` asking for `length` bytes: the last report
+    /// sent, zero-padded, or all zeroes if nothing has been sent yet. `None` if the interface
+    /// isn't one this cache answers for.
+    pub fn get_report(&self, interface: u8, length: usize) -> Option<Vec<u8>> {
+        let (_, cached) = self.reports.iter().find(|(iface, _)| *iface == interface)?;
+        let mut response = vec![0u8; length.min(MAX_REPORT_LEN)];
+        let len = cached.len().min(response.len());
+        response[..len].copy_from_slice(&cached[..len]);
+        Some(response)
+    }
+}
+
+/// Answers HID GET_REPORT requests out of the `ReportCache`. Polled ahead of the HID class, so
+/// it gets the first look at each control request; requests for other interfaces are left
+/// alone for the HID class to handle.
+impl<B: UsbBus> UsbClass<B> for ReportCache {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.request == HID_GET_REPORT
+        {
+            if let Some(report) = self.get_report(req.index as u8, req.length as usize) {
+                xfer.accept_with(&report).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_report() {
+        let mut cache = ReportCache::new(&[0, 2]);
+        // nothing sent yet: a zeroed report of the length asked for
+        assert_eq!(cache.get_report(0, 8), Some(vec![0u8; 8]));
+        // not one of ours
+        assert_eq!(cache.get_report(1, 8), None);
+
+        // shift-A, as the keyboard would have sent it
+        cache.record_keys(0, &[Keyboard::A, Keyboard::LeftShift]);
+        assert_eq!(cache.get_report(0, 8), Some(vec![0x02, 0, 0x04, 0, 0, 0, 0, 0]));
+        // a host asking for the full report gets the rest zero-filled
+        let long = cache.get_report(0, 16).unwrap();
+        assert_eq!(&long[..3], &[0x02, 0, 0x04]);
+        assert!(long[3..].iter().all(|&b| b == 0));
+        // the key-up replaces it
+        cache.record_keys(0, &[]);
+        assert_eq!(cache.get_report(0, 8), Some(vec![0u8; 8]));
+
+        // interfaces are cached separately
+        cache.record(2, &[0xAA; 64]);
+        assert_eq!(cache.get_report(2, 64), Some(vec![0xAA; 64]));
+        assert_eq!(cache.get_report(0, 8), Some(vec![0u8; 8]));
+    }
+}
@@ -4,22 +4,43 @@ pub struct SpinalUdcDescriptor {}
 
 pub struct UdcEpStatus {}
 
+// models the debug lock, and its register losing its value over a suspend, as on hardware
+#[derive(Default)]
 pub struct SpinalUsbMgmt {
+    disable_debug: bool,
 }
 impl SpinalUsbMgmt {
     pub fn print_regs(&self) {}
     pub fn connect_device_core(&mut self, _state: bool) {}
     pub fn is_device_connected(&self) -> bool {false}
-    pub fn disable_debug(&mut self, _disable: bool) {}
-    pub fn get_disable_debug(&self) -> bool {false}
-    pub fn xous_suspend(&mut self) {}
+    pub fn disable_debug(&mut self, disable: bool) {
+        self.disable_debug = disable;
+    }
+    pub fn get_disable_debug(&self) -> bool {
+        self.disable_debug
+    }
+    pub fn xous_suspend(&mut self) {
+        self.disable_debug = false;
+    }
     pub fn xous_resume(&mut self) {}
+    pub fn release_hardware(&mut self) {}
     pub fn descriptor_from_status(&self, _ep_status: &UdcEpStatus) -> SpinalUdcDescriptor {
         SpinalUdcDescriptor {}
     }
     pub fn status_from_index(&self, _index: usize) -> UdcEpStatus {
         UdcEpStatus {}
     }
+    pub fn stats(&self) -> crate::UsbStats {
+        crate::UsbStats::default()
+    }
+    pub fn reset_stats(&self) {}
+    pub fn dump_regs(&self) -> crate::SpinalUdcRegsSnapshot {
+        crate::SpinalUdcRegsSnapshot::default()
+    }
+    pub fn frame_number(&self) -> u16 {0}
+    pub fn descriptor(&self, _kind: u8, _index: u8) -> Result<Vec<u8>, xous::Error> {
+        Err(xous::Error::ServerNotFound)
+    }
 }
 pub struct SpinalUsbDevice {
 }
@@ -29,7 +50,7 @@ impl SpinalUsbDevice {
         SpinalUsbDevice {}
     }
     pub fn get_iface(&self) -> SpinalUsbMgmt {
-        SpinalUsbMgmt {}
+        SpinalUsbMgmt::default()
     }
     pub fn print_ep_stats(&self) {}
 
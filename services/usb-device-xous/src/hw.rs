@@ -2,12 +2,14 @@ use susres::{ManagedMem, SuspendResume};
 use usb_device::bus::PollResult;
 use utralib::generated::*;
 use crate::*;
-use core::sync::atomic::{AtomicPtr, Ordering, AtomicUsize, AtomicU16};
+use core::sync::atomic::{AtomicPtr, Ordering, AtomicUsize, AtomicU16, AtomicU32};
 use std::sync::{Arc, Mutex};
 use usb_device::{class_prelude::*, Result, UsbDirection};
 use std::collections::BTreeMap;
 
 const WRITE_TIMEOUT_MS: u64 = 1000;
+/// sentinel for `iso_last_frame` indicating no packet has been scheduled since the last reset
+const ISO_UNSCHEDULED: u32 = u32::MAX;
 
 fn handle_usb(_irq_no: usize, arg: *mut usize) {
     let usb = unsafe { &mut *(arg as *mut SpinalUsbDevice) };
@@ -27,6 +29,14 @@ pub struct SpinalUsbMgmt {
     eps: AtomicPtr<UdcEpStatus>,
     srmem: ManagedMem<{ utralib::generated::HW_USBDEV_MEM_LEN / core::mem::size_of::<u32>() }>,
     regs: SpinalUdcRegs,
+    // register state captured on suspend, so an enumerated device doesn't have to re-enumerate on resume
+    saved_regs: UdcSavedRegs,
+    // shared with the SpinalUsbDevice that maintains it
+    stats: Arc::<Mutex::<UsbStats>>,
+    // shared with the SpinalUsbDevice that captures them
+    descriptors: Arc::<Mutex::<DescriptorLog>>,
+    // shared with the SpinalUsbDevice that applies it
+    country_code: Arc::<Mutex::<CountryCodePatch>>,
 }
 impl SpinalUsbMgmt {
     #[allow(dead_code)]
@@ -48,13 +58,13 @@ impl SpinalUsbMgmt {
                 }
             }
         }
-        log::trace!("frame id: {}", self.regs.frame_id());
-        log::debug!("usb addr: {}", self.regs.address());
-        log::debug!("ints: {:x?}", self.regs.interrupts());
-        log::trace!("halt: 0x{:x?}", self.regs.halt());
-        log::trace!("config: 0x{:x?}", self.regs.config());
-        log::trace!("ramsize: {}", self.regs.ramsize());
-        assert!(4096 == self.regs.ramsize(), "hardware ramsize parameter does not match our expectations");
+        let snapshot = self.dump_regs();
+        log::debug!("udc regs:\n{}", snapshot);
+        assert!(4096 == snapshot.ramsize, "hardware ramsize parameter does not match our expectations");
+    }
+    /// Decoded copy of the UDC register bank, for diagnostics
+    pub fn dump_regs(&self) -> SpinalUdcRegsSnapshot {
+        self.regs.dump_regs()
     }
     pub fn connect_device_core(&mut self, state: bool) {
         log::trace!("previous state: {}", self.csr.rf(utra::usbdev::USBSELECT_USBSELECT));
@@ -90,14 +100,44 @@ impl SpinalUsbMgmt {
     pub fn xous_suspend(&mut self) {
         self.csr.wo(utra::usbdev::EV_PENDING, 0xFFFF_FFFF);
         self.csr.wo(utra::usbdev::EV_ENABLE, 0x0);
+        self.saved_regs = self.regs.save();
         self.srmem.suspend();
     }
     pub fn xous_resume(&mut self) {
         self.srmem.resume();
+        self.regs.restore(&self.saved_regs);
         let p = self.csr.r(utra::usbdev::EV_PENDING); // this has to be expanded out because AtomicPtr is potentially mutable on read
         self.csr.wo(utra::usbdev::EV_PENDING, p); // clear in case it's pending for some reason
         self.csr.wfo(utra::usbdev::EV_ENABLE_USB, 1);
     }
+    /// Lets go of the bus for good, when the server quits: the device drops off the bus, and stops
+    /// interrupting. The memory mappings go away with the process.
+    pub fn release_hardware(&mut self) {
+        self.csr.wo(utra::usbdev::EV_ENABLE, 0x0);
+        self.csr.wo(utra::usbdev::EV_PENDING, 0xFFFF_FFFF);
+        let mut cfg = UdcConfig(0);
+        cfg.set_pullup_off(true);
+        cfg.set_disable_ints(true);
+        self.regs.set_config(cfg);
+    }
+    pub fn stats(&self) -> UsbStats {
+        *self.stats.lock().unwrap()
+    }
+    pub fn reset_stats(&self) {
+        *self.stats.lock().unwrap() = UsbStats::default();
+    }
+    pub fn frame_number(&self) -> u16 {
+        self.regs.frame_number()
+    }
+    /// The bytes of a descriptor as last sent to the host; see `DescriptorLog::get()`
+    pub fn descriptor(&self, kind: u8, index: u8) -> Result<Vec<u8>, xous::Error> {
+        self.descriptors.lock().unwrap().get(kind, index).map(|bytes| bytes.to_vec())
+    }
+    /// Gives HID interface `interface` the country code `code` the next time the host reads the
+    /// configuration; see `CountryCodePatch`
+    pub fn set_hid_country_code(&self, interface: u8, code: u8) {
+        self.country_code.lock().unwrap().set(interface, code);
+    }
     #[allow(dead_code)]
     pub fn descriptor_from_status(&self, ep_status: &UdcEpStatus) -> SpinalUdcDescriptor {
         SpinalUdcDescriptor::new(
@@ -124,7 +164,8 @@ pub struct SpinalUsbDevice {
     eps: AtomicPtr<UdcEpStatus>,
     // tracks which endpoints have been allocated. ep0 is special. parameter is the maximum size buffer available.
     // parameter is (address, len)
-    ep_allocs: [Option<(usize, usize)>; 16],
+    // behind a mutex so endpoints can be disabled once the bus is in use
+    ep_allocs: Mutex<[Option<(usize, usize)>; 16]>,
     // record a copy of the ep0 IN setup descriptor address - could extract from ep_allocs[0], but it's here for legacy reasons
     ep0in_head: u32,
     // structure to track space allocations within the memory space
@@ -133,6 +174,25 @@ pub struct SpinalUsbDevice {
     address: AtomicUsize,
     // bit vector to track if a read is allowed. This prevents a race condition between polled reads and interrupted reads.
     read_allowed: AtomicU16,
+    // period in frames of isochronous endpoints; 0 means the endpoint is not isochronous
+    iso_periods: [u32; NUM_ENDPOINTS],
+    // frame number on which the last isochronous packet was scheduled, or ISO_UNSCHEDULED
+    iso_last_frame: [AtomicU32; NUM_ENDPOINTS],
+    // when set, bulk IN endpoints are allocated with two buffers that are used in alternation, so the
+    // next packet can be staged while the current one drains. Trades descriptor memory for throughput.
+    double_buffer_bulk: bool,
+    // offset of the second buffer of a double-buffered endpoint, in 16-byte units
+    ep_double_buf: [Option<usize>; NUM_ENDPOINTS],
+    // bit vector that selects which of the two buffers of a double-buffered endpoint gets the next packet
+    ep_buf_select: AtomicU16,
+    // extra descriptor regions allocated for the current chained transfer on each endpoint, as byte offsets
+    ep_chains: Mutex<[Vec<u32>; NUM_ENDPOINTS]>,
+    // bus event counters, for debugging enumeration problems
+    stats: Arc::<Mutex::<UsbStats>>,
+    // descriptors as they were sent to the host, also for debugging enumeration problems
+    descriptors: Arc::<Mutex::<DescriptorLog>>,
+    // the HID country code of the keyboard profile, set in the configuration as it goes out
+    country_code: Arc::<Mutex::<CountryCodePatch>>,
 }
 impl SpinalUsbDevice {
     pub fn new(sid: xous::SID) -> SpinalUsbDevice {
@@ -165,17 +225,32 @@ impl SpinalUsbDevice {
                     (usb.as_mut_ptr().add(0x00) as *mut UdcEpStatus).as_mut().unwrap()
             }),
             ep0in_head: 0,
-            ep_allocs: [None; 16],
+            ep_allocs: Mutex::new([None; 16]),
             allocs: Arc::new(Mutex::new(BTreeMap::new())),
             tt: ticktimer_server::Ticktimer::new().unwrap(),
             address: AtomicUsize::new(0),
             read_allowed: AtomicU16::new(0),
+            iso_periods: [0; NUM_ENDPOINTS],
+            iso_last_frame: Default::default(),
+            double_buffer_bulk: cfg!(feature = "bulk-double-buffer"),
+            ep_double_buf: [None; NUM_ENDPOINTS],
+            ep_buf_select: AtomicU16::new(0),
+            ep_chains: Mutex::new(Default::default()),
+            stats: Arc::new(Mutex::new(UsbStats::default())),
+            descriptors: Arc::new(Mutex::new(DescriptorLog::new())),
+            country_code: Arc::new(Mutex::new(CountryCodePatch::new())),
         };
+        for last in usbdev.iso_last_frame.iter() {
+            last.store(ISO_UNSCHEDULED, Ordering::SeqCst);
+        }
 
-        xous::claim_interrupt(
+        // USB transfers have tight turnaround deadlines, so service this
+        // ahead of any other interrupt that happens to be pending.
+        xous::claim_interrupt_with_priority(
             utra::usbdev::USBDEV_IRQ,
             handle_usb,
             (&mut usbdev) as *mut SpinalUsbDevice as *mut usize,
+            xous::IRQ_PRIORITY_DEFAULT + 1,
         )
         .expect("couldn't claim irq");
         let p = usbdev.csr.r(utra::usbdev::EV_PENDING);
@@ -197,6 +272,10 @@ impl SpinalUsbDevice {
             }),
             srmem: ManagedMem::new(self.usb),
             regs: self.regs.clone(),
+            saved_regs: UdcSavedRegs::default(),
+            stats: self.stats.clone(),
+            descriptors: self.descriptors.clone(),
+            country_code: self.country_code.clone(),
         }
     }
     fn print_poll_result(&self, poll_result: &PollResult) {
@@ -254,7 +333,7 @@ impl SpinalUsbDevice {
         log::info!("{}", eps);
     }
     /// simple but easy to understand allocator for buffers inside the descriptor memory space
-    /// See notes inside src/main.rs `alloc_inner` for the functional description. Returns
+    /// See notes inside src/allocator.rs `alloc_inner` for the functional description. Returns
     /// the full byte-addressed offset of the region, so it must be shifted to the right by
     /// 4 before being put into a SpinalHDL descriptor (it uses 16-byte alignment and thus
     /// discards the lower 4 bits).
@@ -273,6 +352,12 @@ impl SpinalUsbDevice {
             ) as *mut u32}
         )
     }
+    /// `offset` is in 16-byte units, as used by the descriptor and endpoint status fields
+    pub(crate) fn descriptor_from_offset(&self, offset: usize) -> SpinalUdcDescriptor {
+        SpinalUdcDescriptor::new(
+            unsafe{ self.usb.as_mut_ptr().add(offset * 16) as *mut u32 }
+        )
+    }
     /// A dedicated, fixed descriptor that represents EP0 acting as the 0-length OUT to accept the acknowledgement
     /// of IN data write complete. The location of this is at the very top of descriptor space.
     pub(crate) fn descriptor_ep0_out(&self) -> SpinalUdcDescriptor {
@@ -293,6 +378,10 @@ impl SpinalUsbDevice {
         ep0_out_desc.set_next_desc_and_len(0, 0);
         ep0_out_desc.set_desc_flags(UsbDirection::Out, true, true, true);
     }
+    /// The head offset and maximum packet size of an allocated endpoint
+    pub(crate) fn ep_alloc(&self, index: usize) -> Option<(usize, usize)> {
+        self.ep_allocs.lock().unwrap()[index]
+    }
     pub(crate) fn status_read_volatile(&self, index: usize) -> UdcEpStatus {
         unsafe {
             self.eps.load(Ordering::SeqCst).add(index).read_volatile()
@@ -317,6 +406,172 @@ impl SpinalUsbDevice {
     pub(crate) fn udc_hard_unhalt(&self, index: usize) {
         self.regs.set_halt(UdcHalt(index as u32));
     }
+    /// Write path for double-buffered IN endpoints. The packet goes into whichever of the two buffers
+    /// is next in rotation, and is then either handed directly to the hardware (if the endpoint is idle),
+    /// or chained behind the packet that is currently going out via the descriptor's next pointer.
+    fn write_double_buffered(&self, index: usize, offsets: [usize; 2], max_len: usize, buf: &[u8]) -> Result<usize> {
+        let select = ((self.ep_buf_select.load(Ordering::SeqCst) >> index) & 1) as usize;
+        let (fill_offset, other_offset) = (offsets[select], offsets[select ^ 1]);
+        let fill = self.descriptor_from_offset(fill_offset);
+        let other = self.descriptor_from_offset(other_offset);
+
+        // a buffer can only go from busy to free behind our back, so it's safe to check this without halting
+        let now = self.tt.elapsed_ms();
+        while !double_buffer_free(self.status_read_volatile(index).head_offset() as usize,
+            fill_offset, other_offset, other.next_descriptor_addr()) {
+            if self.tt.elapsed_ms() - now >= WRITE_TIMEOUT_MS {
+                log::warn!("ep{} double buffer still full even after waiting", index);
+                return Err(UsbError::WouldBlock);
+            }
+            xous::yield_slice();
+        }
+        fill.write_payload(buf);
+        fill.set_next_desc_and_len(0, buf.len());
+        fill.set_desc_flags(UsbDirection::In, false, true, false);
+        fill.set_offset(0); // also sets in_progress
+
+        // link it in with the endpoint halted, so the head can't move while we decide where it goes
+        self.udc_hard_halt(index);
+        let mut ep_status = self.status_read_volatile(index);
+        queue_in_descriptor(&mut ep_status, fill_offset, &other, other_offset);
+        ep_status.set_max_packet_size(max_len as _);
+        self.status_write_volatile(index, ep_status);
+        self.udc_hard_unhalt(index);
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+
+        let selects = self.ep_buf_select.load(Ordering::SeqCst);
+        self.ep_buf_select.store(selects ^ (1 << index as u16), Ordering::SeqCst);
+        Ok(buf.len())
+    }
+    /// Writes a transfer that spans multiple packets to an IN endpoint. The data is split into
+    /// `max_packet_size` pieces, each in its own descriptor, and the descriptors are linked so that
+    /// the hardware walks the chain without CPU intervention; only the last one fires an interrupt.
+    /// Transfers that are an exact multiple of `max_packet_size` are terminated with a zero-length packet.
+    /// The first packet goes into the endpoint's own buffer. The descriptors for the rest are
+    /// allocated on demand, and released on the next chained write to the endpoint or on a bus reset.
+    /// `write()` hands transfers that don't fit in one packet to this.
+    pub fn write_chained(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let index = ep_addr.index();
+        let (head_offset, max_len) = match self.ep_alloc(index) {
+            Some(alloc) if index != 0 && ep_addr.direction() == UsbDirection::In => alloc,
+            _ => return Err(UsbError::InvalidEndpoint),
+        };
+        // a short transfer fits in a single packet, and terminates itself
+        if buf.len() < max_len {
+            return self.write(ep_addr, buf);
+        }
+        let now = self.tt.elapsed_ms();
+        while self.status_read_volatile(index).head_offset() != 0 {
+            if self.tt.elapsed_ms() - now >= WRITE_TIMEOUT_MS {
+                log::warn!("ep{} previous transfer still pending even after waiting", index);
+                return Err(UsbError::WouldBlock);
+            }
+            xous::yield_slice();
+        }
+        // the previous chain has fully drained, so its descriptors can be recycled
+        self.release_chains(Some(index));
+
+        let mut chain = vec![(head_offset, self.descriptor_from_offset(head_offset))];
+        {
+            let mut chains = self.ep_chains.lock().unwrap();
+            let mut allocs = self.allocs.lock().unwrap();
+            for _ in 1..packets_in_transfer(buf.len(), max_len) {
+                if let Some(offset) = alloc_inner(&mut allocs, max_len as u32) {
+                    chains[index].push(offset);
+                    chain.push((offset as usize / 16, self.descriptor_from_offset(offset as usize / 16)));
+                } else {
+                    log::warn!("out of descriptor memory for a {}-byte transfer on ep{}", buf.len(), index);
+                    for offset in chains[index].drain(..) {
+                        dealloc_inner(&mut allocs, offset);
+                    }
+                    return Err(UsbError::EndpointMemoryOverflow);
+                }
+            }
+        }
+        program_in_chain(&chain, buf, max_len);
+
+        let mut ep_status = self.status_read_volatile(index);
+        ep_status.set_max_packet_size(max_len as _);
+        ep_status.set_head_offset(head_offset as u32);
+        self.status_write_volatile(index, ep_status);
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+        Ok(buf.len())
+    }
+    /// Takes an endpoint out of service: the hardware stops serving it, and its packet memory goes
+    /// back to the allocator, as if it had never been allocated. Using it again takes a fresh
+    /// `alloc_ep()`. Disabling an endpoint that isn't allocated does nothing.
+    ///
+    /// ep0 carries the control pipe, and can't be disabled: that's an `InvalidEndpoint`.
+    pub fn disable_ep(&self, ep_addr: EndpointAddress) -> Result<()> {
+        let index = ep_addr.index();
+        if index == 0 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        if self.ep_alloc(index).is_none() {
+            return Ok(());
+        }
+        self.udc_hard_halt(index);
+        let mut ep_status = self.status_read_volatile(index);
+        ep_status.set_enable(false);
+        ep_status.set_head_offset(0);
+        self.status_write_volatile(index, ep_status);
+        self.udc_hard_unhalt(index);
+        // nothing can be in flight on it any more
+        self.release_chains(Some(index));
+        free_ep_slot(
+            &mut *self.ep_allocs.lock().unwrap(),
+            index,
+            self.ep_double_buf[index],
+            &mut self.allocs.lock().unwrap(),
+        );
+        log::info!("disabled ep{}", index);
+        Ok(())
+    }
+    /// Puts an allocated endpoint that the hardware isn't serving back into service. An endpoint
+    /// freed by `disable_ep()` has to be allocated afresh instead: that's an `InvalidEndpoint`.
+    pub fn enable_ep(&self, ep_addr: EndpointAddress) -> Result<()> {
+        let index = ep_addr.index();
+        if self.ep_alloc(index).is_none() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let mut ep_status = self.status_read_volatile(index);
+        if !ep_status.enable() {
+            ep_status.set_enable(true);
+            self.status_write_volatile(index, ep_status);
+        }
+        Ok(())
+    }
+    /// Holds off the host on an OUT endpoint: while `nack` is set, whatever the host sends to it
+    /// is NACKed rather than taken, so a class whose buffer is full can push back until it has
+    /// room. Unlike a STALL, the host just retries. Any STALL on the endpoint is left as it was.
+    ///
+    /// An IN endpoint already NACKs until data is queued on it, and ep0 carries the control pipe,
+    /// so either is an `InvalidEndpoint`, as is an endpoint that isn't allocated.
+    pub fn set_nack(&self, ep_addr: EndpointAddress, nack: bool) -> Result<()> {
+        let index = ep_addr.index();
+        if !nackable(ep_addr, self.ep_alloc(index).is_some()) {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        self.udc_hard_halt(index);
+        let mut ep_status = self.status_read_volatile(index);
+        apply_nack(&mut ep_status, nack);
+        self.status_write_volatile(index, ep_status);
+        self.udc_hard_unhalt(index);
+        Ok(())
+    }
+    /// Returns the descriptor regions of chained transfers to the allocator, either for just
+    /// one endpoint, or for all of them.
+    fn release_chains(&self, index: Option<usize>) {
+        let mut chains = self.ep_chains.lock().unwrap();
+        let mut allocs = self.allocs.lock().unwrap();
+        for (i, chain) in chains.iter_mut().enumerate() {
+            if index.is_none() || index == Some(i) {
+                for offset in chain.drain(..) {
+                    dealloc_inner(&mut allocs, offset);
+                }
+            }
+        }
+    }
     pub(crate) fn get_setup(&self) -> [u8; 8] {
         let mut setup = [0u8; 8];
         let setup_data_base = unsafe{self.usb.as_ptr().add(0x40) as *const u32};
@@ -348,19 +603,21 @@ impl UsbBus for SpinalUsbDevice {
     /// # Errors
     ///
     /// * [`EndpointOverflow`](crate::UsbError::EndpointOverflow) - Available total number of
-    ///   endpoints, endpoints of the specified type, or endpoind packet memory has been exhausted.
-    ///   This is generally caused when a user tries to add too many classes to a composite device.
+    ///   endpoints has been exhausted. This is generally caused when a user tries to add too many
+    ///   classes to a composite device.
+    /// * [`EndpointMemoryOverflow`](crate::UsbError::EndpointMemoryOverflow) - There's a free
+    ///   endpoint, but not enough packet memory left for `max_packet_size`, or `max_packet_size` is
+    ///   more than the UDC RAM could ever hold.
     /// * [`InvalidEndpoint`](crate::UsbError::InvalidEndpoint) - A specific `ep_addr` was specified
-    ///   but the endpoint in question has already been allocated.
+    ///   but the endpoint in question has already been allocated, or doesn't exist.
     fn alloc_ep(
         &mut self,
         ep_dir: UsbDirection,
         ep_addr: Option<EndpointAddress>,
         ep_type: EndpointType,
         max_packet_size: u16,
-        _interval: u8,
+        interval: u8,
     ) -> Result<EndpointAddress> {
-        // if ep_addr is specified, create a 1-unit range else a range through the entire space
         // note that ep_addr is a packed representation of index and direction,
         // so you must use `.index()` to get just the index part
         log::debug!("alloc ep spec: {:?} of type {:?} dir {:?}", ep_addr, ep_type, ep_dir);
@@ -370,85 +627,99 @@ impl UsbBus for SpinalUsbDevice {
             self.ep0_out_reset();
             return Ok(EndpointAddress::from_parts(0, UsbDirection::Out))
         }
-        for index in ep_addr.map(|a| a.index()..a.index() + 1).unwrap_or(1..NUM_ENDPOINTS) {
-            if self.ep_allocs[index].is_none() {
-                // only if there is memory that can accommodate the max_packet_size
-                if let Some(offset) = self.alloc_region(max_packet_size as _) {
-                    log::debug!("allocated offset {:x}({})", offset, max_packet_size);
-                    let mut ep_status = UdcEpStatus(0);
-                    match ep_type {
-                        EndpointType::Isochronous => ep_status.set_isochronous(true),
-                        _ => ep_status.set_isochronous(false),
-                    }
-                    log::debug!("alloc ep{}@{:x?}{} max_packet_size {}",
-                        index,
-                        offset,
-                        match ep_dir {
-                            UsbDirection::In => "IN",
-                            UsbDirection::Out => "OUT",
-                        },
-                        max_packet_size
-                    );
-                    ep_status.set_head_offset(offset / 16);
-                    ep_status.set_max_packet_size(max_packet_size as u32);
-                    ep_status.set_enable(true);
-                    if index == 0 {
-                        ep_status.set_data_phase(true); // ep0 IN always responds on data phase 1
-                    }
-
-                    // setup descriptors from the yet-to-be-written ep config
-                    let descriptor = self.descriptor_from_status(&ep_status);
-                    descriptor.set_offset_only(0);
-                    descriptor.set_next_desc_and_len(0, max_packet_size as _);
-                    descriptor.set_desc_flags(
-                        ep_dir,
-                        ep_dir == UsbDirection::Out,
-                        true, // this should be equal to "packet_end", but this driver doesn't have that...?
-                        index == 0, // only trigger for ep0 (per spinal linux driver)
-                    );
-                    // clear the descriptor to 0
-                    let init_vec = vec![0u8; max_packet_size as _];
-                    for (index, src) in init_vec.chunks_exact(4).enumerate() {
-                        let w = u32::from_le_bytes(src.try_into().unwrap());
-                        descriptor.write_data(index, w);
-                    }
-                    if init_vec.len() % 4 != 0 { // handle the odd remainder case
-                        let mut remainder = [0u8; 4];
-                        for (index, &src) in init_vec.chunks_exact(4).remainder().iter().enumerate() {
-                            remainder[index] = src;
-                        }
-                        descriptor.write_data(init_vec.len() / 4, u32::from_le_bytes(remainder));
-                    }
-
-                    if index != 0 { // disable tranmission on the In descriptor as there's nothing to send
-                        if ep_dir == UsbDirection::In {
-                            ep_status.set_head_offset(0);
-                        }
-                    }
+        if ep_addr == Some(EndpointAddress::from_parts(0, UsbDirection::In)) && self.ep_alloc(0).is_some() {
+            // the device is being rebuilt (e.g. with a new identity); the control pipe carries over as-is
+            log::debug!("ep0 already allocated, reusing it");
+            return Ok(EndpointAddress::from_parts(0, UsbDirection::In))
+        }
+        // a packet bigger than the UDC RAM can never be placed, whatever else is allocated
+        check_max_packet_size(self.regs.ramsize(), max_packet_size as u32)?;
+        // ep0 is never double buffered; a slot picked for us is never ep0
+        let double_buffered = self.double_buffer_bulk
+            && ep_type == EndpointType::Bulk
+            && ep_dir == UsbDirection::In
+            && ep_addr.map(|a| a.index()) != Some(0);
+        let (index, offset, alt_offset) = alloc_ep_slot(
+            &*self.ep_allocs.lock().unwrap(),
+            ep_addr.map(|a| a.index()),
+            &mut self.allocs.lock().unwrap(),
+            max_packet_size as u32,
+            double_buffered,
+        )?;
+        log::debug!("allocated offset {:x}({})", offset, max_packet_size);
+        let mut ep_status = UdcEpStatus(0);
+        match ep_type {
+            EndpointType::Isochronous => {
+                ep_status.set_isochronous(true);
+                // isochronous endpoints get one packet every `period` frames
+                self.iso_periods[index] = iso_period(interval);
+            }
+            _ => {
+                ep_status.set_isochronous(false);
+                self.iso_periods[index] = 0;
+            }
+        }
+        log::debug!("alloc ep{}@{:x?}{} max_packet_size {}",
+            index,
+            offset,
+            match ep_dir {
+                UsbDirection::In => "IN",
+                UsbDirection::Out => "OUT",
+            },
+            max_packet_size
+        );
+        ep_status.set_head_offset(offset / 16);
+        ep_status.set_max_packet_size(max_packet_size as u32);
+        ep_status.set_enable(true);
+        if index == 0 {
+            ep_status.set_data_phase(true); // ep0 IN always responds on data phase 1
+        }
 
-                    if index == 0 {
-                        // stash a copy of the ep0 IN head location, because the SETUP packet resets this to 0
-                        self.ep0in_head = ep_status.head_offset();
-                    }
+        // setup descriptors from the yet-to-be-written ep config
+        let descriptor = self.descriptor_from_status(&ep_status);
+        descriptor.set_offset_only(0);
+        descriptor.set_next_desc_and_len(0, max_packet_size as _);
+        descriptor.set_desc_flags(
+            ep_dir,
+            ep_dir == UsbDirection::Out,
+            true, // this should be equal to "packet_end", but this driver doesn't have that...?
+            index == 0, // only trigger for ep0 (per spinal linux driver)
+        );
+        // clear the descriptor to 0
+        let init_vec = vec![0u8; max_packet_size as _];
+        descriptor.write_payload(&init_vec);
 
-                    // now commit the ep config
-                    self.status_write_volatile(index, ep_status);
-                    self.ep_allocs[index] = Some((offset as usize / 16, max_packet_size as usize));
+        if let Some(alt_offset) = alt_offset {
+            let alt = self.descriptor_from_offset(alt_offset as usize / 16);
+            alt.set_offset_only(0);
+            alt.set_next_desc_and_len(0, max_packet_size as _);
+            alt.set_desc_flags(ep_dir, false, true, false);
+            alt.write_payload(&init_vec);
+            self.ep_double_buf[index] = Some(alt_offset as usize / 16);
+            log::debug!("ep{} double buffered @{:x}", index, alt_offset);
+        } else {
+            self.ep_double_buf[index] = None;
+        }
 
-                    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
-                    log::info!("alloc ep{} type {:?} dir {:?}, @{:x}({})",
-                        index, ep_type, ep_dir, offset, max_packet_size);
-                    return Ok(EndpointAddress::from_parts(index as usize, ep_dir))
-                } else {
-                    return Err(UsbError::EndpointMemoryOverflow);
-                }
+        if index != 0 { // disable tranmission on the In descriptor as there's nothing to send
+            if ep_dir == UsbDirection::In {
+                ep_status.set_head_offset(0);
             }
         }
-        // nothing matched, so there must be an error
-        Err(match ep_addr {
-            Some(_) => UsbError::InvalidEndpoint,
-            None => UsbError::EndpointOverflow,
-        })
+
+        if index == 0 {
+            // stash a copy of the ep0 IN head location, because the SETUP packet resets this to 0
+            self.ep0in_head = ep_status.head_offset();
+        }
+
+        // now commit the ep config
+        self.status_write_volatile(index, ep_status);
+        self.ep_allocs.lock().unwrap()[index] = Some((offset as usize / 16, max_packet_size as usize));
+
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+        log::info!("alloc ep{} type {:?} dir {:?}, @{:x}({})",
+            index, ep_type, ep_dir, offset, max_packet_size);
+        Ok(EndpointAddress::from_parts(index as usize, ep_dir))
     }
 
     /// Enables and initializes the USB peripheral. Soon after enabling the device will be reset, so
@@ -474,8 +745,16 @@ impl UsbBus for SpinalUsbDevice {
         log::info!("USB reset");
         self.regs.set_address(0x0); // this does *not* require the trigger
         self.address.store(0, Ordering::SeqCst);
+        for last in self.iso_last_frame.iter() {
+            last.store(ISO_UNSCHEDULED, Ordering::SeqCst);
+        }
+        // double buffered endpoints start over on their first buffer
+        self.ep_buf_select.store(0, Ordering::SeqCst);
+        // any chained transfers in flight are abandoned by the reset
+        self.release_chains(None);
         self.ep0_out_reset();
-        for (index, &ep) in self.ep_allocs.iter().enumerate() {
+        let ep_allocs = *self.ep_allocs.lock().unwrap();
+        for (index, &ep) in ep_allocs.iter().enumerate() {
             if let Some((head_offset, max_len)) = ep {
                 if index == 0 {
                     log::trace!("ep0 reset");
@@ -508,7 +787,7 @@ impl UsbBus for SpinalUsbDevice {
         }
         if false {
             // Config confirmation for debug (change above to `true`)
-            for (index, &ep) in self.ep_allocs.iter().enumerate() {
+            for (index, &ep) in ep_allocs.iter().enumerate() {
                 if let Some((head_offset, _max_len)) = ep {
                     let mut ep_status = self.status_read_volatile(index);
                     ep_status.set_head_offset(head_offset as u32);
@@ -551,11 +830,32 @@ impl UsbBus for SpinalUsbDevice {
     ///   the endpoint.
     ///
     /// Implementations may also return other errors if applicable.
+    ///
+    /// On this bus, a buffer longer than `max_packet_size` written to a plain (not isochronous, not
+    /// double-buffered) IN endpoint other than ep0 goes out as a multi-packet transfer, via
+    /// `write_chained()`, instead of being a `BufferOverflow`.
     fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
-        if let Some((head_offset, max_len)) = self.ep_allocs[ep_addr.index()] {
+        if let Some((head_offset, max_len)) = self.ep_alloc(ep_addr.index()) {
             if buf.len() > max_len {
-                Err(UsbError::BufferOverflow)
+                if ep_addr.index() != 0
+                && self.iso_periods[ep_addr.index()] == 0
+                && self.ep_double_buf[ep_addr.index()].is_none() {
+                    self.write_chained(ep_addr, buf)
+                } else {
+                    Err(UsbError::BufferOverflow)
+                }
+            } else if let Some(alt_offset) = self.ep_double_buf[ep_addr.index()] {
+                self.write_double_buffered(ep_addr.index(), [head_offset, alt_offset], max_len, buf)
             } else {
+                // isochronous endpoints may only have one packet scheduled per service period
+                let period = self.iso_periods[ep_addr.index()];
+                let frame = self.regs.frame_id() & FRAME_MASK;
+                if period != 0 {
+                    let last = self.iso_last_frame[ep_addr.index()].load(Ordering::SeqCst);
+                    if !iso_frame_due(if last == ISO_UNSCHEDULED { None } else { Some(last) }, frame, period) {
+                        return Err(UsbError::WouldBlock);
+                    }
+                }
                 #[cfg(feature="mjolnir")] // mjolnir is so powerful, one must halt the USB core entirely for it to be weilded
                 if ep_addr.index() == 1 { self.udc_hard_halt(ep_addr.index()); }
                 let mut ep_status = self.status_read_volatile(ep_addr.index());
@@ -597,30 +897,36 @@ impl UsbBus for SpinalUsbDevice {
                     descriptor.set_desc_flags(UsbDirection::In,
                         true, true, false);
                 }
-                for (index, src) in buf.chunks_exact(4).enumerate() {
-                    let w = u32::from_le_bytes(src.try_into().unwrap());
-                    descriptor.write_data(index, w);
-                }
-                if buf.len() % 4 != 0 { // handle the odd remainder case
-                    let mut remainder = [0u8; 4];
-                    for (index, &src) in buf.chunks_exact(4).remainder().iter().enumerate() {
-                        remainder[index] = src;
-                    }
-                    descriptor.write_data(buf.len() / 4, u32::from_le_bytes(remainder));
+                if ep_addr.index() == 0 {
+                    // patched before it's logged, so the log has what the host saw
+                    let mut packet = buf.to_vec();
+                    self.country_code.lock().unwrap().data_in(&mut packet);
+                    descriptor.write_payload(&packet);
+                    self.descriptors.lock().unwrap().data_in(&packet, max_len);
+                } else {
+                    descriptor.write_payload(buf);
                 }
 
                 ep_status.set_max_packet_size(max_len as _);
                 descriptor.set_next_desc_and_len(0, buf.len());
                 if ep_addr.index() != 0 {
+                    // iso packets are always exactly one descriptor, so completion happens when it is full
                     descriptor.set_desc_flags(UsbDirection::In,
                         false, true, false);
                 }
+                if period != 0 {
+                    // full speed isochronous transfers always use DATA0
+                    ep_status.set_data_phase(false);
+                }
                 descriptor.set_offset(0); // reset the write pointer to 0, also sets in_progress
                 //if ep_addr.index() != 0 {
                 //    log::info!("WR PREdesc{}: {:?}", ep_addr.index(), descriptor);
                 //}
                 // this is required to commit the ep_status record once all the setup is done
                 self.status_write_volatile(ep_addr.index(), ep_status);
+                if period != 0 {
+                    self.iso_last_frame[ep_addr.index()].store(frame, Ordering::SeqCst);
+                }
 
                 #[cfg(feature="mjolnir")]
                 { // mjolnir is so powerful, one must halt the USB core entirely for it to be weilded
@@ -668,7 +974,7 @@ impl UsbBus for SpinalUsbDevice {
     /// Implementations may also return other errors if applicable.
     fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
         log::trace!("read ep{} into buf of len {}", ep_addr.index(), buf.len());
-        if let Some((head_offset, max_len)) = self.ep_allocs[ep_addr.index()] {
+        if let Some((head_offset, max_len)) = self.ep_alloc(ep_addr.index()) {
             if ep_addr.index() == 0 {
                 if buf.len() == 0 {
                     log::info!("STATUS dummy read");
@@ -681,8 +987,11 @@ impl UsbBus for SpinalUsbDevice {
                     return Err(UsbError::BufferOverflow)
                 }
                 // setup data is in a special, fixed location
-                buf[..8].copy_from_slice(&self.get_setup());
+                let setup = self.get_setup();
+                buf[..8].copy_from_slice(&setup);
                 log::debug!("ep0 read: {:x?}", &buf[..8]);
+                self.descriptors.lock().unwrap().setup(&setup);
+                self.country_code.lock().unwrap().setup(&setup);
 
                 // this USB core automatically handles address set timing, so we intercept the
                 // address setup packet and jam it here with the "0x200" bit set which triggers
@@ -735,34 +1044,26 @@ impl UsbBus for SpinalUsbDevice {
                 // log::info!("head_offset{}: {:x}", ep_addr.index(), head_offset * 16);
                 ep_status.set_head_offset(head_offset as u32);
                 let descriptor = self.descriptor_from_status(&ep_status);
-                if descriptor.in_progress() || descriptor.offset() == 0 {
-                    // "early polls" happen because the main loop can be sloppy and request a read report at any time,
-                    // not just when there's an interrupt.
-                    // return before side-effecting any structures
-                    log::warn!("WouldBlock {:?}", ep_addr);
-                    return Err(UsbError::WouldBlock);
-                }
-                let mut len = descriptor.offset();
+                // A completed descriptor with an offset of 0 is a zero-length packet, which is a real packet
+                // and is reported as Ok(0). Only a descriptor that is still in progress means there is nothing to read.
+                let mut len = match out_packet_len(&descriptor) {
+                    Some(len) => len,
+                    None => {
+                        // "early polls" happen because the main loop can be sloppy and request a read report at any time,
+                        // not just when there's an interrupt.
+                        // return before side-effecting any structures
+                        log::warn!("WouldBlock {:?}", ep_addr);
+                        self.udc_hard_unhalt(ep_addr.index());
+                        return Err(UsbError::WouldBlock);
+                    }
+                };
                 if buf.len() < len {
                     log::error!("read ep{} overflows: {} < {}", ep_addr.index(), buf.len(), len);
                     // just return a truncated set of data
                     // return Err(UsbError::BufferOverflow)
                     len = buf.len();
                 }
-                for (index, dst) in buf[..len].chunks_exact_mut(4).enumerate() {
-                    let word = descriptor.read_data(index).to_le_bytes();
-                    dst.copy_from_slice(&word);
-                }
-                if len % 4 != 0 {
-                    // this will "overread" the descriptor area, but it's OK because descriptors must be aligned to 16-byte boundaries
-                    // so even if the length is odd, the space allocated will always include dummy padding which will keep us
-                    // from reading into neighboring data.
-                    let word = descriptor.read_data(len / 4).to_le_bytes();
-                    // write only into the portion of the buffer that's allocated, don't write the extra 0's
-                    for i in 0..len % 4 {
-                        buf[(len / 4) + i] = word[i]
-                    }
-                }
+                descriptor.read_payload(&mut buf[..len]);
 
                 // setup for the next transaction
                 ep_status.set_max_packet_size(max_len as _);
@@ -821,19 +1122,23 @@ impl UsbBus for SpinalUsbDevice {
             if ep_addr.index() != 0 {
                 log::info!("set_stalled ep{}->{} dir {:?}", ep_addr.index(), stalled, ep_addr.direction());
             }
+            if stalled {
+                let mut stats = self.stats.lock().unwrap();
+                stats.stalls[ep_addr.index()] = stats.stalls[ep_addr.index()].wrapping_add(1);
+            }
             self.udc_hard_halt(ep_addr.index());
             let mut ep_status = self.status_read_volatile(ep_addr.index());
-            match (stalled, ep_addr.direction()) {
-                (true, UsbDirection::In) => {
-                    ep_status.set_force_stall(true);
-                },
-                (true, UsbDirection::Out) => ep_status.set_force_stall(true),
-                (false, UsbDirection::In) => {
-                    ep_status.set_force_stall(false);
-                    ep_status.set_force_nack(true);
-                },
-                (false, UsbDirection::Out) => ep_status.set_force_stall(false),
-            };
+            apply_stall(&mut ep_status, ep_addr.index(), ep_addr.direction(), stalled);
+            if !stalled && ep_addr.index() != 0 && ep_addr.direction() == UsbDirection::Out {
+                // re-arm the OUT endpoint so it can receive again once the halt is cleared
+                if let Some((head_offset, max_len)) = self.ep_alloc(ep_addr.index()) {
+                    ep_status.set_head_offset(head_offset as u32);
+                    let descriptor = self.descriptor_from_offset(head_offset);
+                    descriptor.set_next_desc_and_len(0, max_len);
+                    descriptor.set_desc_flags(UsbDirection::Out, true, true, false);
+                    descriptor.set_offset_only(0);
+                }
+            }
             // single volatile commit of the results
             self.status_write_volatile(ep_addr.index(), ep_status);
             self.udc_hard_unhalt(ep_addr.index());
@@ -843,8 +1148,11 @@ impl UsbBus for SpinalUsbDevice {
     /// Gets whether the STALL condition is set for an endpoint.
     fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
         let ep_status = self.status_read_volatile(ep_addr.index());
-        log::info!("is_stalled{} -> {}", ep_addr.index(), ep_status.force_stall());
-        ep_status.force_stall()
+        // `apply_stall()` stalls both directions through `force_stall`. The NACK an IN endpoint
+        // is left with when its stall is cleared only holds off the host, and isn't a stall.
+        let stalled = ep_status.force_stall();
+        log::info!("is_stalled{} {:?} -> {}", ep_addr.index(), ep_addr.direction(), stalled);
+        stalled
     }
 
     /// Causes the USB peripheral to enter USB suspend mode, lowering power consumption and
@@ -892,7 +1200,7 @@ impl UsbBus for SpinalUsbDevice {
                             // (but don't write it back, since we're not ready to send anything --
                             // it will get written back on the next `write`)
                             ep_status.set_head_offset(self.ep0in_head);
-                        } else if let Some((head_offset, _max_len)) = self.ep_allocs[bit] {
+                        } else if let Some((head_offset, _max_len)) = self.ep_alloc(bit) {
                             if ep_status.head_offset() != 0 {
                                 log::warn!("got INT on ep{} but head is not 0", bit);
                             }
@@ -926,7 +1234,7 @@ impl UsbBus for SpinalUsbDevice {
             }
             PollResult::Data { ep_out, ep_in_complete, ep_setup: 0 }
         } else if interrupts.resume() {
-            ints_to_clear.set_reset(true);
+            ints_to_clear.set_resume(true);
             log::trace!("aft resume: {:x?}", interrupts.0);
             PollResult::Resume
         } else if interrupts.suspend() {
@@ -942,6 +1250,7 @@ impl UsbBus for SpinalUsbDevice {
         };
 
         log::debug!("clearing ints: {:x?}", ints_to_clear);
+        record_interrupts(&mut self.stats.lock().unwrap(), &ints_to_clear);
         self.regs.clear_some_interrupts(ints_to_clear);
         if self.regs.interrupts().0 == 0 {
             log::debug!("all interrupts done");
@@ -0,0 +1,162 @@
+// Only polled on real hardware, but the idle bookkeeping is kept free of hardware dependencies
+// so that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+
+/// HID class request code for GET_IDLE
+const HID_GET_IDLE: u8 = 0x02;
+/// HID class request code for SET_IDLE
+const HID_SET_IDLE: u8 = 0x0A;
+/// The idle rate is given in units of 4 ms
+const IDLE_UNIT_MS: u64 = 4;
+
+struct IdleEntry {
+    interface: u8,
+    /// In `IDLE_UNIT_MS` units; 0 means reports are only sent when they change
+    rate: u8,
+    /// When the last resend was due, or when the rate was set; `None` until the server next
+    /// looks at the time after a SET_IDLE
+    since: Option<u64>,
+}
+
+/// The idle rate the host has set with SET_IDLE on each interface whose report the server sends
+/// again from the `ReportCache`, and when it is next due to be sent. Interfaces that keep their
+/// own idle rate, like the keyboard, are left out, so they still answer GET_IDLE themselves.
+/// Times are the ticktimer's `elapsed_ms()`. Resends come at a steady cadence from when the rate
+/// was set; a report sent in between doesn't push the next one back, as a repeat the host has
+/// already seen is harmless.
+pub(crate) struct IdleRates {
+    entries: Vec<IdleEntry>,
+}
+
+impl IdleRates {
+    /// Tracks the interfaces in `interfaces`, each starting out only sending on change
+    pub fn new(interfaces: &[u8]) -> IdleRates {
+        IdleRates {
+            entries: interfaces.iter().map(|&interface| IdleEntry { interface, rate: 0, since: None }).collect(),
+        }
+    }
+    /// Takes the rate from a SET_IDLE. `false` if the interface isn't one of ours.
+    pub fn set_idle(&mut self, interface: u8, rate: u8) -> bool {
+        match self.entries.iter_mut().find(|entry| entry.interface == interface) {
+            Some(entry) => {
+                if entry.rate != rate {
+                    log::info!("idle rate for interface {}: {} ms", interface, rate as u64 * IDLE_UNIT_MS);
+                }
+                entry.rate = rate;
+                entry.since = None;
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn get_idle(&self, interface: u8) -> Option<u8> {
+        self.entries.iter().find(|entry| entry.interface == interface).map(|entry| entry.rate)
+    }
+    /// How long after `now` the next resend is due, or `None` if every interface only sends on
+    /// change. Rates set since the last call are counted from `now`.
+    pub fn schedule(&mut self, now: u64) -> Option<u64> {
+        self.entries
+            .iter_mut()
+            .filter(|entry| entry.rate != 0)
+            .map(|entry| {
+                let since = *entry.since.get_or_insert(now);
+                (since + entry.rate as u64 * IDLE_UNIT_MS).saturating_sub(now)
+            })
+            .min()
+    }
+    /// The interfaces whose report is due to be sent again at `now`. Each one's next resend
+    /// is then counted from `now`.
+    pub fn due(&mut self, now: u64) -> Vec<u8> {
+        let mut due = Vec::new();
+        for entry in self.entries.iter_mut().filter(|entry| entry.rate != 0) {
+            let since = *entry.since.get_or_insert(now);
+            if now >= since + entry.rate as u64 * IDLE_UNIT_MS {
+                entry.since = Some(now);
+                due.push(entry.interface);
+            }
+        }
+        due
+    }
+}
+
+/// Answers GET_IDLE, and notes the rate from each SET_IDLE, for the interfaces in the
+/// `IdleRates`. Polled ahead of the HID class; SET_IDLE is left for it to acknowledge, so the
+/// interface itself still hears about the new rate. Requests for other interfaces are left
+/// alone entirely.
+impl<B: UsbBus> UsbClass<B> for IdleRates {
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.request == HID_SET_IDLE
+        {
+            // the duration is in the high byte of wValue, the report ID in the low byte; none of
+            // the cached interfaces use report IDs, so the one rate covers them all
+            self.set_idle(req.index as u8, (req.value >> 8) as u8);
+        }
+    }
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.request == HID_GET_IDLE
+        {
+            if let Some(rate) = self.get_idle(req.index as u8) {
+                xfer.accept_with(&[rate]).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_report::ReportCache;
+
+    #[test]
+    fn test_idle_resend() {
+        let mut cache = ReportCache::new(&[0, 2]);
+        let mut idle = IdleRates::new(&[0, 2]);
+        cache.record(0, &[0x02, 0, 0x04, 0, 0, 0, 0, 0]);
+
+        // only on change, to start with
+        assert_eq!(idle.get_idle(0), Some(0));
+        assert_eq!(idle.schedule(0), None);
+        assert!(idle.due(10_000).is_empty());
+        // not one of ours
+        assert!(!idle.set_idle(1, 25));
+        assert_eq!(idle.get_idle(1), None);
+
+        // 100 ms: the cached report goes out again every 100 ms, and not in between
+        assert!(idle.set_idle(0, 25));
+        assert_eq!(idle.get_idle(0), Some(25));
+        assert_eq!(idle.schedule(1_000), Some(100));
+        let mut resent = Vec::new();
+        for now in (1_000..=1_500).step_by(10) {
+            for iface in idle.due(now) {
+                resent.push((now, cache.get_report(iface, 8).unwrap()));
+            }
+        }
+        assert_eq!(resent.iter().map(|(now, _)| *now).collect::<Vec<u64>>(), vec![1_100, 1_200, 1_300, 1_400, 1_500]);
+        assert!(resent.iter().all(|(_, report)| report == &vec![0x02, 0, 0x04, 0, 0, 0, 0, 0]));
+        assert_eq!(idle.schedule(1_520), Some(80));
+
+        // a late wakeup is counted from when it came, rather than catching up
+        assert_eq!(idle.due(1_750), vec![0]);
+        assert!(idle.due(1_800).is_empty());
+        assert_eq!(idle.due(1_850), vec![0]);
+
+        // the soonest of several rates is the one to wake for
+        assert!(idle.set_idle(2, 5));
+        assert_eq!(idle.schedule(1_860), Some(20));
+
+        // back to only on change
+        idle.set_idle(0, 0);
+        idle.set_idle(2, 0);
+        assert!(idle.due(100_000).is_empty());
+        assert_eq!(idle.schedule(100_000), None);
+    }
+}
@@ -0,0 +1,87 @@
+// Only used on real hardware, but kept free of hardware dependencies so that it can be tested in
+// hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use usbd_human_interface_device::page::Keyboard;
+
+/// The keypad key for `ch`, if the keypad has one. The digits and `.` only type as such with
+/// NumLock on.
+fn keypad_key(ch: char) -> Option<Keyboard> {
+    Some(match ch {
+        '0' => Keyboard::Keypad0,
+        '1' => Keyboard::Keypad1,
+        '2' => Keyboard::Keypad2,
+        '3' => Keyboard::Keypad3,
+        '4' => Keyboard::Keypad4,
+        '5' => Keyboard::Keypad5,
+        '6' => Keyboard::Keypad6,
+        '7' => Keyboard::Keypad7,
+        '8' => Keyboard::Keypad8,
+        '9' => Keyboard::Keypad9,
+        '.' => Keyboard::KeypadDot,
+        '+' => Keyboard::KeypadAdd,
+        '-' => Keyboard::KeypadSubtract,
+        '*' => Keyboard::KeypadMultiply,
+        '/' => Keyboard::KeypadDivide,
+        '=' => Keyboard::KeypadEqual,
+        _ => return None,
+    })
+}
+
+/// Whether `ch` goes through a keypad key that depends on NumLock in numeric mode
+pub(crate) fn needs_numlock(ch: char) -> bool {
+    ch.is_ascii_digit() || ch == '.'
+}
+
+/// The keys `send_str()` presses to type `ch`. In numeric mode, the digits and arithmetic
+/// operators go through the keypad; everything else, and everything outside numeric mode,
+/// comes from `char_map`, the user's keyboard layout.
+pub(crate) fn char_keys(ch: char, numeric: bool, char_map: impl Fn(char) -> Vec<Keyboard>) -> Vec<Keyboard> {
+    match keypad_key(ch) {
+        Some(key) if numeric => vec![key],
+        _ => char_map(ch),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_enum::FromPrimitive;
+
+    /// stands in for the US101 layout, for the characters the test types
+    fn us101(ch: char) -> Vec<Keyboard> {
+        match ch {
+            '0' => vec![Keyboard::Keyboard0],
+            '1'..='9' => vec![Keyboard::from_primitive(Keyboard::Keyboard1 as u8 + (ch as u8 - b'1'))],
+            '+' => vec![Keyboard::Equal, Keyboard::LeftShift],
+            '.' => vec![Keyboard::Dot],
+            'x' => vec![Keyboard::X],
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn test_numeric_mode() {
+        for ch in '0'..='9' {
+            let row = char_keys(ch, false, us101);
+            let keypad = char_keys(ch, true, us101);
+            assert_eq!(row, us101(ch));
+            assert_eq!(keypad.len(), 1);
+            assert_ne!(row, keypad, "{} typed the same in both modes", ch);
+            assert!(needs_numlock(ch));
+        }
+        assert_eq!(char_keys('0', true, us101), vec![Keyboard::Keypad0]);
+        assert_eq!(char_keys('9', true, us101), vec![Keyboard::Keypad9]);
+
+        // operators move to the keypad too, without a shift
+        assert_eq!(char_keys('+', false, us101), vec![Keyboard::Equal, Keyboard::LeftShift]);
+        assert_eq!(char_keys('+', true, us101), vec![Keyboard::KeypadAdd]);
+        assert!(!needs_numlock('+'));
+        assert_eq!(char_keys('.', true, us101), vec![Keyboard::KeypadDot]);
+        assert!(needs_numlock('.'));
+
+        // letters come from the layout either way
+        assert_eq!(char_keys('x', true, us101), vec![Keyboard::X]);
+        assert!(!needs_numlock('x'));
+    }
+}
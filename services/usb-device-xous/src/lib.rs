@@ -4,32 +4,170 @@ pub mod api;
 pub use api::*;
 use xous::{CID, send_message, Message};
 use num_traits::*;
+use num_enum::FromPrimitive as EnumFromPrimitive;
 pub use usb_device::device::UsbDeviceState;
 pub use usbd_human_interface_device::device::keyboard::KeyboardLedsReport;
 pub use usbd_human_interface_device::page::Keyboard as UsbKeyCode;
 use packed_struct::PackedStruct;
 use xous_ipc::Buffer;
 pub use usbd_human_interface_device::device::fido::FidoMsg;
+#[cfg(feature = "async")]
+mod async_hid;
+#[cfg(feature = "async")]
+pub use async_hid::{AsyncUsbHid, SendStr, UsbFuture};
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum UsbDeviceType {
     Debug = 0,
     Hid = 1,
+    /// The device core, with its DFU interface in DFU mode, taking firmware downloads
+    Dfu = 2,
+}
+
+/// What went wrong with a `UsbHid` request
+#[derive(Debug, PartialEq)]
+pub enum UsbError {
+    /// The host hasn't configured the device, so there's nothing to send to or read from
+    NotConnected,
+    /// Turned away: debug access is restricted, the interface is locked to another process, or
+    /// another server is already registered for what was asked
+    AccessDenied,
+    /// The server's report queue is full. Nothing was queued, so the request can be retried.
+    QueueFull,
+    /// There's no server to take what the request needs handing on, or the USB device server
+    /// couldn't connect back to the one given
+    NoListener,
+    /// There's no macro by that name, no descriptor recorded for what was asked, or no endpoint
+    /// that can be used
+    NotFound,
+    /// The server is already holding as much of the kind of thing being added as it can
+    OutOfSpace,
+    /// `stop_recording()` was called with no recording under way
+    NotRecording,
+    /// The server doesn't know the profile or descriptor type asked for, or was built without
+    /// the feature the request needs
+    Unsupported,
+    /// The server's reply wasn't in the form this library expects, as when the two are built
+    /// from different versions
+    ProtocolMismatch,
+    /// The host didn't get the device where it was waited for in time
+    Timeout,
+    /// The message couldn't be delivered to the server
+    Ipc(xous::Error),
+}
+impl From<xous::Error> for UsbError {
+    fn from(e: xous::Error) -> UsbError {
+        match e {
+            xous::Error::UseBeforeInit => UsbError::NotConnected,
+            xous::Error::AccessDenied => UsbError::AccessDenied,
+            xous::Error::ServerQueueFull => UsbError::QueueFull,
+            xous::Error::Timeout => UsbError::Timeout,
+            e => UsbError::Ipc(e),
+        }
+    }
+}
+/// For callers that deal in `xous::Error`. These are the errors the methods returned before
+/// they had `UsbError`.
+impl From<UsbError> for xous::Error {
+    fn from(e: UsbError) -> xous::Error {
+        match e {
+            UsbError::NotConnected | UsbError::NotRecording => xous::Error::UseBeforeInit,
+            UsbError::AccessDenied => xous::Error::AccessDenied,
+            UsbError::QueueFull => xous::Error::ServerQueueFull,
+            UsbError::NoListener | UsbError::NotFound => xous::Error::ServerNotFound,
+            UsbError::OutOfSpace => xous::Error::OutOfMemory,
+            UsbError::Unsupported => xous::Error::InvalidSyscall,
+            UsbError::ProtocolMismatch => xous::Error::InternalError,
+            UsbError::Timeout => xous::Error::Timeout,
+            UsbError::Ipc(e) => e,
+        }
+    }
+}
+impl core::fmt::Display for UsbError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            UsbError::NotConnected => f.write_str("not connected to a USB host"),
+            UsbError::AccessDenied => f.write_str("access denied"),
+            UsbError::QueueFull => f.write_str("report queue full"),
+            UsbError::NoListener => f.write_str("no server registered to take the request"),
+            UsbError::NotFound => f.write_str("not found"),
+            UsbError::OutOfSpace => f.write_str("out of space"),
+            UsbError::NotRecording => f.write_str("no macro being recorded"),
+            UsbError::Unsupported => f.write_str("unsupported"),
+            UsbError::ProtocolMismatch => f.write_str("unexpected reply from the USB device server"),
+            UsbError::Timeout => f.write_str("timed out"),
+            UsbError::Ipc(e) => write!(f, "IPC error: {:?}", e),
+        }
+    }
+}
+
+/// Named views of the LEDs in a `KeyboardLedsReport`, so callers don't have to know the
+/// packed layout of the HID LED output report.
+pub trait KeyboardLeds {
+    fn num_lock(&self) -> bool;
+    fn caps_lock(&self) -> bool;
+    fn scroll_lock(&self) -> bool;
+    fn compose(&self) -> bool;
+    fn kana(&self) -> bool;
+    /// A report with an LED set for each LED that differs between `self` and `previous`
+    fn toggled_from(&self, previous: &Self) -> Self;
+}
+impl KeyboardLeds for KeyboardLedsReport {
+    fn num_lock(&self) -> bool { self.num_lock }
+    fn caps_lock(&self) -> bool { self.caps_lock }
+    fn scroll_lock(&self) -> bool { self.scroll_lock }
+    fn compose(&self) -> bool { self.compose }
+    fn kana(&self) -> bool { self.kana }
+    fn toggled_from(&self, previous: &Self) -> Self {
+        // work on the fields rather than packing, so a report can never fail to convert
+        let mut toggled = self.clone();
+        toggled.num_lock ^= previous.num_lock;
+        toggled.caps_lock ^= previous.caps_lock;
+        toggled.scroll_lock ^= previous.scroll_lock;
+        toggled.compose ^= previous.compose;
+        toggled.kana ^= previous.kana;
+        toggled
+    }
 }
 
 #[derive(Debug)]
 pub struct UsbHid {
     conn: CID,
+    /// the LED state handed out by the last `get_led_state()` or `led_delta()`
+    last_leds: AtomicU8,
 }
 impl UsbHid {
+    /// Waits for the USB device server to register, and panics if it can't connect
     pub fn new() -> Self {
         let xns = xous_names::XousNames::new().expect("couldn't connect to XousNames");
+        UsbHid::from_connection(xns.request_connection_blocking(api::SERVER_NAME_USB_DEVICE))
+            .expect("Can't connect to USB device server")
+    }
+    /// Like `new()`, but returns `Err(xous::Error::ServerNotFound)` rather than waiting if the
+    /// USB device server hasn't registered yet, and the error rather than panicking if it
+    /// can't connect, for callers that can carry on without USB.
+    pub fn try_new() -> Result<Self, xous::Error> {
+        let xns = xous_names::XousNames::new()?;
+        UsbHid::try_connect(&xns)
+    }
+    fn try_connect<N: NameLookup>(names: &N) -> Result<Self, xous::Error> {
+        UsbHid::from_connection(names.request_connection(api::SERVER_NAME_USB_DEVICE))
+    }
+    fn from_connection(conn: Result<CID, xous::Error>) -> Result<Self, xous::Error> {
+        let conn = conn?;
+        // only counted once there's a connection for `drop()` to close
         REFCOUNT.fetch_add(1, Ordering::Relaxed);
-        let conn = xns.request_connection_blocking(api::SERVER_NAME_USB_DEVICE).expect("Can't connect to USB device server");
-        UsbHid {
-            conn
-        }
+        Ok(UsbHid {
+            conn,
+            last_leds: AtomicU8::new(0),
+        })
     }
-    pub fn switch_to_core(&self, core: UsbDeviceType) -> Result<(), xous::Error> {
+    /// Connects the USB port to `core`.
+    ///
+    /// Switching to `UsbDeviceType::Dfu` returns `Err(UsbError::AccessDenied)` while debug
+    /// access is restricted, and `Err(UsbError::NoListener)` if no server has called
+    /// `hook_dfu_blocks()` to take the firmware.
+    pub fn switch_to_core(&self, core: UsbDeviceType) -> Result<(), UsbError> {
         match send_message(
             self.conn,
             Message::new_blocking_scalar(
@@ -37,6 +175,7 @@ impl UsbHid {
                 match core {
                     UsbDeviceType::Debug => 0,
                     UsbDeviceType::Hid => 1,
+                    UsbDeviceType::Dfu => 2,
                 },
                 0, 0, 0
             )
@@ -44,13 +183,35 @@ impl UsbHid {
             Ok(xous::Result::Scalar1(code)) => {
                 match code {
                     0 => Ok(()),
-                    _ => Err(xous::Error::InternalError)
+                    2 => Err(UsbError::AccessDenied),
+                    3 => Err(UsbError::NoListener),
+                    _ => Err(UsbError::ProtocolMismatch)
                 }
             }
             _ => panic!("Internal error: illegal return type"),
         }
     }
-    pub fn get_current_core(&self) -> Result<UsbDeviceType, xous::Error> {
+    /// Like `switch_to_core()`, but when switching to the device core, also waits for the host
+    /// to enumerate and configure it, so callers needn't guess at a sleep. The debug core has
+    /// no state to wait on, so switching to it returns once it's connected.
+    ///
+    /// Returns `Err(UsbError::Timeout)` if the host hasn't configured the device within
+    /// `timeout_ms`.
+    pub fn switch_to_core_wait(&self, core: UsbDeviceType, timeout_ms: u64) -> Result<(), UsbError> {
+        self.switch_to_core(core)?;
+        if core == UsbDeviceType::Debug {
+            return Ok(());
+        }
+        let tt = ticktimer_server::Ticktimer::new().unwrap();
+        let start = tt.elapsed_ms();
+        wait_until(
+            timeout_ms,
+            || tt.elapsed_ms() - start,
+            |ms| { tt.sleep_ms(ms).ok(); },
+            || self.status() == UsbDeviceState::Configured && self.get_current_core() == Ok(core),
+        )
+    }
+    pub fn get_current_core(&self) -> Result<UsbDeviceType, UsbError> {
         match send_message(
             self.conn,
             Message::new_blocking_scalar(
@@ -62,13 +223,29 @@ impl UsbHid {
                 match code {
                     0 => Ok(UsbDeviceType::Debug),
                     1 => Ok(UsbDeviceType::Hid),
-                    _ => Err(xous::Error::InternalError)
+                    2 => Ok(UsbDeviceType::Dfu),
+                    _ => Err(UsbError::ProtocolMismatch)
                 }
             }
             _ => panic!("Internal error: illegal return type"),
         }
     }
-    pub fn restrict_debug_access(&self, restrict: bool) -> Result<(), xous::Error> {
+    /// The cores that `switch_to_core()` can connect, for offering only those. DFU mode is
+    /// listed once a server has called `hook_dfu_blocks()`.
+    pub fn list_cores(&self) -> Result<Vec<UsbDeviceType>, UsbError> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::ListCores.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(mask)) => Ok(cores_from_mask(mask)),
+            Ok(_) => Err(UsbError::ProtocolMismatch),
+            Err(e) => Err(UsbError::from(e)),
+        }
+    }
+    pub fn restrict_debug_access(&self, restrict: bool) -> Result<(), UsbError> {
         send_message(
             self.conn,
             Message::new_scalar(
@@ -76,9 +253,9 @@ impl UsbHid {
                 if restrict {1} else {0},
                 0, 0, 0
             )
-        ).map(|_| ())
+        ).map(|_| ()).map_err(UsbError::from)
     }
-    pub fn is_debug_restricted(&self) -> Result<bool, xous::Error> {
+    pub fn is_debug_restricted(&self) -> Result<bool, UsbError> {
         match send_message(
             self.conn,
             Message::new_blocking_scalar(
@@ -93,13 +270,35 @@ impl UsbHid {
                     Ok(false)
                 }
             }
-            _ => Err(xous::Error::InternalError),
+            _ => Err(UsbError::ProtocolMismatch),
         }
     }
+    /// Unlocks debug access, and locks it again after `duration_ms`, so that it isn't left open
+    /// by accident. Unlocking again restarts the timer; locking or unlocking with
+    /// `restrict_debug_access()` or `debug_usb()` stops it. `debug_usb()` reports a forced
+    /// update when the lock changes either way, so the status bar redraws.
+    pub fn unlock_debug_for(&self, duration_ms: u32) -> Result<(), UsbError> {
+        send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::UnlockDebugFor.to_usize().unwrap(),
+                duration_ms as usize,
+                0, 0, 0
+            )
+        ).map(|_| ()).map_err(UsbError::from)
+    }
+    /// The last `MAX_DEBUG_AUDIT_ENTRIES` changes to debug access, oldest first, whoever made
+    /// them, for security review. Requests that leave it as it was aren't listed.
+    pub fn get_debug_audit_log(&self) -> Result<Vec<DebugAuditEntry>, UsbError> {
+        let mut buf = Buffer::into_buf(DebugAuditLogIpc::default()).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::GetDebugAuditLog.to_u32().unwrap())?;
+        let log = buf.to_original::<DebugAuditLogIpc, _>().or(Err(UsbError::ProtocolMismatch))?;
+        Ok(log.entries[..log.len as usize].to_vec())
+    }
     // if do_lock is Some(), set the debug USB lock status to locked if true, unlocked if false
     // returns a tuple of (bool, bool) -> (is_locked, force_update)
     // needs_update is so that the polling function knows to redraw the UX after a resume-from-suspend
-    pub fn debug_usb(&self, do_lock: Option<bool>) -> Result<(bool, bool), xous::Error> {
+    pub fn debug_usb(&self, do_lock: Option<bool>) -> Result<(bool, bool), UsbError> {
         // arg1 indicates if an update to the state is requested
         // arg2 is the new state update
         let (arg1, arg2) = if let Some(lock) = do_lock {
@@ -120,7 +319,7 @@ impl UsbHid {
                 (il, fu)
             )
         } else {
-            Err(xous::Error::InternalError)
+            Err(UsbError::ProtocolMismatch)
         }
     }
     pub fn status(&self) -> UsbDeviceState {
@@ -146,7 +345,10 @@ impl UsbHid {
     /// Sends up to three keyboard codes at once as defined by USB HID usage tables;
     /// see See [Universal Serial Bus (USB) HID Usage Tables Version 1.12](<https://www.usb.org/sites/default/files/documents/hut1_12v2.pdf>):
     /// If the vector is empty, you get an all-key-up situation
-    pub fn send_keycode(&self, code: Vec<UsbKeyCode>, auto_keyup: bool) -> Result<(), xous::Error> {
+    ///
+    /// Returns `Err(UsbError::QueueFull)` if the server's report queue is full; nothing is
+    /// queued in that case, so the call can simply be retried once `queue_depth()` drops.
+    pub fn send_keycode(&self, code: Vec<UsbKeyCode>, auto_keyup: bool) -> Result<(), UsbError> {
         if code.len() > 3 {
             log::warn!("Excess keycodes ignored");
         }
@@ -163,28 +365,343 @@ impl UsbHid {
             Ok(xous::Result::Scalar1(code)) => {
                 match code {
                     0 => Ok(()),
+                    // the report queue is full
+                    2 => Err(UsbError::QueueFull),
                     // indicates that we aren't connected to a host to send characters
-                    _ => Err(xous::Error::UseBeforeInit),
+                    _ => Err(UsbError::NotConnected),
                 }
             }
-            _ => Err(xous::Error::UseBeforeInit),
+            Ok(_) => Err(UsbError::ProtocolMismatch),
+            Err(e) => Err(UsbError::from(e)),
         }
     }
-    pub fn send_str(&self, s: &str) -> Result<usize, xous::Error> {
+    /// "Types" a string at the host, waiting for room in the server's report queue as
+    /// needed. Returns the number of characters sent; if `cancel_typing()` stops it partway,
+    /// that's the number of characters the host received.
+    pub fn send_str(&self, s: &str) -> Result<usize, UsbError> {
+        let mut total = 0;
+        let mut remaining = s;
+        let mut tt: Option<ticktimer_server::Ticktimer> = None;
+        let mut total_chars = Some(s.chars().count() as u32);
+        while !remaining.is_empty() {
+            // only the first chunk starts the progress count, even if none of it fit
+            match self.send_str_chunk(remaining, total_chars.take()) {
+                // `cancel_typing()` was called from elsewhere; report how much made it out
+                Ok((typed, true)) => return Ok(typed),
+                Ok((sent, false)) => {
+                    total += sent;
+                    remaining = match remaining.char_indices().nth(sent) {
+                        Some((index, _)) => &remaining[index..],
+                        None => "",
+                    };
+                }
+                Err(UsbError::QueueFull) => {
+                    // give the host a few polls to drain the queue
+                    tt.get_or_insert_with(|| ticktimer_server::Ticktimer::new().unwrap())
+                        .sleep_ms(30).ok();
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(total)
+    }
+    /// Queues as much of a string as fits in the server's report queue, and returns the
+    /// number of characters queued. Returns `Err(UsbError::QueueFull)` if none of it fit.
+    pub fn try_send_str(&self, s: &str) -> Result<usize, UsbError> {
+        self.send_str_chunk(s, Some(s.chars().count() as u32)).map(|(sent, _)| sent)
+    }
+    /// `total_chars` starts a new progress count for `typing_progress()`; pass `None` when
+    /// continuing a string that was only partially queued. Returns the number of characters
+    /// queued, and whether the send has been cancelled.
+    fn send_str_chunk(&self, s: &str, total_chars: Option<u32>) -> Result<(usize, bool), UsbError> {
         let serializer = UsbString {
             s: xous_ipc::String::<4000>::from_str(s),
-            sent: None
+            sent: None,
+            total: total_chars,
+            cancelled: false,
+        };
+        let mut buf = Buffer::into_buf(serializer).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::SendString.to_u32().unwrap())?;
+        let returned = buf.to_original::<UsbString, _>().or(Err(UsbError::ProtocolMismatch))?;
+        chunk_sent(&returned, s)
+    }
+    /// "Types" each byte of `data` at the host through the byte table selected with
+    /// `set_byte_map()`, rather than decoding it as text. Waits for room in the server's report
+    /// queue as needed. Bytes with no mapping are skipped; returns the number of bytes typed,
+    /// so `data.len()` less the return value were skipped.
+    pub fn send_bytes(&self, data: &[u8]) -> Result<usize, UsbError> {
+        let mut typed = 0;
+        let mut remaining = data;
+        let mut tt: Option<ticktimer_server::Ticktimer> = None;
+        while !remaining.is_empty() {
+            let mut chunk = UsbBytes {
+                data: [0u8; USB_BYTES_LEN],
+                len: remaining.len().min(USB_BYTES_LEN) as u32,
+                sent: None,
+                skipped: 0,
+            };
+            chunk.data[..chunk.len as usize].copy_from_slice(&remaining[..chunk.len as usize]);
+            let mut buf = Buffer::into_buf(chunk).or(Err(UsbError::ProtocolMismatch))?;
+            buf.lend_mut(self.conn, Opcode::SendBytes.to_u32().unwrap())?;
+            let returned = buf.to_original::<UsbBytes, _>().or(Err(UsbError::ProtocolMismatch))?;
+            match returned.sent {
+                Some(0) => {
+                    // give the host a few polls to drain the queue
+                    tt.get_or_insert_with(|| ticktimer_server::Ticktimer::new().unwrap())
+                        .sleep_ms(30).ok();
+                }
+                Some(sent) => {
+                    typed += (sent - returned.skipped) as usize;
+                    remaining = &remaining[sent as usize..];
+                }
+                // indicate that probably the USB was not connected
+                None => return Err(UsbError::NotConnected),
+            }
+        }
+        Ok(typed)
+    }
+    /// Selects how `send_bytes()` maps bytes to keystrokes. `ByteMap::Terminal` is the default.
+    pub fn set_byte_map(&self, map: ByteMap) -> Result<(), UsbError> {
+        send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::SetByteMap.to_usize().unwrap(),
+                map.to_usize().unwrap(),
+                0, 0, 0
+            )
+        ).map(|_| ()).map_err(UsbError::from)
+    }
+    /// With `use_keypad` set, `send_str()` types the digits and `. + - * / =` on the numeric
+    /// keypad instead of the main keys, for host software that tells the two apart. The keypad
+    /// digits need NumLock, so if the host reports it off, a send that has any turns it on first.
+    pub fn set_numeric_mode(&self, use_keypad: bool) -> Result<(), UsbError> {
+        send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::SetNumericMode.to_usize().unwrap(),
+                if use_keypad { 1 } else { 0 },
+                0, 0, 0
+            )
+        ).map(|_| ()).map_err(UsbError::from)
+    }
+    /// Starts recording the reports that `send_keycode()` and `send_str()` queue, from any
+    /// caller, into the macro `name`. A recording already under way is abandoned.
+    pub fn start_recording(&self, name: &str) -> Result<(), UsbError> {
+        let buf = Buffer::into_buf(xous_ipc::String::<64>::from_str(name)).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend(self.conn, Opcode::StartRecording.to_u32().unwrap()).map(|_| ()).map_err(UsbError::from)
+    }
+    /// Ends the recording and keeps the macro, replacing any of the same name. Returns the
+    /// number of reports recorded.
+    ///
+    /// Returns `Err(UsbError::OutOfSpace)` if the recording ran past `MAX_MACRO_FRAMES`, or
+    /// `MAX_MACROS` are already kept; the macro is dropped in that case. Returns
+    /// `Err(UsbError::NotRecording)` if nothing was being recorded.
+    pub fn stop_recording(&self) -> Result<usize, UsbError> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(Opcode::StopRecording.to_usize().unwrap(), 0, 0, 0, 0)
+        ) {
+            Ok(xous::Result::Scalar2(0, frames)) => Ok(frames),
+            Ok(xous::Result::Scalar2(1, _)) => Err(UsbError::NotRecording),
+            Ok(xous::Result::Scalar2(2, _)) => Err(UsbError::OutOfSpace),
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    /// Replays the macro `name` at the host, with the delays it was recorded with. Returns
+    /// `Err(UsbError::NotFound)` if there's no such macro.
+    pub fn play_macro(&self, name: &str) -> Result<(), UsbError> {
+        let request = UsbMacro {
+            name: xous_ipc::String::<64>::from_str(name),
+            len: None,
+            frames: [MacroFrame::default(); MAX_MACRO_FRAMES],
+        };
+        let mut buf = Buffer::into_buf(request).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::GetMacro.to_u32().unwrap())?;
+        let usb_macro = buf.to_original::<UsbMacro, _>().or(Err(UsbError::ProtocolMismatch))?;
+        let len = usb_macro.len.ok_or(UsbError::NotFound)? as usize;
+        let tt = ticktimer_server::Ticktimer::new().unwrap();
+        for frame in usb_macro.frames[..len].iter() {
+            if frame.delay_ms != 0 {
+                tt.sleep_ms(frame.delay_ms as usize).ok();
+            }
+            let keys: Vec<UsbKeyCode> = frame.keys.iter()
+                .filter(|&&key| key != 0)
+                .map(|&key| UsbKeyCode::from_primitive(key))
+                .collect();
+            loop {
+                match self.send_keycode(keys.clone(), false) {
+                    // give the host a few polls to drain the queue
+                    Err(UsbError::QueueFull) => tt.sleep_ms(30).ok(),
+                    result => break result?,
+                };
+            }
+        }
+        Ok(())
+    }
+    /// Number of keyboard reports waiting for the host to pick them up, out of
+    /// `HID_REPORT_QUEUE_LEN`. Each keystroke takes two reports.
+    pub fn queue_depth(&self) -> Result<usize, UsbError> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::KeyQueueDepth.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(depth)) => Ok(depth),
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    /// Returns `(chars_sent, chars_total)` for the `send_str()` in flight, or for the last one
+    /// to complete if none is. Characters count as sent once they are queued for the host.
+    pub fn typing_progress(&self) -> Result<(u32, u32), UsbError> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::TypingProgress.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar2(sent, total)) => Ok((sent as u32, total as u32)),
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    /// Stops the `send_str()` in flight, and releases any keys it is holding down. Returns the
+    /// number of characters the host received before the cancellation; if there is no send in
+    /// flight, nothing happens and 0 is returned.
+    pub fn cancel_typing(&self) -> Result<u32, UsbError> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::CancelTyping.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(typed)) => Ok(typed as u32),
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    /// Registers `cb_sid` to hear about the host suspending and resuming the bus. Each change
+    /// arrives at that server as a scalar message whose id is a `PowerEvent`.
+    pub fn hook_power_events(&self, cb_sid: xous::SID) -> Result<(), UsbError> {
+        let sid = cb_sid.to_u32();
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::HookPowerEvents.to_usize().unwrap(),
+                sid.0 as usize, sid.1 as usize, sid.2 as usize, sid.3 as usize
+            )
+        ) {
+            Ok(xous::Result::Scalar1(code)) => {
+                match code {
+                    0 => Ok(()),
+                    // the server couldn't connect back to `cb_sid`
+                    _ => Err(UsbError::NoListener),
+                }
+            }
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    /// Registers `cb_sid` to be sent a scalar message with id `id` each time the host changes
+    /// the keyboard LEDs. The first argument is the new LED byte, which unpacks into a
+    /// `KeyboardLedsReport`. Returns `Err(UsbError::NoListener)` if the server can't connect
+    /// to `cb_sid`.
+    pub fn hook_led_changes(&self, cb_sid: xous::SID, id: u32) -> Result<(), UsbError> {
+        let hook = LedHook {
+            sid: cb_sid.to_u32(),
+            id,
+            registered: false,
+        };
+        let mut buf = Buffer::into_buf(hook).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::HookLedChanges.to_u32().unwrap())?;
+        let returned = buf.to_original::<LedHook, _>().or(Err(UsbError::ProtocolMismatch))?;
+        if returned.registered {
+            Ok(())
+        } else {
+            // the server couldn't connect back to `cb_sid`
+            Err(UsbError::NoListener)
+        }
+    }
+    /// Registers `cb_sid` to be sent the output reports the host writes to the HID interface
+    /// `interface`, whether with SET_REPORT or on an interrupt OUT endpoint. Each arrives at
+    /// that server as an `OutputReport` in a memory message with id `OUTPUT_REPORT_ID`. The
+    /// keyboard's LED report is sent each time it changes. Once the raw HID interface is locked
+    /// to a process, its reports only go to a server that process registered, and U2F packets
+    /// are never passed on.
+    ///
+    /// Each interface has one such server; returns `Err(UsbError::AccessDenied)` if one is
+    /// already registered for `interface`, and `Err(UsbError::NotFound)` if its reports aren't
+    /// passed on.
+    pub fn hook_output_reports(&self, interface: u8, cb_sid: xous::SID) -> Result<(), UsbError> {
+        let hook = OutputReportHook {
+            sid: cb_sid.to_u32(),
+            interface,
+            result: 0,
+        };
+        let mut buf = Buffer::into_buf(hook).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::HookOutputReports.to_u32().unwrap())?;
+        let returned = buf.to_original::<OutputReportHook, _>().or(Err(UsbError::ProtocolMismatch))?;
+        match returned.result {
+            0 => Ok(()),
+            // the server couldn't connect back to `cb_sid`
+            1 => Err(UsbError::NoListener),
+            2 => Err(UsbError::AccessDenied),
+            _ => Err(UsbError::NotFound),
+        }
+    }
+    /// Registers `cb_sid` to take firmware downloaded over DFU, and adds a DFU runtime interface
+    /// to the device so that a host tool can ask it to switch to DFU mode. The host sees the
+    /// interface once it enumerates the device again.
+    ///
+    /// Each block of the image is lent to `cb_sid` as a `DfuBlock` in a mutable memory message
+    /// with id `id`, in order, followed by an empty one with `manifest` set once the host has
+    /// sent it all. The server sets `accepted` once it has written the block, or checked the
+    /// image; the host is told of any failure, and is told to wait until the reply comes.
+    ///
+    /// There can only be one such server, and none while debug access is restricted; returns
+    /// `Err(UsbError::AccessDenied)` if one is already registered or debug access is
+    /// restricted, `Err(UsbError::NoListener)` if the server can't connect to `cb_sid`, and
+    /// `Err(UsbError::Unsupported)` in hosted mode, where there's no DFU interface.
+    pub fn hook_dfu_blocks(&self, cb_sid: xous::SID, id: u32) -> Result<(), UsbError> {
+        let hook = DfuHook {
+            sid: cb_sid.to_u32(),
+            id,
+            result: 0,
         };
-        let mut buf = Buffer::into_buf(serializer).or(Err(xous::Error::InternalError))?;
-        buf.lend_mut(self.conn, Opcode::SendString.to_u32().unwrap()).or(Err(xous::Error::InternalError))?;
-        let returned = buf.to_original::<UsbString, _>().or(Err(xous::Error::InternalError))?;
-        match returned.sent {
-            Some(sent) => Ok(sent as usize),
-            // indicate that probably the USB was not connected
-            None => Err(xous::Error::UseBeforeInit),
+        let mut buf = Buffer::into_buf(hook).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::HookDfuBlocks.to_u32().unwrap())?;
+        let returned = buf.to_original::<DfuHook, _>().or(Err(UsbError::ProtocolMismatch))?;
+        match returned.result {
+            0 => Ok(()),
+            // the server couldn't connect back to `cb_sid`
+            1 => Err(UsbError::NoListener),
+            2 => Err(UsbError::AccessDenied),
+            _ => Err(UsbError::Unsupported),
         }
     }
-    pub fn get_led_state(&self) -> Result<KeyboardLedsReport, xous::Error> {
+    /// Presents the device to the host as `profile`, by re-enumerating with its VID/PID and
+    /// device strings, and the HID country code of the keyboard. Returns
+    /// `Err(UsbError::Unsupported)` if the server doesn't know the profile, or the profile is
+    /// under another vendor's VID and the server wasn't built with the `vendor-profiles` feature.
+    pub fn set_profile(&self, profile: KeyboardProfile) -> Result<(), UsbError> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::SetProfile.to_usize().unwrap(),
+                profile.to_usize().unwrap(),
+                0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(code)) => {
+                match code {
+                    0 => Ok(()),
+                    _ => Err(UsbError::Unsupported),
+                }
+            }
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    pub fn get_led_state(&self) -> Result<KeyboardLedsReport, UsbError> {
         match send_message(
             self.conn,
             Message::new_blocking_scalar(
@@ -194,44 +711,319 @@ impl UsbHid {
         ) {
             Ok(xous::Result::Scalar1(code)) => {
                 match KeyboardLedsReport::unpack(&[code as u8]) {
-                    Ok(r) => Ok(r),
-                    Err(_) => Err(xous::Error::InternalError),
+                    Ok(r) => {
+                        self.last_leds.store(code as u8, Ordering::Relaxed);
+                        Ok(r)
+                    }
+                    Err(_) => Err(UsbError::ProtocolMismatch),
                 }
             }
             _ => panic!("Internal error: illegal return type"),
         }
     }
-    pub fn u2f_wait_incoming(&self) -> Result<FidoMsg, xous::Error> {
+    /// Returns the LEDs that toggled since the state last returned by `get_led_state()` or
+    /// `led_delta()`, e.g. `led_delta()?.caps_lock()` is true if the host flipped Caps Lock.
+    pub fn led_delta(&self) -> Result<KeyboardLedsReport, UsbError> {
+        let previous = KeyboardLedsReport::unpack(&[self.last_leds.load(Ordering::Relaxed)])
+            .or(Err(UsbError::ProtocolMismatch))?;
+        Ok(self.get_led_state()?.toggled_from(&previous))
+    }
+    /// Reads out the counters of USB bus events since boot or the last `reset_stats()`
+    pub fn get_stats(&self) -> Result<UsbStats, UsbError> {
+        let mut buf = Buffer::into_buf(UsbStats::default()).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::GetStats.to_u32().unwrap())?;
+        buf.to_original::<UsbStats, _>().or(Err(UsbError::ProtocolMismatch))
+    }
+    pub fn reset_stats(&self) -> Result<(), UsbError> {
+        send_message(
+            self.conn,
+            Message::new_scalar(
+                Opcode::ResetStats.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ).map(|_| ()).map_err(UsbError::from)
+    }
+    /// Reads out a decoded copy of the USB device controller's registers, for diagnosing
+    /// enumeration problems
+    pub fn get_udc_regs(&self) -> Result<SpinalUdcRegsSnapshot, UsbError> {
+        let mut buf = Buffer::into_buf(SpinalUdcRegsSnapshot::default()).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::GetUdcRegs.to_u32().unwrap())?;
+        buf.to_original::<SpinalUdcRegsSnapshot, _>().or(Err(UsbError::ProtocolMismatch))
+    }
+    /// Takes the endpoint at `address`, direction bit included, out of service, or puts it back.
+    /// Disabling an endpoint gives its packet memory back, and does nothing if it isn't
+    /// allocated. Only an endpoint that is still allocated can be enabled again, so one that was
+    /// disabled stays out of service.
+    ///
+    /// Returns `Err(UsbError::NotFound)` for ep0, which carries the control pipe, and for an
+    /// endpoint that can't be enabled, and `Err(UsbError::Unsupported)` without a USB device.
+    pub fn set_endpoint_enabled(&self, address: u8, enabled: bool) -> Result<(), UsbError> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::SetEndpointEnabled.to_usize().unwrap(),
+                address as usize,
+                enabled as usize,
+                0, 0
+            )
+        )? {
+            xous::Result::Scalar1(0) => Ok(()),
+            xous::Result::Scalar1(1) => Err(UsbError::NotFound),
+            xous::Result::Scalar1(2) => Err(UsbError::Unsupported),
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    /// Holds the host off on the OUT endpoint at `address` while `nack` is set: whatever it sends
+    /// there is NACKed, and retried by the host, rather than taken. A class uses this to push back
+    /// while its buffer is full. A STALL on the endpoint is left as it was.
+    ///
+    /// Returns `Err(UsbError::NotFound)` for ep0, for an IN endpoint and for an endpoint that isn't
+    /// allocated, and `Err(UsbError::Unsupported)` without a USB device.
+    pub fn set_endpoint_nack(&self, address: u8, nack: bool) -> Result<(), UsbError> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::SetEndpointNack.to_usize().unwrap(),
+                address as usize,
+                nack as usize,
+                0, 0
+            )
+        )? {
+            xous::Result::Scalar1(0) => Ok(()),
+            xous::Result::Scalar1(1) => Err(UsbError::NotFound),
+            xous::Result::Scalar1(2) => Err(UsbError::Unsupported),
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    /// Has Windows bind WinUSB to `interface` without a driver being installed, by giving it the
+    /// WinUSB compatible ID in MS OS 2.0 descriptors; `None` takes the descriptors away. Only
+    /// set this while a vendor-specific interface is active. The device re-enumerates so the
+    /// host sees the change.
+    pub fn set_winusb_interface(&self, interface: Option<u8>) -> Result<(), UsbError> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::SetWinUsbInterface.to_usize().unwrap(),
+                interface.is_some() as usize,
+                interface.unwrap_or(0) as usize,
+                0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(0)) => Ok(()),
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    /// Reports the charge of the device's battery to the host, in percent, for it to show like
+    /// that of any other battery-powered peripheral. Values over 100 are taken as 100.
+    ///
+    /// The battery interface is only there when the server is built with the "battery" feature;
+    /// without it, this returns `Err(UsbError::Unsupported)`.
+    pub fn report_battery(&self, percent: u8) -> Result<(), UsbError> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::ReportBattery.to_usize().unwrap(),
+                percent as usize, 0, 0, 0
+            )
+        )? {
+            xous::Result::Scalar1(0) => Ok(()),
+            xous::Result::Scalar1(1) => Err(UsbError::Unsupported),
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    /// Reads back descriptor `index` of `kind`, byte for byte as the stack last sent it to the
+    /// host, for working out why a host rejected the device. Descriptors longer than
+    /// `MAX_DESCRIPTOR_LEN` are cut off.
+    ///
+    /// Returns `Err(UsbError::NotFound)` if the host hasn't asked for the descriptor since the
+    /// device started up, and `Err(UsbError::Unsupported)` if the server doesn't know `kind`.
+    pub fn get_descriptor(&self, kind: DescriptorType, index: u8) -> Result<Vec<u8>, UsbError> {
+        let request = UsbDescriptor {
+            kind: kind.to_u8().unwrap(),
+            index,
+            unknown_kind: false,
+            len: None,
+            data: [0; MAX_DESCRIPTOR_LEN],
+        };
+        let mut buf = Buffer::into_buf(request).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::GetDescriptorBytes.to_u32().unwrap())?;
+        let descriptor = buf.to_original::<UsbDescriptor, _>().or(Err(UsbError::ProtocolMismatch))?;
+        if descriptor.unknown_kind {
+            return Err(UsbError::Unsupported);
+        }
+        let len = descriptor.len.ok_or(UsbError::NotFound)? as usize;
+        Ok(descriptor.data[..len].to_vec())
+    }
+    /// Sets whether the configuration descriptor says the device is self-powered, as when it's
+    /// running on its own battery, or bus-powered, which is the default. Hosts use it for power
+    /// management, and only read it when enumerating the device, so it re-enumerates if it's
+    /// attached. The remote wakeup attribute is left as it is.
+    pub fn set_self_powered(&self, yes: bool) -> Result<(), UsbError> {
+        send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::SetSelfPowered.to_usize().unwrap(),
+                if yes { 1 } else { 0 },
+                0, 0, 0
+            )
+        ).map(|_| ()).map_err(UsbError::from)
+    }
+    /// Gives the device's manufacturer and product names in the language `langid`, a USB
+    /// LANGID such as 0x0407 for German, beside the US English ones of the profile. Hosts are
+    /// answered in the language they ask for, or in US English if it's one the device doesn't
+    /// have. Adding a language it already has replaces its strings. The host sees the new
+    /// strings once it enumerates the device again, which it's made to if it's attached.
+    ///
+    /// Returns `Err(UsbError::OutOfSpace)` if the strings are already in
+    /// `MAX_STRING_LANGUAGES` languages.
+    pub fn add_string_language(&self, langid: u16, strings: DeviceStrings) -> Result<(), UsbError> {
+        let request = StringLanguage {
+            langid,
+            strings,
+            full: false,
+        };
+        let mut buf = Buffer::into_buf(request).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::AddStringLanguage.to_u32().unwrap())?;
+        let language = buf.to_original::<StringLanguage, _>().or(Err(UsbError::ProtocolMismatch))?;
+        if language.full {
+            return Err(UsbError::OutOfSpace);
+        }
+        Ok(())
+    }
+    /// Reads the 11-bit USB frame counter, which the host advances once a millisecond, for
+    /// lining up submissions with frame boundaries.
+    ///
+    /// Returns `Err(UsbError::NotConnected)` if the host hasn't configured the device yet.
+    pub fn get_frame_number(&self) -> Result<u16, UsbError> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(Opcode::GetFrameNumber.to_usize().unwrap(), 0, 0, 0, 0)
+        ) {
+            Ok(xous::Result::Scalar2(0, frame)) => Ok(frame as u16),
+            Ok(xous::Result::Scalar2(1, _)) => Err(UsbError::NotConnected),
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    pub fn u2f_wait_incoming(&self) -> Result<FidoMsg, UsbError> {
         let req = U2fMsgIpc {
             data: [0; 64],
             code: U2fCode::RxWait
         };
-        let mut buf = Buffer::into_buf(req).or(Err(xous::Error::InternalError))?;
-        buf.lend_mut(self.conn, Opcode::U2fRxDeferred.to_u32().unwrap()).or(Err(xous::Error::InternalError))?;
+        let mut buf = Buffer::into_buf(req).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::U2fRxDeferred.to_u32().unwrap())?;
         let ack = buf.to_original::<U2fMsgIpc, _>().unwrap();
+        // turned away: the interface is locked to another process, or the server is quitting
+        if ack.code == U2fCode::Denied {
+            return Err(UsbError::AccessDenied);
+        }
         assert_eq!(ack.code, U2fCode::RxAck, "Expected U2fCode::RxAck");
         let mut u2fmsg = FidoMsg::default();
         u2fmsg.packet.copy_from_slice(&ack.data);
         Ok(u2fmsg)
     }
-    pub fn u2f_send(&self, msg: FidoMsg) -> Result<(), xous::Error> {
+    pub fn u2f_send(&self, msg: FidoMsg) -> Result<(), UsbError> {
         let mut req = U2fMsgIpc {
             data: [0; 64],
             code: U2fCode::Tx
         };
         req.data.copy_from_slice(&msg.packet);
-        let mut buf = Buffer::into_buf(req).or(Err(xous::Error::InternalError))?;
-        buf.lend_mut(self.conn, Opcode::U2fTx.to_u32().unwrap()).or(Err(xous::Error::InternalError))?;
+        let mut buf = Buffer::into_buf(req).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::U2fTx.to_u32().unwrap())?;
         let ack = buf.to_original::<U2fMsgIpc, _>().unwrap();
         match ack.code {
             U2fCode::TxAck => Ok(()),
-            U2fCode::Denied => Err(xous::Error::AccessDenied),
-            _ => Err(xous::Error::InternalError),
+            U2fCode::Denied => Err(UsbError::AccessDenied),
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    /// Sends a 64-byte report to the host on the vendor-defined raw HID interface. Like U2F,
+    /// the interface is locked to the first process that uses it.
+    pub fn raw_hid_send(&self, report: [u8; 64]) -> Result<(), UsbError> {
+        let req = U2fMsgIpc {
+            data: report,
+            code: U2fCode::Tx
+        };
+        let mut buf = Buffer::into_buf(req).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::RawHidTx.to_u32().unwrap())?;
+        let ack = buf.to_original::<U2fMsgIpc, _>().unwrap();
+        match ack.code {
+            U2fCode::TxAck => Ok(()),
+            U2fCode::Denied => Err(UsbError::AccessDenied),
+            _ => Err(UsbError::ProtocolMismatch),
+        }
+    }
+    /// Blocks until the host sends a 64-byte report on the vendor-defined raw HID interface.
+    pub fn raw_hid_recv(&self) -> Result<[u8; 64], UsbError> {
+        let req = U2fMsgIpc {
+            data: [0; 64],
+            code: U2fCode::RxWait
+        };
+        let mut buf = Buffer::into_buf(req).or(Err(UsbError::ProtocolMismatch))?;
+        buf.lend_mut(self.conn, Opcode::RawHidRxDeferred.to_u32().unwrap())?;
+        let ack = buf.to_original::<U2fMsgIpc, _>().unwrap();
+        match ack.code {
+            U2fCode::RxAck => Ok(ack.data),
+            U2fCode::Denied => Err(UsbError::AccessDenied),
+            // another listener took our place before a report arrived
+            _ => Err(UsbError::ProtocolMismatch),
         }
     }
 }
 
-use core::sync::atomic::{AtomicU32, Ordering};
+/// Makes sense of the server's reply to a `send_str()` chunk of `s`, returning the number of
+/// characters queued and whether the send has been cancelled
+fn chunk_sent(returned: &UsbString, s: &str) -> Result<(usize, bool), UsbError> {
+    match returned.sent {
+        // on a cancelled send, `sent` is the number of characters the host got in total
+        Some(typed) if returned.cancelled => Ok((typed as usize, true)),
+        Some(0) if !s.is_empty() => Err(UsbError::QueueFull),
+        Some(sent) => Ok((sent as usize, false)),
+        // indicate that probably the USB was not connected
+        None => Err(UsbError::NotConnected),
+    }
+}
+
+/// How often `switch_to_core_wait()` checks on the device
+const WAIT_POLL_MS: usize = 50;
+/// Checks `done` every `WAIT_POLL_MS` until it's true, or until `elapsed_ms` reaches `timeout_ms`
+fn wait_until(
+    timeout_ms: u64,
+    mut elapsed_ms: impl FnMut() -> u64,
+    mut sleep_ms: impl FnMut(usize),
+    mut done: impl FnMut() -> bool,
+) -> Result<(), UsbError> {
+    loop {
+        if done() {
+            return Ok(());
+        }
+        if elapsed_ms() >= timeout_ms {
+            return Err(UsbError::Timeout);
+        }
+        sleep_ms(WAIT_POLL_MS);
+    }
+}
+
+/// The cores in a `ListCores` answer
+fn cores_from_mask(mask: usize) -> Vec<UsbDeviceType> {
+    [UsbDeviceType::Debug, UsbDeviceType::Hid, UsbDeviceType::Dfu]
+        .iter()
+        .copied()
+        .filter(|&core| mask & (1 << core as usize) != 0)
+        .collect()
+}
+
+/// Where `try_new()` looks the USB device server up
+trait NameLookup {
+    /// A connection to the server registered as `name`, without waiting for it to register
+    fn request_connection(&self, name: &str) -> Result<CID, xous::Error>;
+}
+impl NameLookup for xous_names::XousNames {
+    fn request_connection(&self, name: &str) -> Result<CID, xous::Error> {
+        xous_names::XousNames::request_connection(self, name)
+    }
+}
+
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
 static REFCOUNT: AtomicU32 = AtomicU32::new(0);
 impl Drop for UsbHid {
     fn drop(&mut self) {
@@ -239,4 +1031,125 @@ impl Drop for UsbHid {
             unsafe{xous::disconnect(self.conn).unwrap();}
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_led_accessors() {
+        // caps lock and compose on, as the host would send them in the LED output report
+        let leds = KeyboardLedsReport::unpack(&[0b0_1010]).unwrap();
+        assert!(!leds.num_lock());
+        assert!(leds.caps_lock());
+        assert!(!leds.scroll_lock());
+        assert!(leds.compose());
+        assert!(!leds.kana());
+
+        let all = KeyboardLedsReport::unpack(&[0b1_1111]).unwrap();
+        assert!(all.num_lock() && all.caps_lock() && all.scroll_lock() && all.compose() && all.kana());
+        let none = KeyboardLedsReport::default();
+        assert!(!(none.num_lock() || none.caps_lock() || none.scroll_lock() || none.compose() || none.kana()));
+
+        // the host turns caps lock off and kana on
+        let next = KeyboardLedsReport::unpack(&[0b1_1000]).unwrap();
+        let delta = next.toggled_from(&leds);
+        assert!(delta.caps_lock());
+        assert!(delta.kana());
+        assert!(!(delta.num_lock() || delta.scroll_lock() || delta.compose()));
+        assert_eq!(leds.toggled_from(&leds), KeyboardLedsReport::default());
+    }
+    #[test]
+    fn test_send_not_connected() {
+        let mut returned = UsbString {
+            s: xous_ipc::String::<4000>::from_str("hello"),
+            sent: None, // the server found no host to type at
+            total: Some(5),
+            cancelled: false,
+        };
+        assert_eq!(chunk_sent(&returned, "hello"), Err(UsbError::NotConnected));
+        // callers still dealing in `xous::Error` see what they used to
+        assert_eq!(xous::Error::from(UsbError::NotConnected), xous::Error::UseBeforeInit);
+        assert_eq!(UsbError::from(xous::Error::UseBeforeInit), UsbError::NotConnected);
+
+        // and the other replies are unchanged
+        returned.sent = Some(0);
+        assert_eq!(chunk_sent(&returned, "hello"), Err(UsbError::QueueFull));
+        returned.sent = Some(3);
+        assert_eq!(chunk_sent(&returned, "hello"), Ok((3, false)));
+        returned.cancelled = true;
+        assert_eq!(chunk_sent(&returned, "hello"), Ok((3, true)));
+    }
+    /// A name table, answering lookups as xous-names does
+    struct Names(Vec<(&'static str, CID)>);
+    impl NameLookup for Names {
+        fn request_connection(&self, name: &str) -> Result<CID, xous::Error> {
+            self.0
+                .iter()
+                .find(|(registered, _)| *registered == name)
+                .map(|&(_, cid)| cid)
+                .ok_or(xous::Error::ServerNotFound)
+        }
+    }
+
+    #[test]
+    fn test_try_new() {
+        let before = REFCOUNT.load(Ordering::Relaxed);
+        // the USB device server hasn't registered yet
+        let mut names = Names(vec![("_Some other server_", 3)]);
+        assert_eq!(UsbHid::try_connect(&names).unwrap_err(), xous::Error::ServerNotFound);
+        // nothing was counted, so no later `drop()` closes a connection it doesn't own
+        assert_eq!(REFCOUNT.load(Ordering::Relaxed), before);
+
+        // once it has, the connection is to it, and is counted
+        names.0.push((api::SERVER_NAME_USB_DEVICE, 7));
+        let hid = UsbHid::try_connect(&names).unwrap();
+        assert_eq!(hid.conn, 7);
+        assert_eq!(REFCOUNT.load(Ordering::Relaxed), before + 1);
+        // a second handle shares the connection, which stays open when it's dropped
+        let second = UsbHid::try_connect(&names).unwrap();
+        assert_eq!(second.conn, 7);
+        assert_eq!(REFCOUNT.load(Ordering::Relaxed), before + 2);
+        drop(second);
+        assert_eq!(REFCOUNT.load(Ordering::Relaxed), before + 1);
+        // the last handle would close the connection, which only a running kernel can do
+        std::mem::forget(hid);
+        REFCOUNT.fetch_sub(1, Ordering::Relaxed);
+    }
+    #[test]
+    fn test_list_cores() {
+        // what every build of the server answers
+        let cores = cores_from_mask(CORES_ALWAYS);
+        assert!(cores.contains(&UsbDeviceType::Debug));
+        assert!(cores.contains(&UsbDeviceType::Hid));
+        assert!(!cores.contains(&UsbDeviceType::Dfu));
+        // DFU once a server has hooked the downloads
+        assert_eq!(
+            cores_from_mask(CORES_ALWAYS | CORE_DFU),
+            vec![UsbDeviceType::Debug, UsbDeviceType::Hid, UsbDeviceType::Dfu]
+        );
+    }
+    #[test]
+    fn test_switch_wait() {
+        use std::cell::Cell;
+        // a clock that only moves when the caller sleeps
+        let now = Cell::new(0u64);
+        // the host configures the device 700ms after the switch
+        let configured_at = 700;
+        assert_eq!(
+            wait_until(2000, || now.get(), |ms| now.set(now.get() + ms as u64), || now.get() >= configured_at),
+            Ok(())
+        );
+        assert!(now.get() >= configured_at && now.get() < configured_at + WAIT_POLL_MS as u64);
+
+        // a host that never does
+        now.set(0);
+        assert_eq!(
+            wait_until(2000, || now.get(), |ms| now.set(now.get() + ms as u64), || false),
+            Err(UsbError::Timeout)
+        );
+        assert!(now.get() >= 2000);
+        assert_eq!(xous::Error::from(UsbError::Timeout), xous::Error::Timeout);
+    }
+}
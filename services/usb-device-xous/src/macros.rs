@@ -0,0 +1,177 @@
+// Recording only happens on real hardware, but the store is kept free of hardware dependencies
+// so that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use crate::api::{MACRO_REPORT_KEYS, MAX_MACROS, MAX_MACRO_FRAMES};
+use usbd_human_interface_device::page::Keyboard;
+
+/// One report of a macro, and how long after the one before it the report went out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Frame<T> {
+    pub report: T,
+    pub delay_ms: u32,
+}
+
+struct Recording<T> {
+    name: String,
+    frames: Vec<Frame<T>>,
+    /// time the last frame was recorded, in ms
+    last_ms: Option<u64>,
+    /// a frame didn't fit, so the recording can't be kept
+    overflowed: bool,
+}
+
+/// Named sequences of reports, recorded as they're queued for the host, for replay by
+/// `play_macro()`. Holds up to `MAX_MACROS`, of up to `MAX_MACRO_FRAMES` reports each.
+pub(crate) struct MacroStore<T> {
+    macros: Vec<(String, Vec<Frame<T>>)>,
+    recording: Option<Recording<T>>,
+}
+
+impl<T> MacroStore<T> {
+    pub fn new() -> MacroStore<T> {
+        MacroStore {
+            macros: Vec::new(),
+            recording: None,
+        }
+    }
+    /// Starts recording the macro `name`, abandoning any recording already under way
+    pub fn start(&mut self, name: &str) {
+        self.recording = Some(Recording {
+            name: name.to_string(),
+            frames: Vec::new(),
+            last_ms: None,
+            overflowed: false,
+        });
+    }
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+    /// Adds a group of reports queued together at `now`. The first is delayed by the time since
+    /// the last group; the rest follow it at the host's polling rate, like they did when
+    /// recorded.
+    pub fn record(&mut self, reports: impl ExactSizeIterator<Item = T>, now: u64) {
+        if let Some(rec) = self.recording.as_mut() {
+            if rec.frames.len() + reports.len() > MAX_MACRO_FRAMES {
+                rec.overflowed = true;
+            }
+            if rec.overflowed {
+                return;
+            }
+            let delay = rec.last_ms.map(|last| now.saturating_sub(last)).unwrap_or(0);
+            for (index, report) in reports.enumerate() {
+                rec.frames.push(Frame {
+                    report,
+                    delay_ms: if index == 0 { delay.min(u32::MAX as u64) as u32 } else { 0 },
+                });
+            }
+            rec.last_ms = Some(now);
+        }
+    }
+    /// Ends the recording and stores the macro, replacing any of the same name. Returns the
+    /// number of frames recorded.
+    ///
+    /// Returns `Err(xous::Error::UseBeforeInit)` if nothing was being recorded, and
+    /// `Err(xous::Error::OutOfMemory)` if the recording overflowed `MAX_MACRO_FRAMES` or there's
+    /// no room for another macro; the recording is dropped in either case.
+    pub fn stop(&mut self) -> Result<usize, xous::Error> {
+        let rec = self.recording.take().ok_or(xous::Error::UseBeforeInit)?;
+        if rec.overflowed {
+            return Err(xous::Error::OutOfMemory);
+        }
+        let count = rec.frames.len();
+        match self.macros.iter_mut().find(|(name, _)| *name == rec.name) {
+            Some((_, frames)) => *frames = rec.frames,
+            None if self.macros.len() < MAX_MACROS => self.macros.push((rec.name, rec.frames)),
+            None => return Err(xous::Error::OutOfMemory),
+        }
+        Ok(count)
+    }
+    /// The frames of the macro `name`, or `Err(xous::Error::ServerNotFound)` if there's no such macro
+    pub fn get(&self, name: &str) -> Result<&[Frame<T>], xous::Error> {
+        self.macros
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, frames)| &frames[..])
+            .ok_or(xous::Error::ServerNotFound)
+    }
+}
+
+/// A keyboard report as a macro keeps it. Only the first `MACRO_REPORT_KEYS` keys are kept, which
+/// is as many as `send_keycode()` sends, and more than any character `send_str()` types.
+pub(crate) fn report_keys(report: &[Keyboard]) -> [u8; MACRO_REPORT_KEYS] {
+    let mut keys = [0u8; MACRO_REPORT_KEYS];
+    for (key, &code) in keys.iter_mut().zip(report.iter()) {
+        *key = code as u8;
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_enum::FromPrimitive;
+
+    #[test]
+    fn test_record_and_play() {
+        let mut store = MacroStore::<Vec<Keyboard>>::new();
+        assert_eq!(store.get("ab"), Err(xous::Error::ServerNotFound));
+        assert_eq!(store.stop(), Err(xous::Error::UseBeforeInit));
+
+        // two keystrokes, as `send_keycode()` with an auto key-up queues them
+        let a = vec![vec![Keyboard::A], Vec::new()];
+        let b = vec![vec![Keyboard::B, Keyboard::LeftShift], Vec::new()];
+        store.start("ab");
+        assert!(store.is_recording());
+        store.record(a.iter().cloned(), 1000);
+        store.record(b.iter().cloned(), 1250);
+        assert_eq!(store.stop(), Ok(4));
+        assert!(!store.is_recording());
+
+        // playing it back gives the same reports, in order, with the gap between the keystrokes
+        let frames = store.get("ab").unwrap();
+        let reports: Vec<Vec<Keyboard>> = frames.iter().map(|f| f.report.clone()).collect();
+        assert_eq!(reports, [a.clone(), b.clone()].concat());
+        let delays: Vec<u32> = frames.iter().map(|f| f.delay_ms).collect();
+        assert_eq!(delays, vec![0, 0, 250, 0]);
+        // and so do they after the trip to `play_macro()` and back through `send_keycode()`
+        let replayed: Vec<Vec<Keyboard>> = frames
+            .iter()
+            .map(|f| {
+                report_keys(&f.report)
+                    .iter()
+                    .filter(|&&key| key != 0)
+                    .map(|&key| Keyboard::from_primitive(key))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(replayed, reports);
+
+        // nothing is recorded outside of a recording
+        store.record(a.iter().cloned(), 2000);
+        assert_eq!(store.get("ab").unwrap().len(), 4);
+
+        // re-recording a name replaces it
+        store.start("ab");
+        store.record(b.iter().cloned(), 3000);
+        assert_eq!(store.stop(), Ok(2));
+        assert_eq!(store.get("ab").unwrap()[0].report, vec![Keyboard::B, Keyboard::LeftShift]);
+
+        // a recording that overflows is refused, and leaves nothing behind
+        store.start("long");
+        for i in 0..=MAX_MACRO_FRAMES / 2 {
+            store.record(a.iter().cloned(), i as u64);
+        }
+        assert_eq!(store.stop(), Err(xous::Error::OutOfMemory));
+        assert_eq!(store.get("long"), Err(xous::Error::ServerNotFound));
+
+        // as is a macro past the limit on how many are kept
+        for i in 1..MAX_MACROS {
+            store.start(&format!("m{}", i));
+            assert_eq!(store.stop(), Ok(0));
+        }
+        store.start("one too many");
+        assert_eq!(store.stop(), Err(xous::Error::OutOfMemory));
+        assert!(store.get("ab").is_ok());
+    }
+}
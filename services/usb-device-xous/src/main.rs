@@ -3,9 +3,35 @@
 
 mod api;
 mod mappings;
+mod allocator;
+mod report_queue;
+mod power_events;
+mod raw_hid;
+mod get_report;
+mod idle_rate;
+mod set_report;
+mod output_report;
+mod profiles;
+mod byte_table;
+mod keypad;
+mod macros;
+mod descriptor_log;
+mod country_code;
+mod battery;
+mod ms_os;
+mod string_table;
+mod dfu;
+mod relock;
+mod debug_lock;
+mod wakeup;
+mod debug_audit;
+mod quit;
+mod async_replies;
 
 use api::*;
 #[cfg(any(target_os = "none", target_os = "xous"))]
+use allocator::*;
+#[cfg(any(target_os = "none", target_os = "xous"))]
 mod hw;
 #[cfg(any(target_os = "none", target_os = "xous"))]
 use hw::*;
@@ -15,6 +41,45 @@ mod spinal_udc;
 use packed_struct::PackedStructSlice;
 #[cfg(any(target_os = "none", target_os = "xous"))]
 use spinal_udc::*;
+use relock::RelockDeadline;
+use debug_lock::DebugLock;
+use wakeup::Wakeup;
+use async_replies::AsyncReplies;
+use debug_audit::DebugAuditLog;
+#[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+use report_queue::ReportQueue;
+use report_queue::TypingProgress;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use power_events::PowerEventFilter;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use raw_hid::{RawHidInterface, RAW_HID_REPORT_LEN};
+use raw_hid::DeferredRx;
+#[cfg(all(any(target_os = "none", target_os = "xous"), feature="battery"))]
+use battery::{BatteryInterface, battery_report, BATTERY_REPORT_LEN};
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use get_report::ReportCache;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use idle_rate::IdleRates;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use ms_os::MsOsDescriptors;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use string_table::StringTable;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use profiles::PowerAttributes;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use dfu::{BlockForwarder, DfuClass, DfuMode};
+#[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+use set_report::LedOutput;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use byte_table::ByteTable;
+use macros::MacroStore;
+use output_report::OutputReports;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use descriptor_log::DescriptorLog;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use country_code::CountryCodePatch;
+#[cfg(any(target_os = "none", target_os = "xous"))]
+use usbd_human_interface_device::interface::InterfaceClass;
 
 #[cfg(not(any(target_os = "none", target_os = "xous")))]
 mod hosted;
@@ -25,12 +90,13 @@ use hosted::*;
 use num_traits::*;
 #[cfg(any(target_os = "none", target_os = "xous"))]
 use usb_device_xous::KeyboardLedsReport;
+#[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+use usb_device_xous::KeyboardLeds;
 use usbd_human_interface_device::device::fido::FidoMsg;
 #[cfg(any(target_os = "none", target_os = "xous"))]
 use usbd_human_interface_device::device::fido::FidoInterface;
 use xous::{msg_scalar_unpack, msg_blocking_scalar_unpack};
 use core::num::NonZeroU8;
-use std::collections::BTreeMap;
 
 #[cfg(any(target_os = "none", target_os = "xous"))]
 use usb_device::prelude::*;
@@ -70,6 +136,59 @@ impl Clock for EmbeddedClock {
     }
 }
 
+/// Hand the keyboard report at the head of the queue to the endpoint. The report is only
+/// removed from the queue once the endpoint accepts it, so this is called again every time
+/// the host polls the endpoint until the queue is empty.
+#[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+macro_rules! send_queued_key_report {
+    ($composite:expr, $queue:expr, $cache:expr) => {
+        if let Some(report) = $queue.front() {
+            let keyboard = $composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>();
+            match keyboard.write_report(report) {
+                // a report identical to the last one has nothing new to tell the host
+                Ok(_) | Err(UsbHidError::Duplicate) => {
+                    $cache.record_keys(u8::from(keyboard.id()), report);
+                    $queue.pop();
+                }
+                Err(UsbHidError::WouldBlock) => (),
+                Err(e) => {
+                    log::warn!("dropping keyboard report {:?}: {:?}", report, e);
+                    $queue.pop();
+                }
+            }
+            keyboard.tick().ok();
+        }
+    };
+}
+
+/// Makes sure the server is woken when the next idle resend is due, if any are called for. The
+/// timer thread is only told when the deadline moves.
+#[cfg(any(target_os = "none", target_os = "xous"))]
+macro_rules! arm_idle_resend {
+    ($idle:expr, $deadline:expr, $wakeup:expr, $tt:expr) => {{
+        let now = $tt.elapsed_ms();
+        let deadline = $idle.schedule(now).map(|delay| now + delay);
+        if deadline != $deadline {
+            $deadline = deadline;
+            match deadline {
+                Some(deadline) => $wakeup.set(deadline),
+                None => $wakeup.cancel(),
+            }
+        }
+    }};
+}
+
+/// Drops the device out of DFU mode, as it can't stay there once debug access is restricted
+#[cfg(any(target_os = "none", target_os = "xous"))]
+macro_rules! leave_dfu_mode {
+    ($dfu:expr, $usbmgmt:expr, $tt:expr) => {
+        if $dfu.mode() == DfuMode::Dfu {
+            log::info!("debug access restricted; leaving DFU mode");
+            reenumerate(&mut $usbmgmt, &$tt, || $dfu.set_mode(DfuMode::Runtime));
+        }
+    };
+}
+
 fn main() -> ! {
     log_server::init_wait().unwrap();
     log::set_max_level(log::LevelFilter::Info);
@@ -112,6 +231,10 @@ fn main() -> ! {
                         // block any rx requests forever
                         fido_listener = Some(msg);
                     }
+                    Some(Opcode::HookAsyncReplies) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                        // the async requests that follow are ignored, so they wait forever too
+                        xous::return_scalar2(msg.sender, 0, 0).unwrap();
+                    }),
                     Some(Opcode::Quit) => {
                         break;
                     }
@@ -151,7 +274,7 @@ fn main() -> ! {
     let usb_alloc = UsbBusAllocator::new(usbdev);
     #[cfg(any(target_os = "none", target_os = "xous"))]
     let clock = EmbeddedClock::new();
-    #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+    #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd", feature="battery"))]
     let mut composite = UsbHidClassBuilder::new()
         .add_interface(
             NKROBootKeyboardInterface::default_config(&clock),
@@ -159,20 +282,106 @@ fn main() -> ! {
         .add_interface(
             FidoInterface::default_config()
         )
+        .add_interface(
+            RawHidInterface::default_config()
+        )
+        .add_interface(
+            BatteryInterface::default_config()
+        )
         .build(&usb_alloc);
-    #[cfg(all(any(target_os = "none", target_os = "xous"), not(feature="emukbd")))]
+    #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd", not(feature="battery")))]
     let mut composite = UsbHidClassBuilder::new()
+        .add_interface(
+            NKROBootKeyboardInterface::default_config(&clock),
+        )
         .add_interface(
             FidoInterface::default_config()
         )
+        .add_interface(
+            RawHidInterface::default_config()
+        )
+        .build(&usb_alloc);
+    #[cfg(all(any(target_os = "none", target_os = "xous"), not(feature="emukbd"), feature="battery"))]
+    let mut composite = UsbHidClassBuilder::new()
+        .add_interface(
+            FidoInterface::default_config()
+        )
+        .add_interface(
+            RawHidInterface::default_config()
+        )
+        .add_interface(
+            BatteryInterface::default_config()
+        )
+        .build(&usb_alloc);
+    #[cfg(all(any(target_os = "none", target_os = "xous"), not(feature="emukbd"), not(feature="battery")))]
+    let mut composite = UsbHidClassBuilder::new()
+        .add_interface(
+            FidoInterface::default_config()
+        )
+        .add_interface(
+            RawHidInterface::default_config()
+        )
         .build(&usb_alloc);
 
+    // takes firmware downloads, once a server is registered to take them. Allocated after the
+    // HID interfaces, so its interface comes last.
     #[cfg(any(target_os = "none", target_os = "xous"))]
-    let mut usb_dev = UsbDeviceBuilder::new(&usb_alloc, UsbVidPid(0x1209, 0x0001))
-        .manufacturer("Kosagi")
-        .product("Precursor")
-        .serial_number(&serial_number)
-        .build();
+    let mut dfu = DfuClass::new(&usb_alloc, BlockForwarder::new(dfu::spawn_block_lender(cid)));
+    // what the device is presented as; a change to either takes a rebuild and re-enumeration
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut profile = KeyboardProfile::Generic;
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut power = PowerAttributes::default();
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut usb_dev = build_usb_device(&usb_alloc, profile, power, &serial_number);
+    // the interfaces whose reports the server sends
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let report_interfaces = [
+        #[cfg(feature="emukbd")]
+        u8::from(composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>().id()),
+        u8::from(composite.interface::<RawHidInterface<'_, _>, _>().id()),
+        #[cfg(feature="battery")]
+        u8::from(composite.interface::<BatteryInterface<'_, _>, _>().id()),
+    ];
+    // answers GET_REPORT for them
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut report_cache = ReportCache::new(&report_interfaces);
+    // sends their last reports again as often as the host asks with SET_IDLE. The keyboard isn't
+    // one of them: its interface keeps its own idle rate, and `tick()` does its resends.
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut idle_rates = IdleRates::new(&[
+        u8::from(composite.interface::<RawHidInterface<'_, _>, _>().id()),
+        #[cfg(feature="battery")]
+        u8::from(composite.interface::<BatteryInterface<'_, _>, _>().id()),
+    ]);
+    // when the next idle resend is due, as last handed to its timer
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut idle_deadline: Option<u64> = None;
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let idle_wakeup = Wakeup::spawn(cid, Opcode::IdleResend);
+    // tells Windows which interface, if any, to bind WinUSB to
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut ms_os = MsOsDescriptors::new();
+    // answers for the device's strings in whichever language the host asks for
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut strings = {
+        let ids = profiles::profile_ids(KeyboardProfile::Generic);
+        StringTable::new(ids.manufacturer, ids.product, &serial_number)
+    };
+    // passes the output reports for them on to the servers registered to take them, one per
+    // interface, each known along with the process that registered it
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut output_reports = OutputReports::<xous::CID, Option<NonZeroU8>>::new(&report_interfaces);
+    #[cfg(not(any(target_os = "none", target_os = "xous")))]
+    let mut output_reports = OutputReports::<xous::CID, Option<NonZeroU8>>::new(&[]);
+    // the keyboard's LED report is passed on from where the LED listeners are told of it
+    #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+    output_reports.not_from_control(u8::from(composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>().id()));
+    // takes the keyboard LED output report, however the host chooses to send it
+    #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+    let mut led_output = LedOutput::new(
+        u8::from(composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>().id())
+    );
     #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
     {
         let keyboard = composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>();
@@ -182,27 +391,71 @@ fn main() -> ! {
 
     #[cfg(any(target_os = "none", target_os = "xous"))]
     let mut led_state: KeyboardLedsReport = KeyboardLedsReport::default();
+    // keyboard reports waiting for the host to poll the endpoint
+    #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+    let mut key_queue = ReportQueue::<Vec<Keyboard>>::new(HID_REPORT_QUEUE_LEN);
+    let mut typing_progress = TypingProgress::new();
+    // keystrokes for `send_bytes()`; the printable characters follow the user's keymap
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let keymap_chars = |ch: char| match native_map {
+        KeyMap::Dvorak => mappings::char_to_hid_code_dvorak(ch),
+        _ => mappings::char_to_hid_code_us101(ch),
+    };
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut byte_table = ByteTable::new(ByteMap::Terminal, keymap_chars);
+    // set by `set_numeric_mode()`
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut numeric_mode = false;
+    // the current `send_str()` has keypad digits, but the host has NumLock off
+    #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+    let mut numlock_pending = false;
+    // reports queued by `send_keycode()` and `send_str()`, recorded for `play_macro()`
+    let mut macro_store = MacroStore::<[u8; MACRO_REPORT_KEYS]>::new();
+    // servers to tell when the host suspends or resumes the bus
+    let mut power_listeners = Vec::<xous::CID>::new();
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    let mut power_filter = PowerEventFilter::new();
+    // servers to tell when the host changes the keyboard LEDs, and the message id each wants
+    let mut led_listeners = Vec::<(xous::CID, u32)>::new();
     let mut fido_listener: Option<xous::MessageEnvelope> = None;
     // under the theory that PIDs are unforgeable. TODO: check that PIDs are unforgeable.
     // also if someone commandeers a process, all bets are off within that process (this is a general statement)
     let mut fido_listener_pid: Option<NonZeroU8> = None;
     let mut fido_rx_queue = VecDeque::<[u8; 64]>::new();
+    // an `AsyncUsbHid` waiting on a U2F packet, in place of `fido_listener`: its reply server,
+    // and the token to answer with
+    let mut fido_async: Option<(xous::CID, u32)> = None;
+    let mut async_replies = AsyncReplies::<xous::CID>::new();
+    // the raw HID interface is locked to its first user, like U2F
+    let mut raw_hid_pid: Option<NonZeroU8> = None;
+    let mut raw_hid_rx = DeferredRx::<xous::MessageEnvelope>::new();
 
-    let mut lockstatus_force_update = true; // some state to track if we've been through a susupend/resume, to help out the status thread with its UX update after a restart-from-cold
+    // the debug lock across suspend/resume, and whether the status bar has to redraw it
+    let mut debug_lock = DebugLock::new();
+    // when debug access unlocked by `unlock_debug_for()` locks again
+    let mut relock = RelockDeadline::new();
+    let relock_wakeup = Wakeup::spawn(cid, Opcode::RelockTimeout);
+    // every change to debug access, for review
+    let mut debug_audit = DebugAuditLog::new();
     loop {
         let mut msg = xous::receive_message(usbdev_sid).unwrap();
         match FromPrimitive::from_usize(msg.body.id()) {
             Some(Opcode::SuspendResume) => msg_scalar_unpack!(msg, token, _, _, _, {
+                debug_lock.suspend(&usbmgmt);
                 usbmgmt.xous_suspend();
+                // don't replay stale keystrokes at the host once we come back
+                #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+                key_queue.clear();
                 susres.suspend_until_resume(token).expect("couldn't execute suspend/resume");
                 usbmgmt.xous_resume();
-                lockstatus_force_update = true; // notify the status bar that yes, it does need to redraw the lock status, even if the value hasn't changed since the last read
+                // the lock doesn't survive the power-down, so it's put back, and the status bar redraws it
+                debug_lock.resume(&mut usbmgmt);
             }),
             Some(Opcode::U2fRxDeferred) => {
                 if fido_listener_pid.is_none() {
                     fido_listener_pid = msg.sender.pid();
                 }
-                if fido_listener.is_some() {
+                if fido_listener.is_some() || fido_async.is_some() {
                     log::error!("Double-listener request detected. There should only ever by one registered listener at a time.");
                     log::error!("This will cause an upstream server to misbehave, but not panicing so the problem can be debugged.");
                     // the receiver will get a response with the `code` field still in the `RxWait` state to indicate the problem
@@ -252,18 +505,78 @@ fn main() -> ! {
                 }
                 buffer.replace(u2f_ipc).unwrap();
             }
+            Some(Opcode::RawHidRxDeferred) => {
+                if raw_hid_pid.is_none() {
+                    raw_hid_pid = msg.sender.pid();
+                    #[cfg(any(target_os = "none", target_os = "xous"))]
+                    output_reports.lock(u8::from(composite.interface::<RawHidInterface<'_, _>, _>().id()), raw_hid_pid);
+                }
+                if raw_hid_pid == msg.sender.pid() {
+                    match raw_hid_rx.request(msg) {
+                        Ok((mut listener, data)) => ack_raw_hid_listener(&mut listener, &data),
+                        Err(Some(_displaced)) => {
+                            // dropping the old listener returns its buffer still in the `RxWait` state
+                            log::error!("Double-listener request detected on the raw HID interface; the older one is released empty-handed.");
+                        }
+                        Err(None) => log::trace!("registering deferred raw HID listener"),
+                    }
+                } else {
+                    log::warn!("raw HID interface is locked on first use; additional servers are ignored: {:?}", msg.sender);
+                    let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                    let mut raw_ipc = buffer.to_original::<U2fMsgIpc, _>().unwrap();
+                    raw_ipc.code = U2fCode::Denied;
+                    buffer.replace(raw_ipc).unwrap();
+                }
+            }
+            Some(Opcode::RawHidTx) => {
+                if raw_hid_pid.is_none() {
+                    raw_hid_pid = msg.sender.pid();
+                    #[cfg(any(target_os = "none", target_os = "xous"))]
+                    output_reports.lock(u8::from(composite.interface::<RawHidInterface<'_, _>, _>().id()), raw_hid_pid);
+                }
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut raw_ipc = buffer.to_original::<U2fMsgIpc, _>().unwrap();
+                if raw_hid_pid == msg.sender.pid() {
+                    assert_eq!(raw_ipc.code, U2fCode::Tx, "Expected U2fCode::Tx in wrapper");
+                    #[cfg(any(target_os = "none", target_os = "xous"))]
+                    let raw_hid = composite.interface::<RawHidInterface<'_, _>, _>();
+                    #[cfg(any(target_os = "none", target_os = "xous"))]
+                    if raw_hid.write_report(&raw_ipc.data).is_ok() {
+                        report_cache.record(u8::from(raw_hid.id()), &raw_ipc.data);
+                    }
+                    log::debug!("sent raw HID report {:x?}", &raw_ipc.data[..8]);
+                    raw_ipc.code = U2fCode::TxAck;
+                } else {
+                    raw_ipc.code = U2fCode::Denied;
+                }
+                buffer.replace(raw_ipc).unwrap();
+            }
             Some(Opcode::UsbIrqHandler) => {
+                #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+                let polled = usb_dev.poll(&mut [&mut report_cache, &mut idle_rates, &mut ms_os, &mut strings, &mut output_reports, &mut led_output, &mut dfu, &mut composite]);
+                #[cfg(all(any(target_os = "none", target_os = "xous"), not(feature="emukbd")))]
+                let polled = usb_dev.poll(&mut [&mut report_cache, &mut idle_rates, &mut ms_os, &mut strings, &mut output_reports, &mut dfu, &mut composite]);
                 #[cfg(any(target_os = "none", target_os = "xous"))]
-                if usb_dev.poll(&mut [&mut composite]) {
+                if polled {
                     #[cfg(feature="emukbd")]
                     {
                         let keyboard = composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>();
                         match keyboard.read_report() {
+                            // the LEDs written to the interrupt OUT endpoint
                             Ok(l) => {
-                                led_state = l;
+                                let mut code = [0u8; 1];
+                                l.pack_to_slice(&mut code).unwrap();
+                                led_output.set_report(&code);
                             }
                             Err(e) => log::trace!("KEYB ERR: {:?}", e),
                         }
+                        send_queued_key_report!(composite, key_queue, report_cache);
+                        // a keystroke's worth of room is enough for `AsyncUsbHid::send_str()` to go on
+                        if key_queue.len() + 2 <= HID_REPORT_QUEUE_LEN {
+                            for (cid, token) in async_replies.take_room_waiters() {
+                                send_async_reply(cid, token, 0, [0; 64]);
+                            }
+                        }
                     }
                     let u2f = composite.interface::<FidoInterface<'_, _>, _>();
                     match u2f.read_report() {
@@ -278,6 +591,9 @@ fn main() -> ! {
                                 log::trace!("ret deferred data {:x?}", &u2f_report.packet[..8]);
                                 buf.code = U2fCode::RxAck;
                                 response.replace(buf).unwrap();
+                            } else if let Some((cid, token)) = fido_async.take() {
+                                log::trace!("ret async data {:x?}", &u2f_report.packet[..8]);
+                                send_async_reply(cid, token, 0, u2f_report.packet);
                             } else {
                                 log::debug!("Got U2F packet, but no server to respond...queuing.");
                                 fido_rx_queue.push_back(u2f_report.packet);
@@ -285,10 +601,106 @@ fn main() -> ! {
                         },
                         Err(e) => log::trace!("U2F ERR: {:?}", e),
                     }
+                    let raw_hid = composite.interface::<RawHidInterface<'_, _>, _>();
+                    match raw_hid.read_report() {
+                        Ok(report) => {
+                            // once the interface is locked to its first user, no one else hears from it
+                            output_reports.received(u8::from(raw_hid.id()), &report);
+                            if let Some((mut listener, data)) = raw_hid_rx.incoming(report) {
+                                ack_raw_hid_listener(&mut listener, &data);
+                            } else {
+                                log::debug!("Got raw HID report, but no server to respond...queuing.");
+                            }
+                        }
+                        Err(e) => log::trace!("raw HID ERR: {:?}", e),
+                    }
+                }
+                // SET_IDLE arrives on the control endpoint, so look for a new rate even if `poll()` had nothing
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                arm_idle_resend!(idle_rates, idle_deadline, idle_wakeup, tt);
+                // SET_REPORT arrives on the control endpoint, so check for it even if `poll()` had nothing
+                #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+                if let Some(code) = led_output.take_change() {
+                    led_state = KeyboardLedsReport::unpack_from_slice(&[code]).unwrap();
+                    let keyboard = composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>();
+                    output_reports.received(u8::from(keyboard.id()), &[code]);
+                    led_listeners.retain(|&(cid, id)| {
+                        match xous::try_send_message(cid, xous::Message::new_scalar(id as usize, code as usize, 0, 0, 0)) {
+                            Ok(_) | Err(xous::Error::ServerQueueFull) => true,
+                            Err(e) => {
+                                log::warn!("dropping LED listener {}: {:?}", cid, e);
+                                false
+                            }
+                        }
+                    });
+                }
+                // SET_REPORT arrives on the control endpoint, so send on what there is even if `poll()` had nothing
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                for (cid, e) in output_reports.send(|cid, report| {
+                    Buffer::into_buf(report)
+                        .or(Err(xous::Error::InternalError))
+                        .and_then(|buf| buf.send(cid, OUTPUT_REPORT_ID))
+                        .map(|_| ())
+                }) {
+                    log::warn!("dropping output report observer {}: {:?}", cid, e);
+                    unsafe { xous::disconnect(cid).ok() };
+                }
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                if let Some(event) = power_filter.update(usb_dev.state()) {
+                    log::debug!("USB power event: {:?}", event);
+                    // don't let a listener with a full queue hold up the interrupt handler
+                    power_listeners.retain(|&cid| {
+                        match xous::try_send_message(cid, xous::Message::new_scalar(event.to_usize().unwrap(), 0, 0, 0, 0)) {
+                            Ok(_) | Err(xous::Error::ServerQueueFull) => true,
+                            Err(e) => {
+                                log::warn!("dropping USB power event listener {}: {:?}", cid, e);
+                                false
+                            }
+                        }
+                    });
+                }
+                // DFU_DETACH arrives on the control endpoint, like SET_REPORT
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                if dfu.take_detach() {
+                    if usbmgmt.get_disable_debug() {
+                        log::warn!("host asked for DFU mode, but debug access is restricted");
+                    } else {
+                        log::info!("host asked for DFU mode; re-enumerating");
+                        reenumerate(&mut usbmgmt, &tt, || dfu.set_mode(DfuMode::Dfu));
+                    }
                 }
             },
             Some(Opcode::SwitchCores) => msg_blocking_scalar_unpack!(msg, core, _, _, _, {
-                if core == 1 {
+                // leaving DFU mode takes a re-enumeration, so the host sees the interface change
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                if core != 2 && dfu.mode() == DfuMode::Dfu {
+                    dfu.set_mode(DfuMode::Runtime);
+                    usbmgmt.connect_device_core(false);
+                    tt.sleep_ms(500).unwrap();
+                }
+                let mut code = 0;
+                if core == 2 {
+                    // DFU mode can replace the firmware, so it's guarded like the debug core
+                    #[cfg(any(target_os = "none", target_os = "xous"))]
+                    if usbmgmt.get_disable_debug() {
+                        code = 2;
+                    } else if dfu.mode() == DfuMode::Off {
+                        code = 3;
+                    } else {
+                        log::info!("Connecting USB device core in DFU mode; disconnecting debug USB core");
+                        if usbmgmt.is_device_connected() {
+                            reenumerate(&mut usbmgmt, &tt, || dfu.set_mode(DfuMode::Dfu));
+                        } else {
+                            dfu.set_mode(DfuMode::Dfu);
+                            usbmgmt.connect_device_core(true);
+                            tt.sleep_ms(500).unwrap();
+                        }
+                    }
+                    #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                    {
+                        code = 3;
+                    }
+                } else if core == 1 {
                     log::info!("Connecting USB device core; disconnecting debug USB core");
                     usbmgmt.connect_device_core(true);
                     tt.sleep_ms(500).unwrap();
@@ -297,21 +709,73 @@ fn main() -> ! {
                     usbmgmt.connect_device_core(false);
                     tt.sleep_ms(500).unwrap();
                 }
-                xous::return_scalar(msg.sender, 0).unwrap();
+                xous::return_scalar(msg.sender, code).unwrap();
+            }),
+            Some(Opcode::SetProfile) => msg_blocking_scalar_unpack!(msg, code, _, _, _, {
+                let new_profile: Option<KeyboardProfile> = FromPrimitive::from_usize(code);
+                match new_profile {
+                    Some(new_profile) if !profiles::profile_allowed(new_profile) => {
+                        log::warn!("{:?} profile isn't built in", new_profile);
+                        xous::return_scalar(msg.sender, 1).unwrap();
+                    }
+                    #[cfg(any(target_os = "none", target_os = "xous"))]
+                    Some(new_profile) => {
+                        log::info!("switching to {:?} profile", new_profile);
+                        profile = new_profile;
+                        let ids = profiles::profile_ids(profile);
+                        #[cfg(feature="emukbd")]
+                        usbmgmt.set_hid_country_code(
+                            u8::from(composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>().id()),
+                            ids.country_code
+                        );
+                        reenumerate(&mut usbmgmt, &tt, || {
+                            usb_dev = build_usb_device(&usb_alloc, profile, power, &serial_number);
+                            strings.set_default(ids.manufacturer, ids.product);
+                        });
+                        xous::return_scalar(msg.sender, 0).unwrap();
+                    }
+                    #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                    Some(_) => {
+                        xous::return_scalar(msg.sender, 0).unwrap();
+                    }
+                    None => {
+                        xous::return_scalar(msg.sender, 1).unwrap();
+                    }
+                }
             }),
             Some(Opcode::WhichCore) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                let dfu_mode = dfu.mode() == DfuMode::Dfu;
+                #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                let dfu_mode = false;
                 if usbmgmt.is_device_connected() {
-                    xous::return_scalar(msg.sender, 1).unwrap();
+                    xous::return_scalar(msg.sender, if dfu_mode { 2 } else { 1 }).unwrap();
                 } else {
                     xous::return_scalar(msg.sender, 0).unwrap();
                 }
             }),
+            Some(Opcode::ListCores) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                // DFU mode needs its interface, which is only added once a server takes the firmware
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                let dfu_available = dfu.mode() != DfuMode::Off;
+                #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                let dfu_available = false;
+                let cores = CORES_ALWAYS | if dfu_available { CORE_DFU } else { 0 };
+                xous::return_scalar(msg.sender, cores).unwrap();
+            }),
             Some(Opcode::RestrictDebugAccess) => msg_scalar_unpack!(msg, restrict, _, _, _, {
+                let was_locked = usbmgmt.get_disable_debug();
                 if restrict == 0 {
                     usbmgmt.disable_debug(false);
                 } else {
                     usbmgmt.disable_debug(true);
+                    // and DFU mode goes with it
+                    #[cfg(any(target_os = "none", target_os = "xous"))]
+                    leave_dfu_mode!(dfu, usbmgmt, tt);
                 }
+                relock.cancel();
+                relock_wakeup.cancel();
+                debug_audit.record(tt.elapsed_ms(), was_locked, usbmgmt.get_disable_debug(), msg.sender.pid());
             }),
             Some(Opcode::IsRestricted) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
                 if usbmgmt.get_disable_debug() {
@@ -320,35 +784,276 @@ fn main() -> ! {
                     xous::return_scalar(msg.sender, 0).unwrap();
                 }
             }),
+            Some(Opcode::UnlockDebugFor) => msg_blocking_scalar_unpack!(msg, duration_ms, _, _, _, {
+                let was_locked = usbmgmt.get_disable_debug();
+                usbmgmt.disable_debug(false);
+                debug_audit.record(tt.elapsed_ms(), was_locked, usbmgmt.get_disable_debug(), msg.sender.pid());
+                let deadline = relock.unlock_for(tt.elapsed_ms(), duration_ms as u32);
+                debug_lock.force_update();
+                relock_wakeup.set(deadline);
+                xous::return_scalar(msg.sender, 0).unwrap();
+            }),
+            Some(Opcode::RelockTimeout) => msg_scalar_unpack!(msg, _, _, _, _, {
+                if relock.expired(tt.elapsed_ms()) {
+                    log::info!("debug access unlock timed out; locking");
+                    let was_locked = usbmgmt.get_disable_debug();
+                    usbmgmt.disable_debug(true);
+                    debug_audit.record(tt.elapsed_ms(), was_locked, usbmgmt.get_disable_debug(), None);
+                    #[cfg(any(target_os = "none", target_os = "xous"))]
+                    leave_dfu_mode!(dfu, usbmgmt, tt);
+                    // so the status bar shows the lock without waiting on anything else to change
+                    debug_lock.force_update();
+                }
+            }),
+            #[cfg(any(target_os = "none", target_os = "xous"))]
+            Some(Opcode::IdleResend) => msg_scalar_unpack!(msg, _, _, _, _, {
+                // the timer has let go of the deadline it fired for
+                idle_deadline = None;
+                for iface in idle_rates.due(tt.elapsed_ms()) {
+                    #[cfg(feature="battery")]
+                    {
+                        let battery = composite.interface::<BatteryInterface<'_, _>, _>();
+                        if iface == u8::from(battery.id()) {
+                            let mut report = [0u8; BATTERY_REPORT_LEN];
+                            report.copy_from_slice(&report_cache.get_report(iface, BATTERY_REPORT_LEN).unwrap());
+                            battery.write_report(&report).ok();
+                            continue;
+                        }
+                    }
+                    let raw_hid = composite.interface::<RawHidInterface<'_, _>, _>();
+                    if iface == u8::from(raw_hid.id()) {
+                        let mut report = [0u8; RAW_HID_REPORT_LEN];
+                        report.copy_from_slice(&report_cache.get_report(iface, RAW_HID_REPORT_LEN).unwrap());
+                        raw_hid.write_report(&report).ok();
+                    }
+                }
+                arm_idle_resend!(idle_rates, idle_deadline, idle_wakeup, tt);
+            }),
+            #[cfg(not(any(target_os = "none", target_os = "xous")))]
+            Some(Opcode::IdleResend) => {}
             Some(Opcode::DebugUsbOp) => msg_blocking_scalar_unpack!(msg, update_req, new_state, _, _, {
                 if update_req != 0 {
+                    let was_locked = usbmgmt.get_disable_debug();
                     // if new_state is true (not 0), then try to lock the USB port
                     // if false, try to unlock the USB port
                     if new_state != 0 {
                         usbmgmt.disable_debug(true);
+                        #[cfg(any(target_os = "none", target_os = "xous"))]
+                        leave_dfu_mode!(dfu, usbmgmt, tt);
                     } else {
                         usbmgmt.disable_debug(false);
                     }
+                    relock.cancel();
+                    relock_wakeup.cancel();
+                    debug_audit.record(tt.elapsed_ms(), was_locked, usbmgmt.get_disable_debug(), msg.sender.pid());
                 }
                 // at this point, *read back* the new state -- don't assume it "took". The readback is always based on
-                // a real hardware value and not the requested value. for now, always false.
-                let is_locked = if usbmgmt.get_disable_debug() {
-                    1
-                } else {
-                    0
-                };
-
+                // a real hardware value and not the requested value.
                 // this is a performance optimization. we could always redraw the status, but, instead we only redraw when
                 // the status has changed. However, there is an edge case: on a resume from suspend, the status needs a redraw,
                 // even if nothing has changed. Thus, we have this separate boolean we send back to force an update in the
                 // case that we have just come out of a suspend.
-                let force_update = if lockstatus_force_update {
-                    1
+                let (is_locked, force_update) = debug_lock.status(&usbmgmt);
+                xous::return_scalar2(msg.sender, is_locked as usize, force_update as usize).expect("couldn't return status");
+            }),
+            Some(Opcode::GetDebugAuditLog) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut log = DebugAuditLogIpc::default();
+                for (dst, entry) in log.entries.iter_mut().zip(debug_audit.entries()) {
+                    *dst = *entry;
+                    log.len += 1;
+                }
+                buffer.replace(log).unwrap();
+            }
+            Some(Opcode::GetStats) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                buffer.replace(usbmgmt.stats()).unwrap();
+            }
+            Some(Opcode::ResetStats) => msg_scalar_unpack!(msg, _, _, _, _, {
+                usbmgmt.reset_stats();
+            }),
+            Some(Opcode::GetUdcRegs) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                buffer.replace(usbmgmt.dump_regs()).unwrap();
+            }
+            Some(Opcode::SetEndpointEnabled) => msg_blocking_scalar_unpack!(msg, address, enabled, _, _, {
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                {
+                    let ep_addr = EndpointAddress::from(address as u8);
+                    let result = if enabled != 0 {
+                        usb_dev.bus().enable_ep(ep_addr)
+                    } else {
+                        usb_dev.bus().disable_ep(ep_addr)
+                    };
+                    xous::return_scalar(msg.sender, if result.is_ok() { 0 } else { 1 }).unwrap();
+                }
+                #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                {
+                    let _ = (address, enabled);
+                    xous::return_scalar(msg.sender, 2).unwrap();
+                }
+            }),
+            Some(Opcode::SetEndpointNack) => msg_blocking_scalar_unpack!(msg, address, nack, _, _, {
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                {
+                    let result = usb_dev.bus().set_nack(EndpointAddress::from(address as u8), nack != 0);
+                    xous::return_scalar(msg.sender, if result.is_ok() { 0 } else { 1 }).unwrap();
+                }
+                #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                {
+                    let _ = (address, nack);
+                    xous::return_scalar(msg.sender, 2).unwrap();
+                }
+            }),
+            Some(Opcode::GetFrameNumber) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                // the frame counter only runs once the host has configured the device
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                let configured = usb_dev.state() == UsbDeviceState::Configured;
+                #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                let configured = false;
+                if configured {
+                    xous::return_scalar2(msg.sender, 0, usbmgmt.frame_number() as usize).unwrap();
                 } else {
-                    0
+                    xous::return_scalar2(msg.sender, 1, 0).unwrap();
+                }
+            }),
+            Some(Opcode::GetDescriptorBytes) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut descriptor = buffer.to_original::<api::UsbDescriptor, _>().unwrap();
+                match usbmgmt.descriptor(descriptor.kind, descriptor.index) {
+                    Ok(bytes) => {
+                        descriptor.data[..bytes.len()].copy_from_slice(&bytes);
+                        descriptor.len = Some(bytes.len() as u32);
+                    }
+                    Err(xous::Error::InvalidSyscall) => descriptor.unknown_kind = true,
+                    Err(_) => descriptor.len = None,
+                }
+                buffer.replace(descriptor).unwrap();
+            }
+            #[cfg(all(any(target_os = "none", target_os = "xous"), feature="battery"))]
+            Some(Opcode::ReportBattery) => msg_blocking_scalar_unpack!(msg, percent, _, _, _, {
+                let report = battery_report(percent.min(u8::MAX as usize) as u8);
+                let battery = composite.interface::<BatteryInterface<'_, _>, _>();
+                // a host that isn't listening yet can still read it with GET_REPORT
+                battery.write_report(&report).ok();
+                report_cache.record(u8::from(battery.id()), &report);
+                xous::return_scalar(msg.sender, 0).unwrap();
+            }),
+            #[cfg(not(all(any(target_os = "none", target_os = "xous"), feature="battery")))]
+            Some(Opcode::ReportBattery) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                // there's no battery interface to report on
+                xous::return_scalar(msg.sender, 1).unwrap();
+            }),
+            Some(Opcode::SetWinUsbInterface) => msg_blocking_scalar_unpack!(msg, enable, interface, _, _, {
+                let interface = if enable != 0 { Some(interface as u8) } else { None };
+                log::info!("WinUSB interface: {:?}", interface);
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                {
+                    // hosts only read the MS OS descriptors on enumeration
+                    reenumerate(&mut usbmgmt, &tt, || ms_os.set_interface(interface));
+                }
+                xous::return_scalar(msg.sender, 0).unwrap();
+            }),
+            #[cfg(any(target_os = "none", target_os = "xous"))]
+            Some(Opcode::AddStringLanguage) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut language = buffer.to_original::<api::StringLanguage, _>().unwrap();
+                match strings.add_language(
+                    language.langid,
+                    language.strings.manufacturer.as_str().unwrap_or(""),
+                    language.strings.product.as_str().unwrap_or(""),
+                ) {
+                    Ok(()) => {
+                        log::info!("added strings for LANGID {:04x}", language.langid);
+                        // hosts read the strings when enumerating, and may keep them
+                        reenumerate(&mut usbmgmt, &tt, || ());
+                    }
+                    Err(_) => language.full = true,
+                }
+                buffer.replace(language).unwrap();
+            }
+            #[cfg(not(any(target_os = "none", target_os = "xous")))]
+            Some(Opcode::AddStringLanguage) => {}
+            Some(Opcode::SetSelfPowered) => msg_blocking_scalar_unpack!(msg, self_powered, _, _, _, {
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                {
+                    power.self_powered = self_powered != 0;
+                    log::info!("configuration bmAttributes now {:02x}", power.bm_attributes());
+                    // the attributes are in the configuration descriptor, which hosts only read
+                    // when enumerating
+                    reenumerate(&mut usbmgmt, &tt, || {
+                        usb_dev = build_usb_device(&usb_alloc, profile, power, &serial_number);
+                    });
+                }
+                #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                let _ = self_powered;
+                xous::return_scalar(msg.sender, 0).unwrap();
+            }),
+            Some(Opcode::HookAsyncReplies) => msg_blocking_scalar_unpack!(msg, s0, s1, s2, s3, {
+                let reply_sid = xous::SID::from_u32(s0 as u32, s1 as u32, s2 as u32, s3 as u32);
+                match xous::connect(reply_sid) {
+                    Ok(cid) => {
+                        let handle = async_replies.register(msg.sender.pid(), cid);
+                        xous::return_scalar2(msg.sender, 0, handle as usize).unwrap();
+                    }
+                    Err(e) => {
+                        log::warn!("couldn't connect to async reply server: {:?}", e);
+                        xous::return_scalar2(msg.sender, 1, 0).unwrap();
+                    }
+                }
+            }),
+            Some(Opcode::UnhookAsyncReplies) => msg_blocking_scalar_unpack!(msg, handle, _, _, _, {
+                if let Some(cid) = async_replies.unregister(msg.sender.pid(), handle as u32) {
+                    if fido_async.map(|(waiting, _)| waiting) == Some(cid) {
+                        fido_async = None;
+                    }
+                    unsafe { xous::disconnect(cid).ok() };
+                }
+                xous::return_scalar(msg.sender, 0).unwrap();
+            }),
+            Some(Opcode::U2fRxAsync) => msg_scalar_unpack!(msg, handle, token, _, _, {
+                let cid = match async_replies.server(msg.sender.pid(), handle as u32) {
+                    Some(cid) => cid,
+                    None => {
+                        log::warn!("async U2F request from {:?}, which has no reply server", msg.sender);
+                        continue;
+                    }
                 };
-                xous::return_scalar2(msg.sender, is_locked, force_update).expect("couldn't return status");
-                lockstatus_force_update = false;
+                if fido_listener_pid.is_none() {
+                    fido_listener_pid = msg.sender.pid();
+                }
+                if fido_listener_pid != msg.sender.pid() {
+                    log::warn!("U2F interface capability is locked on first use; additional servers are ignored: {:?}", msg.sender);
+                    send_async_reply(cid, token as u32, 1, [0; 64]);
+                } else if let Some(data) = fido_rx_queue.pop_front() {
+                    send_async_reply(cid, token as u32, 0, data);
+                } else {
+                    if fido_listener.is_some() {
+                        log::error!("Double-listener request detected. There should only ever by one registered listener at a time.");
+                    }
+                    // a request that was already parked is turned away, rather than left hanging
+                    if let Some((waiting, waiting_token)) = fido_async.replace((cid, token as u32)) {
+                        send_async_reply(waiting, waiting_token, 1, [0; 64]);
+                    }
+                }
+            }),
+            Some(Opcode::LinkStatusAsync) => msg_scalar_unpack!(msg, handle, token, _, _, {
+                if let Some(cid) = async_replies.server(msg.sender.pid(), handle as u32) {
+                    #[cfg(any(target_os = "none", target_os = "xous"))]
+                    send_async_reply(cid, token as u32, usb_dev.state() as u32, [0; 64]);
+                    #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                    send_async_reply(cid, token as u32, 0, [0; 64]);
+                }
+            }),
+            Some(Opcode::KeyQueueRoomAsync) => msg_scalar_unpack!(msg, handle, token, _, _, {
+                if let Some(cid) = async_replies.server(msg.sender.pid(), handle as u32) {
+                    #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+                    if key_queue.len() + 2 > HID_REPORT_QUEUE_LEN {
+                        async_replies.wait_for_room(cid, token as u32);
+                        continue;
+                    }
+                    send_async_reply(cid, token as u32, 0, [0; 64]);
+                }
             }),
             Some(Opcode::LinkStatus) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
                 #[cfg(any(target_os = "none", target_os = "xous"))]
@@ -372,16 +1077,20 @@ fn main() -> ! {
                     let auto_up = if autoup == 1 {true} else {false};
                     #[cfg(feature="emukbd")]
                     {
-                        let keyboard = composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>();
-                        keyboard.write_report(&codes).ok();
-                        keyboard.tick().unwrap();
-                        tt.sleep_ms(30).ok();
+                        let mut group = vec![codes];
                         if auto_up {
-                            keyboard.write_report(&[]).ok(); // this is the key-up
-                            keyboard.tick().unwrap();
-                            tt.sleep_ms(30).ok();
+                            group.push(Vec::new()); // this is the key-up
+                        }
+                        if key_queue.push_group(group.clone()).is_ok() {
+                            macro_store.record(group.iter().map(|r| macros::report_keys(r)), tt.elapsed_ms());
+                            send_queued_key_report!(composite, key_queue, report_cache);
+                            xous::return_scalar(msg.sender, 0).unwrap();
+                        } else {
+                            // tell the caller the queue is full, and to try again later
+                            xous::return_scalar(msg.sender, 2).unwrap();
                         }
                     }
+                    #[cfg(not(feature="emukbd"))]
                     xous::return_scalar(msg.sender, 0).unwrap();
                 } else {
                     xous::return_scalar(msg.sender, 1).unwrap();
@@ -397,31 +1106,243 @@ fn main() -> ! {
                 let mut usb_send = buffer.to_original::<api::UsbString, _>().unwrap();
                 #[cfg(not(any(target_os = "none", target_os = "xous")))]
                 let usb_send = buffer.to_original::<api::UsbString, _>().unwrap(); // suppress mut warning on hosted mode
+                if let Some(total) = usb_send.total {
+                    typing_progress.begin(total);
+                    #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+                    {
+                        numlock_pending = numeric_mode && !led_state.num_lock()
+                            && usb_send.s.as_str().unwrap().chars().any(keypad::needs_numlock);
+                    }
+                }
                 #[cfg(any(target_os = "none", target_os = "xous"))]
-                {
+                if usb_send.total.is_none() && typing_progress.cancelled() {
+                    // the rest of this string was cancelled; tell the sender how much made it out
+                    usb_send.cancelled = true;
+                    usb_send.sent = Some(typing_progress.get().0);
+                } else if usb_dev.state() == UsbDeviceState::Configured {
+                    // (without a host, nothing would ever drain the queue; `sent` stays `None` to say so)
                     let mut sent = 0;
+                    // turn NumLock on ahead of the keypad digits
+                    #[cfg(feature="emukbd")]
+                    if numlock_pending && key_queue.push_group(vec![vec![Keyboard::KeypadNumLockAndClear], Vec::new()]).is_ok() {
+                        numlock_pending = false;
+                    }
                     for ch in usb_send.s.as_str().unwrap().chars() {
+                        // nothing goes out until the NumLock toggle has fit in the queue
+                        #[cfg(feature="emukbd")]
+                        if numlock_pending {
+                            break;
+                        }
                         // ASSUME: user's keyboard type matches the preference on their Precursor device.
-                        let codes = match native_map {
-                            KeyMap::Dvorak => mappings::char_to_hid_code_dvorak(ch),
-                            _ => mappings::char_to_hid_code_us101(ch),
-                        };
+                        let codes = keypad::char_keys(ch, numeric_mode, keymap_chars);
+                        // stop at the first character that doesn't fit; the caller resends the rest
                         #[cfg(feature="emukbd")]
                         {
-                            let keyboard = composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>();
-                            keyboard.write_report(&codes).ok();
-                            keyboard.tick().unwrap();
-                            tt.sleep_ms(30).ok();
-                            keyboard.write_report(&[]).ok(); // this is the key-up
-                            keyboard.tick().unwrap();
-                            tt.sleep_ms(30).ok();
+                            let group = vec![codes, Vec::new()];
+                            if key_queue.push_char(group.clone()).is_err() {
+                                break;
+                            }
+                            macro_store.record(group.iter().map(|r| macros::report_keys(r)), tt.elapsed_ms());
                         }
                         sent += 1;
                     }
+                    #[cfg(feature="emukbd")]
+                    send_queued_key_report!(composite, key_queue, report_cache);
+                    typing_progress.advance(sent);
                     usb_send.sent = Some(sent);
                 }
                 buffer.replace(usb_send).unwrap();
             }
+            Some(Opcode::SendBytes) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                let mut usb_send = buffer.to_original::<api::UsbBytes, _>().unwrap();
+                #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                let usb_send = buffer.to_original::<api::UsbBytes, _>().unwrap(); // suppress mut warning on hosted mode
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                if usb_dev.state() == UsbDeviceState::Configured {
+                    let mut sent = 0;
+                    let mut skipped = 0;
+                    for &byte in usb_send.data[..usb_send.len as usize].iter() {
+                        match byte_table.keys(byte) {
+                            Some(codes) => {
+                                // stop at the first byte that doesn't fit; the caller resends the rest
+                                #[cfg(feature="emukbd")]
+                                if key_queue.push_group(vec![codes.clone(), Vec::new()]).is_err() {
+                                    break;
+                                }
+                            }
+                            None => skipped += 1,
+                        }
+                        sent += 1;
+                    }
+                    #[cfg(feature="emukbd")]
+                    send_queued_key_report!(composite, key_queue, report_cache);
+                    usb_send.sent = Some(sent);
+                    usb_send.skipped = skipped;
+                }
+                buffer.replace(usb_send).unwrap();
+            }
+            Some(Opcode::SetByteMap) => msg_blocking_scalar_unpack!(msg, code, _, _, _, {
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                match FromPrimitive::from_usize(code) {
+                    Some(map) => byte_table = ByteTable::new(map, keymap_chars),
+                    None => log::warn!("unknown byte map {}", code),
+                }
+                #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                let _ = code;
+                xous::return_scalar(msg.sender, 0).unwrap();
+            }),
+            Some(Opcode::SetNumericMode) => msg_blocking_scalar_unpack!(msg, use_keypad, _, _, _, {
+                #[cfg(any(target_os = "none", target_os = "xous"))]
+                {
+                    numeric_mode = use_keypad != 0;
+                }
+                #[cfg(not(any(target_os = "none", target_os = "xous")))]
+                let _ = use_keypad;
+                xous::return_scalar(msg.sender, 0).unwrap();
+            }),
+            Some(Opcode::StartRecording) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let name = buffer.to_original::<xous_ipc::String::<64>, _>().unwrap();
+                macro_store.start(name.as_str().unwrap_or(""));
+            }
+            Some(Opcode::StopRecording) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                match macro_store.stop() {
+                    Ok(frames) => xous::return_scalar2(msg.sender, 0, frames).unwrap(),
+                    Err(xous::Error::UseBeforeInit) => xous::return_scalar2(msg.sender, 1, 0).unwrap(),
+                    Err(e) => {
+                        log::warn!("couldn't keep macro: {:?}", e);
+                        xous::return_scalar2(msg.sender, 2, 0).unwrap();
+                    }
+                }
+            }),
+            Some(Opcode::GetMacro) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut usb_macro = buffer.to_original::<api::UsbMacro, _>().unwrap();
+                if let Ok(frames) = macro_store.get(usb_macro.name.as_str().unwrap_or("")) {
+                    for (dst, frame) in usb_macro.frames.iter_mut().zip(frames.iter()) {
+                        *dst = MacroFrame { keys: frame.report, delay_ms: frame.delay_ms };
+                    }
+                    usb_macro.len = Some(frames.len() as u32);
+                }
+                buffer.replace(usb_macro).unwrap();
+            }
+            Some(Opcode::KeyQueueDepth) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+                xous::return_scalar(msg.sender, key_queue.len()).unwrap();
+                #[cfg(not(all(any(target_os = "none", target_os = "xous"), feature="emukbd")))]
+                xous::return_scalar(msg.sender, 0).unwrap();
+            }),
+            Some(Opcode::TypingProgress) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                let (sent, total) = typing_progress.get();
+                xous::return_scalar2(msg.sender, sent as usize, total as usize).unwrap();
+            }),
+            Some(Opcode::CancelTyping) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+                let untyped = key_queue.untyped_chars();
+                #[cfg(not(all(any(target_os = "none", target_os = "xous"), feature="emukbd")))]
+                let untyped = 0;
+                let typed = typing_progress.cancel(untyped);
+                #[cfg(all(any(target_os = "none", target_os = "xous"), feature="emukbd"))]
+                if typed.is_some() {
+                    // drop what the host hasn't seen yet, and let go of any key still held down
+                    key_queue.clear();
+                    key_queue.push_group(vec![Vec::new()]).ok();
+                    send_queued_key_report!(composite, key_queue, report_cache);
+                }
+                xous::return_scalar(msg.sender, typed.unwrap_or(0) as usize).unwrap();
+            }),
+            Some(Opcode::HookLedChanges) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut hook = buffer.to_original::<LedHook, _>().unwrap();
+                let (s0, s1, s2, s3) = hook.sid;
+                match xous::connect(xous::SID::from_u32(s0, s1, s2, s3)) {
+                    Ok(cid) => {
+                        led_listeners.push((cid, hook.id));
+                        hook.registered = true;
+                    }
+                    Err(e) => log::warn!("couldn't connect to LED listener: {:?}", e),
+                }
+                buffer.replace(hook).unwrap();
+            }
+            #[cfg(any(target_os = "none", target_os = "xous"))]
+            Some(Opcode::HookDfuBlocks) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut hook = buffer.to_original::<DfuHook, _>().unwrap();
+                if usbmgmt.get_disable_debug() {
+                    log::warn!("debug access is restricted; not taking DFU blocks for {:?}", msg.sender);
+                    hook.result = 2;
+                } else if dfu.writer_mut().has_listener() {
+                    log::warn!("DFU blocks already go to another server; ignoring {:?}", msg.sender);
+                    hook.result = 2;
+                } else {
+                    let (s0, s1, s2, s3) = hook.sid;
+                    match xous::connect(xous::SID::from_u32(s0, s1, s2, s3)) {
+                        Ok(cid) => {
+                            dfu.writer_mut().set_listener(cid, hook.id);
+                            // the host sees the runtime interface when it next enumerates the device
+                            if dfu.mode() == DfuMode::Off {
+                                dfu.set_mode(DfuMode::Runtime);
+                            }
+                            hook.result = 0;
+                        }
+                        Err(e) => {
+                            log::warn!("couldn't connect to DFU block listener: {:?}", e);
+                            hook.result = 1;
+                        }
+                    }
+                }
+                buffer.replace(hook).unwrap();
+            }
+            #[cfg(any(target_os = "none", target_os = "xous"))]
+            Some(Opcode::DfuBlockAnswered) => msg_scalar_unpack!(msg, seq, answer, _, _, {
+                dfu.writer_mut().answered(seq as u32, answer);
+            }),
+            #[cfg(not(any(target_os = "none", target_os = "xous")))]
+            Some(Opcode::HookDfuBlocks) => {
+                // there's no DFU interface to take the blocks from
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut hook = buffer.to_original::<DfuHook, _>().unwrap();
+                hook.result = 3;
+                buffer.replace(hook).unwrap();
+            }
+            Some(Opcode::HookPowerEvents) => msg_blocking_scalar_unpack!(msg, s0, s1, s2, s3, {
+                let cb_sid = xous::SID::from_u32(s0 as u32, s1 as u32, s2 as u32, s3 as u32);
+                match xous::connect(cb_sid) {
+                    Ok(cid) => {
+                        power_listeners.push(cid);
+                        xous::return_scalar(msg.sender, 0).unwrap();
+                    }
+                    Err(e) => {
+                        log::warn!("couldn't connect to USB power event listener: {:?}", e);
+                        xous::return_scalar(msg.sender, 1).unwrap();
+                    }
+                }
+            }),
+            Some(Opcode::HookOutputReports) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut hook = buffer.to_original::<OutputReportHook, _>().unwrap();
+                if !output_reports.watches(hook.interface) {
+                    hook.result = 3;
+                } else if output_reports.observer(hook.interface).is_some() {
+                    log::warn!("output reports for interface {} already go to another server; ignoring {:?}", hook.interface, msg.sender);
+                    hook.result = 2;
+                } else {
+                    let (s0, s1, s2, s3) = hook.sid;
+                    match xous::connect(xous::SID::from_u32(s0, s1, s2, s3)) {
+                        Ok(cid) => {
+                            output_reports.register(hook.interface, (cid, msg.sender.pid())).ok();
+                            hook.result = 0;
+                        }
+                        Err(e) => {
+                            log::warn!("couldn't connect to output report observer: {:?}", e);
+                            hook.result = 1;
+                        }
+                    }
+                }
+                buffer.replace(hook).unwrap();
+            }
             #[cfg(any(target_os = "none", target_os = "xous"))]
             Some(Opcode::GetLedState) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
                 let mut code = [0u8; 1];
@@ -442,139 +1363,116 @@ fn main() -> ! {
         }
     }
     // clean up our program
-    log::trace!("main loop exit, destroying servers");
-    xns.unregister_server(usbdev_sid).unwrap();
-    xous::destroy_server(usbdev_sid).unwrap();
+    log::trace!("main loop exit, releasing clients and hardware");
+    // anyone still waiting on a report is turned away, rather than left hanging
+    if let Some(mut listener) = fido_listener.take() {
+        deny_listener(&mut listener);
+    }
+    if let Some((cid, token)) = fido_async.take() {
+        send_async_reply(cid, token, 1, [0; 64]);
+    }
+    for (cid, token) in async_replies.take_room_waiters() {
+        send_async_reply(cid, token, 1, [0; 64]);
+    }
+    for cid in async_replies.release() {
+        unsafe { xous::disconnect(cid).ok() };
+    }
+    if let Some(mut listener) = raw_hid_rx.release() {
+        deny_listener(&mut listener);
+    }
+    for cid in power_listeners.drain(..).chain(led_listeners.drain(..).map(|(cid, _)| cid)) {
+        unsafe { xous::disconnect(cid).ok() };
+    }
+    for cid in output_reports.release() {
+        unsafe { xous::disconnect(cid).ok() };
+    }
+    #[cfg(any(target_os = "none", target_os = "xous"))]
+    if let Some(cid) = dfu.writer_mut().take_listener() {
+        unsafe { xous::disconnect(cid).ok() };
+    }
+    usbmgmt.release_hardware();
+    log::trace!("destroying servers");
+    quit::withdraw(&xns, usbdev_sid).unwrap();
     log::trace!("quitting");
     xous::terminate_process(0)
 }
 
+/// Builds the device with the VID/PID and strings of `profile`, and the power attributes of
+/// `power`. The classes are built once against the same allocator and carry over unchanged.
 #[cfg(any(target_os = "none", target_os = "xous"))]
-pub(crate) const START_OFFSET: u32 = 0x0048 + 8 + 16; // align spinal free space to 16-byte boundary + 16 bytes for EP0 read
-#[cfg(any(target_os = "none", target_os = "xous"))]
-pub(crate) const END_OFFSET: u32 = 0x1000; // derived from RAMSIZE parameter: this could be a dynamically read out constant, but, in practice, it's part of the hardware
-/// USB endpoint allocator. The SpinalHDL USB controller appears as a block of
-/// unstructured memory to the host. You can specify pointers into the memory with
-/// an offset and length to define where various USB descriptors should be placed.
-/// This allocator manages that space.
-///
-/// Note that all allocations must be aligned to 16-byte boundaries. This is a restriction
-/// of the USB core.
-///
-/// Returns a full memory address as the pointer. Must be shifted left by 4 to get the
-/// aligned representation used by the SpinalHDL block.
+fn build_usb_device<'a>(
+    usb_alloc: &'a UsbBusAllocator<SpinalUsbDevice>,
+    profile: KeyboardProfile,
+    power: PowerAttributes,
+    serial_number: &'a str,
+) -> UsbDevice<'a, SpinalUsbDevice> {
+    let ids = profiles::profile_ids(profile);
+    let mut usb_dev = UsbDeviceBuilder::new(usb_alloc, UsbVidPid(ids.vid, ids.pid))
+        .manufacturer(ids.manufacturer)
+        .product(ids.product)
+        .serial_number(serial_number)
+        .self_powered(power.self_powered)
+        .supports_remote_wakeup(power.remote_wakeup)
+        .build();
+    // so GET_STATUS agrees with the configuration descriptor
+    usb_dev.set_self_powered(power.self_powered);
+    usb_dev
+}
+
+/// Has the host enumerate the device afresh, to pick up descriptors changed by `change`. The
+/// device core drops off the bus, if it's attached, while `change` runs, and comes back after.
 #[cfg(any(target_os = "none", target_os = "xous"))]
-pub(crate) fn alloc_inner(allocs: &mut BTreeMap<u32, u32>, requested: u32) -> Option<u32> {
-    if requested == 0 {
-        return None;
-    }
-    let with_descriptor = requested + 16; // the descriptor takes 3 words; add 4 because of the alignment requirement
-    let mut alloc_offset = START_OFFSET;
-    for (&offset, &length) in allocs.iter() {
-        // round length up to the nearest 16-byte increment
-        let length = if length & 0xF == 0 { length } else { (length + 16) & !0xF };
-        // println!("aoff: {}, cur: {}+{}", alloc_offset, offset, length);
-        assert!(offset >= alloc_offset, "allocated regions overlap");
-        if offset > alloc_offset {
-            if offset - alloc_offset >= with_descriptor {
-                // there's a hole in the list, insert the element here
-                break;
-            }
-        }
-        alloc_offset = offset + length;
+fn reenumerate(
+    usbmgmt: &mut SpinalUsbMgmt,
+    tt: &ticktimer_server::Ticktimer,
+    change: impl FnOnce(),
+) {
+    let connected = usbmgmt.is_device_connected();
+    if connected {
+        usbmgmt.connect_device_core(false);
+        tt.sleep_ms(500).unwrap();
     }
-    if alloc_offset + with_descriptor <= END_OFFSET {
-        allocs.insert(alloc_offset, with_descriptor);
-        Some(alloc_offset)
-    } else {
-        None
+    change();
+    if connected {
+        usbmgmt.connect_device_core(true);
+        tt.sleep_ms(500).unwrap();
     }
 }
-#[allow(dead_code)]
-pub(crate) fn dealloc_inner(allocs: &mut BTreeMap<u32, u32>, offset: u32) -> bool {
-    allocs.remove(&offset).is_some()
-}
-
-// run with `cargo test -- --nocapture --test-threads=1`:
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn test_alloc() {
-        use rand_chacha::ChaCha8Rng;
-        use rand_chacha::rand_core::SeedableRng;
-        use rand_chacha::rand_core::RngCore;
-        let mut rng = ChaCha8Rng::seed_from_u64(0);
-
-        let mut allocs = BTreeMap::<u32, u32>::new();
-        assert_eq!(alloc_inner(&mut allocs, 128), Some(START_OFFSET));
-        assert_eq!(alloc_inner(&mut allocs, 64), Some(START_OFFSET + 128));
-        assert_eq!(alloc_inner(&mut allocs, 256), Some(START_OFFSET + 128 + 64));
-        assert_eq!(alloc_inner(&mut allocs, 128), Some(START_OFFSET + 128 + 64 + 256));
-        assert_eq!(alloc_inner(&mut allocs, 128), Some(START_OFFSET + 128 + 64 + 256 + 128));
-        assert_eq!(alloc_inner(&mut allocs, 128), Some(START_OFFSET + 128 + 64 + 256 + 128 + 128));
-        assert_eq!(alloc_inner(&mut allocs, 0xFF00), None);
-
-        // create two holes and fill first hole, interleaved
-        assert_eq!(dealloc_inner(&mut allocs, START_OFFSET + 128 + 64), true);
-        let mut last_alloc = 0;
-        // consistency check and print out
-        for (&offset, &len) in allocs.iter() {
-            assert!(offset >= last_alloc, "new offset is inside last allocation!");
-            println!("{}-{}", offset, offset+len);
-            last_alloc = offset + len;
-        }
-
-        assert_eq!(alloc_inner(&mut allocs, 128), Some(START_OFFSET + 128 + 64));
-        assert_eq!(dealloc_inner(&mut allocs, START_OFFSET + 128 + 64 + 256 + 128), true);
-        assert_eq!(alloc_inner(&mut allocs, 128), Some(START_OFFSET + 128 + 64 + 128));
 
-        // alloc something that doesn't fit at all
-        assert_eq!(alloc_inner(&mut allocs, 256), Some(START_OFFSET + 128 + 64 + 256 + 128 + 128 + 128));
-
-        // fill second hole
-        assert_eq!(alloc_inner(&mut allocs, 128), Some(START_OFFSET + 128 + 64 + 256 + 128));
-
-        // final tail alloc
-        assert_eq!(alloc_inner(&mut allocs, 64), Some(START_OFFSET + 128 + 64 + 256 + 128 + 128 + 128 + 256));
-
-        println!("after structured test:");
-        let mut last_alloc = 0;
-        // consistency check and print out
-        for (&offset, &len) in allocs.iter() {
-            assert!(offset >= last_alloc, "new offset is inside last allocation!");
-            println!("{}-{}({})", offset, offset+len, len);
-            last_alloc = offset + len;
-        }
+/// Turns away a client waiting on `u2f_wait_incoming()` or `raw_hid_recv()`. The client is
+/// released when the envelope is dropped.
+fn deny_listener(listener: &mut xous::MessageEnvelope) {
+    let mut response = unsafe {
+        Buffer::from_memory_message_mut(listener.body.memory_message_mut().unwrap())
+    };
+    let mut buf = response.to_original::<U2fMsgIpc, _>().unwrap();
+    buf.code = U2fCode::Denied;
+    response.replace(buf).unwrap();
+}
 
-        // random alloc/dealloc and check for overlapping regions
-        let mut tracker = Vec::<u32>::new();
-        for _ in 0..10240 {
-            if rng.next_u32() % 2 == 0 {
-                if tracker.len() > 0 {
-                    //println!("tracker: {:?}", tracker);
-                    let index = tracker.remove((rng.next_u32() % tracker.len() as u32) as usize);
-                    //println!("removing: {} of {}", index, tracker.len());
-                    assert_eq!(dealloc_inner(&mut allocs, index), true);
-                }
-            } else {
-                let req = rng.next_u32() % 256;
-                if let Some(offset) = alloc_inner(&mut allocs, req) {
-                    //println!("tracker: {:?}", tracker);
-                    //println!("alloc: {}+{}", offset, req);
-                    tracker.push(offset);
-                }
+/// Answers the `AsyncUsbHid` request `token` through the reply server `cid`. The reply is
+/// sent, not lent, so the server doesn't wait on the client.
+fn send_async_reply(cid: xous::CID, token: u32, code: u32, data: [u8; 64]) {
+    let reply = AsyncReply { token, code, data };
+    match Buffer::into_buf(reply) {
+        Ok(buf) => {
+            if let Err(e) = buf.send(cid, ASYNC_REPLY_ID) {
+                log::warn!("couldn't send async reply: {:?}", e);
             }
         }
-
-        let mut last_alloc = 0;
-        // consistency check and print out
-        println!("after random test:");
-        for (&offset, &len) in allocs.iter() {
-            assert!(offset >= last_alloc, "new offset is inside last allocation!");
-            assert!(offset & 0xF == 0, "misaligned allocation detected");
-            println!("{}-{}({})", offset, offset+len, len);
-            last_alloc = offset + len;
-        }
+        Err(_) => log::error!("couldn't serialize async reply"),
     }
 }
+
+/// Fills in the buffer of a client waiting on `raw_hid_recv()`. The client is released when
+/// the envelope is dropped.
+fn ack_raw_hid_listener(listener: &mut xous::MessageEnvelope, data: &[u8; 64]) {
+    let mut response = unsafe {
+        Buffer::from_memory_message_mut(listener.body.memory_message_mut().unwrap())
+    };
+    let mut buf = response.to_original::<U2fMsgIpc, _>().unwrap();
+    assert_eq!(buf.code, U2fCode::RxWait, "Expected U2fcode::RxWait in wrapper");
+    buf.data.copy_from_slice(data);
+    buf.code = U2fCode::RxAck;
+    response.replace(buf).unwrap();
+}
@@ -0,0 +1,171 @@
+// Only polled on real hardware, but the descriptors are kept free of hardware dependencies so
+// that they can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+
+/// `bRequest` of the vendor request Windows reads the MS OS descriptors with. It's advertised in
+/// both the 0xEE string and the platform capability.
+pub(crate) const MS_VENDOR_CODE: u8 = 0x01;
+/// Index of the MS OS string descriptor
+const MS_OS_STRING_INDEX: u8 = 0xEE;
+/// `wIndex` of the vendor request for the MS OS 2.0 descriptor set
+const MS_OS_20_DESCRIPTOR_INDEX: u16 = 7;
+/// Windows 8.1, the first version to read MS OS 2.0 descriptors
+const WINDOWS_VERSION_8_1: u32 = 0x0603_0000;
+/// `bDevCapabilityType` of a platform capability
+const CAPABILITY_PLATFORM: u8 = 0x05;
+/// `bDescriptorType` of a device capability descriptor
+const DEVICE_CAPABILITY: u8 = 0x10;
+/// {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F}, in the byte order it goes out in
+const MS_OS_20_PLATFORM_UUID: [u8; 16] = [
+    0xDF, 0x60, 0xDD, 0xD8, 0x89, 0x45, 0xC7, 0x4C, 0x9C, 0xD2, 0x65, 0x9D, 0x9E, 0x64, 0x8A, 0x9F,
+];
+/// Length of the MS OS 2.0 descriptor set: the set header, and a configuration subset holding a
+/// function subset with the compatible ID
+const MS_OS_20_SET_LEN: usize = 10 + 8 + 8 + 20;
+
+/// The MS OS string descriptor: "MSFT100" and the vendor code
+pub(crate) fn ms_os_string_descriptor() -> [u8; 18] {
+    let mut descriptor = [0u8; 18];
+    descriptor[0] = descriptor.len() as u8;
+    descriptor[1] = 3; // STRING
+    for (dst, &ch) in descriptor[2..16].chunks_exact_mut(2).zip(b"MSFT100".iter()) {
+        dst[0] = ch;
+    }
+    descriptor[16] = MS_VENDOR_CODE;
+    descriptor
+}
+
+/// The BOS platform capability that points Windows at the MS OS 2.0 descriptor set
+pub(crate) fn ms_os_20_platform_capability() -> [u8; 28] {
+    let mut descriptor = [0u8; 28];
+    descriptor[0] = descriptor.len() as u8;
+    descriptor[1] = DEVICE_CAPABILITY;
+    descriptor[2] = CAPABILITY_PLATFORM;
+    // descriptor[3] is reserved
+    descriptor[4..20].copy_from_slice(&MS_OS_20_PLATFORM_UUID);
+    descriptor[20..24].copy_from_slice(&WINDOWS_VERSION_8_1.to_le_bytes());
+    descriptor[24..26].copy_from_slice(&(MS_OS_20_SET_LEN as u16).to_le_bytes());
+    descriptor[26] = MS_VENDOR_CODE;
+    // descriptor[27] is bAltEnumCode, which we don't use
+    descriptor
+}
+
+/// The MS OS 2.0 descriptor set, giving `interface` the WinUSB compatible ID so that Windows
+/// binds it without a driver being installed
+pub(crate) fn ms_os_20_descriptor_set(interface: u8) -> [u8; MS_OS_20_SET_LEN] {
+    let mut set = [0u8; MS_OS_20_SET_LEN];
+    // set header
+    set[0..2].copy_from_slice(&10u16.to_le_bytes());
+    set[2..4].copy_from_slice(&0u16.to_le_bytes());
+    set[4..8].copy_from_slice(&WINDOWS_VERSION_8_1.to_le_bytes());
+    set[8..10].copy_from_slice(&(MS_OS_20_SET_LEN as u16).to_le_bytes());
+    // configuration subset, for the one configuration
+    set[10..12].copy_from_slice(&8u16.to_le_bytes());
+    set[12..14].copy_from_slice(&1u16.to_le_bytes());
+    set[16..18].copy_from_slice(&((MS_OS_20_SET_LEN - 10) as u16).to_le_bytes());
+    // function subset, for the interface
+    set[18..20].copy_from_slice(&8u16.to_le_bytes());
+    set[20..22].copy_from_slice(&2u16.to_le_bytes());
+    set[22] = interface;
+    set[24..26].copy_from_slice(&((MS_OS_20_SET_LEN - 18) as u16).to_le_bytes());
+    // compatible ID, with no sub-compatible ID
+    set[26..28].copy_from_slice(&20u16.to_le_bytes());
+    set[28..30].copy_from_slice(&3u16.to_le_bytes());
+    set[30..36].copy_from_slice(b"WINUSB");
+    set
+}
+
+/// Answers Windows' requests for MS OS descriptors, so that it binds WinUSB to a vendor
+/// interface by itself. Polled ahead of the other classes, like the `ReportCache`. Until an
+/// interface is set, the device has no MS OS descriptors at all.
+pub(crate) struct MsOsDescriptors {
+    interface: Option<u8>,
+}
+
+impl MsOsDescriptors {
+    pub fn new() -> MsOsDescriptors {
+        MsOsDescriptors { interface: None }
+    }
+    /// Sets the interface that gets the WinUSB compatible ID, or `None` for no MS OS descriptors.
+    /// Hosts only read them when enumerating the device.
+    pub fn set_interface(&mut self, interface: Option<u8>) {
+        self.interface = interface;
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for MsOsDescriptors {
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> usb_device::Result<()> {
+        if self.interface.is_some() {
+            // the writer fills in the length and the descriptor and capability types
+            writer.capability(CAPABILITY_PLATFORM, &ms_os_20_platform_capability()[3..])?;
+        }
+        Ok(())
+    }
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let interface = match self.interface {
+            Some(interface) => interface,
+            None => return,
+        };
+        let req = *xfer.request();
+        if req.request_type == RequestType::Standard
+            && req.recipient == Recipient::Device
+            && req.request == Request::GET_DESCRIPTOR
+            && req.descriptor_type_index() == (3, MS_OS_STRING_INDEX)
+        {
+            xfer.accept_with(&ms_os_string_descriptor()).ok();
+        } else if req.request_type == RequestType::Vendor
+            && req.request == MS_VENDOR_CODE
+            && req.index == MS_OS_20_DESCRIPTOR_INDEX
+        {
+            xfer.accept_with(&ms_os_20_descriptor_set(interface)).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ms_os_descriptors() {
+        let string = ms_os_string_descriptor();
+        assert_eq!(string[0] as usize, string.len()); // bLength
+        assert_eq!(string[1], 3); // bDescriptorType: STRING
+        let signature: Vec<u16> = string[2..16].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        assert_eq!(String::from_utf16(&signature).unwrap(), "MSFT100");
+        assert_eq!(string[16], MS_VENDOR_CODE); // bMS_VendorCode
+        assert_eq!(string[17], 0); // bPad
+
+        let capability = ms_os_20_platform_capability();
+        assert_eq!(&capability[..4], &[28, 0x10, 0x05, 0]);
+        assert_eq!(
+            &capability[4..20],
+            &[0xDF, 0x60, 0xDD, 0xD8, 0x89, 0x45, 0xC7, 0x4C, 0x9C, 0xD2, 0x65, 0x9D, 0x9E, 0x64, 0x8A, 0x9F]
+        );
+        assert_eq!(&capability[20..24], &[0x00, 0x00, 0x03, 0x06]); // Windows 8.1
+        // the set the capability promises is the set that's sent, by the same vendor code
+        let set = ms_os_20_descriptor_set(3);
+        assert_eq!(u16::from_le_bytes([capability[24], capability[25]]) as usize, set.len());
+        assert_eq!(capability[26], string[16]);
+
+        // each descriptor in the set has the length it says, and the subsets cover what follows
+        assert_eq!(u16::from_le_bytes([set[8], set[9]]) as usize, set.len());
+        let mut offset = 0;
+        let mut kinds = Vec::new();
+        while offset < set.len() {
+            let len = u16::from_le_bytes([set[offset], set[offset + 1]]) as usize;
+            kinds.push(u16::from_le_bytes([set[offset + 2], set[offset + 3]]));
+            offset += len;
+        }
+        assert_eq!(offset, set.len());
+        assert_eq!(kinds, vec![0, 1, 2, 3]);
+        assert_eq!(u16::from_le_bytes([set[16], set[17]]) as usize, set.len() - 10);
+        assert_eq!(u16::from_le_bytes([set[24], set[25]]) as usize, set.len() - 18);
+        assert_eq!(set[22], 3); // bFirstInterface
+        assert_eq!(&set[30..38], b"WINUSB\0\0");
+        assert!(set[38..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,306 @@
+// Only polled on real hardware, but the forwarding is kept free of hardware dependencies so
+// that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use crate::api::{OutputReport, MAX_OUTPUT_REPORT_LEN};
+use std::collections::VecDeque;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+
+/// HID class request code for SET_REPORT
+const HID_SET_REPORT: u8 = 0x09;
+/// Report type for SET_REPORT, in the high byte of wValue
+const HID_REPORT_TYPE_OUTPUT: u8 = 0x02;
+
+/// One of the interfaces whose output reports are passed on
+struct Watched<C, P> {
+    interface: u8,
+    /// whether its SET_REPORT requests are picked off the control endpoint here
+    from_control: bool,
+    /// where its reports go, and the process that registered it
+    observer: Option<(C, P)>,
+    /// the process the interface is locked to, if it is
+    owner: Option<P>,
+}
+
+/// Passes the output reports the host writes to the interfaces in the list on to observers,
+/// one per interface, `C` being however an observer is reached and `P` the process that
+/// registered it. Reports arrive with SET_REPORT on the control endpoint, or on an interrupt
+/// OUT endpoint, which the server hands to `received()`. Nothing is kept for an interface
+/// while it has no observer.
+pub(crate) struct OutputReports<C, P> {
+    interfaces: Vec<Watched<C, P>>,
+    /// reports not yet sent to their observers, oldest first
+    pending: VecDeque<OutputReport>,
+}
+
+impl<C: Copy, P: Copy + PartialEq> OutputReports<C, P> {
+    /// Watches the interfaces in `interfaces`, with no observers to start with
+    pub fn new(interfaces: &[u8]) -> OutputReports<C, P> {
+        OutputReports {
+            interfaces: interfaces
+                .iter()
+                .map(|&interface| Watched {
+                    interface,
+                    from_control: true,
+                    observer: None,
+                    owner: None,
+                })
+                .collect(),
+            pending: VecDeque::new(),
+        }
+    }
+    fn watched(&mut self, interface: u8) -> Option<&mut Watched<C, P>> {
+        self.interfaces
+            .iter_mut()
+            .find(|w| w.interface == interface)
+    }
+    /// Leaves the SET_REPORT requests for `interface` to a class that handles them itself, as
+    /// `LedOutput` does for the keyboard, so that each report is passed on from one place only:
+    /// wherever the server hands it to `received()`.
+    pub fn not_from_control(&mut self, interface: u8) {
+        if let Some(watched) = self.watched(interface) {
+            watched.from_control = false;
+        }
+    }
+    pub fn watches(&self, interface: u8) -> bool {
+        self.interfaces.iter().any(|w| w.interface == interface)
+    }
+    /// The observer of `interface`, if it has one
+    pub fn observer(&self, interface: u8) -> Option<&(C, P)> {
+        self.interfaces
+            .iter()
+            .find(|w| w.interface == interface)
+            .and_then(|w| w.observer.as_ref())
+    }
+    /// Takes `observer` for `interface`, unless the interface isn't watched or already has
+    /// one, in which case it's handed back
+    pub fn register(&mut self, interface: u8, observer: (C, P)) -> Result<(), (C, P)> {
+        match self.watched(interface) {
+            Some(watched) if watched.observer.is_none() => {
+                watched.observer = Some(observer);
+                Ok(())
+            }
+            _ => Err(observer),
+        }
+    }
+    /// Locks `interface` to `owner`: from then on, its reports only go to an observer that
+    /// process registered, however they arrive. A lock, once taken, is kept.
+    pub fn lock(&mut self, interface: u8, owner: P) {
+        if let Some(watched) = self.watched(interface) {
+            if watched.owner.is_none() {
+                watched.owner = Some(owner);
+            }
+        }
+    }
+    /// Lets go of the observer of `interface`, e.g. once it can't be reached, along with any
+    /// reports it hasn't been sent yet
+    pub fn drop_observer(&mut self, interface: u8) -> Option<C> {
+        self.pending.retain(|report| report.interface != interface);
+        self.watched(interface)
+            .and_then(|w| w.observer.take())
+            .map(|(cid, _)| cid)
+    }
+    /// Takes in an output report written to `interface`. `false` if it isn't passed on, as
+    /// the interface isn't one of those watched, it has no observer, or it's locked to a
+    /// process other than the one that registered the observer.
+    pub fn received(&mut self, interface: u8, report: &[u8]) -> bool {
+        match self.watched(interface) {
+            Some(Watched {
+                observer: Some((_, pid)),
+                owner,
+                ..
+            }) if owner.is_none() || *owner == Some(*pid) => (),
+            _ => return false,
+        }
+        if report.len() > MAX_OUTPUT_REPORT_LEN {
+            log::warn!(
+                "cutting output report of {} bytes on interface {} short",
+                report.len(),
+                interface
+            );
+        }
+        let len = report.len().min(MAX_OUTPUT_REPORT_LEN);
+        let mut data = [0u8; MAX_OUTPUT_REPORT_LEN];
+        data[..len].copy_from_slice(&report[..len]);
+        self.pending.push_back(OutputReport {
+            interface,
+            len: len as u32,
+            data,
+        });
+        true
+    }
+    /// Sends the reports waiting to go out to their observers with `send`, oldest first. An
+    /// observer that a report can't be sent to is dropped, along with the rest of its reports,
+    /// and handed back with the error so that its connection can be closed.
+    pub fn send<E>(
+        &mut self,
+        mut send: impl FnMut(C, OutputReport) -> Result<(), E>,
+    ) -> Vec<(C, E)> {
+        let mut dropped = Vec::new();
+        while let Some(report) = self.pending.pop_front() {
+            let cid = match self.observer(report.interface) {
+                Some(&(cid, _)) => cid,
+                None => continue,
+            };
+            if let Err(e) = send(cid, report) {
+                self.drop_observer(report.interface);
+                dropped.push((cid, e));
+            }
+        }
+        dropped
+    }
+    /// The server is going away: hands back the observers, to be disconnected
+    pub fn release(&mut self) -> Vec<C> {
+        self.pending.clear();
+        self.interfaces
+            .iter_mut()
+            .filter_map(|w| w.observer.take())
+            .map(|(cid, _)| cid)
+            .collect()
+    }
+}
+
+/// Notes the SET_REPORT output reports for the watched interfaces. Polled ahead of the classes
+/// that handle them; the request is left for them to acknowledge.
+impl<B: UsbBus, C: Copy, P: Copy + PartialEq> UsbClass<B> for OutputReports<C, P> {
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.request == HID_SET_REPORT
+            && (req.value >> 8) as u8 == HID_REPORT_TYPE_OUTPUT
+            && matches!(self.watched(req.index as u8), Some(w) if w.from_control)
+        {
+            self.received(req.index as u8, xfer.data());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends the reports waiting to go out, as the server does, to `sent`; the observer `bad`
+    /// can't be reached
+    fn send_all(
+        reports: &mut OutputReports<u32, u8>,
+        sent: &mut Vec<(u32, OutputReport)>,
+        bad: u32,
+    ) -> Vec<u32> {
+        reports
+            .send(|cid, report| {
+                if cid == bad {
+                    return Err(());
+                }
+                sent.push((cid, report));
+                Ok(())
+            })
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .collect()
+    }
+
+    #[test]
+    fn test_output_report_forwarding() {
+        let mut reports = OutputReports::<u32, u8>::new(&[0, 2]);
+        let mut sent = Vec::new();
+
+        // nothing is kept until someone is listening
+        assert!(!reports.received(2, &[1, 2, 3]));
+        assert!(send_all(&mut reports, &mut sent, 0).is_empty());
+        assert!(sent.is_empty());
+
+        // one observer per interface, and only for the watched interfaces
+        assert_eq!(reports.register(2, (7, 1)), Ok(()));
+        assert_eq!(reports.register(2, (8, 1)), Err((8, 1)));
+        assert_eq!(reports.register(0, (8, 2)), Ok(()));
+        assert_eq!(reports.register(1, (9, 2)), Err((9, 2)));
+        assert!(!reports.watches(1));
+        assert_eq!(reports.observer(2), Some(&(7, 1)));
+        assert_eq!(reports.observer(0), Some(&(8, 2)));
+
+        // a full-size vendor report reaches its observer intact
+        let mut vendor = [0u8; MAX_OUTPUT_REPORT_LEN];
+        for (i, b) in vendor.iter_mut().enumerate() {
+            *b = 0xA5 ^ i as u8;
+        }
+        assert!(reports.received(2, &vendor));
+        // as does a keyboard LED report, after it, to the keyboard's observer
+        assert!(reports.received(0, &[0b0_0010]));
+        // not one of the watched interfaces
+        assert!(!reports.received(1, &[0xff]));
+        assert!(send_all(&mut reports, &mut sent, 0).is_empty());
+        assert_eq!(sent.len(), 2);
+        assert_eq!((sent[0].0, sent[0].1.interface), (7, 2));
+        assert_eq!(sent[0].1.report(), &vendor[..]);
+        assert_eq!((sent[1].0, sent[1].1.interface), (8, 0));
+        assert_eq!(sent[1].1.report(), &[0b0_0010]);
+        sent.clear();
+        assert!(send_all(&mut reports, &mut sent, 0).is_empty());
+        assert!(sent.is_empty());
+
+        // anything past the longest report is cut off
+        assert!(reports.received(2, &[0x5a; MAX_OUTPUT_REPORT_LEN + 8]));
+        send_all(&mut reports, &mut sent, 0);
+        assert_eq!(
+            sent.pop().unwrap().1.report(),
+            &[0x5a; MAX_OUTPUT_REPORT_LEN][..]
+        );
+
+        // an observer that can't be reached is dropped, with the rest of its reports, and the
+        // other observers still get theirs
+        reports.received(0, &[1]);
+        reports.received(2, &[2]);
+        reports.received(0, &[3]);
+        assert_eq!(send_all(&mut reports, &mut sent, 8), vec![8]);
+        assert_eq!(sent.len(), 1);
+        assert_eq!((sent[0].0, sent[0].1.report()), (7, &[2][..]));
+        sent.clear();
+        assert_eq!(reports.observer(0), None);
+        assert!(!reports.received(0, &[4]));
+
+        // once an observer is gone, a new one can take its place
+        assert_eq!(reports.register(0, (9, 3)), Ok(()));
+        reports.received(2, &[0]);
+        assert_eq!(reports.drop_observer(2), Some(7));
+        assert!(send_all(&mut reports, &mut sent, 0).is_empty());
+        assert!(sent.is_empty());
+        assert_eq!(reports.register(2, (10, 1)), Ok(()));
+        assert_eq!(reports.release(), vec![9, 10]);
+        assert_eq!(reports.observer(2), None);
+    }
+
+    #[test]
+    fn test_output_report_lock() {
+        let mut reports = OutputReports::<u32, u8>::new(&[0, 2]);
+        let mut sent = Vec::new();
+
+        // process 3 locks the raw HID interface after process 1 hooked it: its reports no longer
+        // reach process 1's observer, through either endpoint, as `control_out()` hands the
+        // SET_REPORT requests to `received()` too
+        assert_eq!(reports.register(2, (7, 1)), Ok(()));
+        assert_eq!(reports.register(0, (8, 1)), Ok(()));
+        assert!(reports.received(2, &[1]));
+        reports.lock(2, 3);
+        assert!(!reports.received(2, &[2]));
+        // the lock is kept, and is the interface's alone
+        reports.lock(2, 1);
+        assert!(!reports.received(2, &[3]));
+        assert!(reports.received(0, &[4]));
+        send_all(&mut reports, &mut sent, 0);
+        let got: Vec<_> = sent
+            .iter()
+            .map(|(cid, report)| (*cid, report.report()[0]))
+            .collect();
+        assert_eq!(got, vec![(7, 1), (8, 4)]);
+        sent.clear();
+
+        // an observer the owner registers hears from it
+        assert_eq!(reports.drop_observer(2), Some(7));
+        assert_eq!(reports.register(2, (9, 3)), Ok(()));
+        assert!(reports.received(2, &[5]));
+        send_all(&mut reports, &mut sent, 0);
+        assert_eq!((sent[0].0, sent[0].1.report()), (9, &[5][..]));
+    }
+}
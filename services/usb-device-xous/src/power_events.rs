@@ -0,0 +1,59 @@
+// The host only suspends the bus on real hardware, but the filter is kept free of hardware
+// dependencies so that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use crate::api::PowerEvent;
+use usb_device::device::UsbDeviceState;
+
+/// Turns the device state seen after each USB interrupt into suspend and resume events. The
+/// state is sampled on every interrupt, so most samples repeat the last one; only a move into
+/// or out of `UsbDeviceState::Suspend` produces an event.
+pub(crate) struct PowerEventFilter {
+    suspended: bool,
+}
+
+impl PowerEventFilter {
+    pub fn new() -> PowerEventFilter {
+        PowerEventFilter { suspended: false }
+    }
+    pub fn update(&mut self, state: UsbDeviceState) -> Option<PowerEvent> {
+        let suspended = state == UsbDeviceState::Suspend;
+        if suspended == self.suspended {
+            return None;
+        }
+        self.suspended = suspended;
+        if suspended {
+            Some(PowerEvent::Suspend)
+        } else {
+            Some(PowerEvent::Resume)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suspend_resume() {
+        let mut filter = PowerEventFilter::new();
+        let mut events = Vec::new();
+        for &state in [
+            UsbDeviceState::Default,
+            UsbDeviceState::Addressed,
+            UsbDeviceState::Configured,
+            UsbDeviceState::Configured,
+            UsbDeviceState::Suspend,
+            // the interrupts keep coming while the bus is idle
+            UsbDeviceState::Suspend,
+            UsbDeviceState::Suspend,
+            UsbDeviceState::Configured,
+            UsbDeviceState::Configured,
+        ].iter() {
+            if let Some(event) = filter.update(state) {
+                events.push(event);
+            }
+        }
+        assert_eq!(events, vec![PowerEvent::Suspend, PowerEvent::Resume]);
+    }
+}
@@ -0,0 +1,131 @@
+// Only used on real hardware, where the device is built, but kept free of hardware
+// dependencies so that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use crate::api::KeyboardProfile;
+
+/// The pid.codes VID the device's own identity is allocated under
+const OWN_VID: u16 = 0x1209;
+/// HID `bCountryCode` for a keyboard that doesn't say what its layout is
+const COUNTRY_NOT_SUPPORTED: u8 = 0;
+/// HID `bCountryCode` of a US keyboard
+const COUNTRY_US: u8 = 33;
+
+/// What the device tells the host about itself under a `KeyboardProfile`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct ProfileIds {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    /// `bCountryCode` of the keyboard's HID descriptor
+    pub country_code: u8,
+}
+
+pub(crate) fn profile_ids(profile: KeyboardProfile) -> ProfileIds {
+    match profile {
+        KeyboardProfile::Generic => ProfileIds {
+            vid: 0x1209,
+            pid: 0x0001,
+            manufacturer: "Kosagi",
+            product: "Precursor",
+            country_code: COUNTRY_NOT_SUPPORTED,
+        },
+        KeyboardProfile::AppleKeyboard => ProfileIds {
+            vid: 0x05AC,
+            pid: 0x0250,
+            manufacturer: "Apple Inc.",
+            product: "Apple Keyboard",
+            country_code: COUNTRY_US,
+        },
+        KeyboardProfile::MicrosoftKeyboard => ProfileIds {
+            vid: 0x045E,
+            pid: 0x0750,
+            manufacturer: "Microsoft",
+            product: "Wired Keyboard 600",
+            country_code: COUNTRY_US,
+        },
+    }
+}
+
+/// Whether the device may present itself as `profile`. Profiles under another vendor's VID
+/// only exist for host software that needs them, and are only available with the
+/// `vendor-profiles` feature.
+pub(crate) fn profile_allowed(profile: KeyboardProfile) -> bool {
+    profile_ids(profile).vid == OWN_VID || cfg!(feature = "vendor-profiles")
+}
+
+/// `bmAttributes` bit 7, reserved and always set
+const ATTRIBUTES_RESERVED: u8 = 0x80;
+/// `bmAttributes` bit 6: the device has its own power
+const ATTRIBUTES_SELF_POWERED: u8 = 0x40;
+/// `bmAttributes` bit 5: the device can wake the host
+const ATTRIBUTES_REMOTE_WAKEUP: u8 = 0x20;
+
+/// How the configuration descriptor says the device is powered. Hosts only read it when
+/// enumerating the device. The default is bus-powered, without remote wakeup, which is what the
+/// device has always said.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub(crate) struct PowerAttributes {
+    pub self_powered: bool,
+    /// The UDC can't signal a resume (see `SpinalUsbDevice::resume()`), so this stays off
+    pub remote_wakeup: bool,
+}
+
+impl PowerAttributes {
+    /// `bmAttributes` of the configuration descriptor, as the stack writes it. Each bit is
+    /// set on its own, so changing one leaves the other alone.
+    pub fn bm_attributes(&self) -> u8 {
+        let mut attributes = ATTRIBUTES_RESERVED;
+        if self.self_powered {
+            attributes |= ATTRIBUTES_SELF_POWERED;
+        }
+        if self.remote_wakeup {
+            attributes |= ATTRIBUTES_REMOTE_WAKEUP;
+        }
+        attributes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::FromPrimitive;
+
+    #[test]
+    fn test_profiles() {
+        let apple = profile_ids(KeyboardProfile::AppleKeyboard);
+        assert_eq!((apple.vid, apple.pid), (0x05AC, 0x0250));
+        let microsoft = profile_ids(KeyboardProfile::MicrosoftKeyboard);
+        assert_eq!((microsoft.vid, microsoft.pid), (0x045E, 0x0750));
+        // the generic profile is the identity the device has always had
+        let generic = profile_ids(KeyboardProfile::Generic);
+        assert_eq!((generic.vid, generic.pid), (0x1209, 0x0001));
+        // only the vendor presets say what layout they are
+        assert_eq!(generic.country_code, 0);
+        assert_eq!((apple.country_code, microsoft.country_code), (33, 33));
+        // other vendors' VIDs are only presented when built in
+        assert!(profile_allowed(KeyboardProfile::Generic));
+        assert_eq!(profile_allowed(KeyboardProfile::AppleKeyboard), cfg!(feature = "vendor-profiles"));
+        assert_eq!(profile_allowed(KeyboardProfile::MicrosoftKeyboard), cfg!(feature = "vendor-profiles"));
+        // numbers that don't name a profile don't decode into one
+        assert!(KeyboardProfile::from_usize(KeyboardProfile::MicrosoftKeyboard as usize + 1).is_none());
+    }
+
+    #[test]
+    fn test_power_attributes() {
+        // bus-powered, as the device has always been
+        assert_eq!(PowerAttributes::default().bm_attributes(), 0b1000_0000);
+        for &self_powered in [false, true].iter() {
+            for &remote_wakeup in [false, true].iter() {
+                let bits = PowerAttributes { self_powered, remote_wakeup }.bm_attributes();
+                // USB 2.0 table 9-10: D7 is reserved and set, D6 is self-powered, D5 is remote
+                // wakeup, and D4..D0 are reserved and zero
+                assert_eq!(bits & (1 << 7), 1 << 7, "D7 clear in {:02x}", bits);
+                assert_eq!(bits & (1 << 6) != 0, self_powered, "D6 wrong in {:02x}", bits);
+                assert_eq!(bits & (1 << 5) != 0, remote_wakeup, "D5 wrong in {:02x}", bits);
+                assert_eq!(bits & 0b1_1111, 0, "reserved bits set in {:02x}", bits);
+            }
+        }
+    }
+}
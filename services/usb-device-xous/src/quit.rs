@@ -0,0 +1,88 @@
+use xous::SID;
+
+/// What clients find the server through: its entry in the name table, and the server itself.
+/// Both have to go for the server to stop being reachable once it quits.
+pub(crate) trait ServerName {
+    /// Takes the server out of the name table, so that looking it up fails
+    fn unregister(&self, sid: SID) -> Result<(), xous::Error>;
+    /// Destroys the server, so that connections made before it quit fail too
+    fn destroy(&self, sid: SID) -> Result<(), xous::Error>;
+}
+
+impl ServerName for xous_names::XousNames {
+    fn unregister(&self, sid: SID) -> Result<(), xous::Error> {
+        self.unregister_server(sid)
+    }
+    fn destroy(&self, sid: SID) -> Result<(), xous::Error> {
+        xous::destroy_server(sid)
+    }
+}
+
+/// Withdraws the server `sid` once it has stopped taking messages. The name goes first, so
+/// that nobody is handed a server that's already gone.
+pub(crate) fn withdraw<N: ServerName>(names: &N, sid: SID) -> Result<(), xous::Error> {
+    names.unregister(sid)?;
+    names.destroy(sid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    /// A name table and the servers it points at, as xous-names and the kernel keep them
+    #[derive(Default)]
+    struct Names {
+        table: RefCell<HashMap<&'static str, SID>>,
+        destroyed: RefCell<Vec<SID>>,
+    }
+    impl Names {
+        fn register(&self, name: &'static str, sid: SID) {
+            self.table.borrow_mut().insert(name, sid);
+        }
+        /// What a client looking `name` up would get a connection to
+        fn resolve(&self, name: &str) -> Option<SID> {
+            self.table
+                .borrow()
+                .get(name)
+                .copied()
+                .filter(|sid| !self.destroyed.borrow().contains(sid))
+        }
+    }
+    impl ServerName for Names {
+        fn unregister(&self, sid: SID) -> Result<(), xous::Error> {
+            let mut table = self.table.borrow_mut();
+            let before = table.len();
+            table.retain(|_, registered| *registered != sid);
+            if table.len() == before {
+                return Err(xous::Error::ServerNotFound);
+            }
+            Ok(())
+        }
+        fn destroy(&self, sid: SID) -> Result<(), xous::Error> {
+            self.destroyed.borrow_mut().push(sid);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_quit_withdraws_name() {
+        let usb = SID::from_u32(1, 2, 3, 4);
+        let other = SID::from_u32(5, 6, 7, 8);
+        let names = Names::default();
+        names.register(crate::api::SERVER_NAME_USB_DEVICE, usb);
+        names.register("_Some other server_", other);
+        assert_eq!(names.resolve(crate::api::SERVER_NAME_USB_DEVICE), Some(usb));
+
+        withdraw(&names, usb).unwrap();
+        assert_eq!(names.resolve(crate::api::SERVER_NAME_USB_DEVICE), None);
+        assert_eq!(*names.destroyed.borrow(), vec![usb]);
+        // other servers are left alone
+        assert_eq!(names.resolve("_Some other server_"), Some(other));
+
+        // a server that was never registered isn't destroyed either
+        assert_eq!(withdraw(&names, usb), Err(xous::Error::ServerNotFound));
+        assert_eq!(names.destroyed.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,190 @@
+// The interface only exists on real hardware, but the mailbox is kept free of hardware
+// dependencies so that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use std::collections::VecDeque;
+use usb_device::bus::{InterfaceNumber, StringIndex, UsbBus};
+use usb_device::class_prelude::DescriptorWriter;
+use usbd_human_interface_device::hid_class::prelude::*;
+use usbd_human_interface_device::interface::raw::{RawInterface, RawInterfaceBuilder, RawInterfaceConfig};
+use usbd_human_interface_device::interface::{InterfaceClass, WrappedInterface, WrappedInterfaceConfig};
+use usbd_human_interface_device::UsbHidError;
+use embedded_time::duration::Milliseconds;
+
+/// Length of every raw HID report, in both directions
+pub const RAW_HID_REPORT_LEN: usize = 64;
+
+/// A single 64-byte report in and out, on a vendor-defined usage page so that host tools can
+/// find it without it being mistaken for the FIDO interface next to it.
+pub const RAW_HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xFF, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,       // Usage (0x01)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x02,       //   Usage (0x02): data to the host
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x40,       //   Report Count (64)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0x09, 0x03,       //   Usage (0x03): data from the host
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x40,       //   Report Count (64)
+    0x91, 0x02,       //   Output (Data, Var, Abs)
+    0xC0,             // End Collection
+];
+
+/// Vendor-defined raw HID interface, built the same way as the `FidoInterface` it sits beside.
+pub struct RawHidInterface<'a, B: UsbBus> {
+    inner: RawInterface<'a, B>,
+}
+
+impl<'a, B: UsbBus> RawHidInterface<'a, B> {
+    pub fn write_report(&self, report: &[u8; RAW_HID_REPORT_LEN]) -> Result<(), UsbHidError> {
+        self.inner.write_report(report).map(|_| ()).map_err(UsbHidError::from)
+    }
+    pub fn read_report(&self) -> usb_device::Result<[u8; RAW_HID_REPORT_LEN]> {
+        let mut report = [0u8; RAW_HID_REPORT_LEN];
+        self.inner.read_report(&mut report).map(|_| report)
+    }
+    pub fn default_config() -> WrappedInterfaceConfig<Self, RawInterfaceConfig<'a>> {
+        WrappedInterfaceConfig::new(
+            RawInterfaceBuilder::new(RAW_HID_REPORT_DESCRIPTOR)
+                .description("Precursor raw HID")
+                .in_endpoint(UsbPacketSize::Bytes64, Milliseconds(5))
+                .unwrap()
+                .with_out_endpoint(UsbPacketSize::Bytes64, Milliseconds(5))
+                .unwrap()
+                .build(),
+            (),
+        )
+    }
+}
+
+impl<'a, B: UsbBus> InterfaceClass<'a> for RawHidInterface<'a, B> {
+    fn report_descriptor(&self) -> &'_ [u8] {
+        self.inner.report_descriptor()
+    }
+    fn id(&self) -> InterfaceNumber {
+        self.inner.id()
+    }
+    fn write_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        self.inner.write_descriptors(writer)
+    }
+    fn get_string(&self, index: StringIndex, lang_id: u16) -> Option<&'_ str> {
+        self.inner.get_string(index, lang_id)
+    }
+    fn reset(&mut self) {
+        self.inner.reset()
+    }
+    fn set_report(&mut self, data: &[u8]) -> usb_device::Result<()> {
+        self.inner.set_report(data)
+    }
+    fn get_report(&mut self, data: &mut [u8]) -> usb_device::Result<usize> {
+        self.inner.get_report(data)
+    }
+    fn get_report_ack(&mut self) -> usb_device::Result<()> {
+        self.inner.get_report_ack()
+    }
+    fn set_idle(&mut self, report_id: u8, value: u8) {
+        self.inner.set_idle(report_id, value)
+    }
+    fn get_idle(&self, report_id: u8) -> u8 {
+        self.inner.get_idle(report_id)
+    }
+    fn set_protocol(&mut self, protocol: HidProtocol) {
+        self.inner.set_protocol(protocol)
+    }
+    fn get_protocol(&self) -> HidProtocol {
+        self.inner.get_protocol()
+    }
+}
+
+impl<'a, B: UsbBus> WrappedInterface<'a, B, RawInterface<'a, B>> for RawHidInterface<'a, B> {
+    fn new(interface: RawInterface<'a, B>, _: ()) -> Self {
+        Self { inner: interface }
+    }
+}
+
+/// Matches reports arriving from the host with clients waiting on `raw_hid_recv()`, in
+/// the same way the U2F path does. A client that asks when nothing has arrived is parked
+/// until a report shows up; reports that arrive with nobody waiting are queued in order.
+pub(crate) struct DeferredRx<L> {
+    listener: Option<L>,
+    queue: VecDeque<[u8; RAW_HID_REPORT_LEN]>,
+}
+
+impl<L> DeferredRx<L> {
+    pub fn new() -> DeferredRx<L> {
+        DeferredRx { listener: None, queue: VecDeque::new() }
+    }
+    /// A report came in from the host. Returns the listener to answer with it, if one was waiting.
+    pub fn incoming(&mut self, report: [u8; RAW_HID_REPORT_LEN]) -> Option<(L, [u8; RAW_HID_REPORT_LEN])> {
+        match self.listener.take() {
+            Some(listener) => Some((listener, report)),
+            None => {
+                self.queue.push_back(report);
+                None
+            }
+        }
+    }
+    /// A client asked for a report. Returns it with the oldest queued report if there is one;
+    /// otherwise the client is parked, and handed back to be released if another was already.
+    pub fn request(&mut self, listener: L) -> Result<(L, [u8; RAW_HID_REPORT_LEN]), Option<L>> {
+        match self.queue.pop_front() {
+            Some(report) => Ok((listener, report)),
+            None => Err(self.listener.replace(listener)),
+        }
+    }
+    /// The server is going away: hands back the parked listener, if any, to be released with an
+    /// error, and drops the reports nobody asked for.
+    pub fn release(&mut self) -> Option<L> {
+        self.queue.clear();
+        self.listener.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(tag: u8) -> [u8; RAW_HID_REPORT_LEN] {
+        let mut r = [0u8; RAW_HID_REPORT_LEN];
+        for (i, b) in r.iter_mut().enumerate() {
+            *b = tag.wrapping_add(i as u8);
+        }
+        r
+    }
+
+    #[test]
+    fn test_raw_hid_round_trip() {
+        // listeners are stood in for by the number of the request they came from
+        let mut rx = DeferredRx::<u32>::new();
+
+        // a client that asks first is parked, and gets the report when it arrives
+        assert!(matches!(rx.request(1), Err(None)));
+        assert_eq!(rx.incoming(report(0x10)), Some((1, report(0x10))));
+
+        // reports that arrive with nobody waiting are handed out in order
+        assert_eq!(rx.incoming(report(0x20)), None);
+        assert_eq!(rx.incoming(report(0x30)), None);
+        assert_eq!(rx.request(2), Ok((2, report(0x20))));
+        assert_eq!(rx.request(3), Ok((3, report(0x30))));
+
+        // a second waiting client displaces the first, which is handed back
+        assert!(matches!(rx.request(4), Err(None)));
+        assert!(matches!(rx.request(5), Err(Some(4))));
+        assert_eq!(rx.incoming(report(0x40)), Some((5, report(0x40))));
+        assert_eq!(rx.incoming(report(0x50)), None);
+
+        // on the way out, a parked listener is handed back, and queued reports are dropped
+        assert!(matches!(rx.request(6), Ok((6, _))));
+        assert!(matches!(rx.request(7), Err(None)));
+        assert_eq!(rx.release(), Some(7));
+        assert_eq!(rx.release(), None);
+        assert_eq!(rx.incoming(report(0x60)), None);
+        assert!(rx.release().is_none());
+        assert!(matches!(rx.request(8), Err(None)));
+    }
+}
@@ -0,0 +1,62 @@
+/// When debug access unlocked by `unlock_debug_for()` locks again. Times are the ticktimer's
+/// `elapsed_ms()`. The server is woken at the deadline by its `Wakeup`, which is moved along
+/// with it; a wakeup that crosses with the deadline moving finds it hasn't passed, and is
+/// ignored.
+pub(crate) struct RelockDeadline {
+    deadline: Option<u64>,
+}
+
+impl RelockDeadline {
+    pub fn new() -> RelockDeadline {
+        RelockDeadline { deadline: None }
+    }
+    /// Starts, or restarts, the timer at `now`, returning the new deadline
+    pub fn unlock_for(&mut self, now: u64, duration_ms: u32) -> u64 {
+        let deadline = now + duration_ms as u64;
+        self.deadline = Some(deadline);
+        deadline
+    }
+    /// Stops the timer, as when debug access is locked or unlocked for good
+    pub fn cancel(&mut self) {
+        self.deadline = None;
+    }
+    /// Whether the deadline has passed at `now`. It only passes once.
+    pub fn expired(&mut self, now: u64) -> bool {
+        match self.deadline {
+            Some(deadline) if now >= deadline => {
+                self.deadline = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relock_deadline() {
+        let mut relock = RelockDeadline::new();
+        assert!(!relock.expired(u64::MAX));
+
+        // the lock re-engages once the time is up, and only once
+        assert_eq!(relock.unlock_for(1_000, 500), 1_500);
+        assert!(!relock.expired(1_000));
+        assert!(!relock.expired(1_499));
+        assert!(relock.expired(1_500));
+        assert!(!relock.expired(1_501));
+
+        // unlocking again restarts the timer, so the first wakeup finds nothing to do
+        relock.unlock_for(2_000, 500);
+        relock.unlock_for(2_400, 500);
+        assert!(!relock.expired(2_500));
+        assert!(relock.expired(2_900));
+
+        // locking or unlocking by hand stops it
+        relock.unlock_for(3_000, 500);
+        relock.cancel();
+        assert!(!relock.expired(3_500));
+    }
+}
@@ -0,0 +1,232 @@
+// The report queue is only drained on real hardware, but it is kept free of hardware dependencies
+// so that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use std::collections::VecDeque;
+
+/// A bounded FIFO of HID reports waiting for the host to poll the endpoint. Reports that
+/// belong together (a key press and its matching all-keys-up) are queued as a group, so
+/// either all of them go in, in order, or none of them do. This guarantees a key-up can
+/// never be sent ahead of the presses before it, and a full queue turns into backpressure
+/// on the sender rather than a dropped keystroke.
+pub(crate) struct ReportQueue<T> {
+    /// each report, and whether it is the key press of a character typed by `send_str()`
+    reports: VecDeque<(T, bool)>,
+    capacity: usize,
+}
+
+impl<T> ReportQueue<T> {
+    pub fn new(capacity: usize) -> ReportQueue<T> {
+        ReportQueue {
+            reports: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+    /// Queue every report in `group`, or none of them if they don't all fit.
+    pub fn push_group(&mut self, group: Vec<T>) -> Result<(), xous::Error> {
+        self.push(group, false)
+    }
+    /// Like `push_group()`, for the reports that type one character of a string. The
+    /// character counts as typed once its first report has gone out.
+    pub fn push_char(&mut self, group: Vec<T>) -> Result<(), xous::Error> {
+        self.push(group, true)
+    }
+    fn push(&mut self, group: Vec<T>, typed: bool) -> Result<(), xous::Error> {
+        if group.len() > self.capacity - self.reports.len() {
+            return Err(xous::Error::ServerQueueFull);
+        }
+        for (index, report) in group.into_iter().enumerate() {
+            self.reports.push_back((report, typed && index == 0));
+        }
+        Ok(())
+    }
+    /// The report that should go out next, if any
+    pub fn front(&self) -> Option<&T> {
+        self.reports.front().map(|(report, _)| report)
+    }
+    /// Drop the report at the head of the queue once it has been handed to the endpoint
+    pub fn pop(&mut self) -> Option<T> {
+        self.reports.pop_front().map(|(report, _)| report)
+    }
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+    pub fn clear(&mut self) {
+        self.reports.clear()
+    }
+    /// Number of characters queued with `push_char()` whose key press hasn't gone out yet
+    pub fn untyped_chars(&self) -> u32 {
+        self.reports.iter().filter(|(_, typed)| *typed).count() as u32
+    }
+}
+
+/// Progress of the most recent `send_str()`, in characters. `send_str()` may hand its string
+/// over in several chunks as the report queue drains, so the totals are set when the first
+/// chunk arrives and the count of characters sent accumulates across the rest. Once a send
+/// completes the totals stay put until the next one begins.
+#[derive(Default)]
+pub(crate) struct TypingProgress {
+    sent: u32,
+    total: u32,
+    cancelled: bool,
+}
+
+impl TypingProgress {
+    pub fn new() -> TypingProgress {
+        TypingProgress::default()
+    }
+    /// Start tracking a new string of `total` characters
+    pub fn begin(&mut self, total: u32) {
+        self.sent = 0;
+        self.total = total;
+        self.cancelled = false;
+    }
+    /// Record that `count` more characters were queued for the host
+    pub fn advance(&mut self, count: u32) {
+        self.sent = (self.sent + count).min(self.total);
+    }
+    /// (chars_sent, chars_total)
+    pub fn get(&self) -> (u32, u32) {
+        (self.sent, self.total)
+    }
+    /// Whether the rest of the current send was cancelled; its remaining chunks are refused.
+    pub fn cancelled(&self) -> bool {
+        self.cancelled
+    }
+    /// Stop the current send. `untyped` is the number of its characters still waiting in the
+    /// report queue, which the caller is about to throw out. Returns how many characters the
+    /// host did get, or `None` if there was no send in flight to cancel.
+    pub fn cancel(&mut self, untyped: u32) -> Option<u32> {
+        if self.cancelled || (self.sent == self.total && untyped == 0) {
+            return None;
+        }
+        self.sent = self.sent.saturating_sub(untyped);
+        self.cancelled = true;
+        Some(self.sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a key press is a non-empty report, and the key-up that follows it is empty
+    fn keystroke(code: u8) -> Vec<Vec<u8>> {
+        vec![vec![code], vec![]]
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let mut queue = ReportQueue::<Vec<u8>>::new(8);
+        for code in 0..4 {
+            assert_eq!(queue.push_group(keystroke(code)), Ok(()));
+        }
+        assert_eq!(queue.len(), 8);
+        // a full queue pushes back on the sender instead of losing the keystroke
+        assert_eq!(queue.push_group(keystroke(4)), Err(xous::Error::ServerQueueFull));
+        assert_eq!(queue.len(), 8);
+
+        // draining one report isn't enough room for a whole keystroke, so the key press
+        // can't go in without its key-up
+        assert_eq!(queue.pop(), Some(vec![0]));
+        assert_eq!(queue.push_group(keystroke(4)), Err(xous::Error::ServerQueueFull));
+        assert_eq!(queue.len(), 7);
+        assert_eq!(queue.pop(), Some(vec![]));
+        assert_eq!(queue.push_group(keystroke(4)), Ok(()));
+
+        // everything comes out in the order it went in, with each key-up after its press
+        let mut drained = vec![];
+        while let Some(report) = queue.pop() {
+            drained.push(report);
+        }
+        let expected: Vec<Vec<u8>> = (1..5).flat_map(keystroke).collect();
+        assert_eq!(drained, expected);
+        assert_eq!(queue.front(), None);
+    }
+
+    #[test]
+    fn test_typing_progress() {
+        let text = "the quick brown fox jumps over the lazy dog";
+        let mut progress = TypingProgress::new();
+        assert_eq!(progress.get(), (0, 0));
+
+        // stage the send through a small queue, the way `send_str()` resends the remainder
+        // of its string each time the queue has drained
+        let mut queue = ReportQueue::<Vec<u8>>::new(8);
+        let mut remaining: Vec<u8> = text.bytes().collect();
+        let mut first = true;
+        let mut last = (0, 0);
+        while !remaining.is_empty() {
+            if first {
+                progress.begin(remaining.len() as u32);
+                first = false;
+            }
+            let mut sent = 0;
+            for &ch in remaining.iter() {
+                if queue.push_char(keystroke(ch)).is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+            progress.advance(sent as u32);
+            remaining.drain(..sent);
+
+            let now = progress.get();
+            assert!(now.0 > last.0, "progress stalled at {:?}", now);
+            assert_eq!(now.1, text.len() as u32);
+            last = now;
+            // the host polls the endpoint dry before the next chunk goes in
+            while queue.pop().is_some() {}
+        }
+        assert_eq!(progress.get(), (text.len() as u32, text.len() as u32));
+
+        // with nothing in flight, the last send's totals remain visible, and the count never
+        // overshoots them
+        progress.advance(5);
+        assert_eq!(progress.get(), (text.len() as u32, text.len() as u32));
+        progress.begin(3);
+        assert_eq!(progress.get(), (0, 3));
+    }
+
+    #[test]
+    fn test_cancel_typing() {
+        let text: Vec<u8> = (0..40).collect();
+        let mut progress = TypingProgress::new();
+        let mut queue = ReportQueue::<Vec<u8>>::new(8);
+        // nothing has been sent yet
+        assert_eq!(progress.cancel(queue.untyped_chars()), None);
+
+        progress.begin(text.len() as u32);
+        let mut remaining = &text[..];
+        // two chunks go in; the host picks up the first in full and the second in part
+        for polls in [8, 3] {
+            let mut sent = 0;
+            for &ch in remaining.iter() {
+                if queue.push_char(keystroke(ch)).is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+            progress.advance(sent);
+            remaining = &remaining[sent as usize..];
+            for _ in 0..polls {
+                queue.pop();
+            }
+        }
+        // the host has the first four characters, plus the press of the sixth; the press
+        // counts as typed, even though its key-up never goes out
+        assert_eq!(progress.get(), (8, 40));
+        assert_eq!(queue.len(), 5);
+        assert_eq!(progress.cancel(queue.untyped_chars()), Some(6));
+        queue.clear();
+        assert!(progress.cancelled());
+        assert_eq!(progress.get(), (6, 40));
+
+        // the rest of the string is refused, and cancelling again is a no-op
+        assert_eq!(progress.cancel(queue.untyped_chars()), None);
+        assert_eq!(progress.get(), (6, 40));
+        // a fresh send isn't affected by the old cancellation
+        progress.begin(4);
+        assert!(!progress.cancelled());
+    }
+}
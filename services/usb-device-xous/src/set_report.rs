@@ -0,0 +1,106 @@
+// Only polled on real hardware, but the LED bookkeeping is kept free of hardware dependencies
+// so that it can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+
+/// HID class request code for SET_REPORT
+const HID_SET_REPORT: u8 = 0x09;
+/// Report type for SET_REPORT, in the high byte of wValue
+const HID_REPORT_TYPE_OUTPUT: u8 = 0x02;
+/// The keyboard output report is a single byte of LED bits
+const LED_REPORT_LEN: usize = 1;
+
+/// Tracks the keyboard LED output report. The host writes it with SET_REPORT on the control
+/// endpoint, or on the keyboard's interrupt OUT endpoint; both end up in `set_report()`.
+pub(crate) struct LedOutput {
+    interface: u8,
+    leds: u8,
+    changed: bool,
+}
+
+impl LedOutput {
+    /// Handles the output reports of the keyboard on `interface`
+    pub fn new(interface: u8) -> LedOutput {
+        LedOutput { interface, leds: 0, changed: false }
+    }
+    /// Takes in an output report. A report of the wrong length is ignored, and `false` returned.
+    pub fn set_report(&mut self, report: &[u8]) -> bool {
+        if report.len() != LED_REPORT_LEN {
+            log::warn!("ignoring keyboard output report of {} bytes", report.len());
+            return false;
+        }
+        if report[0] != self.leds {
+            self.leds = report[0];
+            self.changed = true;
+        }
+        true
+    }
+    /// The LED byte, as the host last set it
+    pub fn leds(&self) -> u8 {
+        self.leds
+    }
+    /// The new LED byte, if it has changed since the last call
+    pub fn take_change(&mut self) -> Option<u8> {
+        if self.changed {
+            self.changed = false;
+            Some(self.leds)
+        } else {
+            None
+        }
+    }
+}
+
+/// Picks SET_REPORT output reports for the keyboard off the control endpoint. Polled ahead of
+/// the HID class; all other control requests are left alone for it to handle.
+impl<B: UsbBus> UsbClass<B> for LedOutput {
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.request == HID_SET_REPORT
+            && req.index as u8 == self.interface
+            && (req.value >> 8) as u8 == HID_REPORT_TYPE_OUTPUT
+        {
+            // a malformed report is dropped, but still acknowledged so the host doesn't see a stall
+            self.set_report(xfer.data());
+            xfer.accept().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usb_device_xous::{KeyboardLeds, KeyboardLedsReport};
+    use packed_struct::PackedStruct;
+
+    #[test]
+    fn test_led_output_report() {
+        let mut output = LedOutput::new(0);
+        assert_eq!(output.take_change(), None);
+
+        // the host turns on caps lock and num lock
+        assert!(output.set_report(&[0b0_0011]));
+        assert_eq!(output.take_change(), Some(0b0_0011));
+        let leds = KeyboardLedsReport::unpack(&[output.leds()]).unwrap();
+        assert!(leds.caps_lock());
+        assert!(leds.num_lock());
+        assert!(!leds.scroll_lock());
+        // the same report again isn't a change
+        assert!(output.set_report(&[0b0_0011]));
+        assert_eq!(output.take_change(), None);
+
+        // reports of the wrong length leave the LEDs alone
+        assert!(!output.set_report(&[]));
+        assert!(!output.set_report(&[0b0_0100, 0]));
+        assert_eq!(output.take_change(), None);
+        assert_eq!(output.leds(), 0b0_0011);
+
+        // caps lock goes off
+        assert!(output.set_report(&[0b0_0001]));
+        assert_eq!(output.take_change(), Some(0b0_0001));
+        assert!(!KeyboardLedsReport::unpack(&[output.leds()]).unwrap().caps_lock());
+    }
+}
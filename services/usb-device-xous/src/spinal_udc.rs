@@ -4,11 +4,33 @@
 
 use bitfield::bitfield;
 use usb_device::UsbDirection;
+use usb_device::endpoint::EndpointAddress;
 use std::fmt;
 use core::sync::atomic::{AtomicPtr, Ordering};
 use core::mem::size_of;
+use std::convert::TryInto;
+use crate::api::{UsbStats, SpinalUdcRegsSnapshot};
 
 pub(crate) const NUM_ENDPOINTS: usize = 16;
+/// The USB frame counter is 11 bits wide, and wraps around every 2048 frames (~2 seconds)
+pub(crate) const FRAME_MASK: u32 = 0x7FF;
+
+/// Converts the `bInterval` of a full-speed isochronous endpoint into a period in frames.
+/// Per the USB 2.0 spec (9.6.6), the period is 2^(bInterval-1) with bInterval in the range of 1-16.
+/// Periods longer than half the frame counter can't be distinguished from a wrap-around, so they
+/// are clamped to 1024 frames.
+pub(crate) fn iso_period(interval: u8) -> u32 {
+    let interval = if interval == 0 { 1 } else if interval > 11 { 11 } else { interval };
+    1 << (interval - 1)
+}
+/// Returns `true` if an isochronous endpoint that last had a packet scheduled on `last_frame`
+/// may schedule its next packet on frame `now`. `None` means nothing has been scheduled yet.
+pub(crate) fn iso_frame_due(last_frame: Option<u32>, now: u32, period: u32) -> bool {
+    match last_frame {
+        None => true,
+        Some(last) => (now.wrapping_sub(last) & FRAME_MASK) >= period,
+    }
+}
 
 bitfield! {
     pub struct UdcInterrupts(u32);
@@ -52,6 +74,25 @@ impl fmt::Debug for UdcInterrupts {
         }
     }
 }
+/// Tallies the bus events in a set of interrupts that have been handled into `stats`.
+/// Endpoint completions are not counted; those are too frequent to be useful here.
+pub(crate) fn record_interrupts(stats: &mut UsbStats, ints: &UdcInterrupts) {
+    if ints.reset() {
+        stats.resets = stats.resets.wrapping_add(1);
+    }
+    if ints.ep0_setup() {
+        stats.setup_packets = stats.setup_packets.wrapping_add(1);
+    }
+    if ints.suspend() {
+        stats.suspends = stats.suspends.wrapping_add(1);
+    }
+    if ints.resume() {
+        stats.resumes = stats.resumes.wrapping_add(1);
+    }
+    if ints.disconnect() {
+        stats.disconnects = stats.disconnects.wrapping_add(1);
+    }
+}
 bitfield! {
     pub struct UdcHalt(u32);
     impl Debug;
@@ -103,6 +144,10 @@ impl SpinalUdcRegs {
             self.regs.load(Ordering::SeqCst).add(FRAME_OFFSET / size_of::<u32>()).read_volatile()
         }
     }
+    /// current USB frame number, with the unused upper bits of the register masked off
+    pub fn frame_number(&self) -> u16 {
+        (self.frame_id() & FRAME_MASK) as u16
+    }
     /// currently active address for tokens. cleared by USB reset
     pub fn address(&self) -> u32 {
         unsafe {
@@ -160,6 +205,95 @@ impl SpinalUdcRegs {
         } & 0xF)
     }
 }
+impl SpinalUdcRegs {
+    pub(crate) fn dump_regs(&self) -> SpinalUdcRegsSnapshot {
+        let address = self.address();
+        let ints = self.interrupts();
+        let halt = self.halt();
+        let config = self.config();
+        SpinalUdcRegsSnapshot {
+            frame: self.frame_id(),
+            address: (address & 0x7F) as u8,
+            address_enable: address & 0x100 != 0,
+            address_trigger: address & 0x200 != 0,
+            int_endpoints: ints.endpoint() as u16,
+            int_reset: ints.reset(),
+            int_ep0_setup: ints.ep0_setup(),
+            int_suspend: ints.suspend(),
+            int_resume: ints.resume(),
+            int_disconnect: ints.disconnect(),
+            halt_endpoint: halt.endpointid() as u8,
+            halt_enable_req: halt.enable_req(),
+            halt_enable_ack: halt.enable_ack(),
+            pullup_on: config.pullup_on(),
+            pullup_off: config.pullup_off(),
+            enable_ints: config.enable_ints(),
+            disable_ints: config.disable_ints(),
+            ramsize: self.ramsize(),
+        }
+    }
+}
+impl fmt::Display for SpinalUdcRegsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "frame: {}", self.frame)?;
+        writeln!(f, "address: {}{}{}", self.address,
+            if self.address_enable {" ENA"} else {""},
+            if self.address_trigger {" TRIG"} else {""},
+        )?;
+        write!(f, "ints:")?;
+        for i in 0..NUM_ENDPOINTS {
+            if self.int_endpoints & (1 << i) != 0 {
+                write!(f, " ep{}", i)?;
+            }
+        }
+        writeln!(f, "{}{}{}{}{}",
+            if self.int_reset {" reset"} else {""},
+            if self.int_ep0_setup {" ep0setup"} else {""},
+            if self.int_suspend {" suspend"} else {""},
+            if self.int_resume {" resume"} else {""},
+            if self.int_disconnect {" disconnect"} else {""},
+        )?;
+        writeln!(f, "halt: ep{}{}{}", self.halt_endpoint,
+            if self.halt_enable_req {" REQ"} else {""},
+            if self.halt_enable_ack {" ACK"} else {""},
+        )?;
+        writeln!(f, "config:{}{}{}{}",
+            if self.pullup_on {" PULLUP_ON"} else {""},
+            if self.pullup_off {" PULLUP_OFF"} else {""},
+            if self.enable_ints {" INTS_ON"} else {""},
+            if self.disable_ints {" INTS_OFF"} else {""},
+        )?;
+        write!(f, "ramsize: {}", self.ramsize)
+    }
+}
+/// Register state that has to be carried across a suspend/resume cycle
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct UdcSavedRegs {
+    address: u32,
+    pullup: bool,
+}
+impl SpinalUdcRegs {
+    pub(crate) fn save(&self) -> UdcSavedRegs {
+        UdcSavedRegs {
+            address: self.address(),
+            pullup: self.config().pullup_on(),
+        }
+    }
+    /// Restores the state captured by `save()`. The address is written back without the trigger bit,
+    /// so the address state machine isn't re-armed: the host still thinks we are at the old address.
+    /// Interrupts are always re-enabled, because they are disabled on the way into suspend.
+    pub(crate) fn restore(&self, saved: &UdcSavedRegs) {
+        self.set_address(saved.address & !0x200);
+        let mut cfg = UdcConfig(0);
+        if saved.pullup {
+            cfg.set_pullup_on(true);
+        } else {
+            cfg.set_pullup_off(true);
+        }
+        cfg.set_enable_ints(true);
+        self.set_config(cfg);
+    }
+}
 impl fmt::Debug for SpinalUdcRegs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "UDC: frame{}, adr{}, ints: {:?}",
@@ -182,6 +316,37 @@ bitfield! {
     pub isochronous, set_isochronous: 16;
     pub max_packet_size, set_max_packet_size: 31, 22;
 }
+/// Applies a change of the STALL condition to an endpoint status record. Clearing the STALL
+/// of an endpoint other than ep0 is the host's CLEAR_FEATURE(ENDPOINT_HALT), which per the
+/// USB 2.0 spec (9.4.5) also resets the endpoint's data toggle to DATA0.
+pub(crate) fn apply_stall(ep_status: &mut UdcEpStatus, index: usize, direction: UsbDirection, stalled: bool) {
+    match (stalled, direction) {
+        (true, UsbDirection::In) => {
+            ep_status.set_force_stall(true);
+        },
+        (true, UsbDirection::Out) => ep_status.set_force_stall(true),
+        (false, UsbDirection::In) => {
+            ep_status.set_force_stall(false);
+            ep_status.set_force_nack(true);
+        },
+        (false, UsbDirection::Out) => ep_status.set_force_stall(false),
+    };
+    if !stalled && index != 0 {
+        ep_status.set_data_phase(false);
+    }
+}
+/// Sets or clears the NACK an OUT endpoint holds the host off with, for flow control. This is
+/// separate from its STALL: `apply_stall()` never touches an OUT endpoint's `force_nack`, and
+/// this never touches `force_stall`.
+pub(crate) fn apply_nack(ep_status: &mut UdcEpStatus, nack: bool) {
+    ep_status.set_force_nack(nack);
+}
+/// Whether `apply_nack()` can hold the host off on `ep_addr`: only an allocated OUT endpoint other
+/// than ep0. An IN endpoint already NACKs until data is queued on it, and ep0 carries the control
+/// pipe.
+pub(crate) fn nackable(ep_addr: EndpointAddress, allocated: bool) -> bool {
+    ep_addr.index() != 0 && ep_addr.direction() == UsbDirection::Out && allocated
+}
 impl fmt::Debug for UdcEpStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Ep{}@0x{:x}^{}: {}{}{}{}",
@@ -315,6 +480,65 @@ impl SpinalUdcDescriptor {
             self.base.load(Ordering::SeqCst).add(3 + offset_word).read_volatile()
         }
     }
+    /// Copies `buf` into the data area of the descriptor. The data area is only word-addressable,
+    /// so a trailing partial word is padded out with 0's.
+    pub fn write_payload(&self, buf: &[u8]) {
+        for (index, src) in buf.chunks_exact(4).enumerate() {
+            let w = u32::from_le_bytes(src.try_into().unwrap());
+            self.write_data(index, w);
+        }
+        if buf.len() % 4 != 0 { // handle the odd remainder case
+            let mut remainder = [0u8; 4];
+            for (index, &src) in buf.chunks_exact(4).remainder().iter().enumerate() {
+                remainder[index] = src;
+            }
+            self.write_data(buf.len() / 4, u32::from_le_bytes(remainder));
+        }
+    }
+    /// Copies the start of the data area of the descriptor into `buf`.
+    pub fn read_payload(&self, buf: &mut [u8]) {
+        let len = buf.len();
+        for (index, dst) in buf.chunks_exact_mut(4).enumerate() {
+            dst.copy_from_slice(&self.read_data(index).to_le_bytes());
+        }
+        if len % 4 != 0 {
+            // this will "overread" the descriptor area, but it's OK because descriptors must be aligned to 16-byte boundaries
+            // so even if the length is odd, the space allocated will always include dummy padding which will keep us
+            // from reading into neighboring data.
+            let word = self.read_data(len / 4).to_le_bytes();
+            // write only into the portion of the buffer that's allocated, don't write the extra 0's
+            buf[len & !3..].copy_from_slice(&word[..len % 4]);
+        }
+    }
+}
+/// Returns the number of bytes received by a completed OUT descriptor, or `None` if the
+/// descriptor is still waiting on the host. Note that `Some(0)` is a zero-length packet,
+/// which is distinct from there being no packet at all.
+pub(crate) fn out_packet_len(desc: &SpinalUdcDescriptor) -> Option<usize> {
+    if desc.in_progress() {
+        None
+    } else {
+        Some(desc.offset())
+    }
+}
+/// Returns `true` if the `fill` buffer of a double-buffered endpoint can accept a new packet:
+/// it is neither being sent (it's not the `head`), nor queued up to go out behind its partner buffer.
+/// All offsets are in 16-byte units, as used by the descriptor and endpoint status fields.
+pub(crate) fn double_buffer_free(head: usize, fill: usize, other: usize, other_next: usize) -> bool {
+    head != fill && !(head == other && other_next == fill)
+}
+/// Hands a freshly filled descriptor at `fill` to the hardware. If the endpoint is idle, the descriptor
+/// becomes the new head; otherwise it is chained behind `other`, which is the descriptor currently
+/// being sent. The data phase is not touched, because the controller toggles it on every packet,
+/// including across chained descriptors.
+///
+/// Must be called with the endpoint halted, so the head can't advance underneath us.
+pub(crate) fn queue_in_descriptor(ep_status: &mut UdcEpStatus, fill: usize, other: &SpinalUdcDescriptor, other_offset: usize) {
+    if ep_status.head_offset() as usize == other_offset {
+        other.set_next_desc_and_len(fill, other.length());
+    } else {
+        ep_status.set_head_offset(fill as u32);
+    }
 }
 impl fmt::Debug for SpinalUdcDescriptor {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -334,4 +558,379 @@ impl fmt::Debug for SpinalUdcDescriptor {
             self.read_data(1).to_le_bytes(),
         )
     }
-}
\ No newline at end of file
+}
+/// Number of descriptors needed to carry a `len`-byte IN transfer as a chain of
+/// `max_packet_size` packets. A transfer that is an exact multiple of `max_packet_size`
+/// (including an empty one) has to be terminated with a zero-length packet, so that the host
+/// knows the transfer is done; that packet needs a descriptor too.
+pub(crate) fn packets_in_transfer(len: usize, max_packet_size: usize) -> usize {
+    len / max_packet_size + 1
+}
+/// Splits `buf` into `max_packet_size` pieces and programs them into a chain of IN descriptors,
+/// given as (offset, descriptor) pairs with the offset in 16-byte units. Each descriptor is linked
+/// to the next one, so the hardware walks the chain on its own. Only the last descriptor
+/// interrupts on completion, so the class hears about the transfer once, when it is done.
+pub(crate) fn program_in_chain(chain: &[(usize, SpinalUdcDescriptor)], buf: &[u8], max_packet_size: usize) {
+    let mut packets = buf.chunks(max_packet_size);
+    for (i, (_offset, desc)) in chain.iter().enumerate() {
+        let packet = packets.next().unwrap_or(&[]);
+        let next = chain.get(i + 1).map(|(offset, _)| *offset).unwrap_or(0);
+        desc.write_payload(packet);
+        desc.set_next_desc_and_len(next, packet.len());
+        // full descriptors must complete so the hardware moves on to the next one
+        desc.set_desc_flags(UsbDirection::In, next == 0, true, false);
+        desc.set_offset(0); // also sets in_progress
+    }
+}
+
+/// A stand-in for the UDC memory space, for exercising descriptor handling off-target. The region is
+/// aligned to 4096 bytes so descriptors land on the same low address bits they would in hardware.
+#[cfg(test)]
+pub(crate) struct FakeUdcRam {
+    _mem: Vec<u32>,
+    base: *mut u32,
+}
+#[cfg(test)]
+impl FakeUdcRam {
+    pub fn new() -> FakeUdcRam {
+        let mut mem = vec![0u32; (0x10000 + 0x1000) / size_of::<u32>()];
+        let misalign = (mem.as_ptr() as usize) & 0xFFF;
+        let skip = if misalign == 0 { 0 } else { (0x1000 - misalign) / size_of::<u32>() };
+        let base = unsafe { mem.as_mut_ptr().add(skip) };
+        FakeUdcRam { _mem: mem, base }
+    }
+    /// `offset` is in 16-byte units, as stored in the descriptor fields
+    pub fn descriptor(&self, offset: usize) -> SpinalUdcDescriptor {
+        SpinalUdcDescriptor::new(unsafe { self.base.add(offset * 16 / size_of::<u32>()) })
+    }
+    pub fn regs(&self) -> SpinalUdcRegs {
+        SpinalUdcRegs::new(unsafe { self.base.add(0xFF00 / size_of::<u32>()) })
+    }
+    pub fn word(&self, byte_offset: usize) -> u32 {
+        unsafe { self.base.add(byte_offset / size_of::<u32>()).read_volatile() }
+    }
+    pub fn set_word(&self, byte_offset: usize, data: u32) {
+        unsafe { self.base.add(byte_offset / size_of::<u32>()).write_volatile(data) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_stats_resets() {
+        let mut stats = UsbStats::default();
+        let mut reset = UdcInterrupts(0);
+        reset.set_reset(true);
+        const N: u32 = 7;
+        for _ in 0..N {
+            record_interrupts(&mut stats, &reset);
+        }
+        assert_eq!(stats.resets, N);
+        assert_eq!(stats.suspends, 0);
+        assert_eq!(stats.setup_packets, 0);
+
+        // a setup packet on ep0 is reported along with its endpoint bit; only the setup is counted
+        let mut setup = UdcInterrupts(0);
+        setup.set_ep0_setup(true);
+        setup.set_endpoint(1);
+        record_interrupts(&mut stats, &setup);
+        assert_eq!(stats.setup_packets, 1);
+        assert_eq!(stats.resets, N);
+    }
+    #[test]
+    fn test_regs_snapshot() {
+        let ram = FakeUdcRam::new();
+        ram.set_word(0xFF00 + FRAME_OFFSET, 0x123);
+        ram.set_word(0xFF00 + ADDRESS_OFFSET, 0x300 | 42);
+        ram.set_word(0xFF00 + INT_OFFSET, (1 << 17) | (1 << 19) | 0b1001);
+        ram.set_word(0xFF00 + HALT_OFFSET, 0x20 | 0x10 | 3);
+        ram.set_word(0xFF00 + CONFIG_OFFSET, 0b0101);
+        ram.set_word(0xFF00 + RAMSIZE_OFFSET, 12);
+        let snap = ram.regs().dump_regs();
+        assert_eq!(snap, SpinalUdcRegsSnapshot {
+            frame: 0x123,
+            address: 42,
+            address_enable: true,
+            address_trigger: true,
+            int_endpoints: 0b1001,
+            int_reset: false,
+            int_ep0_setup: true,
+            int_suspend: false,
+            int_resume: true,
+            int_disconnect: false,
+            halt_endpoint: 3,
+            halt_enable_req: true,
+            halt_enable_ack: true,
+            pullup_on: true,
+            pullup_off: false,
+            enable_ints: true,
+            disable_ints: false,
+            ramsize: 4096,
+        });
+        let text = format!("{}", snap);
+        assert!(text.contains("ints: ep0 ep3 ep0setup resume\n"));
+        assert!(text.contains("address: 42 ENA TRIG\n"));
+    }
+    #[test]
+    fn test_frame_number() {
+        let ram = FakeUdcRam::new();
+        let regs = ram.regs();
+        ram.set_word(0xFF00 + FRAME_OFFSET, 0x7FE);
+        let first = regs.frame_number();
+        assert_eq!(first, 0x7FE);
+        // a frame goes by
+        ram.set_word(0xFF00 + FRAME_OFFSET, 0x7FF);
+        let second = regs.frame_number();
+        assert_ne!(second, first);
+        assert_eq!(second, 0x7FF);
+        // and the counter wraps, whatever the hardware leaves in the bits above it
+        ram.set_word(0xFF00 + FRAME_OFFSET, 0xF800);
+        assert_eq!(regs.frame_number(), 0);
+    }
+    #[test]
+    fn test_iso_schedule() {
+        assert_eq!(iso_period(1), 1);
+        assert_eq!(iso_period(4), 8);
+        assert_eq!(iso_period(0), 1); // illegal bInterval, treated as every frame
+        assert_eq!(iso_period(16), 1024);
+
+        // an endpoint that hasn't sent anything yet can always go
+        assert!(iso_frame_due(None, 0, 8));
+        // interval of 4 -> one packet every 8 frames
+        let period = iso_period(4);
+        let mut last = None;
+        let mut scheduled = Vec::new();
+        for frame in 100..132 {
+            if iso_frame_due(last, frame, period) {
+                scheduled.push(frame);
+                last = Some(frame);
+            }
+        }
+        assert_eq!(scheduled, vec![100, 108, 116, 124]);
+
+        // the schedule is maintained across the wrap-around of the frame counter
+        assert!(!iso_frame_due(Some(FRAME_MASK - 2), 3, period));
+        assert!(iso_frame_due(Some(FRAME_MASK - 2), 5, period));
+    }
+
+    #[test]
+    fn test_double_buffer() {
+        let ram = FakeUdcRam::new();
+        // two buffers of a double-buffered IN endpoint
+        let offsets = [0x100 / 16, 0x180 / 16];
+        let descs = [ram.descriptor(offsets[0]), ram.descriptor(offsets[1])];
+        let mut ep_status = UdcEpStatus(0);
+        ep_status.set_enable(true);
+
+        let mut select = 0;
+        let mut sent = Vec::new(); // (buffer, data phase) of every packet as it went out the wire
+        for packet in 0..6u8 {
+            let (fill, other) = (offsets[select], offsets[select ^ 1]);
+            // the previous packet is still in flight when the next one is queued, except for the first one
+            assert!(double_buffer_free(ep_status.head_offset() as usize, fill, other, descs[select ^ 1].next_descriptor_addr()));
+            descs[select].write_payload(&[packet; 5]);
+            descs[select].set_next_desc_and_len(0, 5);
+            descs[select].set_desc_flags(UsbDirection::In, false, true, false);
+            descs[select].set_offset(0);
+            queue_in_descriptor(&mut ep_status, fill, &descs[select ^ 1], other);
+            // the buffer that was just queued can't be refilled until it has gone out
+            assert!(!double_buffer_free(ep_status.head_offset() as usize, fill, other, descs[select ^ 1].next_descriptor_addr()));
+            select ^= 1;
+
+            // emulate the controller completing the packet at the head, if two are queued
+            if packet > 0 {
+                let head = ep_status.head_offset() as usize;
+                let desc = ram.descriptor(head);
+                sent.push((head, ep_status.data_phase(), desc.read_data(0)));
+                desc.set_offset_only(desc.length());
+                ep_status.set_head_offset(desc.next_descriptor_addr() as u32);
+                ep_status.set_data_phase(!ep_status.data_phase());
+            }
+        }
+        // drain the last packet
+        let head = ep_status.head_offset() as usize;
+        sent.push((head, ep_status.data_phase(), ram.descriptor(head).read_data(0)));
+
+        for (packet, &(buf, data_phase, data)) in sent.iter().enumerate() {
+            assert_eq!(buf, offsets[packet % 2], "buffers must alternate");
+            assert_eq!(data_phase, packet % 2 == 1, "data phase must toggle on every packet");
+            assert_eq!(data, u32::from_le_bytes([packet as u8; 4]));
+        }
+        assert_eq!(descs[0].next_descriptor_addr(), offsets[1]);
+        assert_eq!(descs[1].next_descriptor_addr(), 0, "the last packet terminates the chain");
+    }
+
+    #[test]
+    fn test_chained_transfer() {
+        let ram = FakeUdcRam::new();
+        let max_packet_size = 64;
+        let buf: Vec<u8> = (0..3 * max_packet_size).map(|i| i as u8).collect();
+        assert_eq!(packets_in_transfer(max_packet_size + 1, max_packet_size), 2);
+        assert_eq!(packets_in_transfer(1, max_packet_size), 1);
+
+        // deliberately not contiguous, and not in ascending order
+        let offsets = [0x200 / 16, 0x100 / 16, 0x400 / 16, 0x300 / 16];
+        // three full packets, terminated by a zero-length packet
+        assert_eq!(packets_in_transfer(buf.len(), max_packet_size), offsets.len());
+        let chain: Vec<(usize, SpinalUdcDescriptor)> = offsets.iter().map(|&o| (o, ram.descriptor(o))).collect();
+        program_in_chain(&chain, &buf, max_packet_size);
+
+        // walk the chain the way the hardware would
+        let mut offset = offsets[0];
+        let mut walked = Vec::new();
+        while offset != 0 {
+            let desc = ram.descriptor(offset);
+            assert_eq!(desc.direction(), UsbDirection::In);
+            assert!(desc.in_progress());
+            assert!(desc.completion_on_full());
+            if walked.len() < 3 {
+                assert_eq!(desc.length(), max_packet_size);
+                let mut packet = [0u8; 64];
+                desc.read_payload(&mut packet);
+                assert_eq!(&packet[..], &buf[walked.len() * max_packet_size..(walked.len() + 1) * max_packet_size]);
+            } else {
+                assert_eq!(desc.length(), 0);
+            }
+            walked.push((offset, desc.int_on_done()));
+            offset = desc.next_descriptor_addr();
+        }
+        assert_eq!(walked, vec![(offsets[0], false), (offsets[1], false), (offsets[2], false), (offsets[3], true)]);
+    }
+    #[test]
+    fn test_zlp_in() {
+        let ram = FakeUdcRam::new();
+        // an empty write is a lone zero-length packet
+        assert_eq!(packets_in_transfer(0, 64), 1);
+        let chain = vec![(0x100 / 16, ram.descriptor(0x100 / 16))];
+        program_in_chain(&chain, &[], 64);
+        assert_eq!(chain[0].1.length(), 0);
+        assert!(chain[0].1.in_progress());
+        assert!(chain[0].1.int_on_done());
+        assert_eq!(chain[0].1.next_descriptor_addr(), 0);
+
+        // a short final packet terminates the transfer by itself, so no ZLP is added
+        let buf = [0xAAu8; 100];
+        assert_eq!(packets_in_transfer(buf.len(), 64), 2);
+        // an exact multiple gets a trailing zero-length packet
+        assert_eq!(packets_in_transfer(128, 64), 3);
+        assert_eq!(packets_in_transfer(64, 64), 2);
+    }
+    #[test]
+    fn test_zlp_out() {
+        let ram = FakeUdcRam::new();
+        let desc = ram.descriptor(0x100 / 16);
+        // armed and waiting on the host: no packet
+        desc.set_next_desc_and_len(0, 64);
+        desc.set_desc_flags(UsbDirection::Out, true, true, false);
+        desc.set_offset(0);
+        assert_eq!(out_packet_len(&desc), None);
+        // the host sent a zero-length packet: the descriptor completes without advancing
+        desc.set_offset_only(0);
+        assert_eq!(out_packet_len(&desc), Some(0));
+        // a regular packet with a length that isn't a multiple of the word size
+        desc.write_payload(&[1, 2, 3, 4, 5, 6, 7]);
+        desc.set_offset_only(7);
+        assert_eq!(out_packet_len(&desc), Some(7));
+        let mut buf = [0u8; 7];
+        desc.read_payload(&mut buf);
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7]);
+    }
+    #[test]
+    fn test_suspend_restore_regs() {
+        let ram = FakeUdcRam::new();
+        let regs = ram.regs();
+        // an enumerated device: address 5, enabled
+        regs.set_address(0x105);
+        let mut cfg = UdcConfig(0);
+        cfg.set_pullup_on(true);
+        regs.set_config(cfg);
+        let saved = regs.save();
+
+        // the register block loses its state across the suspend
+        regs.set_address(0);
+        regs.set_config(UdcConfig(0));
+        regs.restore(&saved);
+        assert_eq!(regs.address(), 0x105);
+        assert!(regs.config().pullup_on());
+        assert!(!regs.config().pullup_off());
+        assert!(regs.config().enable_ints());
+        assert_eq!(regs.save(), saved);
+
+        // a pending address trigger is not re-armed on resume
+        regs.set_address(0x200 | 0x7);
+        let saved = regs.save();
+        regs.restore(&saved);
+        assert_eq!(regs.address(), 0x7);
+    }
+    #[test]
+    fn test_clear_halt() {
+        for &dir in [UsbDirection::In, UsbDirection::Out].iter() {
+            let mut ep_status = UdcEpStatus(0);
+            ep_status.set_enable(true);
+            ep_status.set_data_phase(true);
+            apply_stall(&mut ep_status, 2, dir, true);
+            assert!(ep_status.force_stall());
+            assert!(ep_status.data_phase(), "setting a stall leaves the toggle alone");
+            apply_stall(&mut ep_status, 2, dir, false);
+            assert!(!ep_status.force_stall());
+            assert!(!ep_status.data_phase(), "clearing a halt resets the toggle to DATA0");
+            assert!(ep_status.enable());
+        }
+        // ep0 manages its own data phase
+        let mut ep_status = UdcEpStatus(0);
+        ep_status.set_data_phase(true);
+        apply_stall(&mut ep_status, 0, UsbDirection::Out, true);
+        apply_stall(&mut ep_status, 0, UsbDirection::Out, false);
+        assert!(ep_status.data_phase());
+    }
+    #[test]
+    fn test_stall_state() {
+        for &dir in [UsbDirection::In, UsbDirection::Out].iter() {
+            let mut ep_status = UdcEpStatus(0);
+            ep_status.set_enable(true);
+            assert!(!ep_status.force_stall());
+            apply_stall(&mut ep_status, 1, dir, true);
+            assert!(ep_status.force_stall(), "{:?} endpoint didn't read back as stalled", dir);
+            apply_stall(&mut ep_status, 1, dir, false);
+            assert!(!ep_status.force_stall(), "{:?} endpoint still stalled after clearing", dir);
+        }
+        // an IN endpoint comes out of a stall NACKing, which doesn't count as stalled
+        let mut ep_status = UdcEpStatus(0);
+        apply_stall(&mut ep_status, 1, UsbDirection::In, true);
+        apply_stall(&mut ep_status, 1, UsbDirection::In, false);
+        assert!(ep_status.force_nack());
+        assert!(!ep_status.force_stall());
+    }
+    #[test]
+    fn test_out_nack() {
+        let mut ep_status = UdcEpStatus(0);
+        ep_status.set_enable(true);
+        apply_nack(&mut ep_status, true);
+        assert!(ep_status.force_nack());
+        assert!(!ep_status.force_stall());
+        apply_nack(&mut ep_status, false);
+        assert!(!ep_status.force_nack());
+        assert!(ep_status.enable());
+
+        // NACK and STALL come and go independently of each other
+        apply_stall(&mut ep_status, 1, UsbDirection::Out, true);
+        apply_nack(&mut ep_status, true);
+        assert!(ep_status.force_stall() && ep_status.force_nack());
+        apply_nack(&mut ep_status, false);
+        assert!(ep_status.force_stall(), "clearing the NACK cleared the stall");
+        assert!(ep_status.force_stall());
+        apply_nack(&mut ep_status, true);
+        apply_stall(&mut ep_status, 1, UsbDirection::Out, false);
+        assert!(ep_status.force_nack(), "clearing the stall cleared the NACK");
+        assert!(!ep_status.force_stall());
+
+        // only allocated OUT endpoints past ep0 can be NACKed
+        assert!(nackable(EndpointAddress::from(0x01), true));
+        assert!(nackable(EndpointAddress::from(0x0F), true));
+        assert!(!nackable(EndpointAddress::from(0x01), false));
+        assert!(!nackable(EndpointAddress::from(0x81), true));
+        assert!(!nackable(EndpointAddress::from(0x00), true));
+        assert!(!nackable(EndpointAddress::from(0x80), true));
+    }
+}
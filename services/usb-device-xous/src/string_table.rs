@@ -0,0 +1,149 @@
+// Only polled on real hardware, but the table is kept free of hardware dependencies so that it
+// can be tested in hosted mode.
+#![cfg_attr(not(any(target_os = "none", target_os = "xous")), allow(dead_code))]
+
+use crate::api::MAX_STRING_LANGUAGES;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+
+/// LANGID of US English, the language of the profile's strings
+pub(crate) const LANGID_EN_US: u16 = 0x0409;
+/// `bDescriptorType` of a string descriptor
+const STRING: u8 = 3;
+/// String indices the stack gives the device's own strings in the device descriptor
+const MANUFACTURER_INDEX: u8 = 1;
+const PRODUCT_INDEX: u8 = 2;
+const SERIAL_NUMBER_INDEX: u8 = 3;
+
+/// A string descriptor holding `s`, cut short to what fits in a `bLength`
+fn string_descriptor(s: &str) -> Vec<u8> {
+    let mut descriptor = vec![0, STRING];
+    for unit in s.encode_utf16().take((u8::MAX as usize - 2) / 2) {
+        descriptor.extend_from_slice(&unit.to_le_bytes());
+    }
+    descriptor[0] = descriptor.len() as u8;
+    descriptor
+}
+
+/// The device's manufacturer and product names in each language it has them in, the first
+/// being those of the profile, in US English. Polled ahead of the other classes, like the
+/// `ReportCache`, so that the host is answered in the language it asks for, or in the first
+/// language if it asks for one the device doesn't have. The serial number is the same in all.
+pub(crate) struct StringTable {
+    serial_number: String,
+    /// LANGID, manufacturer and product
+    languages: Vec<(u16, String, String)>,
+}
+
+impl StringTable {
+    pub fn new(manufacturer: &str, product: &str, serial_number: &str) -> StringTable {
+        StringTable {
+            serial_number: serial_number.to_string(),
+            languages: vec![(LANGID_EN_US, manufacturer.to_string(), product.to_string())],
+        }
+    }
+    /// Replaces the strings of the first language, as when the profile changes
+    pub fn set_default(&mut self, manufacturer: &str, product: &str) {
+        self.languages[0].1 = manufacturer.to_string();
+        self.languages[0].2 = product.to_string();
+    }
+    /// Adds the strings for `langid`, replacing any it already has.
+    ///
+    /// Returns `Err(xous::Error::OutOfMemory)` if there are already `MAX_STRING_LANGUAGES`.
+    pub fn add_language(&mut self, langid: u16, manufacturer: &str, product: &str) -> Result<(), xous::Error> {
+        let strings = (langid, manufacturer.to_string(), product.to_string());
+        match self.languages.iter().position(|(id, _, _)| *id == langid) {
+            Some(index) => self.languages[index] = strings,
+            None if self.languages.len() >= MAX_STRING_LANGUAGES => return Err(xous::Error::OutOfMemory),
+            None => self.languages.push(strings),
+        }
+        Ok(())
+    }
+    /// String descriptor `index` in `langid`. Index 0 is the list of LANGIDs. `None` for
+    /// strings that aren't the device's own, which the other classes answer for.
+    pub fn descriptor(&self, index: u8, langid: u16) -> Option<Vec<u8>> {
+        let (_, manufacturer, product) = self
+            .languages
+            .iter()
+            .find(|(id, _, _)| *id == langid)
+            .unwrap_or(&self.languages[0]);
+        match index {
+            0 => {
+                let mut descriptor = vec![(2 + 2 * self.languages.len()) as u8, STRING];
+                for (id, _, _) in self.languages.iter() {
+                    descriptor.extend_from_slice(&id.to_le_bytes());
+                }
+                Some(descriptor)
+            }
+            MANUFACTURER_INDEX => Some(string_descriptor(manufacturer)),
+            PRODUCT_INDEX => Some(string_descriptor(product)),
+            SERIAL_NUMBER_INDEX => Some(string_descriptor(&self.serial_number)),
+            _ => None,
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for StringTable {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Standard
+            && req.recipient == Recipient::Device
+            && req.request == Request::GET_DESCRIPTOR
+        {
+            let (kind, index) = req.descriptor_type_index();
+            if kind == STRING {
+                if let Some(descriptor) = self.descriptor(index, req.index) {
+                    xfer.accept_with(&descriptor).ok();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(descriptor: &[u8]) -> String {
+        assert_eq!(descriptor[0] as usize, descriptor.len()); // bLength
+        assert_eq!(descriptor[1], STRING); // bDescriptorType
+        let units: Vec<u16> = descriptor[2..].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        String::from_utf16(&units).unwrap()
+    }
+
+    #[test]
+    fn test_string_languages() {
+        let mut table = StringTable::new("Kosagi", "Precursor", "1234abcd");
+        table.add_language(0x0407, "Kosagi GmbH", "Vorläufer").unwrap();
+
+        // index 0 lists the languages, the profile's first
+        assert_eq!(table.descriptor(0, 0).unwrap(), vec![6, STRING, 0x09, 0x04, 0x07, 0x04]);
+
+        // each language gets its own strings
+        assert_eq!(decode(&table.descriptor(PRODUCT_INDEX, 0x0409).unwrap()), "Precursor");
+        assert_eq!(decode(&table.descriptor(PRODUCT_INDEX, 0x0407).unwrap()), "Vorläufer");
+        assert_eq!(decode(&table.descriptor(MANUFACTURER_INDEX, 0x0407).unwrap()), "Kosagi GmbH");
+        // the serial number is the same in all of them
+        assert_eq!(decode(&table.descriptor(SERIAL_NUMBER_INDEX, 0x0407).unwrap()), "1234abcd");
+        assert_eq!(decode(&table.descriptor(SERIAL_NUMBER_INDEX, 0x0409).unwrap()), "1234abcd");
+        // a language the device doesn't have gets the first one
+        assert_eq!(decode(&table.descriptor(PRODUCT_INDEX, 0x040C).unwrap()), "Precursor");
+        // strings that aren't the device's own are left to the other classes
+        assert!(table.descriptor(4, 0x0409).is_none());
+        assert!(table.descriptor(0xEE, 0x0409).is_none());
+
+        // a profile change replaces the first language only, and re-adding replaces
+        table.set_default("Apple Inc.", "Apple Keyboard");
+        table.add_language(0x0407, "Apple", "Apple Tastatur").unwrap();
+        assert_eq!(decode(&table.descriptor(PRODUCT_INDEX, 0x0409).unwrap()), "Apple Keyboard");
+        assert_eq!(decode(&table.descriptor(PRODUCT_INDEX, 0x0407).unwrap()), "Apple Tastatur");
+        assert_eq!(table.descriptor(0, 0).unwrap().len(), 6);
+
+        // no more languages than the LANGID list can hold
+        for langid in 0x0800..0x0800 + (MAX_STRING_LANGUAGES - 2) as u16 {
+            table.add_language(langid, "m", "p").unwrap();
+        }
+        assert_eq!(table.add_language(0x0C00, "m", "p"), Err(xous::Error::OutOfMemory));
+        assert_eq!(table.descriptor(0, 0).unwrap().len(), 2 + 2 * MAX_STRING_LANGUAGES);
+    }
+}
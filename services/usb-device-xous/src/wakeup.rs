@@ -0,0 +1,92 @@
+use crate::api::Opcode;
+use num_traits::ToPrimitive;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// No deadline set
+const NONE: u64 = u64::MAX;
+
+/// What the timer thread does next, with the deadline as it stands
+#[derive(Debug, PartialEq, Eq)]
+enum Next {
+    /// Nothing is set; wait to be told of a deadline
+    Idle,
+    /// Wait this many ms, or until the deadline moves
+    Wait(u64),
+    /// The deadline has come; wake the server
+    Fire,
+}
+
+fn next(deadline: u64, now: u64) -> Next {
+    if deadline == NONE {
+        Next::Idle
+    } else if now >= deadline {
+        Next::Fire
+    } else {
+        Next::Wait(deadline - now)
+    }
+}
+
+/// Sends the server a scalar message at a deadline on the ticktimer's `elapsed_ms()` clock.
+/// One thread, started with the server, waits for each deadline in turn, and is handed the new
+/// one whenever it moves, so that rearming doesn't cost a thread. A wakeup can still cross with
+/// a deadline moving, so the server checks its own bookkeeping when it gets one.
+pub(crate) struct Wakeup {
+    deadlines: Sender<u64>,
+}
+
+impl Wakeup {
+    /// Starts the timer thread, which sends `opcode` through `cid` when the time comes
+    pub fn spawn(cid: xous::CID, opcode: Opcode) -> Wakeup {
+        let (sender, deadlines) = std::sync::mpsc::channel::<u64>();
+        std::thread::spawn(move || {
+            let tt = ticktimer_server::Ticktimer::new().unwrap();
+            let mut deadline = NONE;
+            loop {
+                let moved = match next(deadline, tt.elapsed_ms()) {
+                    Next::Idle => deadlines.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    Next::Wait(ms) => deadlines.recv_timeout(Duration::from_millis(ms)),
+                    Next::Fire => {
+                        deadline = NONE;
+                        xous::send_message(
+                            cid,
+                            xous::Message::new_scalar(opcode.to_usize().unwrap(), 0, 0, 0, 0),
+                        )
+                        .ok();
+                        continue;
+                    }
+                };
+                match moved {
+                    Ok(moved) => deadline = moved,
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+        Wakeup { deadlines: sender }
+    }
+    /// Wakes the server at `deadline`, in place of any deadline set before
+    pub fn set(&self, deadline: u64) {
+        self.deadlines.send(deadline.min(NONE - 1)).ok();
+    }
+    /// Cancels the wakeup, if one is set
+    pub fn cancel(&self) {
+        self.deadlines.send(NONE).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wakeup_next() {
+        assert_eq!(next(NONE, 0), Next::Idle);
+        assert_eq!(next(NONE, NONE), Next::Idle);
+        assert_eq!(next(1_500, 1_000), Next::Wait(500));
+        assert_eq!(next(1_500, 1_499), Next::Wait(1));
+        assert_eq!(next(1_500, 1_500), Next::Fire);
+        // a wakeup that comes late still fires
+        assert_eq!(next(1_500, 2_000), Next::Fire);
+    }
+}
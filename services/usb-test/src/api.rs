@@ -10,6 +10,8 @@ pub(crate) enum Opcode {
     KeyboardChar,
     /// Keyboard handler input
     HandlerTrigger,
+    /// Run the serial loopback self-test
+    SerialLoopback,
     /// Suspend/resume callback
     SuspendResume,
     /// Exits the server
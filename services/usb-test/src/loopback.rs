@@ -0,0 +1,85 @@
+// Only driven when the `serial-loopback` feature puts a CDC-ACM port on the bus, but the
+// bookkeeping doesn't depend on the port so that it can be tested in hosted mode.
+#![cfg_attr(not(feature = "serial-loopback"), allow(dead_code))]
+
+/// Number of bytes sent round trip by the serial loopback self-test. Long enough to span
+/// many max-size packets, and to wrap the pattern.
+pub(crate) const LOOPBACK_LEN: usize = 1000;
+
+/// One run of the serial loopback self-test. A known pattern goes out through the serial
+/// class, the host echoes it back, and what comes in is checked against what went out.
+/// Exercising both directions this way covers `write`, `read` and `poll` on the bus.
+pub(crate) struct SerialLoopback {
+    pattern: Vec<u8>,
+    written: usize,
+    received: Vec<u8>,
+}
+
+impl SerialLoopback {
+    pub fn new(len: usize) -> SerialLoopback {
+        SerialLoopback {
+            // a prime stride, so consecutive packets don't repeat each other
+            pattern: (0..len).map(|i| (i * 251 + 7) as u8).collect(),
+            written: 0,
+            received: Vec::with_capacity(len),
+        }
+    }
+    /// The part of the pattern that hasn't been written yet
+    pub fn unwritten(&self) -> &[u8] {
+        &self.pattern[self.written..]
+    }
+    /// Record that the serial class took `count` more bytes of the pattern
+    pub fn wrote(&mut self, count: usize) {
+        self.written = (self.written + count).min(self.pattern.len());
+    }
+    /// Takes in echoed bytes. Once the whole pattern is back, returns `Ok(())` if it matches,
+    /// or `Err(index)` with the index of the first byte that doesn't.
+    pub fn receive(&mut self, data: &[u8]) -> Option<Result<(), usize>> {
+        let wanted = self.pattern.len() - self.received.len();
+        self.received.extend_from_slice(&data[..data.len().min(wanted)]);
+        if self.received.len() < self.pattern.len() {
+            return None;
+        }
+        match self.received.iter().zip(self.pattern.iter()).position(|(rx, tx)| rx != tx) {
+            None => Some(Ok(())),
+            Some(index) => Some(Err(index)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for the bus and the host: takes at most one 64-byte packet per poll, and
+    /// echoes each packet back on the next one.
+    fn run(loopback: &mut SerialLoopback, corrupt: Option<usize>) -> Result<(), usize> {
+        let mut in_flight: Vec<u8> = Vec::new();
+        let mut sent = 0;
+        loop {
+            if !in_flight.is_empty() {
+                if let Some(result) = loopback.receive(&in_flight) {
+                    return result;
+                }
+                in_flight.clear();
+            }
+            let packet: Vec<u8> = loopback.unwritten().iter().take(64).cloned().collect();
+            loopback.wrote(packet.len());
+            for (index, &byte) in packet.iter().enumerate() {
+                in_flight.push(if corrupt == Some(sent + index) { !byte } else { byte });
+            }
+            sent += packet.len();
+            assert!(!(packet.is_empty() && in_flight.is_empty()), "ran out of data before the pattern came back");
+        }
+    }
+
+    #[test]
+    fn test_loopback() {
+        let mut loopback = SerialLoopback::new(LOOPBACK_LEN);
+        assert_eq!(run(&mut loopback, None), Ok(()));
+        assert!(loopback.unwritten().is_empty());
+
+        let mut loopback = SerialLoopback::new(LOOPBACK_LEN);
+        assert_eq!(run(&mut loopback, Some(300)), Err(300));
+    }
+}
@@ -2,8 +2,11 @@
 #![cfg_attr(target_os = "none", no_main)]
 
 mod api;
+mod loopback;
 
 use api::*;
+#[cfg(feature = "serial-loopback")]
+use loopback::*;
 #[cfg(any(target_os = "none", target_os = "xous"))]
 mod kbd;
 #[cfg(any(target_os = "none", target_os = "xous"))]
@@ -100,6 +103,10 @@ fn main() -> ! {
             NKROBootKeyboardInterface::default_config(&clock),
         )
         .build(&usb_alloc);
+    #[cfg(feature = "serial-loopback")]
+    let mut serial = usbd_serial::SerialPort::new(&usb_alloc);
+    #[cfg(feature = "serial-loopback")]
+    let mut loopback: Option<SerialLoopback> = None;
     let mut usb_dev = UsbDeviceBuilder::new(&usb_alloc, UsbVidPid(0x1209, 0x0001))
         .manufacturer("usbd-human-interface-device")
         .product("NKRO Keyboard")
@@ -118,7 +125,11 @@ fn main() -> ! {
                 usbmgmt.xous_resume();
             }),
             Some(Opcode::UsbIrqHandler) => {
-                if usb_dev.poll(&mut [&mut keyboard]) {
+                #[cfg(not(feature = "serial-loopback"))]
+                let polled = usb_dev.poll(&mut [&mut keyboard]);
+                #[cfg(feature = "serial-loopback")]
+                let polled = usb_dev.poll(&mut [&mut keyboard, &mut serial]);
+                if polled {
                     match keyboard.interface().read_report() {
                         Ok(l) => {
                             log::info!("got led state {:?}", l);
@@ -126,6 +137,46 @@ fn main() -> ! {
                         Err(e) => log::trace!("KEYB ERR: {:?}", e),
                     }
                 }
+                #[cfg(feature = "serial-loopback")]
+                if let Some(test) = loopback.as_mut() {
+                    match serial.write(test.unwritten()) {
+                        Ok(count) => test.wrote(count),
+                        Err(UsbError::WouldBlock) => (),
+                        Err(e) => log::warn!("serial loopback write error: {:?}", e),
+                    }
+                    let mut buf = [0u8; 64];
+                    let result = match serial.read(&mut buf) {
+                        Ok(count) => test.receive(&buf[..count]),
+                        Err(UsbError::WouldBlock) => None,
+                        Err(e) => {
+                            log::warn!("serial loopback read error: {:?}", e);
+                            None
+                        }
+                    };
+                    match result {
+                        Some(Ok(())) => log::info!("serial loopback PASS: {} bytes echoed intact", LOOPBACK_LEN),
+                        Some(Err(index)) => log::error!("serial loopback FAIL: first mismatch at byte {}", index),
+                        None => (),
+                    }
+                    if result.is_some() {
+                        loopback = None;
+                    }
+                }
+            }
+            Some(Opcode::SerialLoopback) => {
+                // The bytes go out to the host and come back only if something on the host echoes
+                // them, e.g. `stty -F /dev/ttyACM0 raw -echo && cat /dev/ttyACM0 > /dev/ttyACM0`.
+                #[cfg(feature = "serial-loopback")]
+                {
+                    log::info!("starting serial loopback of {} bytes", LOOPBACK_LEN);
+                    loopback = Some(SerialLoopback::new(LOOPBACK_LEN));
+                    // kick off the first packet; the rest go out as the host polls
+                    send_message(cid, Message::new_scalar(
+                        Opcode::UsbIrqHandler.to_usize().unwrap(), 0, 0, 0, 0
+                    )).unwrap();
+                }
+                #[cfg(not(feature = "serial-loopback"))]
+                log::info!("serial loopback skipped: built without the `serial-loopback` feature");
             }
             Some(Opcode::DoCmd) => {
                 log::info!("got command line: {}", cmdline);
@@ -167,6 +218,11 @@ fn main() -> ! {
                         "regs" => {
                             usbmgmt.print_regs();
                         }
+                        "serial" => {
+                            send_message(cid, Message::new_scalar(
+                                Opcode::SerialLoopback.to_usize().unwrap(), 0, 0, 0, 0
+                            )).unwrap();
+                        }
                         _ => {
                             log::info!("unrecognized command");
                         }
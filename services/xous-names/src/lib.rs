@@ -141,6 +141,29 @@ impl XousNames {
         }
     }
 
+    /// Like `request_connection_blocking()`, but gives up with `ServerNotFound` if the
+    /// server hasn't registered within `timeout_ms` milliseconds.
+    pub fn request_connection_timeout(
+        &self,
+        name: &str,
+        timeout_ms: u64,
+    ) -> Result<xous::CID, xous::Error> {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(timeout_ms);
+        loop {
+            match self.request_connection(name) {
+                Ok(val) => return Ok(val),
+                Err(xous::Error::AccessDenied) => return Err(xous::Error::AccessDenied),
+                _ => (),
+            }
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() {
+                return Err(xous::Error::ServerNotFound);
+            }
+            // Sleep on the ticktimer rather than spinning while the server registers
+            std::thread::sleep(remaining.min(std::time::Duration::from_millis(10)));
+        }
+    }
+
     pub fn trusted_init_done(&self) -> Result<bool, xous::Error> {
         let response = xous::send_message(
             self.conn,
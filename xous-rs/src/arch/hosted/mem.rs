@@ -1,8 +1,8 @@
-use crate::{Error, MemoryAddress, MemoryFlags, MemoryRange};
+use crate::{Error, MemoryAddress, MemoryFlags, MemoryRange, SID};
 const PAGE_SIZE: usize = 4096;
 
 extern crate alloc;
-use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
 
 pub fn map_memory_pre(
     _phys: &Option<MemoryAddress>,
@@ -24,7 +24,8 @@ pub fn map_memory_post(
     let layout = Layout::from_size_align(size, PAGE_SIZE)
         .unwrap()
         .pad_to_align();
-    let mem = unsafe { alloc(layout) } as usize;
+    // Mapped memory always starts out zeroed, as it does on hardware
+    let mem = unsafe { alloc_zeroed(layout) } as usize;
 
     // println!("Allocated {} bytes (requested {}) @ {:016x}", rounded_size, size, mem);
     unsafe { MemoryRange::new(mem, size) }
@@ -44,3 +45,24 @@ pub fn unmap_memory_post(range: MemoryRange) -> core::result::Result<(), Error>
     unsafe { dealloc(ptr, layout) };
     Ok(())
 }
+
+/// Each hosted process lives in its own address space, so there's no way to
+/// share memory between them.
+pub fn map_shared_memory_pre(
+    _name: &SID,
+    _size: usize,
+    _flags: MemoryFlags,
+) -> core::result::Result<(), Error> {
+    Err(Error::UnhandledSyscall)
+}
+
+pub fn map_shared_memory_post(
+    _name: SID,
+    _range: MemoryRange,
+) -> core::result::Result<MemoryRange, Error> {
+    Err(Error::UnhandledSyscall)
+}
+
+pub fn unmap_shared_memory_post(_name: SID, _remaining: usize) -> core::result::Result<(), Error> {
+    Ok(())
+}
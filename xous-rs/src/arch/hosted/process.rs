@@ -37,11 +37,14 @@ impl From<&str> for ProcessKey {
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct ProcessInit {
     pub key: ProcessKey,
+    /// How many pages the process may have mapped at once, or 0 for the default
+    pub page_quota: usize,
 }
 
 pub struct ProcessArgs {
     command: String,
     name: String,
+    page_quota: usize,
 }
 
 impl ProcessArgs {
@@ -49,8 +52,16 @@ impl ProcessArgs {
         ProcessArgs {
             command,
             name: name.to_owned(),
+            page_quota: 0,
         }
     }
+
+    /// Limit how many pages the new process may have mapped or reserved at
+    /// once. Leave this at 0 for the default quota.
+    pub fn page_quota(mut self, pages: usize) -> ProcessArgs {
+        self.page_quota = pages;
+        self
+    }
 }
 
 impl Into<[usize; 7]> for &ProcessInit {
@@ -60,7 +71,7 @@ impl Into<[usize; 7]> for &ProcessInit {
             u32::from_le_bytes(self.key.0[4..8].try_into().unwrap()) as _,
             u32::from_le_bytes(self.key.0[8..12].try_into().unwrap()) as _,
             u32::from_le_bytes(self.key.0[12..16].try_into().unwrap()) as _,
-            0,
+            self.page_quota,
             0,
             0,
         ]
@@ -78,6 +89,7 @@ impl TryFrom<[usize; 7]> for ProcessInit {
         key.copy_from_slice(&exploded);
         Ok(ProcessInit {
             key: ProcessKey(key),
+            page_quota: src[4],
         })
     }
 }
@@ -9,7 +9,8 @@ thread_local!(pub static THREAD_ID: RefCell<Option<TID>> = RefCell::new(None));
 /// Describes the parameters required to create a new thread on this platform.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ThreadInit {}
-pub struct WaitHandle<T>(std::thread::JoinHandle<T>);
+/// The thread's return value, and the value itself if it's a `usize`
+pub struct WaitHandle<T>(std::thread::JoinHandle<(T, Option<usize>)>);
 
 pub fn thread_to_args(call: usize, _init: &ThreadInit) -> [usize; 8] {
     [call, 0, 0, 0, 0, 0, 0, 0]
@@ -181,7 +182,12 @@ where
     std::thread::Builder::new()
         .spawn(move || {
             THREAD_ID.with(|tid| *tid.borrow_mut() = Some(thread_id));
-            f()
+            let ret = f();
+            // Threads that return a `usize` pass it back, the same way `JoinThread` does
+            let val = (&ret as &dyn core::any::Any)
+                .downcast_ref::<usize>()
+                .copied();
+            (ret, val)
         })
         .map(WaitHandle)
         .map_err(|_| crate::Error::InternalError)
@@ -191,7 +197,10 @@ pub fn wait_thread<T>(joiner: WaitHandle<T>) -> crate::SysCallResult {
     joiner
         .0
         .join()
-        .map(|_| Result::Ok)
+        .map(|(_, val)| match val {
+            Some(val) => Result::Scalar1(val),
+            None => Result::Ok,
+        })
         .map_err(|_| crate::Error::InternalError)
 }
 
@@ -1,4 +1,4 @@
-use crate::{Error, MemoryAddress, MemoryFlags, MemoryRange};
+use crate::{Error, MemoryAddress, MemoryFlags, MemoryRange, SID};
 
 pub fn map_memory_pre(
     _phys: &Option<MemoryAddress>,
@@ -26,3 +26,22 @@ pub fn unmap_memory_pre(_range: &MemoryRange) -> core::result::Result<(), Error>
 pub fn unmap_memory_post(_range: MemoryRange) -> core::result::Result<(), Error> {
     Ok(())
 }
+
+pub fn map_shared_memory_pre(
+    _name: &SID,
+    _size: usize,
+    _flags: MemoryFlags,
+) -> core::result::Result<(), Error> {
+    Ok(())
+}
+
+pub fn map_shared_memory_post(
+    _name: SID,
+    range: MemoryRange,
+) -> core::result::Result<MemoryRange, Error> {
+    Ok(range)
+}
+
+pub fn unmap_shared_memory_post(_name: SID, _remaining: usize) -> core::result::Result<(), Error> {
+    Ok(())
+}
@@ -295,7 +295,8 @@ pub fn create_thread_n_pre(
 ) -> core::result::Result<ThreadInit, crate::Error> {
     let flags = crate::MemoryFlags::R | crate::MemoryFlags::W | crate::MemoryFlags::RESERVE;
 
-    let stack = crate::map_memory(None, None, 131_072, flags)?;
+    // The kernel turns the lowest page into a guard page, so ask for one extra.
+    let stack = crate::map_memory(None, None, 131_072 + 4096, flags)?;
     Ok(ThreadInit::new(start, stack, *arg1, *arg2, *arg3, *arg4))
 }
 
@@ -9,6 +9,7 @@ pub struct ProcessArgs<'a> {
     stack: crate::MemoryRange,
     load_address: crate::MemoryAddress,
     entrypoint: crate::MemoryAddress,
+    page_quota: usize,
 }
 
 impl<'a> ProcessArgs<'a> {
@@ -22,6 +23,7 @@ impl<'a> ProcessArgs<'a> {
             entrypoint,
             stub,
             stack: unsafe { crate::MemoryRange::new(0x8000_0000 - 131072, 131072).unwrap() },
+            page_quota: 0,
         }
     }
 
@@ -34,6 +36,13 @@ impl<'a> ProcessArgs<'a> {
         self.stack.size = length;
         self
     }
+
+    /// Limit how many pages the new process may have mapped or reserved at
+    /// once, starting with its stack. Leave this at 0 for the default quota.
+    pub fn page_quota(mut self, pages: usize) -> ProcessArgs<'a> {
+        self.page_quota = pages;
+        self
+    }
 }
 
 /// ProcessInit describes the values that are passed to the
@@ -49,7 +58,8 @@ pub struct ProcessInit {
     pub text_destination: crate::MemoryAddress,
     // 5 -- Entrypoint
     pub start: crate::MemoryAddress,
-    // 6
+    // 6 -- Page quota, or 0 for the default
+    pub page_quota: usize,
 }
 
 impl Into<[usize; 7]> for &ProcessInit {
@@ -61,7 +71,7 @@ impl Into<[usize; 7]> for &ProcessInit {
             self.text.size.get(),
             self.text_destination.get(),
             self.start.get(),
-            0,
+            self.page_quota,
         ]
     }
 }
@@ -78,6 +88,7 @@ impl TryFrom<[usize; 7]> for ProcessInit {
             },
             text_destination: crate::MemoryAddress::new(src[4]).ok_or(crate::Error::OutOfMemory)?,
             start: crate::MemoryAddress::new(src[5]).ok_or(crate::Error::OutOfMemory)?,
+            page_quota: src[6],
         })
     }
 }
@@ -162,6 +173,8 @@ pub fn create_process_pre(args: &ProcessArgs) -> core::result::Result<ProcessIni
         text_destination: args.load_address,
         // 5 -- Entrypoint
         start: args.entrypoint,
+        // 6 -- Page quota
+        page_quota: args.page_quota,
     })
 }
 
@@ -1,7 +1,29 @@
-use crate::{Error, MemoryAddress, MemoryFlags, MemoryRange};
+use crate::{Error, MemoryAddress, MemoryFlags, MemoryRange, SID};
 
 extern crate alloc;
-use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    /// Base addresses of every range handed out by `map_memory()`. There is no
+    /// MMU in this environment, so this is how a bad `unmap_memory()` is caught
+    /// rather than freeing memory twice.
+    static ref MAPPED_RANGES: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+    /// Every "process" shares one address space here, so shared memory objects
+    /// are a single allocation per name. The kernel still tracks who maps them.
+    static ref SHARED_RANGES: Mutex<BTreeMap<[u32; 4], MemoryRange>> = Mutex::new(BTreeMap::new());
+}
+
+/// Record memory that was handed to this process outside of `map_memory()`,
+/// such as the contents of a `Move` message.
+pub fn track_mapped_range(range: &MemoryRange) {
+    MAPPED_RANGES
+        .lock()
+        .unwrap()
+        .insert(range.as_ptr() as usize);
+}
 
 pub fn map_memory_pre(
     _phys: &Option<MemoryAddress>,
@@ -20,17 +42,66 @@ pub fn map_memory_post(
     range: MemoryRange,
 ) -> core::result::Result<MemoryRange, Error> {
     let layout = Layout::from_size_align(range.len(), 4096).unwrap();
-    let new_mem = MemoryAddress::new(unsafe { alloc(layout) } as usize).ok_or(Error::BadAddress)?;
+    // Mapped memory always starts out zeroed, as it does on hardware
+    let new_mem =
+        MemoryAddress::new(unsafe { alloc_zeroed(layout) } as usize).ok_or(Error::BadAddress)?;
+    MAPPED_RANGES.lock().unwrap().insert(new_mem.get());
     Ok(unsafe { MemoryRange::new(new_mem.get(), range.len()).unwrap() })
 }
 
-pub fn unmap_memory_pre(_range: &MemoryRange) -> core::result::Result<(), Error> {
+pub fn unmap_memory_pre(range: &MemoryRange) -> core::result::Result<(), Error> {
+    if !MAPPED_RANGES
+        .lock()
+        .unwrap()
+        .contains(&(range.as_ptr() as usize))
+    {
+        return Err(Error::BadAddress);
+    }
     Ok(())
 }
 
 pub fn unmap_memory_post(range: MemoryRange) -> core::result::Result<(), Error> {
+    MAPPED_RANGES
+        .lock()
+        .unwrap()
+        .remove(&(range.as_ptr() as usize));
     let layout = Layout::from_size_align(range.len(), 4096).unwrap();
     let ptr = range.as_mut_ptr();
     unsafe { dealloc(ptr, layout) };
     Ok(())
 }
+
+pub fn map_shared_memory_pre(
+    _name: &SID,
+    _size: usize,
+    _flags: MemoryFlags,
+) -> core::result::Result<(), Error> {
+    Ok(())
+}
+
+pub fn map_shared_memory_post(
+    name: SID,
+    range: MemoryRange,
+) -> core::result::Result<MemoryRange, Error> {
+    let mut shared = SHARED_RANGES.lock().unwrap();
+    if let Some(existing) = shared.get(&name.to_array()) {
+        return Ok(*existing);
+    }
+    let layout = Layout::from_size_align(range.len(), 4096).unwrap();
+    let new_mem =
+        MemoryAddress::new(unsafe { alloc_zeroed(layout) } as usize).ok_or(Error::OutOfMemory)?;
+    let new_range = unsafe { MemoryRange::new(new_mem.get(), range.len())? };
+    shared.insert(name.to_array(), new_range);
+    Ok(new_range)
+}
+
+pub fn unmap_shared_memory_post(name: SID, remaining: usize) -> core::result::Result<(), Error> {
+    if remaining != 0 {
+        return Ok(());
+    }
+    if let Some(range) = SHARED_RANGES.lock().unwrap().remove(&name.to_array()) {
+        let layout = Layout::from_size_align(range.len(), 4096).unwrap();
+        unsafe { dealloc(range.as_mut_ptr(), layout) };
+    }
+    Ok(())
+}
@@ -46,11 +46,14 @@ pub struct ThreadInit {}
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct ProcessInit {
     pub key: ProcessKey,
+    /// How many pages the process may have mapped at once, or 0 for the default
+    pub page_quota: usize,
 }
 
 pub struct ProcessArgsAsThread<F: FnOnce()> {
     main: F,
     name: String,
+    page_quota: usize,
 }
 
 impl<F> ProcessArgsAsThread<F>
@@ -61,15 +64,23 @@ where
         ProcessArgsAsThread {
             main,
             name: name.to_owned(),
+            page_quota: 0,
         }
     }
+
+    /// Limit how many pages the new process may have mapped or reserved at
+    /// once. Leave this at 0 for the default quota.
+    pub fn page_quota(mut self, pages: usize) -> ProcessArgsAsThread<F> {
+        self.page_quota = pages;
+        self
+    }
 }
-pub struct ProcessHandleAsThread(std::thread::JoinHandle<()>);
+pub struct ProcessHandleAsThread(WaitHandle<()>);
 
 /// If no connection exists, create a new connection to the server. This means
 /// our parent PID will be PID1. Otherwise, reuse the same connection.
 pub fn create_process_pre_as_thread<F>(
-    _args: &ProcessArgsAsThread<F>,
+    args: &ProcessArgsAsThread<F>,
 ) -> core::result::Result<ProcessInit, crate::Error>
 where
     F: FnOnce(),
@@ -83,6 +94,7 @@ where
         key: PROCESS_KEY
             .with(|pk| *pk.borrow())
             .unwrap_or_else(default_process_key),
+        page_quota: args.page_quota,
     })
 }
 
@@ -122,11 +134,11 @@ where
         .unwrap()
         .unwrap();
 
-    Ok(ProcessHandleAsThread(thread_main.0))
+    Ok(ProcessHandleAsThread(thread_main))
 }
 
 pub fn wait_process_as_thread(joiner: ProcessHandleAsThread) -> crate::SysCallResult {
-    joiner.0.join().map(|_| Result::Ok).map_err(|_x| {
+    joiner.0 .0.join().map(|_| Result::Ok).map_err(|_x| {
         // panic!("wait error: {:?}", x);
         crate::Error::InternalError
     })
@@ -135,6 +147,7 @@ pub fn wait_process_as_thread(joiner: ProcessHandleAsThread) -> crate::SysCallRe
 pub struct ProcessArgs {
     command: String,
     name: String,
+    page_quota: usize,
 }
 
 impl ProcessArgs {
@@ -142,8 +155,16 @@ impl ProcessArgs {
         ProcessArgs {
             command,
             name: name.to_owned(),
+            page_quota: 0,
         }
     }
+
+    /// Limit how many pages the new process may have mapped or reserved at
+    /// once. Leave this at 0 for the default quota.
+    pub fn page_quota(mut self, pages: usize) -> ProcessArgs {
+        self.page_quota = pages;
+        self
+    }
 }
 
 /// This is returned when a process is created
@@ -197,7 +218,7 @@ impl Into<[usize; 7]> for &ProcessInit {
             u32::from_le_bytes(self.key.0[4..8].try_into().unwrap()) as _,
             u32::from_le_bytes(self.key.0[8..12].try_into().unwrap()) as _,
             u32::from_le_bytes(self.key.0[12..16].try_into().unwrap()) as _,
-            0,
+            self.page_quota,
             0,
             0,
         ]
@@ -215,6 +236,7 @@ impl TryFrom<[usize; 7]> for ProcessInit {
         key.copy_from_slice(&exploded);
         Ok(ProcessInit {
             key: ProcessKey(key),
+            page_quota: src[4],
         })
     }
 }
@@ -224,7 +246,7 @@ pub struct ProcessHandle(std::process::Child);
 
 /// If no connection exists, create a new connection to the server. This means
 /// our parent PID will be PID1. Otherwise, reuse the same connection.
-pub fn create_process_pre(_args: &ProcessArgs) -> core::result::Result<ProcessInit, crate::Error> {
+pub fn create_process_pre(args: &ProcessArgs) -> core::result::Result<ProcessInit, crate::Error> {
     ensure_connection()?;
 
     // Ensure there is a connection, because after this function returns
@@ -234,6 +256,7 @@ pub fn create_process_pre(_args: &ProcessArgs) -> core::result::Result<ProcessIn
         key: PROCESS_KEY
             .with(|pk| *pk.borrow())
             .unwrap_or_else(default_process_key),
+        page_quota: args.page_quota,
     })
 }
 
@@ -284,7 +307,8 @@ pub fn wait_process(mut joiner: ProcessHandle) -> crate::SysCallResult {
         })
 }
 
-pub struct WaitHandle<T>(std::thread::JoinHandle<T>);
+/// The thread's return value, and the value itself if it's a `usize`
+pub struct WaitHandle<T>(std::thread::JoinHandle<(T, Option<usize>)>);
 
 #[derive(Clone)]
 struct ServerConnection {
@@ -327,7 +351,7 @@ pub fn args_to_process(
     a2: usize,
     a3: usize,
     a4: usize,
-    _a5: usize,
+    a5: usize,
     _a6: usize,
     _a7: usize,
 ) -> core::result::Result<ProcessInit, crate::Error> {
@@ -340,6 +364,7 @@ pub fn args_to_process(
     key.copy_from_slice(&v);
     Ok(ProcessInit {
         key: ProcessKey(key),
+        page_quota: a5,
     })
 }
 
@@ -549,7 +574,12 @@ where
             PROCESS_ID.with(|pid| *pid.borrow_mut() = process_id);
             XOUS_SERVER_CONNECTION.with(|xsc| *xsc.borrow_mut() = Some(server_connection));
             CALL_FOR_THREAD.with(|cft| *cft.borrow_mut() = call_for_thread);
-            f()
+            let ret = f();
+            // Threads that return a `usize` pass it back, the same way `JoinThread` does
+            let val = (&ret as &dyn core::any::Any)
+                .downcast_ref::<usize>()
+                .copied();
+            (ret, val)
         })
         .map(WaitHandle)
         .map_err(|_| crate::Error::InternalError)?)
@@ -559,7 +589,10 @@ pub fn wait_thread<T>(joiner: WaitHandle<T>) -> crate::SysCallResult {
     joiner
         .0
         .join()
-        .map(|_| Result::Ok)
+        .map(|(_, val)| match val {
+            Some(val) => Result::Scalar1(val),
+            None => Result::Ok,
+        })
         .map_err(|_| crate::Error::InternalError)
 }
 
@@ -758,6 +791,7 @@ fn _xous_syscall_result(ret: &mut Result, thread_id: TID, server_connection: &Se
 
         // If the client is passing us memory, remap the array to our own space.
         if let Result::Message(msg) = &mut response {
+            let is_move = matches!(msg.body, crate::Message::Move(_));
             match &mut msg.body {
                 crate::Message::Move(ref mut memory_message)
                 | crate::Message::Borrow(ref mut memory_message)
@@ -774,6 +808,10 @@ fn _xous_syscall_result(ret: &mut Result, thread_id: TID, server_connection: &Se
                     let addr = data.as_mut_ptr();
                     memory_message.buf =
                         unsafe { crate::MemoryRange::new(addr as _, len).unwrap() };
+                    // Moved memory now belongs to us, and will be unmapped when dropped
+                    if is_move {
+                        mem::track_mapped_range(&memory_message.buf);
+                    }
                 }
                 _ => (),
             }
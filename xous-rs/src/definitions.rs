@@ -142,6 +142,57 @@ impl From<SID> for [u32; 4] {
     }
 }
 
+/// A 96-bit secret that must be presented with `ConnectWithToken` in order to
+/// connect to a server created with `CreateServerWithToken`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConnectToken([u32; 3]);
+impl ConnectToken {
+    pub const fn from_u32(a0: u32, a1: u32, a2: u32) -> ConnectToken {
+        ConnectToken([a0, a1, a2])
+    }
+    pub const fn to_u32(&self) -> (u32, u32, u32) {
+        (self.0[0], self.0[1], self.0[2])
+    }
+}
+
+/// What a server does with a message that arrives when its queue is already
+/// holding as many unreceived messages as it was created to hold.
+///
+/// Whatever the policy, `send_message()` waits for room rather than return
+/// `ServerQueueFull`, so the policy mostly decides what `try_send_message()` sees.
+#[repr(usize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Turn the message away: `try_send_message()` returns `ServerQueueFull`.
+    Reject = 0,
+
+    /// Make `try_send_message()` wait for room, as `send_message()` does.
+    /// Interrupt handlers cannot wait, so theirs still returns `ServerQueueFull`.
+    Block = 1,
+
+    /// Make room by discarding the oldest unreceived message, as long as that
+    /// message is a non-blocking scalar. Otherwise the message is turned away,
+    /// as with `Reject`.
+    DropOldest = 2,
+}
+
+impl QueuePolicy {
+    pub fn from_usize(arg: usize) -> Option<Self> {
+        match arg {
+            0 => Some(QueuePolicy::Reject),
+            1 => Some(QueuePolicy::Block),
+            2 => Some(QueuePolicy::DropOldest),
+            _ => None,
+        }
+    }
+}
+
+impl Default for QueuePolicy {
+    fn default() -> Self {
+        QueuePolicy::Reject
+    }
+}
+
 /// Connection ID
 pub type CID = u32;
 
@@ -194,6 +245,7 @@ pub enum Error {
     UseBeforeInit = 24,
     DoubleFree = 25,
     DebugInProgress = 26,
+    WouldDeadlock = 27,
 }
 
 impl Error {
@@ -226,6 +278,7 @@ impl Error {
             24 => UseBeforeInit,
             25 => DoubleFree,
             26 => DebugInProgress,
+            27 => WouldDeadlock,
             _ => UnknownError,
         }
     }
@@ -258,6 +311,7 @@ impl Error {
             UseBeforeInit => 24,
             DoubleFree => 25,
             DebugInProgress => 26,
+            WouldDeadlock => 27,
             UnknownError => usize::MAX,
         }
     }
@@ -381,6 +435,123 @@ impl core::fmt::Display for MemoryType {
     }
 }
 
+/// A snapshot of a process' bookkeeping, as returned by `GetProcessInfo`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    /// The ID of the process
+    pub pid: PID,
+
+    /// The ID of the process that created it
+    pub ppid: PID,
+
+    /// Base address of the heap
+    pub heap_base: usize,
+
+    /// Current size of the heap
+    pub heap_size: usize,
+
+    /// Maximum size of the heap
+    pub heap_max: usize,
+
+    /// Number of threads that currently exist in the process
+    pub thread_count: usize,
+
+    /// Name of the lowest-numbered thread that has set one with
+    /// `SetThreadName`, which is usually the main thread
+    pub name: ThreadName,
+}
+
+/// How quickly the kernel has been getting to an interrupt's handler, as
+/// returned by `GetInterruptLatency`. Times are in ticks of the platform timer,
+/// or in microseconds in hosted mode.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct InterruptLatency {
+    /// Number of times the handler has run
+    pub count: usize,
+
+    /// Longest time from the first trap the kernel took with the interrupt
+    /// asserted to jumping to its handler
+    pub max_latency: usize,
+
+    /// Average time from the first trap the kernel took with the interrupt
+    /// asserted to jumping to its handler
+    pub avg_latency: usize,
+
+    /// Longest time the handler took to return
+    pub max_handler: usize,
+
+    /// Average time the handler took to return
+    pub avg_handler: usize,
+}
+
+/// Longest thread name the kernel keeps, in bytes
+pub const THREAD_NAME_LEN: usize = 12;
+
+/// A short, human-readable name for a thread, set with `SetThreadName`.
+/// Names are truncated to `THREAD_NAME_LEN` bytes.
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
+pub struct ThreadName {
+    bytes: [u8; THREAD_NAME_LEN],
+    len: usize,
+}
+
+impl ThreadName {
+    /// Make a name out of `name`, cutting it short at a character boundary
+    /// if it's too long.
+    pub fn new(name: &str) -> ThreadName {
+        let mut len = name.len().min(THREAD_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0u8; THREAD_NAME_LEN];
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+        ThreadName { bytes, len }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Pack the name into 32-bit words for passing through syscall arguments.
+    /// The name is padded with zeroes, so it may not contain any.
+    pub fn to_words(&self) -> [usize; THREAD_NAME_LEN / 4] {
+        let mut words = [0usize; THREAD_NAME_LEN / 4];
+        for (word, chunk) in words.iter_mut().zip(self.bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes(chunk.try_into().unwrap()) as usize;
+        }
+        words
+    }
+
+    pub fn from_words(words: &[usize]) -> ThreadName {
+        let mut bytes = [0u8; THREAD_NAME_LEN];
+        for (chunk, word) in bytes.chunks_exact_mut(4).zip(words.iter()) {
+            chunk.copy_from_slice(&(*word as u32).to_le_bytes());
+        }
+        let len = bytes
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(THREAD_NAME_LEN);
+        // Don't trust that the words hold valid UTF-8
+        ThreadName::new(core::str::from_utf8(&bytes[..len]).unwrap_or(""))
+    }
+}
+
+impl core::fmt::Debug for ThreadName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl core::fmt::Display for ThreadName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, PartialEq)]
 pub enum Result {
@@ -419,6 +590,9 @@ pub enum Result {
     /// A scalar with two values
     Scalar2(usize, usize),
 
+    /// A scalar with five values
+    Scalar5(usize, usize, usize, usize, usize),
+
     /// The syscall should be attempted again. This is returned when calling
     /// functions such as `try_connect()` and `try_send()` that may block.
     RetryCall,
@@ -436,6 +610,9 @@ pub enum Result {
     /// the caller.
     NewProcess(ProcessStartup),
 
+    /// Information about a process, returned by `GetProcessInfo`
+    ProcessInfo(ProcessInfo),
+
     UnknownResult(usize, usize, usize, usize, usize, usize, usize),
 }
 
@@ -499,6 +676,23 @@ impl Result {
                 0,
             ],
             Result::NewProcess(p) => Self::add_opcode(19, p.into()),
+            Result::ProcessInfo(info) => {
+                // PIDs and the thread count all fit in a byte, which leaves room for the name
+                let name = info.name.to_words();
+                [
+                    20,
+                    info.pid.get() as usize
+                        | (info.ppid.get() as usize) << 8
+                        | info.thread_count << 16,
+                    info.heap_base,
+                    info.heap_size,
+                    info.heap_max,
+                    name[0],
+                    name[1],
+                    name[2],
+                ]
+            }
+            Result::Scalar5(a, b, c, d, e) => [21, *a, *b, *c, *d, *e, 0, 0],
             Result::UnknownResult(arg1, arg2, arg3, arg4, arg5, arg6, arg7) => {
                 [usize::MAX, *arg1, *arg2, *arg3, *arg4, *arg5, *arg6, *arg7]
             }
@@ -576,6 +770,19 @@ impl Result {
             17 => Result::None,
             18 => Result::MemoryReturned(MemorySize::new(src[1]), MemorySize::new(src[2])),
             19 => Result::NewProcess(src.into()),
+            20 => match (PID::new(src[1] as u8), PID::new((src[1] >> 8) as u8)) {
+                (Some(pid), Some(ppid)) => Result::ProcessInfo(ProcessInfo {
+                    pid,
+                    ppid,
+                    heap_base: src[2],
+                    heap_size: src[3],
+                    heap_max: src[4],
+                    thread_count: (src[1] >> 16) & 0xff,
+                    name: ThreadName::from_words(&src[5..8]),
+                }),
+                _ => Result::Error(Error::InternalError),
+            },
+            21 => Result::Scalar5(src[1], src[2], src[3], src[4], src[5]),
             _ => Result::UnknownResult(src[0], src[1], src[2], src[3], src[4], src[5], src[6]),
         }
     }
@@ -24,12 +24,18 @@ impl MemoryFlags {
     /// Allow the CPU to execute from this page.
     pub const X: Self = Self { bits: 0b0000_1000 };
 
+    /// When `phys` names main memory, zero each page as it's first
+    /// touched rather than before the call returns. Only for memory that
+    /// nothing else writes to in the meantime, such as by DMA, as the first
+    /// touch wipes whatever is there.
+    pub const LAZY_ZERO: Self = Self { bits: 0b0001_0000 };
+
     pub fn bits(&self) -> usize {
         self.bits
     }
 
     pub fn from_bits(raw: usize) -> Option<MemoryFlags> {
-        if raw > 16 {
+        if raw > 0b0001_1111 {
             None
         } else {
             Some(MemoryFlags { bits: raw })
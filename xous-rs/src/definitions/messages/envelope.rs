@@ -117,6 +117,11 @@ impl Envelope {
                         return Err((ManuallyDrop::into_inner(manual_self), e));
                     }
                     return Ok(());
+                } else if let Ok(crate::Result::Scalar5(v1, v2, v3, v4, v5)) = result {
+                    if let Err(e) = crate::return_scalar5(sender, v1, v2, v3, v4, v5) {
+                        return Err((ManuallyDrop::into_inner(manual_self), e));
+                    }
+                    return Ok(());
                 }
                 return Err((
                     ManuallyDrop::into_inner(manual_self),
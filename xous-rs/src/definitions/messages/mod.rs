@@ -94,6 +94,11 @@ impl MemoryMessage {
 #[repr(C)]
 #[derive(Debug, PartialEq, Clone, Copy)]
 /// A simple scalar message.  This is similar to a `move` message.
+///
+/// It carries at most four arguments: with the id and the connection, they
+/// fill every register of `SendMessage` and of the envelope the server
+/// receives, so a fifth value has to go in a memory message. Only the reply
+/// to a blocking scalar can carry five, through `return_scalar5()`.
 pub struct ScalarMessage {
     pub id: MessageId,
     pub arg1: usize,
@@ -14,6 +14,8 @@ impl Sender {
         Sender { data }
     }
 
+    /// The process that sent the message. The kernel fills this in whether the
+    /// message was handed straight to a waiting server or queued first.
     pub fn pid(&self) -> Option<PID> {
         let pid_u8 = ((self.data >> 24) & 0xff) as u8;
         PID::new(pid_u8)
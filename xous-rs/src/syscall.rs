@@ -1,7 +1,7 @@
 use crate::{
-    pid_from_usize, CpuID, Error, MemoryAddress, MemoryFlags, MemoryMessage, MemoryRange,
-    MemorySize, MemoryType, Message, MessageEnvelope, MessageSender, ProcessArgs, ProcessInit,
-    Result, ScalarMessage, SysCallResult, ThreadInit, CID, PID, SID, TID,
+    pid_from_usize, CpuID, Error, InterruptLatency, MemoryAddress, MemoryFlags, MemoryMessage,
+    MemoryRange, MemorySize, MemoryType, Message, MessageEnvelope, MessageSender, ProcessArgs,
+    ProcessInit, Result, ScalarMessage, SysCallResult, ThreadInit, CID, PID, SID, TID,
 };
 use core::convert::{TryFrom, TryInto};
 /* https://github.com/betrusted-io/xous-core/issues/90
@@ -14,6 +14,13 @@ use crate::Exception
 #[cfg(feature = "processes-as-threads")]
 pub use crate::arch::ProcessArgsAsThread;
 
+/// The number of words each call in a `Batch` takes up, and each result it is
+/// replaced with
+pub const BATCH_CALL_WORDS: usize = 8;
+
+/// The most calls that one `Batch` may hold
+pub const MAX_BATCH_CALLS: usize = 16;
+
 #[derive(Debug, PartialEq)]
 pub enum SysCall {
     /// Allocates pages of memory, equal to a total of `size` bytes.  A physical
@@ -41,12 +48,14 @@ pub enum SysCall {
         MemoryFlags,           /* flags */
     ),
 
-    /// Release the memory back to the operating system.
+    /// Release the memory back to the operating system. The entire range must
+    /// belong to the calling process, otherwise nothing is released.
     ///
     /// # Errors
     ///
     /// * **BadAlignment**: The memory range was not page-aligned
     /// * **BadAddress**: A page in the range was not mapped
+    /// * **ShareViolation**: A page in the range is currently lent out
     ///
     UnmapMemory(MemoryRange),
 
@@ -74,6 +83,10 @@ pub enum SysCall {
     /// specified flags.  To get the current heap base, call this with a size of
     /// `0`.
     ///
+    /// The new pages are only reserved, and get backed by memory the first time
+    /// they are touched. They always read as zero, just like pages returned by
+    /// `MapMemory`.
+    ///
     /// # Returns
     ///
     /// * **MemoryRange(*mut usize /* The base of the heap */, usize /* the new
@@ -100,8 +113,7 @@ pub enum SysCall {
     /// * **BadAlignment**: Either the physical or virtual addresses aren't
     ///                     page-aligned, or the size isn't a multiple of the
     ///                     page width.
-    /// * **OutOfMemory**: A contiguous chunk of memory couldn't be found, or
-    ///                    the system's memory size has been exceeded.
+    /// * **BadAddress**: The heap is smaller than the amount to remove
     DecreaseHeap(usize /* desired heap size */),
 
     /// Set the specified flags on the virtual address range. This can be used
@@ -117,10 +129,12 @@ pub enum SysCall {
     ///
     /// # Errors
     ///
+    /// * **BadAlignment**: The range does not lie on page boundaries
     /// * **ProcessNotChild**: The given PID is not a child of the current
     ///                        process.
     /// * **MemoryInUse**: The given PID has already been started, and it is not
-    ///                    legal to modify memory flags anymore.
+    ///                    legal to modify memory flags anymore, or the new flags
+    ///                    would add permissions the range doesn't already have.
     UpdateMemoryFlags(
         MemoryRange, /* range of memory to update flags for */
         MemoryFlags, /* new flags */
@@ -206,10 +220,14 @@ pub enum SysCall {
     /// * **InterruptNotFound**: The specified interrupt isn't valid on this
     ///   system
     /// * **InterruptInUse**: The specified interrupt has already been claimed
+    ///
+    /// When several interrupts are pending at once, the one with the highest
+    /// priority is serviced first.
     ClaimInterrupt(
         usize,                 /* IRQ number */
         MemoryAddress,         /* function pointer */
         Option<MemoryAddress>, /* argument */
+        usize,                 /* priority */
     ),
 
     /// Returns the interrupt back to the operating system and masks it again.
@@ -316,6 +334,7 @@ pub enum SysCall {
     /// # Errors
     ///
     /// * **ServerNotFound**: The server could not be found.
+    /// * **AccessDenied**: The server was created with `CreateServerWithToken`
     TryConnect(SID /* server id */),
 
     /// Send a message to a server (blocking until it's ready)
@@ -325,12 +344,15 @@ pub enum SysCall {
     /// * **Ok**: The Scalar / Send message was successfully sent, or the Borrow has finished
     /// * **Scalar1**: The Server returned a `Scalar1` value
     /// * **Scalar2**: The Server returned a `Scalar2` value
+    /// * **Scalar5**: The Server returned a `Scalar5` value
     /// * **BlockedProcess**: In Hosted mode, the target process is now blocked
     ///
     /// # Errors
     ///
     /// * **ServerNotFound**: The server could not be found.
     /// * **ProcessNotFound**: Internal error -- the parent process couldn't be found when blocking
+    /// * **WouldDeadlock**: The message is blocking, and the server is in this
+    ///                      process, which has no other thread to answer it
     SendMessage(CID, Message),
 
     /// Try to send a message to a server
@@ -340,6 +362,7 @@ pub enum SysCall {
     /// * **Ok**: The Scalar / Send message was successfully sent, or the Borrow has finished
     /// * **Scalar1**: The Server returned a `Scalar1` value
     /// * **Scalar2**: The Server returned a `Scalar2` value
+    /// * **Scalar5**: The Server returned a `Scalar5` value
     /// * **BlockedProcess**: In Hosted mode, the target process is now blocked
     ///
     /// # Errors
@@ -347,6 +370,8 @@ pub enum SysCall {
     /// * **ServerNotFound**: The server could not be found.
     /// * **ServerQueueFull**: The server's mailbox is full
     /// * **ProcessNotFound**: Internal error -- the parent process couldn't be found when blocking
+    /// * **WouldDeadlock**: The message is blocking, and the server is in this
+    ///                      process, which has no other thread to answer it
     TrySendMessage(CID, Message),
 
     /// Return a Borrowed memory region to the sender
@@ -367,7 +392,17 @@ pub enum SysCall {
     CreateThread(ThreadInit),
 
     /// Create a new process, setting the current process as the parent ID.
-    /// Starts the process immediately and returns a `ProcessStartup` value.
+    /// The new process is parked until the parent runs it with `SwitchTo`.
+    ///
+    /// # Returns
+    ///
+    /// * **NewProcess**: A `ProcessStartup` value containing the new PID
+    ///
+    /// # Errors
+    ///
+    /// * **BadAlignment**: The load address or stack was not page-aligned
+    /// * **OutOfMemory**: There was no memory for the new process
+    /// * **ProcessNotFound**: The process table is full
     CreateProcess(ProcessInit),
 
     /// Terminate the current process, closing all server connections.
@@ -425,6 +460,313 @@ pub enum SysCall {
         usize, /* stack pointer */
     ),
 
+    /// Abandons the blocking scalar message that the given thread of this
+    /// process is waiting on. The waiting thread wakes up with a `Timeout`
+    /// error. If the server has not yet received the message it is removed
+    /// from the queue, otherwise the server's eventual response is dropped.
+    ///
+    /// ## Returns
+    ///
+    /// * **Ok**: The message was cancelled
+    /// * **None**: The thread was not waiting on a blocking scalar message
+    CancelMessage(TID),
+
+    /// Terminate a child process, closing its servers and connections and
+    /// releasing all of its memory. Use `TerminateProcess` to exit the
+    /// current process.
+    ///
+    /// # Errors
+    ///
+    /// * **ProcessNotFound**: The given process does not exist
+    /// * **ProcessNotChild**: The given process is not a child of the current
+    ///   process
+    TerminateChildProcess(PID),
+
+    /// Map the shared memory object with the given name into this process,
+    /// creating it if it doesn't exist yet. Every process that maps the same
+    /// name sees the same physical pages, which are freed once the last
+    /// process unmaps them.
+    ///
+    /// # Returns
+    ///
+    /// * **MemoryRange**: The address of the region in this process
+    ///
+    /// # Errors
+    ///
+    /// * **BadAlignment**: The size was not a multiple of the page size
+    /// * **BadAddress**: The object exists but has a different size
+    /// * **MemoryInUse**: This process has already mapped the object
+    /// * **OutOfMemory**: There was no room for a new object or its pages
+    MapSharedMemory(SID /* name */, MemorySize, MemoryFlags),
+
+    /// Unmap a shared memory object from this process.
+    ///
+    /// # Returns
+    ///
+    /// * **Scalar1**: The number of processes that still have it mapped
+    ///
+    /// # Errors
+    ///
+    /// * **BadAddress**: This process does not have the object mapped
+    UnmapSharedMemory(SID /* name */),
+
+    /// Mask or unmask an interrupt that this process has claimed. A masked
+    /// interrupt remains pending, and its handler is called once it is
+    /// unmasked again.
+    ///
+    /// # Errors
+    ///
+    /// * **InterruptNotFound**: The specified interrupt isn't valid on this
+    ///   system
+    /// * **AccessDenied**: The interrupt isn't claimed by this process
+    SetInterruptEnabled(usize /* IRQ number */, bool /* enabled */),
+
+    /// Stop running this process and hand the remainder of the quantum
+    /// directly to the given process. If that process has no threads ready
+    /// to run, this behaves exactly like `Yield`.
+    YieldTo(PID),
+
+    /// Read the PID, parent PID, heap layout, and thread count of a process.
+    ///
+    /// # Returns
+    ///
+    /// * **ProcessInfo**: The details of the given process
+    ///
+    /// # Errors
+    ///
+    /// * **ProcessNotFound**: The process doesn't exist
+    GetProcessInfo(PID),
+
+    /// Read how long a process has spent running. This is measured in ticks
+    /// of the platform timer, or in microseconds in hosted mode.
+    ///
+    /// # Returns
+    ///
+    /// * **Scalar2**: The low and high 32 bits of the running time
+    ///
+    /// # Errors
+    ///
+    /// * **ProcessNotFound**: The process doesn't exist
+    GetCpuTime(PID),
+
+    /// Limit how many pages of memory a child process may have mapped or
+    /// reserved at once. Processes start out with a default quota, and
+    /// mapping memory beyond the quota fails with `OutOfMemory`. To limit a
+    /// process before it runs, set `ProcessArgs::page_quota()` instead.
+    ///
+    /// # Returns
+    ///
+    /// * **Ok**: The new quota is in effect
+    ///
+    /// # Errors
+    ///
+    /// * **ProcessNotFound**: The process doesn't exist
+    /// * **ProcessNotChild**: The process isn't a child of the caller
+    SetMemoryQuota(PID, usize),
+
+    /// Connect to a server, waiting at most the given number of milliseconds
+    /// for it to be created. A timeout of 0 behaves like `TryConnect`.
+    ///
+    /// # Returns
+    ///
+    /// * **ConnectionID(cid)**: The new connection ID for communicating with the server.
+    ///
+    /// # Errors
+    ///
+    /// * **ServerNotFound**: The server wasn't created before the timeout
+    /// * **OutOfMemory**: Too many threads are already waiting to connect
+    ConnectWithTimeout(SID /* server id */, usize /* timeout in ms */),
+
+    /// Park this thread until the given event is signaled by another thread
+    /// of the same process, or by one of its interrupt handlers. If the event
+    /// was signaled while no thread was waiting on it, this consumes that
+    /// signal and returns at once.
+    ///
+    /// # Returns
+    ///
+    /// * **Ok**: The event was signaled
+    ///
+    /// # Errors
+    ///
+    /// * **OutOfMemory**: Too many threads are already waiting on events
+    WaitForEvent(usize /* event id */),
+
+    /// Wake every thread in this process that is waiting on the given event.
+    /// If no thread is waiting, the event stays pending until one waits on it;
+    /// further signals before then are folded into it.
+    ///
+    /// # Returns
+    ///
+    /// * **Scalar1(count)**: The number of threads that were woken
+    ///
+    /// # Errors
+    ///
+    /// * **OutOfMemory**: Too many events are already pending
+    SignalEvent(usize /* event id */),
+
+    /// Park this thread for at least the given number of milliseconds. A
+    /// duration of 0 behaves like `Yield`.
+    ///
+    /// # Returns
+    ///
+    /// * **Ok**: The duration has passed
+    ///
+    /// # Errors
+    ///
+    /// * **OutOfMemory**: Too many threads are already sleeping
+    SleepMs(usize /* duration in ms */),
+
+    /// Read the number of milliseconds since the system booted. This never
+    /// goes backwards.
+    ///
+    /// # Returns
+    ///
+    /// * **Scalar2**: The low and high 32 bits of the time
+    ///
+    /// # Errors
+    ///
+    /// This syscall will never return an error.
+    GetMonotonicMs,
+
+    /// Give the calling thread a short name for debugging. Names longer than
+    /// `THREAD_NAME_LEN` bytes are truncated.
+    ///
+    /// # Returns
+    ///
+    /// * **Ok**: The name was set
+    ///
+    /// # Errors
+    ///
+    /// * **OutOfMemory**: Too many threads have names already
+    SetThreadName(crate::ThreadName),
+
+    /// Create a new server with the given SID that only accepts connections
+    /// presenting `token` with `ConnectWithToken`. The owning process may
+    /// connect without it.
+    ///
+    /// # Returns
+    ///
+    /// * **NewServerID(sid, cid)**: The specified SID, along with the connection ID
+    ///                              for this process to talk to the server.
+    ///
+    /// # Errors
+    ///
+    /// * **OutOfMemory**: The server table was full and a new server couldn't
+    ///                    be created.
+    CreateServerWithToken(SID /* server hash */, crate::ConnectToken),
+
+    /// Try to connect to a server, presenting a token. This is the only way to
+    /// connect to a server created with `CreateServerWithToken`, but works for
+    /// any server.
+    ///
+    /// # Returns
+    ///
+    /// * **ConnectionID(cid)**: The new connection ID for communicating with the server.
+    ///
+    /// # Errors
+    ///
+    /// * **ServerNotFound**: The server could not be found.
+    /// * **AccessDenied**: The server requires a different token
+    ConnectWithToken(SID /* server id */, crate::ConnectToken),
+
+    /// Return five scalars to the sender, which receives them as `Scalar5`.
+    /// There is no five-argument counterpart for sending; see `ScalarMessage`.
+    ReturnScalar5(MessageSender, usize, usize, usize, usize, usize),
+
+    /// Create a server with the given SID whose queue holds at most `depth`
+    /// messages that it has not yet received, and that deals with a message
+    /// sent beyond that according to `policy`. Servers created any other way
+    /// hold as many messages as their queue has room for, and `Reject` the rest.
+    /// Messages the server has received but not yet returned still take up
+    /// room, so a message may be turned away before `depth` are waiting.
+    ///
+    /// # Returns
+    ///
+    /// * **NewServerID(sid, cid)**: The specified SID, along with the connection ID
+    ///                              for this process to talk to the server.
+    ///
+    /// # Errors
+    ///
+    /// * **OutOfMemory**: The server table was full and a new server couldn't
+    ///                    be created, or the queue can't hold `depth` messages.
+    /// * **InvalidSyscall**: `depth` is 0
+    CreateServerWithQueue(
+        SID,   /* server hash */
+        usize, /* depth */
+        crate::QueuePolicy,
+    ),
+
+    /// Make several non-blocking syscalls in one trip into the kernel. `range`
+    /// holds up to `MAX_BATCH_CALLS` calls, each as the `BATCH_CALL_WORDS`
+    /// words that `SysCall::as_args()` gives for it. The calls are made in
+    /// order, and each is overwritten with its result, as the words that
+    /// `Result::to_args()` gives for it. A call that fails leaves an `Error`
+    /// result in its place, and the rest of the batch still runs.
+    ///
+    /// Only the calls `SysCall::can_batch()` allows may be batched; if any
+    /// other is in the list, none of them are made.
+    ///
+    /// # Returns
+    ///
+    /// * **Scalar1**: The number of calls that were made
+    ///
+    /// # Errors
+    ///
+    /// * **InvalidSyscall**: A call in the batch could block or can't be
+    ///                       decoded, or there are too many of them
+    /// * **BadAlignment**: `range` isn't word-aligned, or isn't a whole number
+    ///                     of calls
+    /// * **BadAddress**: `range` isn't writable memory belonging to the caller
+    Batch(MemoryRange),
+
+    /// Arm this process' watchdog, which must then be petted with
+    /// `PetWatchdog` at least every `ms` milliseconds. If a deadline passes
+    /// without one, the kernel terminates the process. Arming it again
+    /// replaces the old period and restarts the countdown, and an `ms` of 0
+    /// disarms it.
+    ///
+    /// # Returns
+    ///
+    /// * **Ok**: The watchdog is armed, or disarmed
+    ///
+    /// # Errors
+    ///
+    /// * **AccessDenied**: PID 1 can't be terminated, so it can't have a
+    ///                     watchdog
+    SetWatchdog(usize /* period in ms */),
+
+    /// Push this process' watchdog deadline back to a full period from now.
+    ///
+    /// # Returns
+    ///
+    /// * **Ok**: The deadline has moved
+    ///
+    /// # Errors
+    ///
+    /// * **UseBeforeInit**: The watchdog isn't armed
+    PetWatchdog,
+
+    /// Read how quickly the kernel has been getting to an interrupt's
+    /// handler. Latency runs from the entry of the first trap the kernel
+    /// takes with the interrupt asserted, even if masked behind another
+    /// handler, until it jumps to the handler. The time before that first
+    /// trap can't be seen, so it's a lower bound. Handler time runs from
+    /// then until the handler returns. Both are measured in ticks of the
+    /// platform timer, or in microseconds in hosted mode. Only kernels built
+    /// with the `irq-latency` feature keep these.
+    ///
+    /// # Returns
+    ///
+    /// * **Scalar5**: The number of times the handler has run, then the
+    ///                maximum and average latency, then the maximum and
+    ///                average handler time
+    ///
+    /// # Errors
+    ///
+    /// * **InterruptNotFound**: There's no such interrupt
+    /// * **UnhandledSyscall**: The kernel doesn't measure interrupt latency
+    GetInterruptLatency(usize /* IRQ number */),
+
     /// This syscall does not exist. It captures all possible
     /// arguments so detailed analysis can be performed.
     Invalid(usize, usize, usize, usize, usize, usize, usize),
@@ -468,6 +810,29 @@ pub enum SysCallNumber {
     Disconnect = 35,
     JoinThread = 36,
     SetExceptionHandler = 37,
+    CancelMessage = 38,
+    TerminateChildProcess = 39,
+    MapSharedMemory = 40,
+    UnmapSharedMemory = 41,
+    SetInterruptEnabled = 42,
+    YieldTo = 43,
+    GetProcessInfo = 44,
+    GetCpuTime = 45,
+    SetMemoryQuota = 46,
+    ConnectWithTimeout = 47,
+    WaitForEvent = 48,
+    SignalEvent = 49,
+    SleepMs = 50,
+    GetMonotonicMs = 51,
+    SetThreadName = 52,
+    CreateServerWithToken = 53,
+    ConnectWithToken = 54,
+    ReturnScalar5 = 55,
+    CreateServerWithQueue = 56,
+    Batch = 57,
+    SetWatchdog = 58,
+    PetWatchdog = 59,
+    GetInterruptLatency = 60,
     Invalid,
 }
 
@@ -511,6 +876,29 @@ impl SysCallNumber {
             35 => Disconnect,
             36 => JoinThread,
             37 => SetExceptionHandler,
+            38 => CancelMessage,
+            39 => TerminateChildProcess,
+            40 => MapSharedMemory,
+            41 => UnmapSharedMemory,
+            42 => SetInterruptEnabled,
+            43 => YieldTo,
+            44 => GetProcessInfo,
+            45 => GetCpuTime,
+            46 => SetMemoryQuota,
+            47 => ConnectWithTimeout,
+            48 => WaitForEvent,
+            49 => SignalEvent,
+            50 => SleepMs,
+            51 => GetMonotonicMs,
+            52 => SetThreadName,
+            53 => CreateServerWithToken,
+            54 => ConnectWithToken,
+            55 => ReturnScalar5,
+            56 => CreateServerWithQueue,
+            57 => Batch,
+            58 => SetWatchdog,
+            59 => PetWatchdog,
+            60 => GetInterruptLatency,
             _ => Invalid,
         }
     }
@@ -615,12 +1003,12 @@ impl SysCall {
                 0,
                 0,
             ],
-            SysCall::ClaimInterrupt(a1, a2, a3) => [
+            SysCall::ClaimInterrupt(a1, a2, a3, a4) => [
                 SysCallNumber::ClaimInterrupt as usize,
                 *a1,
                 a2.get(),
                 a3.map(|x| x.get()).unwrap_or_default(),
-                0,
+                *a4,
                 0,
                 0,
                 0,
@@ -865,6 +1253,227 @@ impl SysCall {
                 0,
                 0,
             ],
+            SysCall::CancelMessage(tid) => [
+                SysCallNumber::CancelMessage as usize,
+                *tid as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            SysCall::TerminateChildProcess(pid) => [
+                SysCallNumber::TerminateChildProcess as usize,
+                pid.get() as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            SysCall::MapSharedMemory(name, size, flags) => {
+                let name = name.to_u32();
+                [
+                    SysCallNumber::MapSharedMemory as usize,
+                    name.0 as _,
+                    name.1 as _,
+                    name.2 as _,
+                    name.3 as _,
+                    size.get(),
+                    flags.bits(),
+                    0,
+                ]
+            }
+            SysCall::UnmapSharedMemory(name) => {
+                let name = name.to_u32();
+                [
+                    SysCallNumber::UnmapSharedMemory as usize,
+                    name.0 as _,
+                    name.1 as _,
+                    name.2 as _,
+                    name.3 as _,
+                    0,
+                    0,
+                    0,
+                ]
+            }
+            SysCall::SetInterruptEnabled(irq, enabled) => [
+                SysCallNumber::SetInterruptEnabled as usize,
+                *irq,
+                if *enabled { 1 } else { 0 },
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            SysCall::YieldTo(pid) => [
+                SysCallNumber::YieldTo as usize,
+                pid.get() as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            SysCall::GetProcessInfo(pid) => [
+                SysCallNumber::GetProcessInfo as usize,
+                pid.get() as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            SysCall::GetCpuTime(pid) => [
+                SysCallNumber::GetCpuTime as usize,
+                pid.get() as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            SysCall::SetMemoryQuota(pid, pages) => [
+                SysCallNumber::SetMemoryQuota as usize,
+                pid.get() as usize,
+                *pages,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            SysCall::ConnectWithTimeout(sid, timeout_ms) => {
+                let s = sid.to_u32();
+                [
+                    SysCallNumber::ConnectWithTimeout as usize,
+                    s.0 as _,
+                    s.1 as _,
+                    s.2 as _,
+                    s.3 as _,
+                    *timeout_ms,
+                    0,
+                    0,
+                ]
+            }
+            SysCall::WaitForEvent(event) => [
+                SysCallNumber::WaitForEvent as usize,
+                *event,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            SysCall::SignalEvent(event) => [
+                SysCallNumber::SignalEvent as usize,
+                *event,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            SysCall::SleepMs(ms) => [SysCallNumber::SleepMs as usize, *ms, 0, 0, 0, 0, 0, 0],
+            SysCall::CreateServerWithToken(sid, token) => {
+                let s = sid.to_u32();
+                let t = token.to_u32();
+                [
+                    SysCallNumber::CreateServerWithToken as usize,
+                    s.0 as _,
+                    s.1 as _,
+                    s.2 as _,
+                    s.3 as _,
+                    t.0 as _,
+                    t.1 as _,
+                    t.2 as _,
+                ]
+            }
+            SysCall::ConnectWithToken(sid, token) => {
+                let s = sid.to_u32();
+                let t = token.to_u32();
+                [
+                    SysCallNumber::ConnectWithToken as usize,
+                    s.0 as _,
+                    s.1 as _,
+                    s.2 as _,
+                    s.3 as _,
+                    t.0 as _,
+                    t.1 as _,
+                    t.2 as _,
+                ]
+            }
+            SysCall::ReturnScalar5(sender, arg1, arg2, arg3, arg4, arg5) => [
+                SysCallNumber::ReturnScalar5 as usize,
+                sender.to_usize(),
+                *arg1,
+                *arg2,
+                *arg3,
+                *arg4,
+                *arg5,
+                0,
+            ],
+            SysCall::CreateServerWithQueue(sid, depth, policy) => {
+                let s = sid.to_u32();
+                [
+                    SysCallNumber::CreateServerWithQueue as usize,
+                    s.0 as _,
+                    s.1 as _,
+                    s.2 as _,
+                    s.3 as _,
+                    *depth,
+                    *policy as usize,
+                    0,
+                ]
+            }
+            SysCall::SetThreadName(name) => {
+                let words = name.to_words();
+                [
+                    SysCallNumber::SetThreadName as usize,
+                    words[0],
+                    words[1],
+                    words[2],
+                    0,
+                    0,
+                    0,
+                    0,
+                ]
+            }
+            SysCall::GetMonotonicMs => {
+                [SysCallNumber::GetMonotonicMs as usize, 0, 0, 0, 0, 0, 0, 0]
+            }
+            SysCall::Batch(range) => [
+                SysCallNumber::Batch as usize,
+                range.as_ptr() as usize,
+                range.len(),
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            SysCall::SetWatchdog(ms) => {
+                [SysCallNumber::SetWatchdog as usize, *ms, 0, 0, 0, 0, 0, 0]
+            }
+            SysCall::PetWatchdog => [SysCallNumber::PetWatchdog as usize, 0, 0, 0, 0, 0, 0, 0],
+            SysCall::GetInterruptLatency(irq) => [
+                SysCallNumber::GetInterruptLatency as usize,
+                *irq,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
             SysCall::Invalid(a1, a2, a3, a4, a5, a6, a7) => [
                 SysCallNumber::Invalid as usize,
                 *a1,
@@ -912,6 +1521,7 @@ impl SysCall {
                 a1,
                 MemoryAddress::new(a2).ok_or(Error::InvalidSyscall)?,
                 MemoryAddress::new(a3),
+                a4,
             ),
             SysCallNumber::FreeInterrupt => SysCall::FreeInterrupt(a1),
             SysCallNumber::SwitchTo => SysCall::SwitchTo(pid_from_usize(a1)?, a2 as usize),
@@ -1028,6 +1638,53 @@ impl SysCall {
             SysCallNumber::Disconnect => SysCall::Disconnect(a1 as _),
             SysCallNumber::JoinThread => SysCall::JoinThread(a1 as _),
             SysCallNumber::SetExceptionHandler => SysCall::SetExceptionHandler(a1 as _, a2 as _),
+            SysCallNumber::CancelMessage => SysCall::CancelMessage(a1 as _),
+            SysCallNumber::TerminateChildProcess => {
+                SysCall::TerminateChildProcess(pid_from_usize(a1)?)
+            }
+            SysCallNumber::MapSharedMemory => SysCall::MapSharedMemory(
+                SID::from_u32(a1 as _, a2 as _, a3 as _, a4 as _),
+                MemorySize::new(a5).ok_or(Error::InvalidSyscall)?,
+                crate::MemoryFlags::from_bits(a6).ok_or(Error::InvalidSyscall)?,
+            ),
+            SysCallNumber::UnmapSharedMemory => {
+                SysCall::UnmapSharedMemory(SID::from_u32(a1 as _, a2 as _, a3 as _, a4 as _))
+            }
+            SysCallNumber::SetInterruptEnabled => SysCall::SetInterruptEnabled(a1, a2 != 0),
+            SysCallNumber::YieldTo => SysCall::YieldTo(pid_from_usize(a1)?),
+            SysCallNumber::GetProcessInfo => SysCall::GetProcessInfo(pid_from_usize(a1)?),
+            SysCallNumber::GetCpuTime => SysCall::GetCpuTime(pid_from_usize(a1)?),
+            SysCallNumber::SetMemoryQuota => SysCall::SetMemoryQuota(pid_from_usize(a1)?, a2),
+            SysCallNumber::ConnectWithTimeout => {
+                SysCall::ConnectWithTimeout(SID::from_u32(a1 as _, a2 as _, a3 as _, a4 as _), a5)
+            }
+            SysCallNumber::WaitForEvent => SysCall::WaitForEvent(a1),
+            SysCallNumber::SignalEvent => SysCall::SignalEvent(a1),
+            SysCallNumber::SleepMs => SysCall::SleepMs(a1),
+            SysCallNumber::GetMonotonicMs => SysCall::GetMonotonicMs,
+            SysCallNumber::CreateServerWithToken => SysCall::CreateServerWithToken(
+                SID::from_u32(a1 as _, a2 as _, a3 as _, a4 as _),
+                crate::ConnectToken::from_u32(a5 as _, a6 as _, a7 as _),
+            ),
+            SysCallNumber::ConnectWithToken => SysCall::ConnectWithToken(
+                SID::from_u32(a1 as _, a2 as _, a3 as _, a4 as _),
+                crate::ConnectToken::from_u32(a5 as _, a6 as _, a7 as _),
+            ),
+            SysCallNumber::SetThreadName => {
+                SysCall::SetThreadName(crate::ThreadName::from_words(&[a1, a2, a3]))
+            }
+            SysCallNumber::ReturnScalar5 => {
+                SysCall::ReturnScalar5(MessageSender::from_usize(a1), a2, a3, a4, a5, a6)
+            }
+            SysCallNumber::CreateServerWithQueue => SysCall::CreateServerWithQueue(
+                SID::from_u32(a1 as _, a2 as _, a3 as _, a4 as _),
+                a5,
+                crate::QueuePolicy::from_usize(a6).ok_or(Error::InvalidSyscall)?,
+            ),
+            SysCallNumber::Batch => SysCall::Batch(unsafe { MemoryRange::new(a1, a2) }?),
+            SysCallNumber::SetWatchdog => SysCall::SetWatchdog(a1),
+            SysCallNumber::PetWatchdog => SysCall::PetWatchdog,
+            SysCallNumber::GetInterruptLatency => SysCall::GetInterruptLatency(a1),
             SysCallNumber::Invalid => SysCall::Invalid(a1, a2, a3, a4, a5, a6, a7),
         })
     }
@@ -1042,6 +1699,7 @@ impl SysCall {
                 )
             }
             SysCall::ReturnMemory(_, _, _, _) => true,
+            SysCall::Batch(_) => true,
             _ => false,
         }
     }
@@ -1066,12 +1724,14 @@ impl SysCall {
         }
     }
 
-    /// Returns `true` if the associated syscall is a message that is a MutableBorrow
+    /// Returns `true` if the associated syscall is a message that is a MutableBorrow,
+    /// or is a `Batch`, whose memory the kernel writes the results back to
     pub fn is_mutableborrow(&self) -> bool {
         match self {
             SysCall::TrySendMessage(_, msg) | SysCall::SendMessage(_, msg) => {
                 matches!(msg, Message::MutableBorrow(_))
             }
+            SysCall::Batch(_) => true,
             _ => false,
         }
     }
@@ -1091,6 +1751,7 @@ impl SysCall {
                 _ => None,
             },
             SysCall::ReturnMemory(_, range, _, _) => Some(*range),
+            SysCall::Batch(range) => Some(*range),
             _ => None,
         }
     }
@@ -1110,6 +1771,7 @@ impl SysCall {
                 _ => None,
             },
             SysCall::ReturnMemory(_, range, _, _) => Some(range),
+            SysCall::Batch(range) => Some(range),
             _ => None,
         }
     }
@@ -1125,8 +1787,26 @@ impl SysCall {
                 | SysCall::TryReceiveMessage(_)
                 | SysCall::ReturnToParent(_, _)
                 | SysCall::ReturnScalar2(_, _, _)
+                | SysCall::ReturnScalar5(_, _, _, _, _, _)
                 | SysCall::ReturnScalar1(_, _)
                 | SysCall::ReturnMemory(_, _, _, _)
+                | SysCall::SignalEvent(_)
+                | SysCall::GetMonotonicMs
+        )
+    }
+
+    /// Returns `true` if the given syscall may be made as part of a `Batch`.
+    /// These never block or switch to another thread, and have no memory
+    /// attached.
+    pub fn can_batch(&self) -> bool {
+        matches!(
+            self,
+            SysCall::GetMonotonicMs
+                | SysCall::GetThreadId
+                | SysCall::GetProcessId
+                | SysCall::GetCpuTime(_)
+                | SysCall::GetProcessInfo(_)
+                | SysCall::TryConnect(_)
         )
     }
 }
@@ -1172,6 +1852,37 @@ pub fn unmap_memory(range: MemoryRange) -> core::result::Result<(), Error> {
     }
 }
 
+/// Map the shared memory object called `name` into this process, creating it
+/// if nobody has mapped it yet. All processes that map the same name see each
+/// other's writes. The size must match the size the object was created with.
+pub fn map_shared_memory(
+    name: SID,
+    size: usize,
+    flags: MemoryFlags,
+) -> core::result::Result<MemoryRange, Error> {
+    crate::arch::map_shared_memory_pre(&name, size, flags)?;
+    let size = MemorySize::new(size).ok_or(Error::BadAlignment)?;
+    rsyscall(SysCall::MapSharedMemory(name, size, flags)).and_then(|result| {
+        if let Result::MemoryRange(range) = result {
+            crate::arch::map_shared_memory_post(name, range)
+        } else {
+            Err(Error::InternalError)
+        }
+    })
+}
+
+/// Unmap a shared memory object from this process. The backing memory is
+/// freed once every process has unmapped it.
+pub fn unmap_shared_memory(name: SID) -> core::result::Result<(), Error> {
+    rsyscall(SysCall::UnmapSharedMemory(name)).and_then(|result| {
+        if let Result::Scalar1(remaining) = result {
+            crate::arch::unmap_shared_memory_post(name, remaining)
+        } else {
+            Err(Error::InternalError)
+        }
+    })
+}
+
 /// Update the permissions on the given memory range. Note that permissions may
 /// only be stripped here -- they may never be added.
 pub fn update_memory_flags(
@@ -1266,16 +1977,51 @@ pub fn return_scalar2(
     }
 }
 
-/// Claim a hardware interrupt for this process.
+/// Answer a blocking scalar message with five values, which the sender
+/// receives as `Result::Scalar5`.
+pub fn return_scalar5(
+    sender: MessageSender,
+    val1: usize,
+    val2: usize,
+    val3: usize,
+    val4: usize,
+    val5: usize,
+) -> core::result::Result<(), Error> {
+    let result = rsyscall(SysCall::ReturnScalar5(sender, val1, val2, val3, val4, val5))?;
+    if let crate::Result::Ok = result {
+        Ok(())
+    } else if let Result::Error(e) = result {
+        Err(e)
+    } else {
+        Err(Error::InternalError)
+    }
+}
+
+/// The priority given to interrupts claimed with `claim_interrupt()`.
+pub const IRQ_PRIORITY_DEFAULT: usize = 0;
+
+/// Claim a hardware interrupt for this process at the default priority.
 pub fn claim_interrupt(
     irq_no: usize,
     callback: fn(irq_no: usize, arg: *mut usize),
     arg: *mut usize,
+) -> core::result::Result<(), Error> {
+    claim_interrupt_with_priority(irq_no, callback, arg, IRQ_PRIORITY_DEFAULT)
+}
+
+/// Claim a hardware interrupt for this process. If several interrupts are
+/// pending at once, the one with the highest `priority` is handled first.
+pub fn claim_interrupt_with_priority(
+    irq_no: usize,
+    callback: fn(irq_no: usize, arg: *mut usize),
+    arg: *mut usize,
+    priority: usize,
 ) -> core::result::Result<(), Error> {
     let result = rsyscall(SysCall::ClaimInterrupt(
         irq_no,
         MemoryAddress::new(callback as *mut usize as usize).ok_or(Error::InvalidSyscall)?,
         MemoryAddress::new(arg as *mut usize as usize),
+        priority,
     ))?;
     if let crate::Result::Ok = result {
         Ok(())
@@ -1286,6 +2032,18 @@ pub fn claim_interrupt(
     }
 }
 
+/// Mask or unmask a hardware interrupt that this process has claimed. While
+/// masked, the interrupt stays pending and its handler is not called.
+pub fn set_interrupt_enabled(irq_no: usize, enabled: bool) -> core::result::Result<(), Error> {
+    rsyscall(SysCall::SetInterruptEnabled(irq_no, enabled)).and_then(|result| {
+        if let Result::Ok = result {
+            Ok(())
+        } else {
+            Err(Error::InternalError)
+        }
+    })
+}
+
 /// Create a new server with the given name.  This enables other processes to
 /// connect to this server to send messages.  The name is a UTF-8 token that
 /// will be mixed with other random data that is unique to each process.
@@ -1312,6 +2070,56 @@ pub fn create_server_with_address(name_bytes: &[u8; 16]) -> core::result::Result
     }
 }
 
+/// Create a new server with the given name that only accepts connections from
+/// processes that present `token` to `connect_with_token()`.
+///
+/// # Errors
+///
+/// * **OutOfMemory**: No more servers may be created
+/// * **InvalidString**: The name was not a valid UTF-8 string
+pub fn create_server_with_token(
+    name_bytes: &[u8; 16],
+    token: crate::ConnectToken,
+) -> core::result::Result<SID, Error> {
+    let sid = SID::from_bytes(name_bytes).ok_or(Error::InvalidString)?;
+
+    let result = rsyscall(SysCall::CreateServerWithToken(sid, token))?;
+    if let Result::NewServerID(sid, _cid) = result {
+        Ok(sid)
+    } else if let Result::Error(e) = result {
+        Err(e)
+    } else {
+        Err(Error::InternalError)
+    }
+}
+
+/// Create a new server with the given name whose queue holds at most `depth`
+/// messages it has not yet received. A message sent to a full queue is dealt
+/// with according to `policy`.
+///
+/// # Errors
+///
+/// * **OutOfMemory**: No more servers may be created, or the queue can't hold
+///                    `depth` messages
+/// * **InvalidSyscall**: `depth` was 0
+/// * **InvalidString**: The name was not a valid UTF-8 string
+pub fn create_server_with_queue(
+    name_bytes: &[u8; 16],
+    depth: usize,
+    policy: crate::QueuePolicy,
+) -> core::result::Result<SID, Error> {
+    let sid = SID::from_bytes(name_bytes).ok_or(Error::InvalidString)?;
+
+    let result = rsyscall(SysCall::CreateServerWithQueue(sid, depth, policy))?;
+    if let Result::NewServerID(sid, _cid) = result {
+        Ok(sid)
+    } else if let Result::Error(e) = result {
+        Err(e)
+    } else {
+        Err(Error::InternalError)
+    }
+}
+
 /// Create a new server with the given SID.  This enables other processes to
 /// connect to this server to send messages.  The name is a unique 128-bit SID.
 /// That way, if a process crashes and is restarted, it can keep the same
@@ -1401,13 +2209,44 @@ pub fn try_connect(server: SID) -> core::result::Result<CID, Error> {
     }
 }
 
+/// Connect to a server with the given SID, presenting `token`. Servers created
+/// with `create_server_with_token()` reject connections with any other token
+/// with `AccessDenied`.
+pub fn connect_with_token(
+    server: SID,
+    token: crate::ConnectToken,
+) -> core::result::Result<CID, Error> {
+    let result = rsyscall(SysCall::ConnectWithToken(server, token))?;
+    if let Result::ConnectionID(cid) = result {
+        Ok(cid)
+    } else if let Result::Error(e) = result {
+        Err(e)
+    } else {
+        Err(Error::InternalError)
+    }
+}
+
+/// Connect to a server with the given SID, giving up with `ServerNotFound` if
+/// it hasn't been created within `timeout_ms` milliseconds
+pub fn connect_with_timeout(server: SID, timeout_ms: usize) -> core::result::Result<CID, Error> {
+    let result = rsyscall(SysCall::ConnectWithTimeout(server, timeout_ms))?;
+    if let Result::ConnectionID(cid) = result {
+        Ok(cid)
+    } else if let Result::Error(e) = result {
+        Err(e)
+    } else {
+        Err(Error::InternalError)
+    }
+}
+
 /// Suspend the current process until a message is received.  This thread will
 /// block until a message is received.
 ///
 /// # Errors
 ///
+/// * **ServerNotFound**: The server does not exist or is not owned by this process
 pub fn receive_message(server: SID) -> core::result::Result<MessageEnvelope, Error> {
-    let result = rsyscall(SysCall::ReceiveMessage(server)).expect("Couldn't call ReceiveMessage");
+    let result = rsyscall(SysCall::ReceiveMessage(server))?;
     if let Result::Message(envelope) = result {
         Ok(envelope)
     } else if let Result::Error(e) = result {
@@ -1422,9 +2261,9 @@ pub fn receive_message(server: SID) -> core::result::Result<MessageEnvelope, Err
 ///
 /// # Errors
 ///
+/// * **ServerNotFound**: The server does not exist or is not owned by this process
 pub fn try_receive_message(server: SID) -> core::result::Result<Option<MessageEnvelope>, Error> {
-    let result =
-        rsyscall(SysCall::TryReceiveMessage(server)).expect("Couldn't call ReceiveMessage");
+    let result = rsyscall(SysCall::TryReceiveMessage(server))?;
     if let Result::Message(envelope) = result {
         Ok(Some(envelope))
     } else if result == Result::None {
@@ -1453,6 +2292,7 @@ pub fn try_send_message(connection: CID, message: Message) -> core::result::Resu
         Ok(Result::Ok) => Ok(Result::Ok),
         Ok(Result::Scalar1(a)) => Ok(Result::Scalar1(a)),
         Ok(Result::Scalar2(a, b)) => Ok(Result::Scalar2(a, b)),
+        Ok(Result::Scalar5(a, b, c, d, e)) => Ok(Result::Scalar5(a, b, c, d, e)),
         Ok(Result::MemoryReturned(offset, valid)) => Ok(Result::MemoryReturned(offset, valid)),
         Err(e) => Err(e),
         v => panic!("Unexpected return value: {:?}", v),
@@ -1495,6 +2335,7 @@ pub fn send_message(connection: CID, message: Message) -> core::result::Result<R
         Ok(Result::Ok) => Ok(Result::Ok),
         Ok(Result::Scalar1(a)) => Ok(Result::Scalar1(a)),
         Ok(Result::Scalar2(a, b)) => Ok(Result::Scalar2(a, b)),
+        Ok(Result::Scalar5(a, b, c, d, e)) => Ok(Result::Scalar5(a, b, c, d, e)),
         Ok(Result::MemoryReturned(offset, valid)) => Ok(Result::MemoryReturned(offset, valid)),
         Err(e) => Err(e),
         v => panic!("Unexpected return value: {:?}", v),
@@ -1506,12 +2347,155 @@ pub fn terminate_process(exit_code: u32) -> ! {
     panic!("process didn't terminate");
 }
 
+/// Terminate a child of the current process and reclaim its resources
+pub fn terminate_child_process(pid: PID) -> core::result::Result<(), Error> {
+    rsyscall(SysCall::TerminateChildProcess(pid)).map(|_| ())
+}
+
 /// Return execution to the kernel. This function may return at any time,
 /// including immediately
 pub fn yield_slice() {
     rsyscall(SysCall::Yield).ok();
 }
 
+/// Hand execution directly to another process. If it has nothing to run, this
+/// is the same as `yield_slice()`.
+pub fn yield_to(pid: PID) {
+    rsyscall(SysCall::YieldTo(pid)).ok();
+}
+
+/// Look up the parent, heap layout, and thread count of the given process.
+pub fn get_process_info(pid: PID) -> core::result::Result<crate::ProcessInfo, Error> {
+    rsyscall(SysCall::GetProcessInfo(pid)).and_then(|result| {
+        if let Result::ProcessInfo(info) = result {
+            Ok(info)
+        } else {
+            Err(Error::InternalError)
+        }
+    })
+}
+
+/// Return how long the given process has spent running, in ticks of the
+/// platform timer. In hosted mode this is in microseconds.
+pub fn get_cpu_time(pid: PID) -> core::result::Result<u64, Error> {
+    rsyscall(SysCall::GetCpuTime(pid)).and_then(|result| {
+        if let Result::Scalar2(low, high) = result {
+            Ok((low as u32 as u64) | ((high as u32 as u64) << 32))
+        } else {
+            Err(Error::InternalError)
+        }
+    })
+}
+
+/// Return the number of milliseconds since the system booted.
+pub fn get_monotonic_ms() -> core::result::Result<u64, Error> {
+    rsyscall(SysCall::GetMonotonicMs).and_then(|result| {
+        if let Result::Scalar2(low, high) = result {
+            Ok((low as u32 as u64) | ((high as u32 as u64) << 32))
+        } else {
+            Err(Error::InternalError)
+        }
+    })
+}
+
+/// Give the calling thread a name, which shows up in kernel debug output and in
+/// `get_process_info()`. Names longer than `THREAD_NAME_LEN` bytes are truncated.
+pub fn set_thread_name(name: &str) -> core::result::Result<(), Error> {
+    rsyscall(SysCall::SetThreadName(crate::ThreadName::new(name))).map(|_| ())
+}
+
+/// Make each of `calls` in one trip into the kernel, and return their results
+/// in the same order. Each call's result is what `rsyscall()` would have
+/// returned for it, except that errors come back as `Result::Error`.
+///
+/// # Errors
+///
+/// * **InvalidSyscall**: One of `calls` isn't allowed in a batch (see
+///                       `SysCall::can_batch()`), in which case none of them
+///                       were made, or there are more than `MAX_BATCH_CALLS`
+pub fn batch<const N: usize>(calls: &[SysCall; N]) -> core::result::Result<[Result; N], Error> {
+    let mut words = [[0usize; BATCH_CALL_WORDS]; N];
+    if N == 0 {
+        return Ok(core::array::from_fn(|_| Result::Ok));
+    }
+    for (entry, call) in words.iter_mut().zip(calls.iter()) {
+        *entry = call.as_args();
+    }
+    let range = unsafe {
+        MemoryRange::new(words.as_mut_ptr() as usize, core::mem::size_of_val(&words))
+    }?;
+    match rsyscall(SysCall::Batch(range))? {
+        Result::Scalar1(count) if count == N => {
+            Ok(core::array::from_fn(|i| Result::from_args(words[i])))
+        }
+        Result::Error(e) => Err(e),
+        _ => Err(Error::InternalError),
+    }
+}
+
+/// Arm this process' watchdog with a period of `ms` milliseconds, or disarm it
+/// if `ms` is 0. Once armed, the process is terminated if it goes a whole
+/// period without calling `pet_watchdog()`.
+pub fn set_watchdog(ms: usize) -> core::result::Result<(), Error> {
+    rsyscall(SysCall::SetWatchdog(ms)).map(|_| ())
+}
+
+/// Restart this process' watchdog countdown.
+pub fn pet_watchdog() -> core::result::Result<(), Error> {
+    rsyscall(SysCall::PetWatchdog).map(|_| ())
+}
+
+/// Return how quickly the kernel has been getting to the handler for `irq`.
+/// Only kernels built with the `irq-latency` feature measure this; others
+/// return `Error::UnhandledSyscall`.
+pub fn get_interrupt_latency(irq: usize) -> core::result::Result<InterruptLatency, Error> {
+    match rsyscall(SysCall::GetInterruptLatency(irq))? {
+        Result::Scalar5(count, max_latency, avg_latency, max_handler, avg_handler) => {
+            Ok(InterruptLatency {
+                count,
+                max_latency,
+                avg_latency,
+                max_handler,
+                avg_handler,
+            })
+        }
+        Result::Error(e) => Err(e),
+        _ => Err(Error::InternalError),
+    }
+}
+
+/// Limit how many pages of memory the child process `pid` may have mapped or
+/// reserved at once.
+pub fn set_memory_quota(pid: PID, pages: usize) -> core::result::Result<(), Error> {
+    rsyscall(SysCall::SetMemoryQuota(pid, pages)).map(|_| ())
+}
+
+/// Park this thread until `event` is signaled with `signal_event()` by
+/// another thread or an interrupt handler in this process. Returns at once
+/// if `event` was signaled since the last wait on it.
+pub fn wait_for_event(event: usize) -> core::result::Result<(), Error> {
+    rsyscall(SysCall::WaitForEvent(event)).map(|_| ())
+}
+
+/// Wake every thread in this process waiting on `event`, returning how many
+/// were woken. A signal with no waiters is kept for the next thread to wait
+/// on `event`.
+pub fn signal_event(event: usize) -> core::result::Result<usize, Error> {
+    rsyscall(SysCall::SignalEvent(event)).and_then(|result| {
+        if let Result::Scalar1(count) = result {
+            Ok(count)
+        } else {
+            Err(Error::InternalError)
+        }
+    })
+}
+
+/// Park this thread for at least `ms` milliseconds without using any CPU time.
+/// A duration of 0 gives up the rest of this quantum, like `yield_slice()`.
+pub fn sleep_ms(ms: usize) -> core::result::Result<(), Error> {
+    rsyscall(SysCall::SleepMs(ms)).map(|_| ())
+}
+
 /// Return execution to the kernel and wait for a message or an interrupt.
 pub fn wait_event() {
     rsyscall(SysCall::WaitEvent).ok();
@@ -1691,6 +2675,12 @@ pub fn wait_process(joiner: crate::arch::ProcessHandle) -> SysCallResult {
     crate::arch::wait_process(joiner)
 }
 
+/// Run the given thread of a child process. A newly-created process will not
+/// run until its parent switches to it at least once.
+pub fn switch_to(pid: PID, tid: TID) -> core::result::Result<(), Error> {
+    rsyscall(SysCall::SwitchTo(pid, tid)).map(|_| ())
+}
+
 /// Get the current process ID
 pub fn current_pid() -> core::result::Result<PID, Error> {
     rsyscall(SysCall::GetProcessId).and_then(|result| {
@@ -1760,6 +2750,21 @@ pub fn join_thread(tid: TID) -> core::result::Result<usize, Error> {
         }
     })
 }
+
+/// Abandon the blocking scalar message that thread `tid` of this process is
+/// waiting on, causing its `send_message()` to return `Err(Error::Timeout)`.
+/// This is the building block for sends with a timeout: a second thread waits
+/// out the deadline and then cancels the send.
+///
+/// Returns `true` if a message was cancelled, or `false` if the thread was
+/// not waiting on a response.
+pub fn cancel_message(tid: TID) -> core::result::Result<bool, Error> {
+    rsyscall(SysCall::CancelMessage(tid)).and_then(|result| match result {
+        Result::Ok => Ok(true),
+        Result::None => Ok(false),
+        _ => Err(Error::InternalError),
+    })
+}
 /* https://github.com/betrusted-io/xous-core/issues/90
 static EXCEPTION_HANDLER: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
 fn handle_exception(exception_type: usize, arg1: usize, arg2: usize) -> isize {